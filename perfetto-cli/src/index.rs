@@ -0,0 +1,82 @@
+use anyhow::{Context as _, Result, bail};
+use serde_json::Value;
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+/// The files the index at `path` lists with events in `window`, and of
+/// `session` if given, in the order of their first events.
+pub(crate) fn files_in(
+    path: &Path,
+    window: Range<u64>,
+    session: Option<&str>,
+) -> Result<Vec<PathBuf>> {
+    let index = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut files = Vec::new();
+    for (line, entry) in index.lines().enumerate() {
+        if entry.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(entry)
+            .with_context(|| format!("parsing {}:{}", path.display(), line + 1))?;
+        let (Some(file), Some(start), Some(end)) = (
+            entry["file"].as_str(),
+            entry["start"].as_u64(),
+            entry["end"].as_u64(),
+        ) else {
+            bail!("{}:{} is not an index entry", path.display(), line + 1);
+        };
+        let in_session = session.is_none_or(|session| {
+            entry["sessions"]
+                .as_array()
+                .is_some_and(|ids| ids.iter().any(|id| id.as_str() == Some(session)))
+        });
+        if in_session && start < window.end && end >= window.start {
+            files.push((start, dir.join(file)));
+        }
+    }
+    files.sort_by_key(|(start, _)| *start);
+    Ok(files.into_iter().map(|(_, file)| file).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_writer::IndexEntry;
+
+    #[test]
+    fn picks_the_files_overlapping_the_window() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("perfetto-index-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let index = dir.join("index.jsonl");
+        let _ = fs::remove_file(&index);
+        for (file, start, end, session) in [
+            ("c", 300, 399, "2"),
+            ("a", 100, 199, "1"),
+            ("b", 200, 299, "1"),
+        ] {
+            IndexEntry {
+                file: file.into(),
+                start,
+                end,
+                sessions: vec![session.into()],
+            }
+            .append(&index)?;
+        }
+
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().display().to_string())
+                .collect()
+        };
+        assert_eq!(names(files_in(&index, 150..350, None)?), ["a", "b", "c"]);
+        assert_eq!(names(files_in(&index, 200..300, None)?), ["b"]);
+        assert_eq!(names(files_in(&index, 0..u64::MAX, Some("1"))?), ["a", "b"]);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
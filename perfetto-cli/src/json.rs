@@ -0,0 +1,306 @@
+use perfetto_writer::protos::{
+    debug_annotation::DebugAnnotation, trace::Trace, track_event::track_event::Type,
+};
+use serde_json::{Map, Value, json};
+use std::collections::{HashMap, HashSet};
+
+use crate::resolve::{Interned, Resolver};
+
+/// Where a track is drawn in the JSON trace event format, which only knows
+/// processes and threads.
+struct Lane {
+    pid: i64,
+    tid: i64,
+}
+
+/// The annotation a `Color` is written as.
+const COLOR_ARG: &str = "cname";
+
+/// Converts `trace` to the Chrome JSON trace event format, for tools that
+/// don't read protobuf traces.
+///
+/// Thread and process tracks keep their ids. Other tracks become a named
+/// thread of their own, with a tid of its own, under their process if they
+/// have one. Counter
+/// values are keyed by their track's name. An event's color becomes its
+/// `cname`.
+pub fn to_json(trace: &Trace) -> Value {
+    let mut events = Vec::new();
+    let mut names: HashMap<u64, String> = HashMap::new();
+    let mut parents: HashMap<u64, u64> = HashMap::new();
+    let mut lanes: HashMap<u64, Lane> = HashMap::new();
+    for packet in &trace.packet {
+        if !packet.has_track_descriptor() {
+            continue;
+        }
+        let track = packet.track_descriptor();
+        names.insert(track.uuid(), track.name().to_string());
+        if track.has_parent_uuid() {
+            parents.insert(track.uuid(), track.parent_uuid());
+        }
+        if let Some(thread) = track.thread.as_ref() {
+            let lane = Lane {
+                pid: thread.pid().into(),
+                tid: thread.tid().into(),
+            };
+            let name = thread
+                .thread_name
+                .as_deref()
+                .filter(|n| !n.is_empty())
+                .unwrap_or(track.name());
+            events.push(metadata("thread_name", &lane, name));
+            lanes.insert(track.uuid(), lane);
+        } else if let Some(process) = track.process.as_ref() {
+            let lane = Lane {
+                pid: process.pid().into(),
+                tid: 0,
+            };
+            let name = process
+                .process_name
+                .as_deref()
+                .filter(|n| !n.is_empty())
+                .unwrap_or(track.name());
+            events.push(metadata("process_name", &lane, name));
+            lanes.insert(track.uuid(), lane);
+        }
+    }
+    let mut tracks: Vec<u64> = names.keys().copied().collect();
+    tracks.sort_unstable();
+    // Synthetic tids count up from a range real ones rarely reach, skipping
+    // any that are taken.
+    let taken: HashSet<i64> = lanes.values().map(|lane| lane.tid).collect();
+    let mut tids = (1 << 30..).filter(|tid| !taken.contains(tid));
+    for uuid in tracks {
+        if lanes.contains_key(&uuid) {
+            continue;
+        }
+        let mut pid = 0;
+        let mut parent = parents.get(&uuid);
+        while let Some(p) = parent {
+            if let Some(lane) = lanes.get(p) {
+                pid = lane.pid;
+                break;
+            }
+            parent = parents.get(p);
+        }
+        let lane = Lane {
+            pid,
+            tid: tids.next().unwrap(),
+        };
+        events.push(metadata("thread_name", &lane, &names[&uuid]));
+        lanes.insert(uuid, lane);
+    }
+
+    let mut resolver = Resolver::default();
+    for packet in &trace.packet {
+        let ts = resolver.update(packet);
+        if !packet.has_track_event() {
+            continue;
+        }
+        let seq = packet.trusted_packet_sequence_id();
+        let event = packet.track_event();
+        let Some(lane) = lanes.get(&event.track_uuid()) else {
+            continue;
+        };
+        let mut out = json!({
+            "pid": lane.pid,
+            "tid": lane.tid,
+            "ts": ts.unwrap_or_default() as f64 / 1000.0,
+        });
+        let phase = match event.type_() {
+            Type::TYPE_SLICE_BEGIN => "B",
+            Type::TYPE_SLICE_END => "E",
+            Type::TYPE_INSTANT => {
+                out["s"] = "t".into();
+                "i"
+            }
+            Type::TYPE_COUNTER => {
+                let value = if event.has_double_counter_value() {
+                    json!(event.double_counter_value())
+                } else {
+                    json!(event.counter_value())
+                };
+                let name = names.get(&event.track_uuid()).cloned().unwrap_or_default();
+                out["name"] = name.clone().into();
+                out["args"] = json!({ name: value });
+                out["ph"] = "C".into();
+                events.push(out);
+                continue;
+            }
+            _ => continue,
+        };
+        out["ph"] = phase.into();
+        if let Some(name) = resolver.event_name(seq, event) {
+            out["name"] = name.into();
+        }
+        let interned = resolver.interned(seq);
+        let categories: Vec<&str> = event
+            .category_iids
+            .iter()
+            .filter_map(|iid| interned?.categories.get(iid).map(String::as_str))
+            .chain(event.categories.iter().map(String::as_str))
+            .collect();
+        if !categories.is_empty() {
+            out["cat"] = categories.join(",").into();
+        }
+        if !event.debug_annotations.is_empty() {
+            let mut args: Map<String, Value> = event
+                .debug_annotations
+                .iter()
+                .map(|a| (annotation_name(a, interned), annotation_value(a, interned)))
+                .collect();
+            // The trace viewer colors events by their reserved color name.
+            if let Some(color) = args.remove(COLOR_ARG) {
+                out["cname"] = color;
+            }
+            if !args.is_empty() {
+                out["args"] = args.into();
+            }
+        }
+        events.push(out);
+    }
+    json!({ "traceEvents": events, "displayTimeUnit": "ns" })
+}
+
+fn metadata(kind: &str, lane: &Lane, name: &str) -> Value {
+    json!({
+        "ph": "M",
+        "name": kind,
+        "pid": lane.pid,
+        "tid": lane.tid,
+        "args": { "name": name },
+    })
+}
+
+fn annotation_name(annotation: &DebugAnnotation, interned: Option<&Interned>) -> String {
+    if annotation.has_name_iid() {
+        interned
+            .and_then(|i| i.annotation_names.get(&annotation.name_iid()))
+            .cloned()
+            .unwrap_or_default()
+    } else {
+        annotation.name().to_string()
+    }
+}
+
+fn annotation_value(annotation: &DebugAnnotation, interned: Option<&Interned>) -> Value {
+    if annotation.has_bool_value() {
+        annotation.bool_value().into()
+    } else if annotation.has_uint_value() {
+        annotation.uint_value().into()
+    } else if annotation.has_int_value() {
+        annotation.int_value().into()
+    } else if annotation.has_double_value() {
+        annotation.double_value().into()
+    } else if annotation.has_string_value() {
+        annotation.string_value().into()
+    } else if annotation.has_string_value_iid() {
+        interned
+            .and_then(|i| i.strings.get(&annotation.string_value_iid()))
+            .cloned()
+            .into()
+    } else if annotation.has_pointer_value() {
+        format!("{:#x}", annotation.pointer_value()).into()
+    } else if !annotation.dict_entries.is_empty() {
+        annotation
+            .dict_entries
+            .iter()
+            .map(|a| (annotation_name(a, interned), annotation_value(a, interned)))
+            .collect::<Map<_, _>>()
+            .into()
+    } else if !annotation.array_values.is_empty() {
+        annotation
+            .array_values
+            .iter()
+            .map(|a| annotation_value(a, interned))
+            .collect::<Vec<_>>()
+            .into()
+    } else {
+        Value::Null
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use perfetto_writer::{Color, Context};
+
+    #[test]
+    fn events_become_trace_events() -> anyhow::Result<()> {
+        let mut ctx = Context::new();
+        let thread = ctx.current_thread_track();
+        let counter = ctx.counter_track("queue");
+        ctx.event()
+            .with_begin()
+            .with_timestamp_ns(2_000)
+            .with_track_uuid(thread)
+            .with_name("load")
+            .with_debug_str("file", "a.txt")
+            .with_debug_uint("bytes", 12)
+            .with_color(Color::Terrible)
+            .build();
+        ctx.event()
+            .with_counter()
+            .with_timestamp_ns(2_500)
+            .with_track_uuid(counter)
+            .with_counter_value(3)
+            .build();
+        ctx.event()
+            .with_end()
+            .with_timestamp_ns(3_000)
+            .with_track_uuid(thread)
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+
+        let json = to_json(&parse(&buf)?);
+        let events: Vec<_> = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["ph"], "B");
+        assert_eq!(events[0]["name"], "load");
+        assert_eq!(events[0]["ts"], 2.0);
+        assert_eq!(events[0]["args"], json!({"file": "a.txt", "bytes": 12}));
+        assert_eq!(events[0]["cname"], "terrible");
+        assert_eq!(events[1]["ph"], "C");
+        assert_eq!(events[1]["args"], json!({"queue": 3}));
+        assert_eq!(events[2]["ph"], "E");
+        assert_eq!(events[0]["tid"], events[2]["tid"]);
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_get_tids_of_their_own() -> anyhow::Result<()> {
+        let mut ctx = Context::new();
+        let tracks: Vec<_> = (0..3)
+            .map(|i| ctx.create_track(format!("lane {i}")))
+            .collect();
+        for &track in &tracks {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(1_000)
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+
+        let json = to_json(&parse(&buf)?);
+        let mut tids: Vec<_> = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] == "i")
+            .map(|e| e["tid"].as_i64().unwrap())
+            .collect();
+        tids.sort_unstable();
+        assert_eq!(tids, [1 << 30, (1 << 30) + 1, (1 << 30) + 2]);
+        Ok(())
+    }
+}
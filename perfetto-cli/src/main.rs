@@ -0,0 +1,249 @@
+//! `perfetto-rs`: common trace manipulations from the command line.
+//!
+//! ```sh
+//! perfetto-rs stats app.pftrace
+//! perfetto-rs trim app.pftrace --start 5000000000 --end 10000000000 -o slow.pftrace
+//! perfetto-rs merge --index dumps/index.jsonl --start 5000000000 --end 10000000000 -o slow.pftrace
+//! perfetto-rs anonymize app.pftrace --annotation 'user.*' --file-paths -o shared.pftrace
+//! ```
+//!
+//! Inputs and outputs of `-` are stdin and stdout.
+
+use anyhow::{Context as _, Result, bail};
+use clap::{Parser, Subcommand};
+use perfetto_writer::{
+    Collector, Redactor, merge, protobuf::Message, protos::trace::Trace, trim, validate,
+};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+mod index;
+mod json;
+mod resolve;
+mod stats;
+
+#[derive(Parser)]
+#[command(
+    name = "perfetto-rs",
+    version,
+    about = "Inspect and rewrite perfetto traces"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Summarizes the packets, tracks and events in a trace.
+    Stats { input: PathBuf },
+    /// Combines traces into one, keeping their sequences and tracks apart,
+    /// with only the events in [start, end), in nanoseconds.
+    Merge {
+        #[arg(required_unless_present = "index")]
+        inputs: Vec<PathBuf>,
+        /// Adds the files this index from `dump_on_signal` lists with events
+        /// in the window.
+        #[arg(long)]
+        index: Option<PathBuf>,
+        /// Takes only files of this trace uuid from the index.
+        #[arg(long, requires = "index")]
+        session: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        start: u64,
+        #[arg(long, default_value_t = u64::MAX)]
+        end: u64,
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Keeps only the events in [start, end), in nanoseconds.
+    Trim {
+        input: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        start: u64,
+        #[arg(long, default_value_t = u64::MAX)]
+        end: u64,
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Converts to the Chrome JSON trace event format.
+    ToJson {
+        input: PathBuf,
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Checks that a trace is well formed, failing if it isn't.
+    Validate { input: PathBuf },
+    /// Receives traces from `RemoteSink`s, writing a file per host.
+    Collect {
+        /// Only local senders by default; listen on e.g. `0.0.0.0:9090` to
+        /// collect from other hosts.
+        #[arg(long, default_value = "127.0.0.1:9090")]
+        listen: String,
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Hashes user data so the trace can be shared.
+    Anonymize {
+        input: PathBuf,
+        /// Annotation names whose string values are hashed; `*` is a wildcard.
+        #[arg(long)]
+        annotation: Vec<String>,
+        /// Event and track names to hash; `*` is a wildcard.
+        #[arg(long)]
+        event_name: Vec<String>,
+        /// Log messages to hash; `*` is a wildcard.
+        #[arg(long)]
+        log_body: Vec<String>,
+        /// Strips directories from source file paths.
+        #[arg(long)]
+        file_paths: bool,
+        /// Key for the hashes, to match values across runs. Random if unset.
+        #[arg(long)]
+        salt: Option<u128>,
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+}
+
+/// Parses an encoded trace.
+pub(crate) fn parse(bytes: &[u8]) -> Result<Trace> {
+    Ok(Trace::parse_from_bytes(bytes)?)
+}
+
+fn open(path: &Path) -> Result<Box<dyn Read>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    Ok(Box::new(file))
+}
+
+fn create(path: &Path) -> Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdout().lock()));
+    }
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    Ok(Box::new(io::BufWriter::new(file)))
+}
+
+fn read(path: &Path) -> Result<Trace> {
+    let mut bytes = Vec::new();
+    open(path)?.read_to_end(&mut bytes)?;
+    parse(&bytes).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn write(trace: &Trace, path: &Path) -> Result<()> {
+    let mut out = create(path)?;
+    trace.write_to_writer(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run(command: Command) -> Result<ExitCode> {
+    match command {
+        Command::Stats { input } => print!("{}", stats::Stats::new(&read(&input)?)),
+        Command::Merge {
+            mut inputs,
+            index,
+            session,
+            start,
+            end,
+            output,
+        } => {
+            if start >= end {
+                bail!("--start must be before --end");
+            }
+            if let Some(index) = &index {
+                let files = index::files_in(index, start..end, session.as_deref())?;
+                if files.is_empty() {
+                    bail!("no file in {} has events in the window", index.display());
+                }
+                inputs.extend(files);
+            }
+            let inputs = inputs.iter().map(|p| open(p)).collect::<Result<Vec<_>>>()?;
+            if start == 0 && end == u64::MAX {
+                let mut out = create(&output)?;
+                merge(inputs, &mut out)?;
+                out.flush()?;
+            } else {
+                let mut merged = Vec::new();
+                merge(inputs, &mut merged)?;
+                let mut trace = parse(&merged)?;
+                trim(&mut trace, start..end);
+                write(&trace, &output)?;
+            }
+        }
+        Command::Trim {
+            input,
+            start,
+            end,
+            output,
+        } => {
+            if start >= end {
+                bail!("--start must be before --end");
+            }
+            let mut trace = read(&input)?;
+            trim(&mut trace, start..end);
+            write(&trace, &output)?;
+        }
+        Command::ToJson { input, output } => {
+            let mut out = create(&output)?;
+            serde_json::to_writer(&mut out, &json::to_json(&read(&input)?))?;
+            out.flush()?;
+        }
+        Command::Validate { input } => {
+            let diagnostics = validate(&read(&input)?);
+            for diagnostic in &diagnostics {
+                eprintln!("{diagnostic}");
+            }
+            if !diagnostics.is_empty() {
+                eprintln!("{} problems found", diagnostics.len());
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Collect { listen, dir } => {
+            let collector = Collector::bind(&listen, dir)?;
+            eprintln!("collecting on {}", collector.local_addr()?);
+            collector.run()?;
+        }
+        Command::Anonymize {
+            input,
+            annotation,
+            event_name,
+            log_body,
+            file_paths,
+            salt,
+            output,
+        } => {
+            let mut redactor = Redactor::new();
+            if let Some(salt) = salt {
+                redactor = redactor.salt(salt);
+            }
+            for pattern in annotation {
+                redactor = redactor.annotation(pattern);
+            }
+            for pattern in event_name {
+                redactor = redactor.event_name(pattern);
+            }
+            for pattern in log_body {
+                redactor = redactor.log_body(pattern);
+            }
+            if file_paths {
+                redactor = redactor.file_paths();
+            }
+            let mut trace = read(&input)?;
+            redactor.redact(&mut trace);
+            write(&trace, &output)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> Result<ExitCode> {
+    run(Cli::parse().command)
+}
@@ -0,0 +1,94 @@
+use perfetto_writer::{
+    INCREMENTAL_CLOCK_ID,
+    protos::{
+        trace_packet::{TracePacket, trace_packet::SequenceFlags},
+        track_event::TrackEvent,
+    },
+};
+use std::collections::HashMap;
+
+/// Interned strings of one packet sequence.
+#[derive(Default)]
+pub struct Interned {
+    pub event_names: HashMap<u64, String>,
+    pub categories: HashMap<u64, String>,
+    pub annotation_names: HashMap<u64, String>,
+    pub strings: HashMap<u64, String>,
+}
+
+/// Follows the per-sequence state a reader needs to make sense of a
+/// packet: interned strings and the incremental clock.
+#[derive(Default)]
+pub struct Resolver {
+    sequences: HashMap<u32, Interned>,
+    clocks: HashMap<u32, u64>,
+}
+
+impl Resolver {
+    /// Takes in `packet`'s state and returns its timestamp, resolving
+    /// incremental ones to absolute.
+    pub fn update(&mut self, packet: &TracePacket) -> Option<u64> {
+        let seq = packet.trusted_packet_sequence_id();
+        if packet.sequence_flags() & SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32 != 0 {
+            self.sequences.remove(&seq);
+        }
+        let interned = self.sequences.entry(seq).or_default();
+        if let Some(data) = packet.interned_data.as_ref() {
+            for name in &data.event_names {
+                interned
+                    .event_names
+                    .insert(name.iid(), name.name().to_string());
+            }
+            for category in &data.event_categories {
+                interned
+                    .categories
+                    .insert(category.iid(), category.name().to_string());
+            }
+            for name in &data.debug_annotation_names {
+                interned
+                    .annotation_names
+                    .insert(name.iid(), name.name().to_string());
+            }
+            for value in &data.debug_annotation_string_values {
+                interned.strings.insert(
+                    value.iid(),
+                    String::from_utf8_lossy(value.str()).into_owned(),
+                );
+            }
+        }
+        if packet.has_clock_snapshot() {
+            for clock in &packet.clock_snapshot().clocks {
+                if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                    self.clocks.insert(seq, clock.timestamp());
+                }
+            }
+        }
+        if !packet.has_timestamp() {
+            return None;
+        }
+        if packet.timestamp_clock_id() == INCREMENTAL_CLOCK_ID {
+            let last = self.clocks.entry(seq).or_default();
+            *last += packet.timestamp();
+            return Some(*last);
+        }
+        Some(packet.timestamp())
+    }
+
+    pub fn interned(&self, seq: u32) -> Option<&Interned> {
+        self.sequences.get(&seq)
+    }
+
+    /// Name of `event`, whether inline or interned.
+    pub fn event_name<'a>(&'a self, seq: u32, event: &'a TrackEvent) -> Option<&'a str> {
+        if event.has_name_iid() {
+            self.interned(seq)?
+                .event_names
+                .get(&event.name_iid())
+                .map(String::as_str)
+        } else if event.has_name() {
+            Some(event.name())
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use perfetto_writer::protos::{trace::Trace, track_event::track_event::Type};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+use crate::resolve::Resolver;
+
+/// A summary of what a trace contains.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub packets: usize,
+    pub sequences: usize,
+    pub tracks: usize,
+    pub slices: usize,
+    pub instants: usize,
+    pub counters: usize,
+    /// Earliest and latest event timestamps, in their clock's units.
+    pub span: Option<(u64, u64)>,
+    /// How many times each event name occurs.
+    pub names: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    pub fn new(trace: &Trace) -> Self {
+        let mut stats = Self {
+            packets: trace.packet.len(),
+            ..Self::default()
+        };
+        let mut sequences = HashSet::new();
+        let mut resolver = Resolver::default();
+        for packet in &trace.packet {
+            let seq = packet.trusted_packet_sequence_id();
+            sequences.insert(seq);
+            let ts = resolver.update(packet);
+            if packet.has_track_descriptor() {
+                stats.tracks += 1;
+            }
+            if !packet.has_track_event() {
+                continue;
+            }
+            let event = packet.track_event();
+            match event.type_() {
+                Type::TYPE_SLICE_BEGIN => stats.slices += 1,
+                Type::TYPE_INSTANT => stats.instants += 1,
+                Type::TYPE_COUNTER => stats.counters += 1,
+                _ => {}
+            }
+            if let Some(ts) = ts {
+                stats.span = Some(match stats.span {
+                    Some((start, end)) => (start.min(ts), end.max(ts)),
+                    None => (ts, ts),
+                });
+            }
+            if let Some(name) = resolver.event_name(seq, event) {
+                *stats.names.entry(name.to_string()).or_default() += 1;
+            }
+        }
+        stats.sequences = sequences.len();
+        stats
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "packets:   {}", self.packets)?;
+        writeln!(f, "sequences: {}", self.sequences)?;
+        writeln!(f, "tracks:    {}", self.tracks)?;
+        writeln!(f, "slices:    {}", self.slices)?;
+        writeln!(f, "instants:  {}", self.instants)?;
+        writeln!(f, "counters:  {}", self.counters)?;
+        if let Some((start, end)) = self.span {
+            writeln!(f, "duration:  {} ns ({start}..{end})", end - start)?;
+        }
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        if !names.is_empty() {
+            writeln!(f, "top names:")?;
+        }
+        for (name, count) in names.into_iter().take(10) {
+            writeln!(f, "  {count:>8}  {name}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use perfetto_writer::Context;
+
+    #[test]
+    fn counts_events() -> anyhow::Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for ts in [100, 300] {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(ts)
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+
+        let stats = Stats::new(&parse(&buf)?);
+        assert_eq!(stats.instants, 2);
+        assert_eq!(stats.slices, 0);
+        assert_eq!(stats.span, Some((100, 300)));
+        assert_eq!(stats.names["tick"], 2);
+        assert!(stats.to_string().contains("duration:  200 ns"));
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! A `no_std` + `alloc` writer for Perfetto track event traces, for
+//! embedded and kernel-adjacent code that cannot use `perfetto-writer`.
+//!
+//! It covers the core of `perfetto-writer`'s `Context`: named tracks,
+//! slices, instants and counters, with interned event names, buffered until
+//! [`Writer::flush`]. Time comes from a [`Clock`] and encoded bytes go to a
+//! [`Sink`], both supplied by the caller.
+//!
+//! The encoding goes through [`wire`], which `perfetto-writer` shares for
+//! the messages it encodes by hand.
+//!
+//! ```
+//! use perfetto_core::Writer;
+//!
+//! let mut ticks = 0;
+//! let mut writer = Writer::new(move || { ticks += 10; ticks }, Vec::new(), 1);
+//! let track = writer.track("main");
+//! writer.begin(track, "boot");
+//! writer.end(track);
+//! writer.flush().unwrap();
+//! let trace: Vec<u8> = writer.into_sink();
+//! assert!(!trace.is_empty());
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::convert::Infallible;
+
+pub mod wire;
+
+use wire::{Message, write_varint};
+
+/// A source of timestamps in nanoseconds, on the boot clock unless the
+/// trace says otherwise.
+pub trait Clock {
+    fn now_ns(&mut self) -> u64;
+}
+
+impl<F: FnMut() -> u64> Clock for F {
+    fn now_ns(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// Where encoded trace bytes go, e.g. a UART, a ring buffer or a file.
+pub trait Sink {
+    type Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+impl Sink for Vec<u8> {
+    type Error = Infallible;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Infallible> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// Field numbers from perfetto/trace/trace_packet.proto and friends.
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_INTERNED_DATA: u32 = 12;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const EVENT_TYPE: u32 = 9;
+const EVENT_NAME_IID: u32 = 10;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_COUNTER_VALUE: u32 = 30;
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_COUNTER: u32 = 8;
+const INTERNED_EVENT_NAMES: u32 = 2;
+const INTERNED_IID: u32 = 1;
+const INTERNED_NAME: u32 = 2;
+
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+/// Buffers track events for one packet sequence and writes them to a
+/// [`Sink`] as an encoded `Trace`.
+pub struct Writer<C, S> {
+    clock: C,
+    sink: S,
+    buf: Vec<u8>,
+    sequence_id: u32,
+    next_uuid: u64,
+    names: BTreeMap<String, u64>,
+}
+
+impl<C: Clock, S: Sink> Writer<C, S> {
+    /// Starts a sequence. `sequence_id` must be unique among the writers
+    /// feeding the same trace.
+    pub fn new(clock: C, sink: S, sequence_id: u32) -> Self {
+        let mut writer = Self {
+            clock,
+            sink,
+            buf: Vec::new(),
+            sequence_id,
+            next_uuid: u64::from(sequence_id) << 32,
+            names: BTreeMap::new(),
+        };
+        let packet = writer
+            .packet()
+            .varint(PACKET_SEQUENCE_FLAGS, SEQ_INCREMENTAL_STATE_CLEARED);
+        writer.push(&packet);
+        writer
+    }
+
+    /// Creates a top level track and returns its uuid.
+    pub fn track(&mut self, name: &str) -> u64 {
+        self.describe_track(name, false)
+    }
+
+    /// Creates a counter track and returns its uuid.
+    pub fn counter_track(&mut self, name: &str) -> u64 {
+        self.describe_track(name, true)
+    }
+
+    pub fn begin(&mut self, track: u64, name: &str) {
+        self.event(track, TYPE_SLICE_BEGIN, Some(name), None);
+    }
+
+    pub fn end(&mut self, track: u64) {
+        self.event(track, TYPE_SLICE_END, None, None);
+    }
+
+    pub fn instant(&mut self, track: u64, name: &str) {
+        self.event(track, TYPE_INSTANT, Some(name), None);
+    }
+
+    pub fn counter(&mut self, track: u64, value: i64) {
+        self.event(track, TYPE_COUNTER, None, Some(value));
+    }
+
+    /// Encoded bytes waiting for [`Writer::flush`].
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Writes the buffered packets to the sink. The buffer is kept on error
+    /// so the flush can be retried.
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        self.sink.write(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Returns the sink, dropping anything not yet flushed.
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    fn describe_track(&mut self, name: &str, counter: bool) -> u64 {
+        self.next_uuid += 1;
+        let uuid = self.next_uuid;
+        let mut track = Message::default()
+            .varint(TRACK_UUID, uuid)
+            .bytes(TRACK_NAME, name.as_bytes());
+        if counter {
+            track = track.message(TRACK_COUNTER, &Message::default());
+        }
+        let packet = self.packet().message(PACKET_TRACK_DESCRIPTOR, &track);
+        self.push(&packet);
+        uuid
+    }
+
+    fn event(&mut self, track: u64, kind: u64, name: Option<&str>, counter: Option<i64>) {
+        let timestamp = self.clock.now_ns();
+        let mut event = Message::default()
+            .varint(EVENT_TYPE, kind)
+            .varint(EVENT_TRACK_UUID, track);
+        let mut packet = self.packet().varint(PACKET_TIMESTAMP, timestamp);
+        if let Some(name) = name {
+            let (iid, new) = self.intern(name);
+            if new {
+                let entry = Message::default()
+                    .varint(INTERNED_IID, iid)
+                    .bytes(INTERNED_NAME, name.as_bytes());
+                let interned = Message::default().message(INTERNED_EVENT_NAMES, &entry);
+                packet = packet.message(PACKET_INTERNED_DATA, &interned);
+            }
+            event = event.varint(EVENT_NAME_IID, iid);
+            packet = packet.varint(PACKET_SEQUENCE_FLAGS, SEQ_NEEDS_INCREMENTAL_STATE);
+        }
+        if let Some(value) = counter {
+            event = event.varint(EVENT_COUNTER_VALUE, value as u64);
+        }
+        let packet = packet.message(PACKET_TRACK_EVENT, &event);
+        self.push(&packet);
+    }
+
+    fn intern(&mut self, name: &str) -> (u64, bool) {
+        if let Some(iid) = self.names.get(name) {
+            return (*iid, false);
+        }
+        let iid = self.names.len() as u64 + 1;
+        self.names.insert(name.into(), iid);
+        (iid, true)
+    }
+
+    fn packet(&self) -> Message {
+        Message::default().varint(PACKET_SEQUENCE_ID, u64::from(self.sequence_id))
+    }
+
+    fn push(&mut self, packet: &Message) {
+        write_varint(&mut self.buf, u64::from(TRACE_PACKET << 3 | 2));
+        write_varint(&mut self.buf, packet.0.len() as u64);
+        self.buf.extend_from_slice(&packet.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message as _;
+
+    #[test]
+    fn decodes_as_a_trace() {
+        let mut now = 0;
+        let mut writer = Writer::new(
+            move || {
+                now += 5;
+                now
+            },
+            Vec::new(),
+            7,
+        );
+        let track = writer.track("main");
+        let counter = writer.counter_track("depth");
+        writer.begin(track, "work");
+        writer.instant(track, "work");
+        writer.counter(counter, -3);
+        writer.end(track);
+        writer.flush().unwrap();
+
+        let trace = Trace::parse_from_bytes(&writer.into_sink()).unwrap();
+        assert!(
+            trace
+                .packet
+                .iter()
+                .all(|p| p.trusted_packet_sequence_id() == 7)
+        );
+        assert_eq!(trace.packet[0].sequence_flags(), 1);
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .collect();
+        assert_eq!(tracks[0].name(), "main");
+        assert!(tracks[1].counter.is_some());
+
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| (n.iid(), n.name()))
+            .collect();
+        assert_eq!(names, [(1, "work")]);
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.timestamp(), p.track_event().clone()))
+            .collect();
+        let types: Vec<_> = events.iter().map(|(_, e)| e.type_()).collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_INSTANT,
+                Type::TYPE_COUNTER,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        assert_eq!(events[1].1.name_iid(), 1);
+        assert_eq!(events[2].1.counter_value(), -3);
+        assert_eq!(events[3].0, 20);
+    }
+}
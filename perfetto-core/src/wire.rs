@@ -0,0 +1,132 @@
+//! The protobuf wire format, as far as hand encoded messages need it: the
+//! packets of [`Writer`](crate::Writer), and the messages `perfetto-writer`
+//! exchanges that `perfetto_protos` does not generate.
+
+use alloc::vec::Vec;
+
+const VARINT: u32 = 0;
+const LEN: u32 = 2;
+
+/// An encoded protobuf message, built one field at a time.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Message(pub Vec<u8>);
+
+impl Message {
+    pub fn varint(mut self, field: u32, value: u64) -> Self {
+        write_varint(&mut self.0, u64::from(field << 3 | VARINT));
+        write_varint(&mut self.0, value);
+        self
+    }
+
+    pub fn bytes(mut self, field: u32, value: &[u8]) -> Self {
+        write_varint(&mut self.0, u64::from(field << 3 | LEN));
+        write_varint(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub fn message(self, field: u32, value: &Message) -> Self {
+        self.bytes(field, &value.0)
+    }
+}
+
+/// Appends `value` to `buf` as a varint.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Decodes the varint at the start of `data`, returning it and its length,
+/// or None if `data` does not start with one.
+pub fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().take(10).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Bytes `value` takes up as a varint.
+pub fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+/// A field value, by wire type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A 32 or 64 bit field, skipped.
+    Fixed,
+}
+
+/// The fields of an encoded protobuf message, up to the first malformed
+/// one.
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl Fields<'_> {
+    fn raw(&mut self) -> Option<u64> {
+        let (value, len) = read_varint(self.0)?;
+        self.0 = &self.0[len..];
+        Some(value)
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (u32, Value<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.raw()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.raw()?),
+            2 => {
+                let len = usize::try_from(self.raw()?).ok()?;
+                let bytes = self.0.get(..len)?;
+                self.0 = &self.0[len..];
+                Value::Bytes(bytes)
+            }
+            wire @ (1 | 5) => {
+                let len = if wire == 1 { 8 } else { 4 };
+                self.0 = self.0.get(len..)?;
+                Value::Fixed
+            }
+            _ => return None,
+        };
+        Some(((key >> 3) as u32, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn varints() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xac, 0x02]);
+        assert_eq!(read_varint(&buf[1..]), Some((300, 2)));
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(varint_len(0), 1);
+        assert_eq!(varint_len(127), 1);
+        assert_eq!(varint_len(128), 2);
+        assert_eq!(varint_len(u64::MAX), 10);
+    }
+
+    #[test]
+    fn nested_messages() {
+        let inner = Message::default().varint(1, 150);
+        let outer = Message::default().message(3, &inner).varint(4, 1);
+        assert_eq!(outer.0, vec![0x1a, 0x03, 0x08, 0x96, 0x01, 0x20, 0x01]);
+        let fields: Vec<_> = Fields(&outer.0).collect();
+        assert_eq!(fields, [(3, Value::Bytes(&inner.0)), (4, Value::Varint(1))]);
+    }
+}
@@ -0,0 +1,90 @@
+//! Attribute macros re-exported by `perfetto-writer` behind its `macros`
+//! feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{ItemFn, LitStr, Path, meta, parse_macro_input, parse_quote};
+
+/// Records every call of the function as a slice named after it on the
+/// calling thread's track of the global context (see
+/// `perfetto_writer::init_global`).
+///
+/// `#[trace(category = "db")]` also sets the slice's category; the slice is
+/// compiled out of builds that leave the category out (see
+/// `perfetto_writer::COMPILED_OUT_CATEGORIES`). An `async fn` gets a track
+/// of its own instead, as with `perfetto_writer::FutureExt::traced`, since
+/// its future may be polled on any thread and interleave with others.
+///
+/// `#[trace(crate = "path::to::perfetto_writer")]` names the crate for
+/// callers that only depend on it through a re-export.
+#[proc_macro_attribute]
+pub fn trace(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut category: Option<LitStr> = None;
+    let mut krate: Path = parse_quote!(::perfetto_writer);
+    let parser = meta::parser(|meta| {
+        if meta.path.is_ident("category") {
+            category = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("crate") {
+            krate = meta.value()?.parse::<LitStr>()?.parse()?;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported trace argument, expected `category` or `crate`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    let name = sig.ident.to_string();
+    let body = if sig.asyncness.is_some() {
+        let traced = |category| {
+            quote! {
+                #krate::guard::global_future(__perfetto_future, #name, #category).await
+            }
+        };
+        let traced = match category {
+            Some(category) => {
+                let traced = traced(quote!(::core::option::Option::Some(#category)));
+                quote! {
+                    if const { #krate::category_compiled_in(#category) } {
+                        #traced
+                    } else {
+                        __perfetto_future.await
+                    }
+                }
+            }
+            None => traced(quote!(::core::option::Option::None)),
+        };
+        quote! {
+            let __perfetto_future = async move #block;
+            #traced
+        }
+    } else {
+        let slice = match category {
+            Some(category) => quote! {
+                if const { #krate::category_compiled_in(#category) } {
+                    #krate::guard::global_slice(#name, ::core::option::Option::Some(#category))
+                } else {
+                    ::core::option::Option::None
+                }
+            },
+            None => quote!(#krate::guard::global_slice(#name, ::core::option::Option::None)),
+        };
+        quote! {
+            let __perfetto_slice = #slice;
+            #block
+        }
+    };
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            #body
+        }
+    }
+    .into()
+}
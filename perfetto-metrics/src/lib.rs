@@ -0,0 +1,279 @@
+//! A [`metrics::Recorder`] that writes counters, gauges and histograms as
+//! Perfetto counter tracks in a shared [`Context`], so an app instrumented
+//! with both `metrics` and `tracing` gets a single trace.
+//!
+//! ```
+//! use perfetto_metrics::PerfettoRecorder;
+//! use perfetto_writer::Context;
+//! use std::sync::{Arc, Mutex};
+//!
+//! let ctx = Arc::new(Mutex::new(Context::new()));
+//! PerfettoRecorder::new(Arc::clone(&ctx)).install().unwrap();
+//! metrics::counter!("requests").increment(1);
+//! ```
+//!
+//! With a `tracing-perfetto-writer` layer, pass its `shared_context()`.
+
+use dashmap::DashMap;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SetRecorderError, SharedString, Unit,
+};
+use perfetto_writer::{Context, CounterUnit};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError,
+    atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+/// Records metrics into a [`Context`] shared with other writers, e.g. a
+/// `tracing-perfetto-writer` layer.
+pub struct PerfettoRecorder {
+    ctx: Arc<Mutex<Context>>,
+    units: DashMap<KeyName, Unit>,
+    counters: DashMap<Key, Arc<CounterTrack>>,
+    gauges: DashMap<Key, Arc<GaugeTrack>>,
+    histograms: DashMap<Key, Arc<HistogramTrack>>,
+}
+
+impl PerfettoRecorder {
+    pub fn new(ctx: Arc<Mutex<Context>>) -> Self {
+        Self {
+            ctx,
+            units: DashMap::new(),
+            counters: DashMap::new(),
+            gauges: DashMap::new(),
+            histograms: DashMap::new(),
+        }
+    }
+
+    /// Installs the recorder as the global `metrics` recorder.
+    pub fn install(self) -> Result<(), Box<SetRecorderError<Self>>> {
+        metrics::set_global_recorder(self).map_err(Box::new)
+    }
+
+    fn describe(&self, key: KeyName, unit: Option<Unit>) {
+        if let Some(unit) = unit {
+            self.units.insert(key, unit);
+        }
+    }
+
+    /// Creates the counter track for `key`, named after the metric and its
+    /// labels, e.g. `requests{method=GET}`.
+    fn track(&self, key: &Key) -> u64 {
+        let mut name = key.name().to_string();
+        let labels: Vec<_> = key
+            .labels()
+            .map(|l| format!("{}={}", l.key(), l.value()))
+            .collect();
+        if !labels.is_empty() {
+            name = format!("{}{{{}}}", name, labels.join(","));
+        }
+        let unit = self.units.get(key.name()).map(|u| *u);
+        let mut ctx = lock(&self.ctx);
+        let mut track = ctx.track().name(name).counter();
+        track = match unit.and_then(counter_unit) {
+            Some(unit) => track.unit(unit),
+            None => match unit {
+                Some(unit) => track.unit_name(unit.as_canonical_label()),
+                None => track,
+            },
+        };
+        track.build()
+    }
+}
+
+fn lock(ctx: &Mutex<Context>) -> MutexGuard<'_, Context> {
+    ctx.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn counter_unit(unit: Unit) -> Option<CounterUnit> {
+    match unit {
+        Unit::Count => Some(CounterUnit::UNIT_COUNT),
+        Unit::Nanoseconds => Some(CounterUnit::UNIT_TIME_NS),
+        Unit::Bytes => Some(CounterUnit::UNIT_SIZE_BYTES),
+        _ => None,
+    }
+}
+
+/// A monotonic counter, written as its running total.
+struct CounterTrack {
+    ctx: Arc<Mutex<Context>>,
+    track: u64,
+    total: AtomicU64,
+}
+
+impl CounterTrack {
+    /// Updates the total with `f` and writes it. Both happen under the
+    /// context lock, so totals are written in the order they were reached.
+    fn update(&self, f: impl FnOnce(&AtomicU64) -> u64) {
+        let mut ctx = lock(&self.ctx);
+        let total = f(&self.total);
+        ctx.event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(self.track)
+            .with_counter_value(total as i64)
+            .build();
+    }
+}
+
+impl CounterFn for CounterTrack {
+    fn increment(&self, value: u64) {
+        self.update(|total| total.fetch_add(value, Relaxed) + value);
+    }
+
+    fn absolute(&self, value: u64) {
+        self.update(|total| total.fetch_max(value, Relaxed).max(value));
+    }
+}
+
+/// A gauge, written as its current value.
+struct GaugeTrack {
+    ctx: Arc<Mutex<Context>>,
+    track: u64,
+    bits: AtomicU64,
+}
+
+impl GaugeTrack {
+    /// Updates the value with `f` and writes it, both under the context
+    /// lock like [`CounterTrack::update`].
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let mut ctx = lock(&self.ctx);
+        let value = f(f64::from_bits(self.bits.load(Relaxed)));
+        self.bits.store(value.to_bits(), Relaxed);
+        ctx.event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(self.track)
+            .with_double_counter_value(value)
+            .build();
+    }
+}
+
+impl GaugeFn for GaugeTrack {
+    fn increment(&self, value: f64) {
+        self.update(|v| v + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|v| v - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+/// A histogram, written as one counter value per sample.
+struct HistogramTrack {
+    ctx: Arc<Mutex<Context>>,
+    track: u64,
+}
+
+impl HistogramFn for HistogramTrack {
+    fn record(&self, value: f64) {
+        lock(&self.ctx)
+            .event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(self.track)
+            .with_double_counter_value(value)
+            .build();
+    }
+}
+
+impl Recorder for PerfettoRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        let counter = self.counters.entry(key.clone()).or_insert_with(|| {
+            Arc::new(CounterTrack {
+                ctx: Arc::clone(&self.ctx),
+                track: self.track(key),
+                total: AtomicU64::new(0),
+            })
+        });
+        Counter::from_arc(Arc::clone(&counter))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        let gauge = self.gauges.entry(key.clone()).or_insert_with(|| {
+            Arc::new(GaugeTrack {
+                ctx: Arc::clone(&self.ctx),
+                track: self.track(key),
+                bits: AtomicU64::new(0f64.to_bits()),
+            })
+        });
+        Gauge::from_arc(Arc::clone(&gauge))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        let histogram = self.histograms.entry(key.clone()).or_insert_with(|| {
+            Arc::new(HistogramTrack {
+                ctx: Arc::clone(&self.ctx),
+                track: self.track(key),
+            })
+        });
+        Histogram::from_arc(Arc::clone(&histogram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[test]
+    fn metrics_become_counter_tracks() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let recorder = PerfettoRecorder::new(Arc::clone(&ctx));
+        metrics::with_local_recorder(&recorder, || {
+            metrics::describe_gauge!("queue_bytes", Unit::Bytes, "bytes queued");
+            metrics::counter!("requests", "method" => "GET").increment(2);
+            metrics::counter!("requests", "method" => "GET").increment(3);
+            metrics::gauge!("queue_bytes").set(10.0);
+            metrics::gauge!("queue_bytes").decrement(4.0);
+            metrics::histogram!("latency").record(1.5);
+        });
+
+        let mut buf = Vec::new();
+        ctx.lock().unwrap().write_to(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .collect();
+        let names: Vec<_> = tracks.iter().map(|t| t.name()).collect();
+        assert_eq!(names, ["requests{method=GET}", "queue_bytes", "latency"]);
+        assert_eq!(tracks[1].counter.unit(), CounterUnit::UNIT_SIZE_BYTES);
+
+        let values: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .filter(|e| e.type_() == Type::TYPE_COUNTER)
+            .map(|e| {
+                if e.has_counter_value() {
+                    e.counter_value() as f64
+                } else {
+                    e.double_counter_value()
+                }
+            })
+            .collect();
+        assert_eq!(values, [2.0, 5.0, 10.0, 6.0, 1.5]);
+    }
+}
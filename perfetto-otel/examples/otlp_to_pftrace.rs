@@ -0,0 +1,25 @@
+//! Converts an OTLP trace export to a perfetto trace:
+//!
+//! ```sh
+//! cargo run -p perfetto-otel --features otlp --example otlp_to_pftrace -- traces.json out.pftrace
+//! ```
+//!
+//! Files ending in `.json` or `.jsonl` are read as OTLP/JSON, anything else
+//! as protobuf.
+
+use perfetto_otel::{OtlpFormat, convert_otlp};
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(input), Some(output)) = (args.next(), args.next()) else {
+        anyhow::bail!("usage: otlp_to_pftrace <input> <output.pftrace>");
+    };
+    let format = if input.ends_with(".json") || input.ends_with(".jsonl") {
+        OtlpFormat::Json
+    } else {
+        OtlpFormat::Protobuf
+    };
+    let trace = convert_otlp(&std::fs::read(&input)?, format)?;
+    std::fs::write(&output, trace)?;
+    Ok(())
+}
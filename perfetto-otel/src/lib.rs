@@ -0,0 +1,380 @@
+//! An OpenTelemetry [`SpanExporter`] that records spans into a shared
+//! [`Context`], so services already instrumented with the OTel SDK can
+//! write `.pftrace` files for local deep dives without changing their
+//! instrumentation.
+//!
+//! The spans of a trace are held until its root span ends, then written in
+//! timestamp order as slices on the service's tracks: children nest in
+//! their parent's slice, and spans that overlap without nesting go to
+//! another lane of the service. Attributes become debug annotations and
+//! span events become instants.
+//!
+//! ```
+//! use opentelemetry_sdk::trace::SdkTracerProvider;
+//! use perfetto_otel::PerfettoExporter;
+//! use perfetto_writer::Context;
+//! use std::sync::{Arc, Mutex};
+//!
+//! let ctx = Arc::new(Mutex::new(Context::new()));
+//! let exporter = PerfettoExporter::new(ctx).sink(Vec::new());
+//! let provider = SdkTracerProvider::builder()
+//!     .with_simple_exporter(exporter)
+//!     .build();
+//! ```
+
+use opentelemetry::{
+    KeyValue, Value,
+    trace::{SpanId, Status, TraceId},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    trace::{SpanData, SpanExporter},
+};
+use perfetto_writer::{ClockId, Context, EventBuilder, TrackUuid};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Write,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "otlp")]
+mod otlp;
+
+#[cfg(feature = "otlp")]
+pub use otlp::{OtlpFormat, convert_otlp, read_otlp, write_otlp};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn unix_ns(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn annotate(event: &mut EventBuilder<'_>, attribute: &KeyValue) {
+    let name = attribute.key.as_str();
+    match &attribute.value {
+        Value::Bool(v) => event.debug_bool(name, *v),
+        Value::I64(v) => event.debug_int(name, *v),
+        Value::F64(v) => event.debug_double(name, *v),
+        Value::String(v) => event.debug_str(name, v.as_str()),
+        other => event.debug_str(name, other.to_string()),
+    }
+}
+
+/// Exports OpenTelemetry spans as Perfetto slices.
+pub struct PerfettoExporter {
+    ctx: Arc<Mutex<Context>>,
+    sink: Mutex<Option<Box<dyn Write + Send>>>,
+    service: String,
+    /// The spans of traces whose root has not ended yet.
+    pending: Mutex<HashMap<TraceId, Vec<SpanData>>>,
+    lanes: Mutex<Vec<Lane>>,
+}
+
+/// A track of the service, and the ends of the slices open on it,
+/// innermost last.
+struct Lane {
+    track: TrackUuid,
+    open: Vec<u64>,
+}
+
+/// A point in a span's life, the event index for span events.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Begin,
+    Event(usize),
+    End,
+}
+
+impl fmt::Debug for PerfettoExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PerfettoExporter").finish_non_exhaustive()
+    }
+}
+
+impl PerfettoExporter {
+    pub fn new(ctx: Arc<Mutex<Context>>) -> Self {
+        Self {
+            ctx,
+            sink: Mutex::new(None),
+            service: "unknown service".to_string(),
+            pending: Mutex::new(HashMap::new()),
+            lanes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Where `force_flush` and `shutdown` write the trace recorded so far,
+    /// e.g. a `.pftrace` file. Successive flushes append to it.
+    pub fn sink(self, sink: impl Write + Send + 'static) -> Self {
+        *lock(&self.sink) = Some(Box::new(sink));
+        self
+    }
+
+    /// Holds `span` until its trace's root span ends, then writes the trace.
+    fn record(&self, ctx: &mut Context, span: SpanData) {
+        let root = span.parent_span_id == SpanId::INVALID || span.parent_span_is_remote;
+        let trace_id = span.span_context.trace_id();
+        let mut pending = lock(&self.pending);
+        pending.entry(trace_id).or_default().push(span);
+        if root && let Some(spans) = pending.remove(&trace_id) {
+            drop(pending);
+            self.write_spans(ctx, spans);
+        }
+    }
+
+    /// Writes `spans` in timestamp order, so timestamps never go back
+    /// within the trace.
+    fn write_spans(&self, ctx: &mut Context, mut spans: Vec<SpanData>) {
+        spans.sort_by_key(|span| span.start_time);
+        let mut tracks = Vec::with_capacity(spans.len());
+        let mut phases = Vec::new();
+        for (i, span) in spans.iter().enumerate() {
+            let (start, end) = (unix_ns(span.start_time), unix_ns(span.end_time));
+            tracks.push(self.lane(ctx, start, end));
+            phases.push((start, i, Phase::Begin));
+            for (j, event) in span.events.iter().enumerate() {
+                phases.push((unix_ns(event.timestamp), i, Phase::Event(j)));
+            }
+            phases.push((end, i, Phase::End));
+        }
+        // Stable, so a span's begin stays ahead of its end at equal
+        // timestamps.
+        phases.sort_by_key(|&(timestamp, _, _)| timestamp);
+
+        for (timestamp, i, phase) in phases {
+            let (span, track) = (&spans[i], tracks[i]);
+            match phase {
+                Phase::Begin => {
+                    let mut begin = ctx
+                        .event()
+                        .with_begin()
+                        .with_clock(ClockId::Realtime)
+                        .with_timestamp_ns(timestamp)
+                        .with_track_uuid(track)
+                        .with_name(span.name.to_string())
+                        .with_category(span.instrumentation_scope.name().to_string());
+                    for attribute in &span.attributes {
+                        annotate(&mut begin, attribute);
+                    }
+                    begin.build();
+                }
+                Phase::Event(j) => {
+                    let event = &span.events[j];
+                    let mut instant = ctx
+                        .event()
+                        .with_instant()
+                        .with_clock(ClockId::Realtime)
+                        .with_timestamp_ns(timestamp)
+                        .with_track_uuid(track)
+                        .with_name(event.name.to_string());
+                    for attribute in &event.attributes {
+                        annotate(&mut instant, attribute);
+                    }
+                    instant.build();
+                }
+                Phase::End => {
+                    let mut end = ctx
+                        .event()
+                        .with_end()
+                        .with_clock(ClockId::Realtime)
+                        .with_timestamp_ns(timestamp)
+                        .with_track_uuid(track);
+                    if let Status::Error { description } = &span.status {
+                        end.debug_str("error", description.to_string());
+                    }
+                    end.build();
+                }
+            }
+        }
+    }
+
+    /// The first lane where a slice from `start` to `end` nests in the
+    /// slices open there, adding one if none does.
+    fn lane(&self, ctx: &mut Context, start: u64, end: u64) -> TrackUuid {
+        let mut lanes = lock(&self.lanes);
+        for lane in lanes.iter_mut() {
+            while lane.open.last().is_some_and(|&open| open <= start) {
+                lane.open.pop();
+            }
+            if lane.open.last().is_none_or(|&open| end <= open) {
+                lane.open.push(end);
+                return lane.track;
+            }
+        }
+        let track = match lanes.first() {
+            Some(first) => {
+                let name = format!("{} {}", self.service, lanes.len() + 1);
+                ctx.create_child_track(first.track, name)
+            }
+            None => ctx.create_track(self.service.as_str()),
+        };
+        lanes.push(Lane {
+            track,
+            open: vec![end],
+        });
+        track
+    }
+
+    fn flush(&self) -> OTelSdkResult {
+        let mut ctx = lock(&self.ctx);
+        // Traces whose root has not ended, e.g. because it is still running
+        // or was dropped, are written as they are.
+        let pending: Vec<_> = lock(&self.pending)
+            .drain()
+            .map(|(_, spans)| spans)
+            .collect();
+        for spans in pending {
+            self.write_spans(&mut ctx, spans);
+        }
+        let mut sink = lock(&self.sink);
+        let Some(sink) = sink.as_mut() else {
+            return Ok(());
+        };
+        ctx.drain_to(sink)
+            .and_then(|_| Ok(sink.flush()?))
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))
+    }
+}
+
+impl SpanExporter for PerfettoExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut ctx = lock(&self.ctx);
+        for span in batch {
+            self.record(&mut ctx, span);
+        }
+        Ok(())
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        if let Some(service) = resource.get(&opentelemetry::Key::new("service.name")) {
+            self.service = service.to_string();
+        }
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: std::time::Duration) -> OTelSdkResult {
+        self.flush()
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Span, TraceContextExt, Tracer, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            lock(&self.0).write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn export(spans: impl FnOnce(&opentelemetry_sdk::trace::SdkTracer)) -> Trace {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let out = SharedBuf::default();
+        let exporter = PerfettoExporter::new(ctx).sink(out.clone());
+        let provider = SdkTracerProvider::builder()
+            .with_resource(Resource::builder().with_service_name("shop").build())
+            .with_simple_exporter(exporter)
+            .build();
+        spans(&provider.tracer("app"));
+        provider.shutdown().unwrap();
+        let trace = Trace::parse_from_bytes(&lock(&out.0)).unwrap();
+        assert_eq!(perfetto_writer::validate(&trace), []);
+        trace
+    }
+
+    fn track_names(trace: &Trace) -> Vec<&str> {
+        trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name())
+            .collect()
+    }
+
+    #[test]
+    fn traces_nest_on_the_service_track() {
+        let trace = export(|tracer| {
+            tracer.in_span("request", |cx| {
+                cx.span().set_attribute(KeyValue::new("user", "bob"));
+                let mut child = tracer.start("query");
+                child.add_event("cache miss", vec![]);
+                child.set_status(Status::error("timeout"));
+                child.end();
+            });
+            tracer.in_span("request", |_| {});
+        });
+        assert_eq!(track_names(&trace), ["shop"]);
+
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_INSTANT,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_spans_get_another_lane() {
+        let trace = export(|tracer| {
+            tracer.in_span("request", |cx| {
+                let first = tracer.start_with_context("fetch", &cx);
+                let second = tracer.start_with_context("fetch", &cx);
+                drop(first);
+                drop(second);
+            });
+        });
+        assert_eq!(track_names(&trace), ["shop", "shop 2"]);
+    }
+
+    #[test]
+    fn unfinished_traces_are_written_on_flush() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let exporter = PerfettoExporter::new(Arc::clone(&ctx));
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+        let tracer = provider.tracer("app");
+        let root = tracer.start("request");
+        let cx = opentelemetry::Context::current_with_span(root);
+        tracer.start_with_context("query", &cx).end();
+        provider.shutdown().unwrap();
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to_vec(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 2);
+    }
+}
@@ -0,0 +1,292 @@
+use anyhow::Result;
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::ExportTraceServiceRequest,
+    common::v1::{KeyValue, any_value},
+    trace::v1::status::StatusCode,
+};
+use perfetto_writer::{ClockId, Context, EventBuilder, TrackUuid};
+use prost::Message;
+use std::collections::HashMap;
+
+/// The encoding of an OTLP trace export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpFormat {
+    /// OTLP/JSON, one export request per line as written by the collector's
+    /// file exporter, or a single request.
+    Json,
+    /// A binary `ExportTraceServiceRequest`.
+    Protobuf,
+}
+
+/// Decodes an OTLP trace export, merging every request in a JSON lines file.
+pub fn read_otlp(bytes: &[u8], format: OtlpFormat) -> Result<ExportTraceServiceRequest> {
+    match format {
+        OtlpFormat::Protobuf => Ok(ExportTraceServiceRequest::decode(bytes)?),
+        OtlpFormat::Json => {
+            let mut merged = ExportTraceServiceRequest::default();
+            for request in serde_json::Deserializer::from_slice(bytes).into_iter() {
+                let request: ExportTraceServiceRequest = request?;
+                merged.resource_spans.extend(request.resource_spans);
+            }
+            Ok(merged)
+        }
+    }
+}
+
+/// Converts an OTLP trace export into an encoded Perfetto trace.
+pub fn convert_otlp(bytes: &[u8], format: OtlpFormat) -> Result<Vec<u8>> {
+    let request = read_otlp(bytes, format)?;
+    let mut ctx = Context::new();
+    write_otlp(&mut ctx, &request);
+    let mut buf = Vec::new();
+    ctx.write_to_vec(&mut buf)?;
+    Ok(buf)
+}
+
+fn span_id(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn service_name(attributes: &[KeyValue]) -> Option<&str> {
+    let value = attributes.iter().find(|kv| kv.key == "service.name")?;
+    match value.value.as_ref()?.value.as_ref()? {
+        any_value::Value::StringValue(name) => Some(name),
+        _ => None,
+    }
+}
+
+fn annotate(event: &mut EventBuilder<'_>, attribute: &KeyValue) {
+    let name = attribute.key.as_str();
+    let Some(value) = attribute.value.as_ref().and_then(|v| v.value.as_ref()) else {
+        return;
+    };
+    match value {
+        any_value::Value::StringValue(v) => event.debug_str(name, v.as_str()),
+        any_value::Value::BoolValue(v) => event.debug_bool(name, *v),
+        any_value::Value::IntValue(v) => event.debug_int(name, *v),
+        any_value::Value::DoubleValue(v) => event.debug_double(name, *v),
+        other => event.debug_str(name, format!("{:?}", other)),
+    }
+}
+
+/// Records every span of `request` in `ctx`. Each service gets a track with
+/// its spans nested under their parents, and a span whose parent belongs to
+/// another service is linked to it with a flow arrow.
+pub fn write_otlp(ctx: &mut Context, request: &ExportTraceServiceRequest) {
+    let services: Vec<_> = request
+        .resource_spans
+        .iter()
+        .map(|resource| {
+            resource
+                .resource
+                .as_ref()
+                .and_then(|r| service_name(&r.attributes))
+                .unwrap_or("unknown service")
+        })
+        .collect();
+    let spans = || {
+        request
+            .resource_spans
+            .iter()
+            .zip(&services)
+            .flat_map(|(resource, service)| {
+                resource.scope_spans.iter().flat_map(move |scope| {
+                    scope.spans.iter().map(move |span| (*service, scope, span))
+                })
+            })
+    };
+
+    let owners: HashMap<u64, &str> = spans()
+        .filter_map(|(service, _, span)| Some((span_id(&span.span_id)?, service)))
+        .collect();
+    let mut flows: HashMap<u64, Vec<u64>> = HashMap::new();
+    for (service, _, span) in spans() {
+        let (Some(id), Some(parent)) = (span_id(&span.span_id), span_id(&span.parent_span_id))
+        else {
+            continue;
+        };
+        if owners.get(&parent).is_some_and(|owner| *owner != service) {
+            flows.entry(parent).or_default().push(id);
+        }
+    }
+
+    // Spans are written in start order and their events in timestamp
+    // order, so timestamps never go back on the trace's sequence.
+    let mut spans: Vec<_> = spans()
+        .filter_map(|(service, scope, span)| Some((service, scope, span, span_id(&span.span_id)?)))
+        .collect();
+    spans.sort_by_key(|(_, _, span, _)| span.start_time_unix_nano);
+
+    let mut service_tracks: HashMap<&str, TrackUuid> = HashMap::new();
+    let mut tracks = Vec::with_capacity(spans.len());
+    let mut phases = Vec::new();
+    for (i, &(service, _, span, id)) in spans.iter().enumerate() {
+        let service_track = *service_tracks
+            .entry(service)
+            .or_insert_with(|| ctx.track().name(service).build());
+        let parent = span_id(&span.parent_span_id);
+        let remote_parent = parent.filter(|p| owners.get(p).is_some_and(|o| *o != service));
+        let parent_track = match parent {
+            Some(parent) if owners.contains_key(&parent) && remote_parent.is_none() => parent,
+            _ => service_track,
+        };
+        tracks.push(
+            ctx.track()
+                .uuid(id)
+                .name(span.name.as_str())
+                .parent_uuid(parent_track)
+                .build(),
+        );
+        phases.push((span.start_time_unix_nano, i, Phase::Begin));
+        for (j, event) in span.events.iter().enumerate() {
+            phases.push((event.time_unix_nano, i, Phase::Event(j)));
+        }
+        phases.push((span.end_time_unix_nano, i, Phase::End));
+    }
+    // Stable, so a span's begin stays ahead of its end at equal timestamps.
+    phases.sort_by_key(|&(timestamp, _, _)| timestamp);
+
+    for (timestamp, i, phase) in phases {
+        let (service, scope, span, id) = spans[i];
+        let track = tracks[i];
+        match phase {
+            Phase::Begin => {
+                let mut begin = ctx
+                    .event()
+                    .with_begin()
+                    .with_clock(ClockId::Realtime)
+                    .with_timestamp_ns(timestamp)
+                    .with_track_uuid(track)
+                    .with_name(span.name.as_str());
+                if let Some(scope) = scope.scope.as_ref().filter(|s| !s.name.is_empty()) {
+                    begin.category(scope.name.as_str());
+                }
+                for attribute in &span.attributes {
+                    annotate(&mut begin, attribute);
+                }
+                if let Some(children) = flows.get(&id) {
+                    for child in children {
+                        begin.flow_id(*child);
+                    }
+                }
+                let parent = span_id(&span.parent_span_id);
+                if parent.is_some_and(|p| owners.get(&p).is_some_and(|o| *o != service)) {
+                    begin.terminating_flow_id(id);
+                }
+                begin.build();
+            }
+            Phase::Event(j) => {
+                let event = &span.events[j];
+                let mut instant = ctx
+                    .event()
+                    .with_instant()
+                    .with_clock(ClockId::Realtime)
+                    .with_timestamp_ns(timestamp)
+                    .with_track_uuid(track)
+                    .with_name(event.name.as_str());
+                for attribute in &event.attributes {
+                    annotate(&mut instant, attribute);
+                }
+                instant.build();
+            }
+            Phase::End => {
+                let mut end = ctx
+                    .event()
+                    .with_end()
+                    .with_clock(ClockId::Realtime)
+                    .with_timestamp_ns(timestamp)
+                    .with_track_uuid(track);
+                if let Some(status) = &span.status
+                    && status.code == StatusCode::Error as i32
+                {
+                    end.debug_str("error", status.message.as_str());
+                }
+                end.build();
+            }
+        }
+    }
+}
+
+/// A point in a span's life, the event index for span events.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Begin,
+    Event(usize),
+    End,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+
+    const EXPORT: &str = r#"{"resourceSpans":[
+        {"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"frontend"}}]},
+         "scopeSpans":[{"scope":{"name":"http"},"spans":[
+            {"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","spanId":"00f067aa0ba902b7",
+             "name":"GET /","startTimeUnixNano":"1000","endTimeUnixNano":"9000",
+             "attributes":[{"key":"http.status","value":{"intValue":"200"}}]}]}]},
+        {"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"backend"}}]},
+         "scopeSpans":[{"spans":[
+            {"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","spanId":"00000000000000aa",
+             "parentSpanId":"00f067aa0ba902b7","name":"query",
+             "startTimeUnixNano":"2000","endTimeUnixNano":"5000",
+             "status":{"code":2,"message":"timeout"}},
+            {"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","spanId":"00000000000000bb",
+             "parentSpanId":"00000000000000aa","name":"fetch",
+             "startTimeUnixNano":"3000","endTimeUnixNano":"4000"}]}]}]}"#;
+
+    #[test]
+    fn services_get_tracks_and_flows() -> Result<()> {
+        let request = read_otlp(EXPORT.as_bytes(), OtlpFormat::Json)?;
+        let protobuf = read_otlp(&request.encode_to_vec(), OtlpFormat::Protobuf)?;
+        assert_eq!(request, protobuf);
+
+        let trace = <Trace as protobuf::Message>::parse_from_bytes(&convert_otlp(
+            EXPORT.as_bytes(),
+            OtlpFormat::Json,
+        )?)?;
+        let tracks: HashMap<_, _> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .map(|t| (t.name().to_string(), (t.uuid(), t.parent_uuid())))
+            .collect();
+        assert_eq!(tracks["GET /"].1, tracks["frontend"].0);
+        assert_eq!(tracks["query"].1, tracks["backend"].0);
+        assert_eq!(tracks["fetch"].1, tracks["query"].0);
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert_eq!(events[0].flow_ids, [0xaa]);
+        assert_eq!(events[1].terminating_flow_ids, [0xaa]);
+        assert!(events[2].terminating_flow_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn converted_traces_validate() -> Result<()> {
+        let mut request = read_otlp(EXPORT.as_bytes(), OtlpFormat::Json)?;
+        request.resource_spans.reverse();
+        let mut ctx = Context::new();
+        write_otlp(&mut ctx, &request);
+        let mut buf = Vec::new();
+        ctx.write_to_vec(&mut buf)?;
+
+        let trace = <Trace as protobuf::Message>::parse_from_bytes(&buf)?;
+        assert_eq!(perfetto_writer::validate(&trace), []);
+        let starts: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.timestamp())
+            .collect();
+        assert!(starts.is_sorted(), "{starts:?}");
+        Ok(())
+    }
+}
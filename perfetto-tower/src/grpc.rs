@@ -0,0 +1,161 @@
+use perfetto_writer::{Context, TraceParent};
+use std::sync::{Arc, Mutex};
+use tonic::{
+    GrpcMethod, Request, Status,
+    metadata::{MetadataMap, MetadataValue},
+    service::Interceptor,
+};
+
+use crate::{FLOW_HEADER, Side, flow_id, lock};
+
+/// W3C trace context header. Servers end the flow named by its parent id
+/// when no [`FLOW_HEADER`] is present, so services traced with OpenTelemetry
+/// link up.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// A tonic interceptor that links gRPC calls across services with flow
+/// arrows. The client side records an instant carrying a new flow id for
+/// every call, even when the request already carries one, and sends the id in
+/// the [`FLOW_HEADER`] metadata; the server side records an
+/// instant ending that flow, so merged traces show an arrow between them.
+#[derive(Clone)]
+pub struct FlowInterceptor {
+    ctx: Arc<Mutex<Context>>,
+    side: Side,
+}
+
+impl FlowInterceptor {
+    /// For clients: starts a flow on every outgoing call.
+    pub fn client(ctx: Arc<Mutex<Context>>) -> Self {
+        Self {
+            ctx,
+            side: Side::Client,
+        }
+    }
+
+    /// For servers: ends the flow carried by every incoming call.
+    pub fn server(ctx: Arc<Mutex<Context>>) -> Self {
+        Self {
+            ctx,
+            side: Side::Server,
+        }
+    }
+}
+
+impl Interceptor for FlowInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let name = match request.extensions().get::<GrpcMethod<'static>>() {
+            Some(method) => format!("{}/{}", method.service(), method.method()),
+            None => "grpc".to_string(),
+        };
+        let mut ctx = lock(&self.ctx);
+        let (flow, carried) = match self.side {
+            Side::Client => {
+                let flow = flow_id(&ctx);
+                request
+                    .metadata_mut()
+                    .insert(FLOW_HEADER, MetadataValue::from(flow));
+                (Some(flow), None)
+            }
+            Side::Server => (None, carried_flow(request.metadata())),
+        };
+        let track = ctx.current_thread_track();
+        let mut event = ctx
+            .event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name(name);
+        if let Some(flow) = flow {
+            event.flow_id(flow);
+        }
+        if let Some(flow) = carried {
+            event.terminating_flow_id(flow);
+        }
+        event.build();
+        Ok(request)
+    }
+}
+
+/// The flow id an incoming call carries in its metadata.
+fn carried_flow(metadata: &MetadataMap) -> Option<u64> {
+    metadata
+        .get(FLOW_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or_else(|| {
+            metadata
+                .get(TRACEPARENT_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<TraceParent>().ok())
+                .map(|parent| parent.flow_id())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn flow_ids_cross_the_call() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let mut client = FlowInterceptor::client(Arc::clone(&ctx));
+        let mut server = FlowInterceptor::server(Arc::clone(&ctx));
+        let sent = client.call(Request::new(())).unwrap();
+        let flow: u64 = sent
+            .metadata()
+            .get(FLOW_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let received = Request::from_parts(sent.metadata().clone(), Default::default(), ());
+        server.call(received).unwrap();
+
+        let mut traced = Request::new(());
+        traced.metadata_mut().insert(
+            TRACEPARENT_HEADER,
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        server.call(traced).unwrap();
+
+        let fanned_out: Vec<u64> = (0..2)
+            .map(|_| {
+                let mut call = Request::new(());
+                call.metadata_mut()
+                    .insert(FLOW_HEADER, MetadataValue::from(flow));
+                let sent = client.call(call).unwrap();
+                sent.metadata()
+                    .get(FLOW_HEADER)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        assert_ne!(fanned_out[0], flow);
+        assert_ne!(fanned_out[0], fanned_out[1]);
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0].flow_ids, [flow]);
+        assert_eq!(events[1].terminating_flow_ids, [flow]);
+        assert_eq!(events[2].terminating_flow_ids, [0x00f0_67aa_0ba9_02b7]);
+        assert_eq!(events[3].flow_ids, [fanned_out[0]]);
+        assert_eq!(events[4].flow_ids, [fanned_out[1]]);
+    }
+}
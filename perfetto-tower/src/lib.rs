@@ -0,0 +1,340 @@
+//! A [`tower_layer::Layer`] that records every HTTP request as a slice on a
+//! "requests" track of a shared [`Context`], annotated with its method,
+//! uri, status and latency. A client layer passes a flow id to the server
+//! in the [`FLOW_HEADER`] header, so the trace draws an arrow from the
+//! client's request to the server's.
+//!
+//! ```
+//! use perfetto_tower::PerfettoHttpLayer;
+//! use perfetto_writer::Context;
+//! use std::sync::{Arc, Mutex};
+//! use tower::ServiceBuilder;
+//!
+//! let ctx = Arc::new(Mutex::new(Context::new()));
+//! let service = ServiceBuilder::new()
+//!     .layer(PerfettoHttpLayer::server(ctx))
+//!     .service_fn(|_: http::Request<()>| async {
+//!         Ok::<_, std::convert::Infallible>(http::Response::new(()))
+//!     });
+//! ```
+
+use http::{HeaderValue, Request, Response};
+use perfetto_writer::{Context, TrackUuid};
+use std::{
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError},
+    task::{Context as TaskContext, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+use web_time::Instant;
+
+#[cfg(feature = "tonic")]
+mod grpc;
+mod network;
+
+#[cfg(feature = "tonic")]
+pub use grpc::{FlowInterceptor, TRACEPARENT_HEADER};
+pub use network::{NetworkTracer, PhaseFuture, PhaseService};
+
+/// Header carrying the flow id from a client request to the server's.
+pub const FLOW_HEADER: &str = "x-perfetto-flow";
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A flow id unlikely to collide with another process's, since client and
+/// server traces are usually merged.
+fn flow_id(ctx: &Context) -> u64 {
+    static SALT: OnceLock<u64> = OnceLock::new();
+    let salt = SALT.get_or_init(|| RandomState::new().hash_one(std::process::id()));
+    ctx.next_id() ^ salt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Server,
+}
+
+/// Child tracks of a parent track such as "requests". Concurrent slices go
+/// on separate lanes so they don't have to nest, and lanes are reused once
+/// their slice ends.
+struct Lanes {
+    name: &'static str,
+    parent: Option<TrackUuid>,
+    free: Vec<TrackUuid>,
+    count: usize,
+}
+
+impl Lanes {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            parent: None,
+            free: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn acquire(&mut self, ctx: &mut Context) -> TrackUuid {
+        if let Some(lane) = self.free.pop() {
+            return lane;
+        }
+        let parent = *self
+            .parent
+            .get_or_insert_with(|| ctx.track().name(self.name).current_process().build());
+        self.count += 1;
+        ctx.create_child_track(parent, format!("{} {}", self.name, self.count))
+    }
+}
+
+/// Records HTTP requests handled or sent by the wrapped service.
+#[derive(Clone)]
+pub struct PerfettoHttpLayer {
+    ctx: Arc<Mutex<Context>>,
+    lanes: Arc<Mutex<Lanes>>,
+    side: Side,
+}
+
+impl PerfettoHttpLayer {
+    fn new(ctx: Arc<Mutex<Context>>, side: Side, name: &'static str) -> Self {
+        Self {
+            ctx,
+            lanes: Arc::new(Mutex::new(Lanes::new(name))),
+            side,
+        }
+    }
+
+    /// Records incoming requests, ending the flow started by a client
+    /// layer when the request carries a [`FLOW_HEADER`].
+    pub fn server(ctx: Arc<Mutex<Context>>) -> Self {
+        Self::new(ctx, Side::Server, "requests")
+    }
+
+    /// Records outgoing requests and adds a [`FLOW_HEADER`] to each, so a
+    /// server recording with [`PerfettoHttpLayer::server`] links to it.
+    pub fn client(ctx: Arc<Mutex<Context>>) -> Self {
+        Self::new(ctx, Side::Client, "client requests")
+    }
+}
+
+impl<S> Layer<S> for PerfettoHttpLayer {
+    type Service = PerfettoHttpService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PerfettoHttpService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// A service recording its requests. Created by [`PerfettoHttpLayer`].
+#[derive(Clone)]
+pub struct PerfettoHttpService<S> {
+    inner: S,
+    layer: PerfettoHttpLayer,
+}
+
+impl<S, B, R> Service<Request<B>> for PerfettoHttpService<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<R>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let layer = &self.layer;
+        let mut ctx = lock(&layer.ctx);
+        let lane = lock(&layer.lanes).acquire(&mut ctx);
+        let flow = match layer.side {
+            Side::Client => {
+                let flow = flow_id(&ctx);
+                request
+                    .headers_mut()
+                    .insert(FLOW_HEADER, HeaderValue::from(flow));
+                Some(flow)
+            }
+            Side::Server => None,
+        };
+        let incoming = request
+            .headers()
+            .get(FLOW_HEADER)
+            .filter(|_| layer.side == Side::Server)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let mut event = ctx
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(lane)
+            .with_name(format!("{} {}", request.method(), request.uri().path()))
+            .with_debug_str("method", request.method().as_str())
+            .with_debug_str("uri", request.uri().to_string());
+        if let Some(flow) = flow {
+            event.flow_id(flow);
+        }
+        if let Some(flow) = incoming {
+            event.terminating_flow_id(flow);
+        }
+        event.build();
+        drop(ctx);
+        ResponseFuture {
+            future: self.inner.call(request),
+            layer: layer.clone(),
+            lane: Some(lane),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// Ends the request's slice once the response is ready.
+pub struct ResponseFuture<F> {
+    future: F,
+    layer: PerfettoHttpLayer,
+    /// The request's lane while its slice is open.
+    lane: Option<TrackUuid>,
+    start: Instant,
+}
+
+impl<F> ResponseFuture<F> {
+    /// Ends the slice with `status`, or as failed when there is none, and
+    /// frees the lane.
+    fn end(&mut self, status: Option<u16>) {
+        let Some(lane) = self.lane.take() else {
+            return;
+        };
+        let latency = self.start.elapsed().as_nanos() as u64;
+        let mut ctx = lock(&self.layer.ctx);
+        let mut event = ctx
+            .event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(lane)
+            .with_debug_uint("latency_ns", latency);
+        match status {
+            Some(status) => event.debug_uint("status", status.into()),
+            None => event.debug_bool("error", true),
+        }
+        event.build();
+        drop(ctx);
+        lock(&self.layer.lanes).free.push(lane);
+    }
+}
+
+impl<F, R, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<R>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is never moved out of `self`, and no other field
+        // is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let poll = future.poll(cx);
+        if let Poll::Ready(result) = &poll {
+            let status = result.as_ref().ok().map(|r| r.status().as_u16());
+            this.end(status);
+        }
+        poll
+    }
+}
+
+impl<F> Drop for ResponseFuture<F> {
+    fn drop(&mut self) {
+        self.end(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::{convert::Infallible, task::Waker};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    fn ready<T>(future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        match future
+            .as_mut()
+            .poll(&mut TaskContext::from_waker(Waker::noop()))
+        {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future was not ready"),
+        }
+    }
+
+    #[test]
+    fn requests_are_linked_from_client_to_server() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let server = ServiceBuilder::new()
+            .layer(PerfettoHttpLayer::server(Arc::clone(&ctx)))
+            .service_fn(|_: Request<()>| async {
+                let mut response = Response::new(());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                Ok::<_, Infallible>(response)
+            });
+        let client = ServiceBuilder::new()
+            .layer(PerfettoHttpLayer::client(Arc::clone(&ctx)))
+            .service(server);
+        let request = Request::get("/users/7").body(()).unwrap();
+        let response = ready(client.oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name().to_string())
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                "client requests",
+                "client requests 1",
+                "requests",
+                "requests 1"
+            ]
+        );
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let types: Vec<_> = events.iter().map(|e| e.type_()).collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        assert_eq!(events[0].flow_ids.len(), 1);
+        assert_eq!(events[0].flow_ids, events[1].terminating_flow_ids);
+        assert!(
+            events[2]
+                .debug_annotations
+                .iter()
+                .any(|a| a.uint_value() == 404)
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use perfetto_writer::{Context, TrackUuid};
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+use tower_service::Service;
+
+use crate::{Lanes, lock};
+
+thread_local! {
+    /// The request being polled on this thread, whose connection phases
+    /// nest on its lane.
+    static CURRENT: RefCell<Option<Arc<Mutex<InFlight>>>> = const { RefCell::new(None) };
+}
+
+/// Records outgoing requests as slices on lanes of a "network" track, with
+/// the phases of each (DNS, connect, TLS, first byte) nested in it on the
+/// same lane, giving a request waterfall like a browser's devtools.
+///
+/// Each phase wraps the `tower` service performing it. With `hyper-util`,
+/// wrap the resolver passed to `HttpConnector::new_with_resolver` with
+/// [`NetworkTracer::resolver`], the `HttpConnector` with
+/// [`NetworkTracer::connector`], the TLS connector around it with
+/// [`NetworkTracer::tls`], and the client with [`NetworkTracer::request`].
+/// Phases called while a request is polled go on its lane; others get a
+/// lane of their own.
+#[derive(Clone)]
+pub struct NetworkTracer {
+    ctx: Arc<Mutex<Context>>,
+    lanes: Arc<Mutex<Lanes>>,
+}
+
+impl NetworkTracer {
+    pub fn new(ctx: Arc<Mutex<Context>>) -> Self {
+        Self {
+            ctx,
+            lanes: Arc::new(Mutex::new(Lanes::new("network"))),
+        }
+    }
+
+    /// Records each name lookup as a "dns" slice.
+    pub fn resolver<S>(&self, inner: S) -> PhaseService<S> {
+        self.phase(Some("dns"), inner)
+    }
+
+    /// Records each connection as a "connect" slice.
+    pub fn connector<S>(&self, inner: S) -> PhaseService<S> {
+        self.phase(Some("connect"), inner)
+    }
+
+    /// Records each connection made through a TLS connector as a "tls"
+    /// slice, which includes the connect it wraps.
+    pub fn tls<S>(&self, inner: S) -> PhaseService<S> {
+        self.phase(Some("tls"), inner)
+    }
+
+    /// Records each request as a "request" slice until the response
+    /// headers arrive. The connection phases it waits for nest in it,
+    /// followed by a "first byte" slice while it waits for the response.
+    pub fn request<S>(&self, inner: S) -> PhaseService<S> {
+        self.phase(None, inner)
+    }
+
+    fn phase<S>(&self, phase: Option<&'static str>, inner: S) -> PhaseService<S> {
+        PhaseService {
+            inner,
+            tracer: self.clone(),
+            phase,
+        }
+    }
+}
+
+/// A request in flight.
+struct InFlight {
+    lane: TrackUuid,
+    /// Connection phases open on the lane.
+    phases: usize,
+    /// Whether the "first byte" slice is open.
+    waiting: bool,
+    /// Set once the request completed, to whether it failed. Its slice
+    /// ends when its last phase does.
+    ended: Option<bool>,
+}
+
+/// What a slice is part of.
+enum Role {
+    /// A phase outside any request, on a lane of its own.
+    Alone,
+    /// A phase of a request, on the request's lane.
+    Phase(Arc<Mutex<InFlight>>),
+    Request(Arc<Mutex<InFlight>>),
+}
+
+/// A service whose calls are recorded as slices. Created by
+/// [`NetworkTracer`].
+#[derive(Clone)]
+pub struct PhaseService<S> {
+    inner: S,
+    tracer: NetworkTracer,
+    /// The phase's name, or None for requests.
+    phase: Option<&'static str>,
+}
+
+impl<S, Req, T, E> Service<Req> for PhaseService<S>
+where
+    S: Service<Req, Response = T, Error = E>,
+{
+    type Response = T;
+    type Error = E;
+    type Future = PhaseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), E>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let tracer = &self.tracer;
+        let mut ctx = lock(&tracer.ctx);
+        let current = CURRENT.with(|current| current.borrow().clone());
+        let (lane, role) = match (self.phase, current) {
+            (None, _) => {
+                let lane = lock(&tracer.lanes).acquire(&mut ctx);
+                let in_flight = InFlight {
+                    lane,
+                    phases: 0,
+                    waiting: false,
+                    ended: None,
+                };
+                (lane, Role::Request(Arc::new(Mutex::new(in_flight))))
+            }
+            (Some(_), Some(current)) => {
+                let mut in_flight = lock(&current);
+                if in_flight.waiting {
+                    // Reconnecting: the wait resumes after the new phases.
+                    in_flight.waiting = false;
+                    ctx.event()
+                        .with_end()
+                        .with_now()
+                        .with_track_uuid(in_flight.lane)
+                        .build();
+                }
+                in_flight.phases += 1;
+                let lane = in_flight.lane;
+                drop(in_flight);
+                (lane, Role::Phase(current))
+            }
+            (Some(_), None) => (lock(&tracer.lanes).acquire(&mut ctx), Role::Alone),
+        };
+        ctx.event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(lane)
+            .with_name(self.phase.unwrap_or("request"))
+            .build();
+        drop(ctx);
+        PhaseFuture {
+            future: self.inner.call(request),
+            tracer: tracer.clone(),
+            lane: Some(lane),
+            role,
+        }
+    }
+}
+
+/// Ends a phase's or request's slice once it completes.
+pub struct PhaseFuture<F> {
+    future: F,
+    tracer: NetworkTracer,
+    /// The lane while the slice is open.
+    lane: Option<TrackUuid>,
+    role: Role,
+}
+
+/// Makes a request current on this thread until dropped.
+struct Polling(Option<Arc<Mutex<InFlight>>>);
+
+impl Polling {
+    fn enter(request: &Arc<Mutex<InFlight>>) -> Self {
+        Self(CURRENT.with(|current| current.replace(Some(Arc::clone(request)))))
+    }
+}
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+impl<F> PhaseFuture<F> {
+    /// Starts waiting for the response once a pending request has no
+    /// connection phase open.
+    fn wait(&mut self) {
+        let (Some(lane), Role::Request(request)) = (self.lane, &self.role) else {
+            return;
+        };
+        let mut ctx = lock(&self.tracer.ctx);
+        let mut in_flight = lock(request);
+        if in_flight.phases == 0 && !in_flight.waiting {
+            in_flight.waiting = true;
+            ctx.event()
+                .with_begin()
+                .with_now()
+                .with_track_uuid(lane)
+                .with_name("first byte")
+                .build();
+        }
+    }
+
+    fn end(&mut self, failed: bool) {
+        let Some(lane) = self.lane.take() else {
+            return;
+        };
+        let mut ctx = lock(&self.tracer.ctx);
+        let end = |ctx: &mut Context, failed: bool| {
+            let mut event = ctx.event().with_end().with_now().with_track_uuid(lane);
+            if failed {
+                event.debug_bool("error", true);
+            }
+            event.build();
+        };
+        let free = match &self.role {
+            Role::Alone => {
+                end(&mut ctx, failed);
+                true
+            }
+            Role::Phase(request) => {
+                end(&mut ctx, failed);
+                let mut in_flight = lock(request);
+                in_flight.phases -= 1;
+                let last = in_flight.phases == 0;
+                if let (true, Some(failed)) = (last, in_flight.ended) {
+                    end(&mut ctx, failed);
+                }
+                last && in_flight.ended.is_some()
+            }
+            Role::Request(request) => {
+                let mut in_flight = lock(request);
+                if in_flight.waiting {
+                    in_flight.waiting = false;
+                    end(&mut ctx, false);
+                }
+                // A phase still open, e.g. a connection left to finish in
+                // the background, ends the request after its own slice so
+                // the lane stays nested.
+                in_flight.ended = Some(failed);
+                if in_flight.phases == 0 {
+                    end(&mut ctx, failed);
+                }
+                in_flight.phases == 0
+            }
+        };
+        drop(ctx);
+        if free {
+            lock(&self.tracer.lanes).free.push(lane);
+        }
+    }
+}
+
+impl<F, T, E> Future for PhaseFuture<F>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is never moved out of `self`, and no other field
+        // is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let polling = match &this.role {
+            Role::Request(request) => Some(Polling::enter(request)),
+            _ => None,
+        };
+        let poll = future.poll(cx);
+        drop(polling);
+        match &poll {
+            Poll::Ready(result) => this.end(result.is_err()),
+            Poll::Pending => this.wait(),
+        }
+        poll
+    }
+}
+
+impl<F> Drop for PhaseFuture<F> {
+    fn drop(&mut self) {
+        self.end(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::task::Waker;
+    use tower::{ServiceExt, service_fn};
+
+    /// Pending on the first poll.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<()> {
+            if std::mem::replace(&mut self.0, true) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn phases_are_slices_on_network_lanes() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let tracer = NetworkTracer::new(Arc::clone(&ctx));
+        let resolver = tracer.resolver(service_fn(|_: &str| async { Ok::<_, ()>(1) }));
+        let connector = tracer.connector(service_fn(|_: u32| async { Err::<(), _>(()) }));
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        let mut lookup = std::pin::pin!(resolver.oneshot("example.com"));
+        assert_eq!(lookup.as_mut().poll(&mut cx), Poll::Ready(Ok(1)));
+        let mut connect = std::pin::pin!(connector.oneshot(1));
+        assert_eq!(connect.as_mut().poll(&mut cx), Poll::Ready(Err(())));
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name().to_string())
+            .collect();
+        assert_eq!(tracks, ["network", "network 1"]);
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let types: Vec<_> = events.iter().map(|e| e.type_()).collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        assert!(events[1].debug_annotations.is_empty());
+        assert!(events[3].debug_annotations[0].bool_value());
+    }
+
+    #[test]
+    fn phases_nest_in_their_request() {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let tracer = NetworkTracer::new(Arc::clone(&ctx));
+        let connector = tracer.connector(service_fn(|_: u32| async {
+            YieldOnce(false).await;
+            Ok::<_, ()>(())
+        }));
+        let client = tracer.request(service_fn(move |_: &str| {
+            let connector = connector.clone();
+            async move {
+                connector.oneshot(1).await?;
+                YieldOnce(false).await;
+                Ok::<_, ()>(200)
+            }
+        }));
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        let mut request = std::pin::pin!(client.oneshot("/"));
+        assert!(request.as_mut().poll(&mut cx).is_pending());
+        assert!(request.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(request.as_mut().poll(&mut cx), Poll::Ready(Ok(200)));
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to(&mut buf).unwrap();
+        let trace = Trace::parse_from_bytes(&buf).unwrap();
+        let names: std::collections::HashMap<_, _> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| (n.iid(), n.name()))
+            .collect();
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert!(
+            events
+                .iter()
+                .all(|e| e.track_uuid() == events[0].track_uuid())
+        );
+        let slices: Vec<_> = events
+            .iter()
+            .map(|e| match e.type_() {
+                Type::TYPE_SLICE_BEGIN => names[&e.name_iid()],
+                _ => "end",
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            ["request", "connect", "end", "first byte", "end", "end"]
+        );
+    }
+}
@@ -0,0 +1,31 @@
+/* C API for perfetto-writer. Link against libperfetto_writer_ffi. */
+#ifndef PERFETTO_WRITER_H
+#define PERFETTO_WRITER_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A trace buffer. Safe to share between threads. */
+typedef struct PerfettoContext PerfettoContext;
+
+PerfettoContext *perfetto_context_new(void);
+void perfetto_context_free(PerfettoContext *ctx);
+
+/* Slices nest on the calling thread's track; end closes the innermost. */
+void perfetto_slice_begin(PerfettoContext *ctx, const char *name);
+void perfetto_slice_end(PerfettoContext *ctx);
+
+void perfetto_instant(PerfettoContext *ctx, const char *name);
+
+/* Sets the counter track called name, created on first use. */
+void perfetto_counter(PerfettoContext *ctx, const char *name, double value);
+
+/* Appends the buffered events to the file at path. Returns 0 on success. */
+int perfetto_flush_to_file(PerfettoContext *ctx, const char *path);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,221 @@
+//! A C API for [`perfetto_writer`], so C and C++ code can record into the
+//! same trace as Rust. The declarations are in `include/perfetto_writer.h`.
+//!
+//! Strings must be valid, NUL terminated and UTF-8; invalid UTF-8 is
+//! replaced. Null contexts and names are ignored.
+
+use perfetto_writer::Context;
+use std::{
+    borrow::Cow,
+    ffi::{CStr, c_char, c_int},
+    fs::OpenOptions,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// A [`Context`] behind a lock, shared by all C threads.
+pub struct PerfettoContext(Mutex<Context>);
+
+impl PerfettoContext {
+    fn lock(&self) -> MutexGuard<'_, Context> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Turns the C arguments into references, or `None` if either is null.
+///
+/// # Safety
+///
+/// `ctx` and `s` must be null or valid for the duration of the call.
+unsafe fn args<'a>(
+    ctx: *const PerfettoContext,
+    s: *const c_char,
+) -> Option<(&'a PerfettoContext, Cow<'a, str>)> {
+    if ctx.is_null() || s.is_null() {
+        return None;
+    }
+    // SAFETY: both pointers were checked for null and are valid per the
+    // caller's contract.
+    unsafe { Some((&*ctx, CStr::from_ptr(s).to_string_lossy())) }
+}
+
+/// Creates a context. Release it with [`perfetto_context_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn perfetto_context_new() -> *mut PerfettoContext {
+    Box::into_raw(Box::new(PerfettoContext(Mutex::new(Context::new()))))
+}
+
+/// Releases a context, dropping events that were not flushed.
+///
+/// # Safety
+///
+/// `ctx` must be null or come from [`perfetto_context_new`], and must not
+/// be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_context_free(ctx: *mut PerfettoContext) {
+    if !ctx.is_null() {
+        // SAFETY: the pointer came from `Box::into_raw` per the contract.
+        drop(unsafe { Box::from_raw(ctx) });
+    }
+}
+
+/// Begins a slice on the calling thread's track.
+///
+/// # Safety
+///
+/// `ctx` must be a live context and `name` a NUL terminated string, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_slice_begin(ctx: *const PerfettoContext, name: *const c_char) {
+    let Some((ctx, name)) = (unsafe { args(ctx, name) }) else {
+        return;
+    };
+    let mut ctx = ctx.lock();
+    let track = ctx.current_thread_track();
+    ctx.event()
+        .with_begin()
+        .with_now()
+        .with_track_uuid(track)
+        .with_name(name.as_ref())
+        .build();
+}
+
+/// Ends the innermost slice on the calling thread's track.
+///
+/// # Safety
+///
+/// `ctx` must be a live context or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_slice_end(ctx: *const PerfettoContext) {
+    if ctx.is_null() {
+        return;
+    }
+    // SAFETY: checked for null and live per the contract.
+    let mut ctx = unsafe { &*ctx }.lock();
+    let track = ctx.current_thread_track();
+    ctx.event()
+        .with_end()
+        .with_now()
+        .with_track_uuid(track)
+        .build();
+}
+
+/// Records an instant on the calling thread's track.
+///
+/// # Safety
+///
+/// `ctx` must be a live context and `name` a NUL terminated string, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_instant(ctx: *const PerfettoContext, name: *const c_char) {
+    let Some((ctx, name)) = (unsafe { args(ctx, name) }) else {
+        return;
+    };
+    let mut ctx = ctx.lock();
+    let track = ctx.current_thread_track();
+    ctx.event()
+        .with_instant()
+        .with_now()
+        .with_track_uuid(track)
+        .with_name(name.as_ref())
+        .build();
+}
+
+/// Sets the counter track called `name` to `value`.
+///
+/// # Safety
+///
+/// `ctx` must be a live context and `name` a NUL terminated string, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_counter(
+    ctx: *const PerfettoContext,
+    name: *const c_char,
+    value: f64,
+) {
+    let Some((ctx, name)) = (unsafe { args(ctx, name) }) else {
+        return;
+    };
+    let mut ctx = ctx.lock();
+    let track = ctx.counter_track(&name);
+    ctx.event()
+        .with_counter()
+        .with_now()
+        .with_track_uuid(track)
+        .with_double_counter_value(value)
+        .build();
+}
+
+/// Appends the buffered events to the file at `path`, creating it if
+/// needed. Returns 0 on success and -1 on error.
+///
+/// # Safety
+///
+/// `ctx` must be a live context and `path` a NUL terminated string, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn perfetto_flush_to_file(
+    ctx: *const PerfettoContext,
+    path: *const c_char,
+) -> c_int {
+    let Some((ctx, path)) = (unsafe { args(ctx, path) }) else {
+        return -1;
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.as_ref());
+    match file
+        .map_err(Into::into)
+        .and_then(|mut f| ctx.lock().write_to(&mut f))
+    {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::ffi::CString;
+
+    #[test]
+    fn c_calls_produce_a_trace() {
+        let path =
+            std::env::temp_dir().join(format!("perfetto-ffi-{}.pftrace", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new("work").unwrap();
+        let depth = CString::new("depth").unwrap();
+        unsafe {
+            let ctx = perfetto_context_new();
+            perfetto_slice_begin(ctx, name.as_ptr());
+            perfetto_counter(ctx, depth.as_ptr(), 2.0);
+            perfetto_instant(ctx, std::ptr::null());
+            perfetto_slice_end(ctx);
+            assert_eq!(perfetto_flush_to_file(ctx, c_path.as_ptr()), 0);
+            perfetto_instant(ctx, name.as_ptr());
+            assert_eq!(perfetto_flush_to_file(ctx, c_path.as_ptr()), 0);
+            assert_eq!(
+                perfetto_flush_to_file(std::ptr::null(), c_path.as_ptr()),
+                -1
+            );
+            perfetto_context_free(ctx);
+        }
+
+        let trace = Trace::parse_from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_COUNTER,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_INSTANT
+            ]
+        );
+    }
+}
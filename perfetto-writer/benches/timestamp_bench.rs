@@ -0,0 +1,57 @@
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use perfetto_writer::timestamp::{Absolute, Delta, DeltaOfDelta, TimestampEncoding, encoded_len};
+
+const START: u64 = 1_700_000_000_000_000_000;
+
+/// Timestamps of a dense trace: bursts of events a few hundred nanoseconds
+/// apart, separated by longer gaps.
+fn dense() -> Vec<u64> {
+    let mut ts = START;
+    (0..10_000u64)
+        .map(|i| {
+            ts += if i % 64 == 0 {
+                250_000
+            } else {
+                200 + (i * 37) % 300
+            };
+            ts
+        })
+        .collect()
+}
+
+/// Timestamps of a counter sampled at a fixed period.
+fn periodic() -> Vec<u64> {
+    (0..10_000u64).map(|i| START + i * 1_000_000).collect()
+}
+
+const ENCODINGS: [fn() -> Box<dyn TimestampEncoding>; 3] = [
+    || Box::new(Absolute),
+    || Box::new(Delta::default()),
+    || Box::new(DeltaOfDelta::default()),
+];
+
+fn bench_timestamp_encodings(c: &mut Criterion) {
+    for (workload, timestamps) in [("dense", dense()), ("periodic", periodic())] {
+        for new in ENCODINGS {
+            let mut encoding = new();
+            let bytes = encoded_len(encoding.as_mut(), &timestamps);
+            println!(
+                "{}/{}: {} bytes, {:.2} per event",
+                workload,
+                encoding.name(),
+                bytes,
+                bytes as f64 / timestamps.len() as f64
+            );
+        }
+        let mut group = c.benchmark_group(workload);
+        for new in ENCODINGS {
+            group.bench_function(new().name(), |b| {
+                b.iter(|| encoded_len(new().as_mut(), black_box(&timestamps)))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_timestamp_encodings);
+criterion_main!(benches);
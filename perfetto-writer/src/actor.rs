@@ -0,0 +1,188 @@
+use smol_str::SmolStr;
+use std::sync::{
+    Arc, Mutex, PoisonError,
+    atomic::{AtomicI64, Ordering::Relaxed},
+};
+
+use crate::{Context, CounterUnit};
+
+/// A message in flight to an actor, returned by [`ActorTracer::send`] and
+/// consumed by [`ActorTracer::handle`] to link the two with a flow.
+#[derive(Debug)]
+pub struct Envelope {
+    flow: u64,
+}
+
+/// Gives an actor its own track, with a slice per handled message, a
+/// mailbox-depth counter and flows from each send to its handler.
+///
+/// The tracer is framework agnostic: with actix or ractor, call
+/// [`ActorTracer::send`] next to `do_send`/`cast` and carry the [`Envelope`]
+/// inside the message, then wrap the body of `handle` in
+/// [`ActorTracer::handle`].
+pub struct ActorTracer {
+    ctx: Arc<Mutex<Context>>,
+    track: u64,
+    mailbox_track: u64,
+    depth: AtomicI64,
+}
+
+impl ActorTracer {
+    pub fn new(ctx: Arc<Mutex<Context>>, name: &str) -> Self {
+        let mut guard = ctx.lock().unwrap();
+        let track = guard.track().name(format!("actor {}", name)).build();
+        let mailbox_track = guard
+            .track()
+            .name(format!("{} mailbox", name))
+            .parent_uuid(track)
+            .counter()
+            .unit(CounterUnit::UNIT_COUNT)
+            .build();
+        drop(guard);
+        Self {
+            ctx,
+            track,
+            mailbox_track,
+            depth: AtomicI64::new(0),
+        }
+    }
+
+    pub fn track_uuid(&self) -> u64 {
+        self.track
+    }
+
+    /// Current number of sent but unhandled messages.
+    pub fn mailbox_depth(&self) -> i64 {
+        self.depth.load(Relaxed)
+    }
+
+    /// Records `message` being sent to the actor as an instant on the
+    /// sending thread's track.
+    pub fn send(&self, message: impl Into<SmolStr>) -> Envelope {
+        let mut ctx = self.ctx.lock().unwrap();
+        let flow = ctx.next_id();
+        let sender = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(sender)
+            .with_category("actor")
+            .with_name(message)
+            .with_flow_id(flow)
+            .build();
+        let depth = self.depth.fetch_add(1, Relaxed) + 1;
+        self.record_depth(&mut ctx, depth);
+        Envelope { flow }
+    }
+
+    /// Runs `f` inside a slice named `message` on the actor's track.
+    pub fn handle<R>(
+        &self,
+        envelope: Envelope,
+        message: impl Into<SmolStr>,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        {
+            let mut ctx = self.ctx.lock().unwrap();
+            let depth = self.depth.fetch_sub(1, Relaxed) - 1;
+            self.record_depth(&mut ctx, depth);
+            ctx.event()
+                .with_begin()
+                .with_now()
+                .with_track_uuid(self.track)
+                .with_category("actor")
+                .with_name(message)
+                .with_terminating_flow_id(envelope.flow)
+                .build();
+        }
+        let result = f();
+        self.ctx
+            .lock()
+            .unwrap()
+            .event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(self.track)
+            .build();
+        result
+    }
+
+    fn record_depth(&self, ctx: &mut Context, depth: i64) {
+        ctx.event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(self.mailbox_track)
+            .with_counter_value(depth)
+            .build();
+    }
+}
+
+impl Drop for ActorTracer {
+    fn drop(&mut self) {
+        // The mailbox track is nested in the actor's.
+        self.ctx
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .release_track(self.track);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[test]
+    fn messages_flow_to_actor_track() -> Result<()> {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let actor = ActorTracer::new(Arc::clone(&ctx), "cache");
+
+        let first = actor.send("get");
+        let second = actor.send("put");
+        assert_eq!(actor.mailbox_depth(), 2);
+        assert_eq!(actor.handle(first, "get", || 7), 7);
+        actor.handle(second, "put", || ());
+        assert_eq!(actor.mailbox_depth(), 0);
+
+        let mut buf = Vec::new();
+        ctx.lock().unwrap().write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name().to_string())
+            .collect();
+        assert_eq!(tracks[..2], ["actor cache", "cache mailbox"]);
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let depths: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == Type::TYPE_COUNTER)
+            .map(|e| e.counter_value())
+            .collect();
+        assert_eq!(depths, [1, 2, 1, 0]);
+
+        let sent: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == Type::TYPE_INSTANT)
+            .map(|e| e.flow_ids[0])
+            .collect();
+        let handled: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == Type::TYPE_SLICE_BEGIN)
+            .inspect(|e| assert_eq!(e.track_uuid(), actor.track_uuid()))
+            .map(|e| e.terminating_flow_ids[0])
+            .collect();
+        assert_eq!(sent, handled);
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    panic::Location,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use crate::{Context, CounterUnit};
+
+/// A snapshot of the counters maintained by a [`TracingAllocator`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+    pub allocs: u64,
+    pub deallocs: u64,
+    pub allocated_bytes: u64,
+    pub freed_bytes: u64,
+}
+
+impl AllocStats {
+    /// Bytes currently held by live allocations.
+    pub fn live_bytes(&self) -> u64 {
+        self.allocated_bytes.saturating_sub(self.freed_bytes)
+    }
+
+    fn since(&self, start: &AllocStats) -> AllocStats {
+        AllocStats {
+            allocs: self.allocs.wrapping_sub(start.allocs),
+            deallocs: self.deallocs.wrapping_sub(start.deallocs),
+            allocated_bytes: self.allocated_bytes.wrapping_sub(start.allocated_bytes),
+            freed_bytes: self.freed_bytes.wrapping_sub(start.freed_bytes),
+        }
+    }
+}
+
+thread_local! {
+    /// This thread's allocations, for [`AllocSite`]. Const initialized and
+    /// without a destructor, so the allocator can reach it without allocating.
+    static THREAD_STATS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            allocs: 0,
+            deallocs: 0,
+            allocated_bytes: 0,
+            freed_bytes: 0,
+        })
+    };
+}
+
+fn thread_stats() -> AllocStats {
+    THREAD_STATS.try_with(Cell::get).unwrap_or_default()
+}
+
+fn update_thread_stats(f: impl FnOnce(&mut AllocStats)) {
+    let _ = THREAD_STATS.try_with(|cell| {
+        let mut stats = cell.get();
+        f(&mut stats);
+        cell.set(stats);
+    });
+}
+
+/// A slice on the current thread's track covering the allocations made
+/// between [`TracingAllocator::site`] and [`AllocSite::end`]. Created with
+/// [`TracingAllocator::site`].
+#[derive(Debug)]
+#[must_use = "the slice stays open until `end` is called"]
+pub struct AllocSite {
+    start: AllocStats,
+}
+
+impl AllocSite {
+    /// Ends the slice, annotated with what this thread allocated and freed
+    /// since it began.
+    pub fn end(self, ctx: &mut Context) {
+        let delta = thread_stats().since(&self.start);
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(track)
+            .with_debug_uint("allocs", delta.allocs)
+            .with_debug_uint("deallocs", delta.deallocs)
+            .with_debug_uint("allocated_bytes", delta.allocated_bytes)
+            .with_debug_uint("freed_bytes", delta.freed_bytes)
+            .build();
+    }
+}
+
+/// A [`GlobalAlloc`] wrapper that counts allocations and bytes.
+///
+/// The allocator itself never touches a [`Context`]: recording from inside
+/// `alloc` would recurse into the allocator. Instead the counters are kept in
+/// atomics and written out as counter tracks with [`TracingAllocator::record`].
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOC: TracingAllocator = TracingAllocator::system();
+///
+/// ALLOC.record(&mut ctx);
+/// ```
+///
+/// For a per-call-site view, [`TracingAllocator::site`] opens a slice named
+/// after its caller that records what the current thread allocated until
+/// [`AllocSite::end`]:
+///
+/// ```ignore
+/// let site = ALLOC.site(&mut ctx);
+/// let rows = load_rows();
+/// site.end(&mut ctx);
+/// ```
+#[derive(Debug, Default)]
+pub struct TracingAllocator<A = System> {
+    inner: A,
+    allocs: AtomicU64,
+    deallocs: AtomicU64,
+    allocated_bytes: AtomicU64,
+    freed_bytes: AtomicU64,
+}
+
+impl TracingAllocator<System> {
+    pub const fn system() -> Self {
+        Self::new(System)
+    }
+}
+
+impl<A> TracingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocs: AtomicU64::new(0),
+            deallocs: AtomicU64::new(0),
+            allocated_bytes: AtomicU64::new(0),
+            freed_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            allocs: self.allocs.load(Relaxed),
+            deallocs: self.deallocs.load(Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Relaxed),
+            freed_bytes: self.freed_bytes.load(Relaxed),
+        }
+    }
+
+    /// Writes the current counters to `heap.*` counter tracks in `ctx`.
+    pub fn record(&self, ctx: &mut Context) {
+        let stats = self.stats();
+        let counters = [
+            (
+                "heap.live_bytes",
+                CounterUnit::UNIT_SIZE_BYTES,
+                stats.live_bytes(),
+            ),
+            (
+                "heap.allocated_bytes",
+                CounterUnit::UNIT_SIZE_BYTES,
+                stats.allocated_bytes,
+            ),
+            ("heap.allocs", CounterUnit::UNIT_COUNT, stats.allocs),
+            ("heap.deallocs", CounterUnit::UNIT_COUNT, stats.deallocs),
+        ];
+        for (name, unit, value) in counters {
+            let track = ctx.named_counter_track(name, unit);
+            ctx.event()
+                .with_counter()
+                .with_now()
+                .with_track_uuid(track)
+                .with_counter_value(value as i64)
+                .build();
+        }
+    }
+
+    /// Begins a slice on the current thread's track named after the calling
+    /// file and line, to be closed with [`AllocSite::end`].
+    #[track_caller]
+    pub fn site(&self, ctx: &mut Context) -> AllocSite {
+        let location = Location::caller();
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_category("heap")
+            .with_name(format!("{}:{}", location.file(), location.line()))
+            .with_source_location(location.file(), location.line())
+            .build();
+        AllocSite {
+            start: thread_stats(),
+        }
+    }
+
+    fn on_alloc(&self, size: usize) {
+        self.allocs.fetch_add(1, Relaxed);
+        self.allocated_bytes.fetch_add(size as u64, Relaxed);
+        update_thread_stats(|stats| {
+            stats.allocs = stats.allocs.wrapping_add(1);
+            stats.allocated_bytes = stats.allocated_bytes.wrapping_add(size as u64);
+        });
+    }
+
+    fn on_dealloc(&self, size: usize) {
+        self.deallocs.fetch_add(1, Relaxed);
+        self.freed_bytes.fetch_add(size as u64, Relaxed);
+        update_thread_stats(|stats| {
+            stats.deallocs = stats.deallocs.wrapping_add(1);
+            stats.freed_bytes = stats.freed_bytes.wrapping_add(size as u64);
+        });
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            self.on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            self.on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        self.on_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            self.on_dealloc(layout.size());
+            self.on_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn counts_allocations() {
+        let alloc = TracingAllocator::system();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            let ptr = alloc.realloc(ptr, layout, 128);
+            alloc.dealloc(ptr, Layout::from_size_align(128, 8).unwrap());
+        }
+
+        let stats = alloc.stats();
+        assert_eq!(stats.allocs, 2);
+        assert_eq!(stats.deallocs, 2);
+        assert_eq!(stats.allocated_bytes, 192);
+        assert_eq!(stats.live_bytes(), 0);
+    }
+
+    #[test]
+    fn record_writes_counter_tracks() -> Result<()> {
+        let alloc = TracingAllocator::system();
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        alloc.record(&mut ctx);
+        alloc.record(&mut ctx);
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name().to_string())
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                "heap.live_bytes",
+                "heap.allocated_bytes",
+                "heap.allocs",
+                "heap.deallocs"
+            ]
+        );
+
+        let values: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().counter_value())
+            .collect();
+        assert_eq!(values, [32, 32, 1, 0, 32, 32, 1, 0]);
+
+        unsafe { alloc.dealloc(ptr, layout) };
+        Ok(())
+    }
+
+    #[test]
+    fn sites_are_slices_with_their_allocations() -> Result<()> {
+        let alloc = TracingAllocator::system();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let line = line!() + 1;
+        let site = alloc.site(&mut ctx);
+        let kept = unsafe {
+            let ptr = alloc.alloc(layout);
+            alloc.dealloc(ptr, layout);
+            alloc.alloc(layout)
+        };
+        site.end(&mut ctx);
+        unsafe { alloc.dealloc(kept, layout) };
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let names = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter());
+        let name = format!("{}:{}", file!(), line);
+        assert!(names.map(|n| n.name()).any(|n| n == name));
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].has_source_location_iid());
+        let values: Vec<_> = events[1]
+            .debug_annotations
+            .iter()
+            .map(|a| a.uint_value())
+            .collect();
+        assert_eq!(values, [2, 1, 96, 48]);
+        Ok(())
+    }
+}
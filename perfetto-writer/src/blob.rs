@@ -0,0 +1,144 @@
+use anyhow::Result;
+use perfetto_protos::{debug_annotation::DebugAnnotation, screenshot::Screenshot, trace::Trace};
+use protobuf::{Message, MessageField};
+
+use crate::{Context, TrackUuid};
+
+const MIME_ANNOTATION: &str = "mime";
+const BLOB_ANNOTATION: &str = "blob";
+
+/// A binary attachment recorded with [`Context::attach_blob`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    pub name: String,
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+impl Context {
+    /// Records `data` inside the trace as an instant named `name` on an
+    /// "Attachments" track, e.g. a screenshot at a jank moment or a config
+    /// dump, so all incident artifacts travel in one file. JPEGs are also
+    /// written as a screenshot the UI can display. Use [`extract_blobs`] to
+    /// get them back out.
+    pub fn attach_blob(&mut self, name: &str, mime: &str, data: impl Into<Vec<u8>>) {
+        let data = data.into();
+        let track = self.attachments_track();
+        let mut builder = self
+            .event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track);
+        let event = &mut builder.event;
+        event.set_name(name.to_string());
+        if mime == "image/jpeg" {
+            event.screenshot = MessageField::some(Screenshot {
+                jpg_image: Some(data.clone()),
+                ..Default::default()
+            });
+        }
+        let mut mime_annotation = DebugAnnotation::new();
+        mime_annotation.set_name(MIME_ANNOTATION.to_string());
+        mime_annotation.set_string_value(mime.to_string());
+        let mut blob = DebugAnnotation::new();
+        blob.set_name(BLOB_ANNOTATION.to_string());
+        blob.set_proto_value(data);
+        event.debug_annotations.push(mime_annotation);
+        event.debug_annotations.push(blob);
+        builder.build();
+    }
+
+    fn attachments_track(&mut self) -> TrackUuid {
+        match self.attachments_track {
+            Some(track) => track,
+            None => {
+                let track = self.create_track("Attachments");
+                self.attachments_track = Some(track);
+                track
+            }
+        }
+    }
+}
+
+/// Returns every blob attached to an encoded trace, in recording order.
+pub fn extract_blobs(trace: &[u8]) -> Result<Vec<Blob>> {
+    let trace = Trace::parse_from_bytes(trace)?;
+    let blobs = trace
+        .packet
+        .iter()
+        .filter(|p| p.has_track_event())
+        .filter_map(|p| {
+            let event = p.track_event();
+            let annotation = |name: &str| {
+                event
+                    .debug_annotations
+                    .iter()
+                    .find(|a| a.has_name() && a.name() == name)
+            };
+            let blob = annotation(BLOB_ANNOTATION)?;
+            Some(Blob {
+                name: event.name().to_string(),
+                mime: annotation(MIME_ANNOTATION)
+                    .map(|a| a.string_value().to_string())
+                    .unwrap_or_default(),
+                data: blob.proto_value().to_vec(),
+            })
+        })
+        .collect();
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blobs_round_trip() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.attach_blob("config", "application/json", br#"{"threads":4}"#.as_slice());
+        ctx.attach_blob("jank", "image/jpeg", vec![0xff, 0xd8, 0xff]);
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+
+        let blobs = extract_blobs(&buf)?;
+        assert_eq!(
+            blobs,
+            [
+                Blob {
+                    name: "config".into(),
+                    mime: "application/json".into(),
+                    data: br#"{"threads":4}"#.to_vec(),
+                },
+                Blob {
+                    name: "jank".into(),
+                    mime: "image/jpeg".into(),
+                    data: vec![0xff, 0xd8, 0xff],
+                },
+            ]
+        );
+
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let screenshots = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().screenshot.is_some())
+            .count();
+        assert_eq!(screenshots, 1);
+        // The MIME type is not a protobuf type name.
+        assert!(
+            trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event())
+                .flat_map(|p| p.track_event().debug_annotations.iter())
+                .all(|a| !a.has_proto_type_name())
+        );
+        let tracks = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .count();
+        assert_eq!(tracks, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use smol_str::SmolStr;
+
+use perfetto_protos::{
+    interned_data::InternedData,
+    profile_common::{Callstack, Frame, InternedString, Mapping},
+    profile_packet::PerfSample,
+    trace_packet::TracePacket,
+};
+
+use crate::{ClockId, Context, InternID};
+
+/// One resolved frame of a sampled callstack.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StackFrame {
+    pub function_name: SmolStr,
+    /// The program counter relative to the start of the mapped file.
+    pub rel_pc: u64,
+    /// The mapping the frame's code was loaded from, if known.
+    pub mapping: Option<StackMapping>,
+}
+
+impl StackFrame {
+    pub fn new(function_name: impl Into<SmolStr>, rel_pc: u64) -> Self {
+        Self {
+            function_name: function_name.into(),
+            rel_pc,
+            mapping: None,
+        }
+    }
+
+    pub fn with_mapping(mut self, mapping: StackMapping) -> Self {
+        self.mapping = Some(mapping);
+        self
+    }
+}
+
+/// An executable file mapped into the process, as listed in
+/// `/proc/self/maps`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct StackMapping {
+    pub path: SmolStr,
+    pub start: u64,
+    pub end: u64,
+    /// Offset of `start` into the file.
+    pub offset: u64,
+}
+
+impl StackMapping {
+    /// The mapping of frames whose origin is not known.
+    fn unknown() -> Self {
+        Self {
+            path: "[unknown]".into(),
+            start: 0,
+            end: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl Context {
+    /// Writes a `PerfSample` packet for `tid` with the given callstack.
+    ///
+    /// `timestamp_ns` is in the [`ClockId::Monotonic`] domain and `frames`
+    /// are ordered innermost first, the way a backtrace is captured.
+    /// Function names, mappings, frames and the callstack itself are
+    /// interned so repeated samples of the same stack only cost a callstack
+    /// iid.
+    pub fn perf_sample(&mut self, timestamp_ns: u64, tid: i32, frames: &[StackFrame]) {
+        let callstack = self.intern_callstack(frames);
+        let mut tp = TracePacket::new();
+        tp.set_timestamp(timestamp_ns);
+        tp.set_timestamp_clock_id(ClockId::Monotonic.as_u32());
+        tp.set_perf_sample(PerfSample {
+            pid: Some(std::process::id()),
+            tid: Some(tid as u32),
+            callstack_iid: Some(callstack),
+            ..Default::default()
+        });
+        self.push_packet(tp);
+    }
+
+    fn intern_callstack(&mut self, frames: &[StackFrame]) -> u64 {
+        // Perfetto wants the bottom (outermost) frame first.
+        let frame_ids: Vec<u64> = frames.iter().rev().map(|f| self.intern_frame(f)).collect();
+        let id = self.callstacks.intern(frame_ids.clone());
+        if id.is_new() {
+            let mut tp = TracePacket::new();
+            let mut itd = InternedData::new();
+            itd.callstacks.push(Callstack {
+                iid: Some(id.as_u64()),
+                frame_ids,
+                ..Default::default()
+            });
+            tp.interned_data = protobuf::MessageField::some(itd);
+            self.push_packet(tp);
+        }
+        id.as_u64()
+    }
+
+    fn intern_frame(&mut self, frame: &StackFrame) -> u64 {
+        let mapping = self.intern_mapping(frame.mapping.as_ref());
+        let name = self.intern_function_name(frame.function_name.clone());
+        let id = self.frames.intern((name.as_u64(), frame.rel_pc, mapping));
+        if id.is_new() {
+            let mut tp = TracePacket::new();
+            let mut itd = InternedData::new();
+            itd.frames.push(Frame {
+                iid: Some(id.as_u64()),
+                function_name_id: Some(name.as_u64()),
+                mapping_id: Some(mapping),
+                rel_pc: Some(frame.rel_pc),
+                ..Default::default()
+            });
+            tp.interned_data = protobuf::MessageField::some(itd);
+            self.push_packet(tp);
+        }
+        id.as_u64()
+    }
+
+    fn intern_function_name(&mut self, name: SmolStr) -> InternID {
+        let id = self.function_names.intern(name.clone());
+        if id.is_new() {
+            let mut tp = TracePacket::new();
+            let mut itd = InternedData::new();
+            itd.function_names.push(InternedString {
+                iid: Some(id.as_u64()),
+                str: Some(name.as_bytes().to_vec()),
+                ..Default::default()
+            });
+            tp.interned_data = protobuf::MessageField::some(itd);
+            self.push_packet(tp);
+        }
+        id
+    }
+
+    fn intern_mapping(&mut self, mapping: Option<&StackMapping>) -> u64 {
+        let mapping = mapping.cloned().unwrap_or_else(StackMapping::unknown);
+        let id = self.mappings.intern(mapping.clone());
+        if id.is_new() {
+            let mut tp = TracePacket::new();
+            let mut itd = InternedData::new();
+            // Each mapping gets its own path string, under the same iid.
+            itd.mapping_paths.push(InternedString {
+                iid: Some(id.as_u64()),
+                str: Some(mapping.path.as_bytes().to_vec()),
+                ..Default::default()
+            });
+            let known = mapping.end > mapping.start;
+            itd.mappings.push(Mapping {
+                iid: Some(id.as_u64()),
+                start: known.then_some(mapping.start),
+                end: known.then_some(mapping.end),
+                start_offset: known.then_some(mapping.offset),
+                exact_offset: known.then_some(mapping.offset),
+                path_string_ids: vec![id.as_u64()],
+                ..Default::default()
+            });
+            tp.interned_data = protobuf::MessageField::some(itd);
+            self.push_packet(tp);
+        }
+        id.as_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn repeated_stacks_are_interned() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let stack = [StackFrame::new("leaf", 0x20), StackFrame::new("main", 0x10)];
+
+        ctx.perf_sample(1_000, 7, &stack);
+        ctx.perf_sample(2_000, 7, &stack);
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let samples: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_perf_sample())
+            .collect();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].timestamp(), 1_000);
+        assert_eq!(samples[0].perf_sample().tid(), 7);
+        assert_eq!(
+            samples[0].perf_sample().callstack_iid(),
+            samples[1].perf_sample().callstack_iid()
+        );
+
+        let callstacks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.callstacks.iter())
+            .collect();
+        assert_eq!(callstacks.len(), 1);
+
+        let frames: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.frames.iter())
+            .collect();
+        // Bottom frame first.
+        assert_eq!(frames[0].rel_pc(), 0x10);
+        assert_eq!(callstacks[0].frame_ids, [frames[0].iid(), frames[1].iid()]);
+        Ok(())
+    }
+
+    #[test]
+    fn frames_point_at_their_mapping() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let lib = StackMapping {
+            path: "/usr/lib/libfoo.so".into(),
+            start: 0x7000,
+            end: 0x9000,
+            offset: 0x1000,
+        };
+        let stack = [
+            StackFrame::new("foo", 0x1200).with_mapping(lib.clone()),
+            StackFrame::new("main", 0x10),
+        ];
+        ctx.perf_sample(1_000, 7, &stack);
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let interned: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .collect();
+        let mappings: Vec<_> = interned.iter().flat_map(|i| i.mappings.iter()).collect();
+        assert_eq!(mappings.len(), 2);
+        let foo = mappings.iter().find(|m| m.start() == 0x7000).unwrap();
+        assert_eq!(foo.end(), 0x9000);
+        assert_eq!(foo.start_offset(), 0x1000);
+        let path = interned
+            .iter()
+            .flat_map(|i| i.mapping_paths.iter())
+            .find(|p| p.iid() == foo.path_string_ids[0])
+            .unwrap();
+        assert_eq!(path.str(), b"/usr/lib/libfoo.so");
+        let frame = interned
+            .iter()
+            .flat_map(|i| i.frames.iter())
+            .find(|f| f.rel_pc() == 0x1200)
+            .unwrap();
+        assert_eq!(frame.mapping_id(), foo.iid());
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use dashmap::DashMap;
+use perfetto_protos::track_event::track_event::Type;
+use smol_str::SmolStr;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+};
+
+use crate::{Context, EventBuilder, TrackUuid};
+
+/// Categories compiled out of this build: the comma separated
+/// `PERFETTO_DISABLED_CATEGORIES` environment variable when the crate was
+/// built, e.g. `PERFETTO_DISABLED_CATEGORIES=verbose,gc cargo build --release`.
+/// Whitespace around the names is ignored.
+pub const COMPILED_OUT_CATEGORIES: &str = match option_env!("PERFETTO_DISABLED_CATEGORIES") {
+    Some(categories) => categories,
+    None => "",
+};
+
+/// Whether `category` is compiled into this build, see
+/// [`COMPILED_OUT_CATEGORIES`]. The category forms of
+/// [`perfetto_span!`](crate::perfetto_span) and `#[trace]` check this in a
+/// `const` block, so their events are removed entirely from builds that
+/// leave the category out.
+pub const fn category_compiled_in(category: &str) -> bool {
+    !list_contains(COMPILED_OUT_CATEGORIES.as_bytes(), category.as_bytes())
+}
+
+const fn list_contains(list: &[u8], item: &[u8]) -> bool {
+    let mut start = 0;
+    while start <= list.len() {
+        let mut end = start;
+        while end < list.len() && list[end] != b',' {
+            end += 1;
+        }
+        let next = end + 1;
+        while start < end && list[start].is_ascii_whitespace() {
+            start += 1;
+        }
+        while end > start && list[end - 1].is_ascii_whitespace() {
+            end -= 1;
+        }
+        if end - start == item.len() {
+            let mut i = 0;
+            while i < item.len() && list[start + i] == item[i] {
+                i += 1;
+            }
+            if i == item.len() {
+                return true;
+            }
+        }
+        start = next;
+    }
+    false
+}
+
+/// Categories declared up front, like Perfetto's
+/// `PERFETTO_DEFINE_CATEGORIES`. Each gets its interned id when registered,
+/// and a new one the first time it is used after the context rotates or
+/// forks. Clones share state, so a category can be switched on and off from
+/// any thread without locking the context.
+///
+/// Events in a disabled category are dropped by [`EventBuilder::build`]
+/// before anything is encoded, and the end of a dropped slice is dropped
+/// with it. Categories that were never registered are always enabled,
+/// unless they are compiled out.
+#[derive(Debug, Clone, Default)]
+pub struct CategoryRegistry {
+    categories: Arc<DashMap<SmolStr, RegisteredCategory>>,
+}
+
+#[derive(Debug)]
+struct RegisteredCategory {
+    /// Zero until the category is interned in the current segment.
+    iid: AtomicU64,
+    enabled: AtomicBool,
+}
+
+impl CategoryRegistry {
+    /// The interned id of a registered category, if it has one in the
+    /// current segment.
+    pub fn id(&self, name: &str) -> Option<u64> {
+        self.categories
+            .get(name)
+            .map(|c| c.iid.load(Relaxed))
+            .filter(|&iid| iid != 0)
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        category_compiled_in(name)
+            && self
+                .categories
+                .get(name)
+                .is_none_or(|c| c.enabled.load(Relaxed))
+    }
+
+    /// Enables or disables a registered category. Returns false if `name`
+    /// was never registered.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        match self.categories.get(name) {
+            Some(category) => {
+                category.enabled.store(enabled, Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn enable(&self, name: &str) -> bool {
+        self.set_enabled(name, true)
+    }
+
+    pub fn disable(&self, name: &str) -> bool {
+        self.set_enabled(name, false)
+    }
+
+    /// Notes the id `name` was interned with, or zero once it was forgotten.
+    pub(crate) fn interned(&self, name: &str, iid: u64) {
+        if let Some(category) = self.categories.get(name) {
+            category.iid.store(iid, Relaxed);
+        }
+    }
+
+    /// Forgets every id, when the interning state is reset.
+    pub(crate) fn invalidate(&self) {
+        for category in self.categories.iter() {
+            category.iid.store(0, Relaxed);
+        }
+    }
+}
+
+/// The slices open on a track since one of them was dropped, so their ends
+/// can follow their begins.
+#[derive(Debug, Default)]
+pub(crate) struct SuppressedSlices {
+    depth: usize,
+    dropped: Vec<usize>,
+}
+
+impl Context {
+    /// Registers `name`, enabled, and returns its interned id. Registering
+    /// a category again returns the same id and leaves its state alone.
+    pub fn register_category(&mut self, name: impl Into<SmolStr>) -> u64 {
+        let name = name.into();
+        let iid = self.intern_category(name.clone()).as_u64();
+        self.category_registry
+            .categories
+            .entry(name)
+            .or_insert_with(|| RegisteredCategory {
+                iid: AtomicU64::new(0),
+                enabled: AtomicBool::new(true),
+            })
+            .iid
+            .store(iid, Relaxed);
+        iid
+    }
+
+    /// Registers every category in `names`, see
+    /// [`Context::register_category`].
+    pub fn register_categories<I>(&mut self, names: I) -> CategoryRegistry
+    where
+        I: IntoIterator,
+        I::Item: Into<SmolStr>,
+    {
+        for name in names {
+            self.register_category(name);
+        }
+        self.categories()
+    }
+
+    /// A handle to the registered categories, for toggling them at runtime.
+    pub fn categories(&self) -> CategoryRegistry {
+        self.category_registry.clone()
+    }
+
+    /// Whether events in `category` are recorded. Check this before
+    /// computing expensive annotations.
+    pub fn is_category_enabled(&self, category: &str) -> bool {
+        self.category_registry.is_enabled(category)
+    }
+
+    /// Notes a slice beginning on `track`. Returns whether it is dropped.
+    fn begin_slice(&mut self, track: TrackUuid, dropped: bool) -> bool {
+        match self.suppressed_slices.get_mut(&track) {
+            Some(open) => {
+                open.depth += 1;
+                if dropped {
+                    open.dropped.push(open.depth);
+                }
+            }
+            None if dropped => {
+                let open = SuppressedSlices {
+                    depth: 1,
+                    dropped: vec![1],
+                };
+                self.suppressed_slices.insert(track, open);
+            }
+            None => {}
+        }
+        dropped
+    }
+
+    /// Notes a slice ending on `track`. Returns whether its begin was
+    /// dropped.
+    fn end_slice(&mut self, track: TrackUuid) -> bool {
+        let Some(open) = self.suppressed_slices.get_mut(&track) else {
+            return false;
+        };
+        let dropped = open.dropped.last() == Some(&open.depth);
+        if dropped {
+            open.dropped.pop();
+        }
+        open.depth -= 1;
+        if open.dropped.is_empty() {
+            self.suppressed_slices.remove(&track);
+        }
+        dropped
+    }
+}
+
+impl EventBuilder<'_> {
+    /// Whether the event is left out of the trace. A slice's end follows
+    /// its begin, whatever its own categories.
+    pub(crate) fn is_dropped(&mut self) -> bool {
+        let track = self.event.track_uuid();
+        match self.event.type_() {
+            Type::TYPE_SLICE_BEGIN => self.ctx.begin_slice(track, self.dropped),
+            Type::TYPE_SLICE_END => self.ctx.end_slice(track),
+            _ => self.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn compiled_out_lists() {
+        assert!(list_contains(b"verbose,gc", b"gc"));
+        assert!(list_contains(b"verbose,gc", b"verbose"));
+        assert!(!list_contains(b"verbose,gc", b"verb"));
+        assert!(!list_contains(b"", b"gc"));
+        assert!(list_contains(b"verbose, gc ", b"gc"));
+        assert!(list_contains(b" verbose ,gc", b"verbose"));
+        assert!(category_compiled_in("db"));
+    }
+
+    #[test]
+    fn disabled_categories_are_dropped() -> Result<()> {
+        let mut ctx = Context::new();
+        let categories = ctx.register_categories(["db", "net"]);
+        let db = categories.id("db").unwrap();
+        assert_eq!(ctx.register_category("db"), db);
+        assert!(categories.disable("net"));
+        assert!(!categories.disable("gpu"));
+        assert!(ctx.is_category_enabled("gpu"));
+
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_category("net")
+            .with_debug_lazy("query", || panic!("evaluated for a dropped event"))
+            .build();
+        categories.enable("net");
+        categories.disable("db");
+        for category in ["db", "net", "gpu"] {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_category(category)
+                .build();
+        }
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().category_iids.clone())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], [categories.id("net").unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn ends_follow_their_begin() -> Result<()> {
+        let mut ctx = Context::new();
+        let categories = ctx.register_categories(["db", "net"]);
+        categories.disable("net");
+        let track = ctx.current_thread_track();
+        let slice = |ctx: &mut Context, begin: bool, category: &str| {
+            let mut event = ctx.event();
+            if begin {
+                event.begin();
+            } else {
+                event.end();
+            }
+            event.now();
+            event.track_uuid(track);
+            event.category(category);
+            event.build();
+        };
+        slice(&mut ctx, true, "net");
+        slice(&mut ctx, true, "db");
+        categories.enable("net");
+        categories.disable("db");
+        slice(&mut ctx, false, "db");
+        slice(&mut ctx, false, "gpu");
+
+        let trace = ctx.take_trace();
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(events, [Type::TYPE_SLICE_BEGIN, Type::TYPE_SLICE_END]);
+        assert!(ctx.suppressed_slices.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_events_intern_nothing() {
+        let mut ctx = Context::new();
+        let categories = ctx.register_categories(["net"]);
+        categories.disable("net");
+        let track = ctx.current_thread_track();
+        let instant = |ctx: &mut Context| {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("query")
+                .with_debug_str("table", "users")
+                .with_category("net")
+                .build();
+        };
+        instant(&mut ctx);
+        let names = |trace: &Trace| {
+            trace
+                .packet
+                .iter()
+                .filter_map(|p| p.interned_data.as_ref())
+                .flat_map(|i| i.event_names.iter().map(|n| n.name().to_string()))
+                .collect::<Vec<_>>()
+        };
+        let trace = ctx.take_trace();
+        assert!(!trace.packet.iter().any(|p| p.has_track_event()));
+        assert!(names(&trace).is_empty());
+
+        categories.enable("net");
+        instant(&mut ctx);
+        assert_eq!(names(&ctx.take_trace()), ["query"]);
+    }
+
+    #[test]
+    fn ids_are_renewed_after_rotating() -> Result<()> {
+        let mut ctx = Context::new();
+        let categories = ctx.register_categories(["db"]);
+        assert!(categories.id("db").is_some());
+        ctx.rotate(&mut std::io::sink())?;
+        assert_eq!(categories.id("db"), None);
+
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_category("db")
+            .build();
+        let trace = ctx.take_trace();
+        let interned = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_categories.iter())
+            .find(|c| c.name() == "db")
+            .unwrap()
+            .iid();
+        assert_eq!(categories.id("db"), Some(interned));
+        Ok(())
+    }
+}
@@ -0,0 +1,671 @@
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering::Relaxed},
+    },
+    time::Duration,
+};
+use web_time::Instant;
+
+use perfetto_protos::{
+    builtin_clock::BuiltinClock,
+    clock_snapshot::{ClockSnapshot, clock_snapshot::Clock as SnapshotClock},
+    trace_packet::TracePacket,
+};
+
+use crate::Context;
+
+/// The clock domain a timestamp was taken in.
+///
+/// Builtin clocks map onto Perfetto's `BuiltinClock` ids and are correlated
+/// with each other through `ClockSnapshot` packets, which is what lets
+/// trace_processor line app events up with system traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ClockId {
+    #[default]
+    Realtime,
+    Monotonic,
+    Boottime,
+    /// A user defined clock id, which must not collide with a builtin id.
+    Custom(u32),
+}
+
+impl ClockId {
+    pub fn as_u32(self) -> u32 {
+        match self {
+            ClockId::Realtime => BuiltinClock::BUILTIN_CLOCK_REALTIME as u32,
+            ClockId::Monotonic => BuiltinClock::BUILTIN_CLOCK_MONOTONIC as u32,
+            ClockId::Boottime => BuiltinClock::BUILTIN_CLOCK_BOOTTIME as u32,
+            ClockId::Custom(id) => id,
+        }
+    }
+
+    /// Reads the current value of a builtin clock in nanoseconds.
+    pub fn now_ns(self) -> Option<u64> {
+        match self {
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            ClockId::Realtime => Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+            ),
+            #[cfg(unix)]
+            ClockId::Monotonic => clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClockId::Boottime => clock_gettime(nix::time::ClockId::CLOCK_BOOTTIME),
+            #[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+            ClockId::Boottime => clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC),
+            #[cfg(not(any(unix, all(target_arch = "wasm32", target_os = "unknown"))))]
+            ClockId::Monotonic | ClockId::Boottime => Some(monotonic_ns()),
+            #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+            ClockId::Realtime => Some(crate::web::realtime_ns()),
+            #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+            ClockId::Monotonic | ClockId::Boottime => Some(crate::web::performance_now_ns()),
+            ClockId::Custom(_) => None,
+        }
+    }
+}
+
+/// Nanoseconds since the first read, for platforms without a monotonic
+/// clock API wired up here, e.g. Windows. Clock snapshots correlate it with
+/// the wall clock like the others.
+#[cfg(not(any(unix, all(target_arch = "wasm32", target_os = "unknown"))))]
+fn monotonic_ns() -> u64 {
+    static ANCHOR: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    ANCHOR.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Sequence-scoped clock used for delta encoded timestamps. User defined
+/// [`ClockId::Custom`] clocks must pick a different id.
+pub const INCREMENTAL_CLOCK_ID: u32 = 127;
+
+/// A source of timestamps for [`EventBuilder::now`](crate::EventBuilder::now).
+///
+/// Plain closures returning nanoseconds are clocks in the realtime domain,
+/// which makes deterministic test clocks a one-liner:
+///
+/// ```ignore
+/// let ticks = AtomicU64::new(0);
+/// ctx.set_clock(move || ticks.fetch_add(1000, Relaxed));
+/// ```
+///
+/// Clocks in their own domain (a frame counter, a hardware cycle counter)
+/// should return a [`ClockId::Custom`] id so they are declared in the trace's
+/// clock snapshots alongside the builtin clocks.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+
+    fn id(&self) -> ClockId {
+        ClockId::Realtime
+    }
+
+    /// How many nanoseconds one unit of [`Clock::now`] represents.
+    fn unit_multiplier_ns(&self) -> u64 {
+        1
+    }
+
+    /// Synthetic clocks (simulation ticks) are anchored at boottime zero
+    /// instead of being correlated with the wall clock, and don't trigger
+    /// periodic snapshots, so the resulting trace is fully deterministic.
+    fn synthetic(&self) -> bool {
+        false
+    }
+}
+
+impl<F: Fn() -> u64 + Send + Sync> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+/// Reads one of the builtin OS clocks. This is what a [`Context`] uses until
+/// another clock is installed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock(pub ClockId);
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.0.now_ns().unwrap_or_default()
+    }
+
+    fn id(&self) -> ClockId {
+        self.0
+    }
+}
+
+/// A logical clock driven by a simulation or deterministic runtime.
+///
+/// Clones share the same tick counter, so the simulator can keep one handle
+/// to [`LogicalClock::advance`] while the [`Context`] reads the other.
+#[derive(Debug, Clone)]
+pub struct LogicalClock {
+    ticks: Arc<AtomicU64>,
+    id: u32,
+    tick_ns: u64,
+}
+
+impl LogicalClock {
+    /// Creates a clock with the given [`ClockId::Custom`] id, where each tick
+    /// is rendered as one nanosecond.
+    pub fn new(id: u32) -> Self {
+        Self {
+            ticks: Arc::new(AtomicU64::new(0)),
+            id,
+            tick_ns: 1,
+        }
+    }
+
+    /// How long one tick is displayed as on the timeline.
+    pub fn with_tick_duration(mut self, tick: Duration) -> Self {
+        self.tick_ns = (tick.as_nanos() as u64).max(1);
+        self
+    }
+
+    pub fn advance(&self, ticks: u64) -> u64 {
+        self.ticks.fetch_add(ticks, Relaxed) + ticks
+    }
+
+    pub fn set(&self, tick: u64) {
+        self.ticks.store(tick, Relaxed);
+    }
+}
+
+impl Clock for LogicalClock {
+    fn now(&self) -> u64 {
+        self.ticks.load(Relaxed)
+    }
+
+    fn id(&self) -> ClockId {
+        ClockId::Custom(self.id)
+    }
+
+    fn unit_multiplier_ns(&self) -> u64 {
+        self.tick_ns
+    }
+
+    fn synthetic(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn clock_gettime(clock: nix::time::ClockId) -> Option<u64> {
+    let ts = nix::time::clock_gettime(clock).ok()?;
+    Some(ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64)
+}
+
+pub(crate) fn snapshot_now(custom: Option<&dyn Clock>) -> ClockSnapshot {
+    let mut snapshot = ClockSnapshot::new();
+    for clock in [ClockId::Boottime, ClockId::Monotonic, ClockId::Realtime] {
+        if let Some(ts) = clock.now_ns() {
+            snapshot.clocks.push(SnapshotClock {
+                clock_id: Some(clock.as_u32()),
+                timestamp: Some(ts),
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(clock) = custom.filter(|c| matches!(c.id(), ClockId::Custom(_)) && !c.synthetic()) {
+        snapshot.clocks.push(SnapshotClock {
+            clock_id: Some(clock.id().as_u32()),
+            timestamp: Some(clock.now()),
+            unit_multiplier_ns: Some(clock.unit_multiplier_ns()).filter(|m| *m != 1),
+            ..Default::default()
+        });
+    }
+    snapshot
+}
+
+impl Context {
+    /// Replaces the clock used for [`EventBuilder::now`](crate::EventBuilder::now)
+    /// timestamps. A snapshot is emitted right away so the new clock's domain
+    /// can be correlated from this point on.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        let synthetic = clock.synthetic();
+        self.clock = Some(Box::new(clock));
+        if synthetic {
+            let mut tp = TracePacket::new();
+            tp.set_clock_snapshot(self.clock_state());
+            self.push_packet(tp);
+            self.clock_snapshot_interval = None;
+        } else {
+            self.clock_snapshot();
+        }
+    }
+
+    /// The snapshot that establishes the current clock's domain: an anchor
+    /// at zero for synthetic clocks, the builtin clocks right now otherwise.
+    pub(crate) fn clock_state(&self) -> ClockSnapshot {
+        let clock = self.clock();
+        if !clock.synthetic() {
+            return snapshot_now(self.clock.as_deref());
+        }
+        let mut snapshot = ClockSnapshot::new();
+        snapshot.clocks.push(SnapshotClock {
+            clock_id: Some(ClockId::Boottime.as_u32()),
+            timestamp: Some(0),
+            ..Default::default()
+        });
+        snapshot.clocks.push(SnapshotClock {
+            clock_id: Some(clock.id().as_u32()),
+            timestamp: Some(0),
+            unit_multiplier_ns: Some(clock.unit_multiplier_ns()).filter(|m| *m != 1),
+            ..Default::default()
+        });
+        snapshot
+    }
+
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        match &self.clock {
+            Some(clock) => clock.as_ref(),
+            None => &SystemClock(ClockId::Realtime),
+        }
+    }
+
+    /// Emits a `ClockSnapshot` correlating the builtin clocks right now.
+    pub fn clock_snapshot(&mut self) {
+        self.last_clock_snapshot = Some(Instant::now());
+        let mut tp = TracePacket::new();
+        tp.set_clock_snapshot(snapshot_now(self.clock.as_deref()));
+        self.push_packet(tp);
+    }
+
+    /// Sets how often a fresh `ClockSnapshot` is written while events are
+    /// being recorded, or disables periodic snapshots with `None`.
+    pub fn set_clock_snapshot_interval(&mut self, interval: Option<Duration>) {
+        self.clock_snapshot_interval = interval;
+    }
+
+    /// Switches to delta encoded timestamps.
+    ///
+    /// When enabled, an incremental clock is declared on this sequence and
+    /// every event in the context clock's domain stores the distance to the
+    /// previous one instead of a full absolute timestamp, which shrinks
+    /// traces of high-frequency events considerably. Events in another
+    /// domain, or that go back in time, fall back to absolute timestamps.
+    pub fn set_delta_timestamps(&mut self, enabled: bool) {
+        self.retracted_delta = 0;
+        if !enabled {
+            self.delta_base = None;
+            return;
+        }
+        let clock = self.clock();
+        let (id, base, multiplier) = (clock.id(), clock.now(), clock.unit_multiplier_ns());
+        let mut snapshot = ClockSnapshot::new();
+        snapshot.clocks.push(SnapshotClock {
+            clock_id: Some(id.as_u32()),
+            timestamp: Some(base),
+            unit_multiplier_ns: Some(multiplier).filter(|m| *m != 1),
+            ..Default::default()
+        });
+        snapshot.clocks.push(SnapshotClock {
+            clock_id: Some(INCREMENTAL_CLOCK_ID),
+            timestamp: Some(base),
+            is_incremental: Some(true),
+            unit_multiplier_ns: Some(multiplier).filter(|m| *m != 1),
+            ..Default::default()
+        });
+        let mut tp = TracePacket::new();
+        tp.set_clock_snapshot(snapshot);
+        self.push_packet(tp);
+        self.delta_base = Some((id, base));
+    }
+
+    pub(crate) fn set_packet_timestamp(&mut self, tp: &mut TracePacket, ts: u64, clock: ClockId) {
+        if let Some((base_clock, last)) = self.delta_base.as_mut()
+            && *base_clock == clock
+            && ts >= *last
+        {
+            tp.set_timestamp(ts - *last + std::mem::take(&mut self.retracted_delta));
+            tp.set_timestamp_clock_id(INCREMENTAL_CLOCK_ID);
+            *last = ts;
+            return;
+        }
+        tp.set_timestamp(ts);
+        tp.set_timestamp_clock_id(clock.as_u32());
+    }
+
+    pub(crate) fn maybe_clock_snapshot(&mut self) {
+        let Some(interval) = self.clock_snapshot_interval else {
+            return;
+        };
+        match self.last_clock_snapshot {
+            Some(last) if last.elapsed() < interval => {}
+            _ => self.clock_snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn new_context_starts_with_snapshot() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let snapshot = trace.packet[0].clock_snapshot();
+        let ids: Vec<_> = snapshot.clocks.iter().map(|c| c.clock_id()).collect();
+        assert_eq!(ids, [6, 3, 1]);
+        assert!(snapshot.clocks.iter().all(|c| c.timestamp() > 0));
+        Ok(())
+    }
+
+    #[test]
+    fn periodic_snapshots() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock_snapshot_interval(Some(Duration::ZERO));
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let snapshots = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_clock_snapshot())
+            .count();
+        assert_eq!(snapshots, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn events_declare_their_clock() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.event()
+            .with_instant()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(1_500)
+            .with_track_uuid(1)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_timestamp_us(2)
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .collect();
+        assert_eq!(events[0].timestamp(), 1_500);
+        assert_eq!(events[0].timestamp_clock_id(), 6);
+        assert_eq!(events[1].timestamp(), 2_000);
+        assert_eq!(events[1].timestamp_clock_id(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn closure_clock() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock(|| 42);
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let event = trace.packet.iter().find(|p| p.has_track_event()).unwrap();
+        assert_eq!(event.timestamp(), 42);
+        assert_eq!(event.timestamp_clock_id(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn now_follows_the_declared_clock_in_either_order() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock(|| 42);
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_clock(ClockId::Boottime)
+            .with_track_uuid(1)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_clock(ClockId::Boottime)
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_timestamp_us(u64::MAX)
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .collect();
+        for event in &events[..2] {
+            assert_ne!(event.timestamp(), 42);
+            assert_eq!(event.timestamp_clock_id(), 6);
+        }
+        assert_eq!(events[2].timestamp(), u64::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn retracted_deltas_carry_forward() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock(|| 1_000);
+        ctx.set_delta_timestamps(true);
+        let mut positions = Vec::new();
+        for ts in [1_100, 1_300, 1_600, 2_000] {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(ts)
+                .with_track_uuid(1)
+                .build();
+            positions.push(ctx.last_packet_position().unwrap());
+        }
+        assert!(ctx.retract_packet(positions[2]));
+        assert!(ctx.retract_packet(positions[1]));
+        assert!(ctx.retract_packet(positions[3]));
+        assert!(!ctx.retract_packet(positions[3]));
+        ctx.event()
+            .with_instant()
+            .with_timestamp_ns(2_500)
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        assert!(!ctx.retract_packet(positions[0]));
+
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let mut now = 1_000;
+        let timestamps: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| {
+                now += p.timestamp();
+                now
+            })
+            .collect();
+        assert_eq!(timestamps, [1_100, 2_500]);
+        Ok(())
+    }
+
+    #[test]
+    fn delta_timestamps() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock(|| 1_000);
+        ctx.set_delta_timestamps(true);
+        for ts in [1_500, 1_700, 1_600] {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(ts)
+                .with_track_uuid(1)
+                .build();
+        }
+        ctx.event()
+            .with_instant()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(1_800)
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let declared = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_clock_snapshot())
+            .flat_map(|p| p.clock_snapshot().clocks.iter())
+            .find(|c| c.clock_id() == INCREMENTAL_CLOCK_ID)
+            .unwrap();
+        assert!(declared.is_incremental());
+        assert_eq!(declared.timestamp(), 1_000);
+
+        let timestamps: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.timestamp(), p.timestamp_clock_id()))
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                (500, INCREMENTAL_CLOCK_ID),
+                (200, INCREMENTAL_CLOCK_ID),
+                (1_600, 1),
+                (1_800, 6)
+            ]
+        );
+        Ok(())
+    }
+
+    fn simulate() -> Result<Trace> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let clock = LogicalClock::new(65).with_tick_duration(Duration::from_millis(1));
+        ctx.set_clock(clock.clone());
+        for node in 0..3u64 {
+            clock.advance(10);
+            ctx.event()
+                .with_begin()
+                .with_now()
+                .with_name("handle")
+                .with_track_uuid(node)
+                .build();
+            ctx.event()
+                .with_end()
+                .with_timestamp_ns(clock.now() + 5)
+                .with_track_uuid(node)
+                .build();
+        }
+        ctx.write_to(&mut buf)?;
+        Ok(Trace::parse_from_bytes(&buf)?)
+    }
+
+    #[test]
+    fn logical_clock_is_deterministic() -> Result<()> {
+        let first = simulate()?;
+        let second = simulate()?;
+        // Everything but the wall clock snapshot in the init packet matches.
+        assert_eq!(first.packet[1..], second.packet[1..]);
+
+        let anchor = first.packet[1].clock_snapshot();
+        assert_eq!(anchor.clocks[0].clock_id(), 6);
+        assert_eq!(anchor.clocks[0].timestamp(), 0);
+        assert_eq!(anchor.clocks[1].clock_id(), 65);
+        assert_eq!(anchor.clocks[1].unit_multiplier_ns(), 1_000_000);
+
+        let timestamps: Vec<_> = first
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.timestamp(), p.timestamp_clock_id()))
+            .collect();
+        assert_eq!(
+            timestamps,
+            [(10, 65), (15, 65), (20, 65), (25, 65), (30, 65), (35, 65)]
+        );
+        Ok(())
+    }
+
+    struct CycleCounter;
+
+    impl Clock for CycleCounter {
+        fn now(&self) -> u64 {
+            1_000
+        }
+
+        fn id(&self) -> ClockId {
+            ClockId::Custom(64)
+        }
+
+        fn unit_multiplier_ns(&self) -> u64 {
+            3
+        }
+    }
+
+    #[test]
+    fn custom_clock_is_declared() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        ctx.set_clock(CycleCounter);
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        // An explicit builtin clock still reads the OS clock.
+        ctx.event()
+            .with_instant()
+            .with_clock(ClockId::Monotonic)
+            .with_now()
+            .with_track_uuid(1)
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let snapshot = trace.packet[1].clock_snapshot();
+        let custom = snapshot.clocks.last().unwrap();
+        assert_eq!(custom.clock_id(), 64);
+        assert_eq!(custom.timestamp(), 1_000);
+        assert_eq!(custom.unit_multiplier_ns(), 3);
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .collect();
+        assert_eq!(events[0].timestamp(), 1_000);
+        assert_eq!(events[0].timestamp_clock_id(), 64);
+        assert_eq!(events[1].timestamp_clock_id(), 3);
+        assert_ne!(events[1].timestamp(), 1_000);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use smol_str::SmolStr;
+
+use crate::{Context, EventBuilder};
+
+/// Debug annotation carrying an event's color name.
+pub(crate) const COLOR_ANNOTATION: &str = "cname";
+
+/// The reserved color names of the Chrome trace viewer (`cname`).
+///
+/// Perfetto picks slice colors itself, so in protobuf traces the color is
+/// only a `cname` argument to filter and query by. Converting the trace to
+/// Chrome JSON, e.g. with `perfetto-rs to-json`, makes it the event's
+/// `cname`, which chrome://tracing colors the slice by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Color {
+    Good,
+    Bad,
+    Terrible,
+    Yellow,
+    Olive,
+    Grey,
+    Black,
+    White,
+    GenericWork,
+    ThreadStateRunning,
+    ThreadStateRunnable,
+    ThreadStateIoWait,
+    ThreadStateUninterruptible,
+    ThreadStateSleeping,
+    RailResponse,
+    RailAnimation,
+    RailIdle,
+    RailLoad,
+    Startup,
+}
+
+impl Color {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Color::Good => "good",
+            Color::Bad => "bad",
+            Color::Terrible => "terrible",
+            Color::Yellow => "yellow",
+            Color::Olive => "olive",
+            Color::Grey => "grey",
+            Color::Black => "black",
+            Color::White => "white",
+            Color::GenericWork => "generic_work",
+            Color::ThreadStateRunning => "thread_state_running",
+            Color::ThreadStateRunnable => "thread_state_runnable",
+            Color::ThreadStateIoWait => "thread_state_iowait",
+            Color::ThreadStateUninterruptible => "thread_state_uninterruptible",
+            Color::ThreadStateSleeping => "thread_state_sleeping",
+            Color::RailResponse => "rail_response",
+            Color::RailAnimation => "rail_animation",
+            Color::RailIdle => "rail_idle",
+            Color::RailLoad => "rail_load",
+            Color::Startup => "startup",
+        }
+    }
+
+    /// Suggested color for a few well known category names, e.g. red for
+    /// `error` and blue for `io`.
+    pub fn for_category(category: &str) -> Option<Color> {
+        let color = match category.to_ascii_lowercase().as_str() {
+            "error" | "panic" | "fatal" => Color::Terrible,
+            "warn" | "warning" => Color::Yellow,
+            "io" | "disk" | "fs" | "net" | "network" => Color::ThreadStateRunnable,
+            "wait" | "lock" | "blocked" => Color::ThreadStateIoWait,
+            "idle" | "sleep" => Color::ThreadStateSleeping,
+            "gc" | "alloc" => Color::Olive,
+            "startup" | "init" => Color::Startup,
+            _ => return None,
+        };
+        Some(color)
+    }
+}
+
+impl Context {
+    /// Colors every event in `category` that does not set its own color.
+    pub fn set_category_color(&mut self, category: impl Into<SmolStr>, color: Color) {
+        self.category_colors.insert(category.into(), color);
+    }
+
+    /// Colors the categories known to [`Color::for_category`].
+    pub fn use_known_category_colors(&mut self) {
+        for category in [
+            "error", "panic", "fatal", "warn", "warning", "io", "disk", "fs", "net", "network",
+            "wait", "lock", "blocked", "idle", "sleep", "gc", "alloc", "startup", "init",
+        ] {
+            if let Some(color) = Color::for_category(category) {
+                self.set_category_color(category, color);
+            }
+        }
+    }
+}
+
+impl<'a> EventBuilder<'a> {
+    pub fn color(&mut self, color: Color) {
+        self.color = Some(color);
+    }
+
+    pub fn with_color(mut self, color: Color) -> Self {
+        self.color(color);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    fn colors(ctx: &mut Context) -> Result<Vec<String>> {
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        Ok(trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| String::from_utf8_lossy(s.str()).into_owned())
+            .collect())
+    }
+
+    #[test]
+    fn explicit_and_category_colors() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.set_category_color("io", Color::ThreadStateRunnable);
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(1)
+            .with_category("io")
+            .with_name("read")
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(1)
+            .with_category("io")
+            .with_color(Color::Terrible)
+            .with_name("read failed")
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(1)
+            .with_category("compute")
+            .with_name("plain")
+            .build();
+        assert_eq!(colors(&mut ctx)?, ["thread_state_runnable", "terrible"]);
+        Ok(())
+    }
+
+    #[test]
+    fn known_category_colors() {
+        assert_eq!(Color::for_category("ERROR"), Some(Color::Terrible));
+        assert_eq!(Color::for_category("render"), None);
+        let mut ctx = Context::new();
+        ctx.use_known_category_colors();
+        assert_eq!(
+            ctx.category_colors.get("io"),
+            Some(&Color::ThreadStateRunnable)
+        );
+    }
+}
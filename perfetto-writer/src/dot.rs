@@ -0,0 +1,198 @@
+use anyhow::Result;
+use protobuf::Message;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+};
+
+use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+
+struct Node {
+    name: String,
+    instant: bool,
+}
+
+/// Renders the slice hierarchy and flows of an encoded trace as a graphviz
+/// DOT digraph.
+///
+/// Nesting on a track becomes a solid parent → child edge and events sharing a
+/// flow id are joined by dashed edges in the order they were recorded. When
+/// `root` is set only slices with that name and everything reachable from
+/// them are included, which is handy for pulling a single request out of a
+/// busy trace.
+pub fn to_dot(trace: &[u8], root: Option<&str>) -> Result<String> {
+    let trace = Trace::parse_from_bytes(trace)?;
+
+    let mut names: HashMap<(u32, u64), String> = HashMap::new();
+    let mut stacks: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut flows: HashMap<u64, usize> = HashMap::new();
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut children: Vec<(usize, usize)> = Vec::new();
+    let mut flow_edges: Vec<(usize, usize)> = Vec::new();
+
+    for packet in &trace.packet {
+        let seq = packet.trusted_packet_sequence_id();
+        if let Some(interned) = packet.interned_data.as_ref() {
+            for name in &interned.event_names {
+                names.insert((seq, name.iid()), name.name().to_string());
+            }
+        }
+        if !packet.has_track_event() {
+            continue;
+        }
+        let event = packet.track_event();
+        let stack = stacks.entry(event.track_uuid()).or_default();
+        match event.type_() {
+            Type::TYPE_SLICE_BEGIN | Type::TYPE_INSTANT => {}
+            Type::TYPE_SLICE_END => {
+                stack.pop();
+                continue;
+            }
+            _ => continue,
+        }
+
+        let id = nodes.len();
+        let name = if event.has_name_iid() {
+            names.get(&(seq, event.name_iid())).cloned()
+        } else {
+            event.has_name().then(|| event.name().to_string())
+        };
+        let instant = event.type_() == Type::TYPE_INSTANT;
+        nodes.push(Node {
+            name: name.unwrap_or_default(),
+            instant,
+        });
+        if let Some(parent) = stack.last() {
+            children.push((*parent, id));
+        }
+        if !instant {
+            stack.push(id);
+        }
+        for flow in &event.flow_ids {
+            if let Some(prev) = flows.insert(*flow, id) {
+                flow_edges.push((prev, id));
+            }
+        }
+        for flow in &event.terminating_flow_ids {
+            if let Some(prev) = flows.remove(flow) {
+                flow_edges.push((prev, id));
+            }
+        }
+    }
+
+    let included: HashSet<usize> = match root {
+        None => (0..nodes.len()).collect(),
+        Some(root) => {
+            let mut adjacency: HashMap<usize, Vec<usize>> = HashMap::new();
+            for (from, to) in children.iter().chain(&flow_edges) {
+                adjacency.entry(*from).or_default().push(*to);
+            }
+            let mut seen = HashSet::new();
+            let mut queue: VecDeque<usize> = nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| n.name == root)
+                .map(|(i, _)| i)
+                .collect();
+            while let Some(id) = queue.pop_front() {
+                if seen.insert(id) {
+                    queue.extend(adjacency.get(&id).into_iter().flatten());
+                }
+            }
+            seen
+        }
+    };
+
+    let mut out = String::from("digraph trace {\n    node [shape=box];\n");
+    for (id, node) in nodes.iter().enumerate() {
+        if !included.contains(&id) {
+            continue;
+        }
+        let shape = if node.instant { ", shape=ellipse" } else { "" };
+        writeln!(
+            out,
+            "    n{} [label=\"{}\"{}];",
+            id,
+            escape(&node.name),
+            shape
+        )?;
+    }
+    for (from, to) in &children {
+        if included.contains(from) && included.contains(to) {
+            writeln!(out, "    n{} -> n{};", from, to)?;
+        }
+    }
+    for (from, to) in &flow_edges {
+        if included.contains(from) && included.contains(to) {
+            writeln!(out, "    n{} -> n{} [style=dashed];", from, to)?;
+        }
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+
+    fn sample_trace() -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        for request in ["req_a", "req_b"] {
+            ctx.event()
+                .with_begin()
+                .with_name(request)
+                .with_track_uuid(1)
+                .with_flow_id(7)
+                .build();
+            ctx.event()
+                .with_begin()
+                .with_name("query \"users\"")
+                .with_track_uuid(1)
+                .build();
+            ctx.event().with_end().with_track_uuid(1).build();
+            ctx.event().with_end().with_track_uuid(1).build();
+        }
+        ctx.event()
+            .with_instant()
+            .with_name("respond")
+            .with_track_uuid(2)
+            .with_terminating_flow_id(7)
+            .build();
+        ctx.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn renders_hierarchy_and_flows() -> Result<()> {
+        let dot = to_dot(&sample_trace()?, None)?;
+        assert_eq!(
+            dot,
+            "digraph trace {\n    node [shape=box];\n    \
+             n0 [label=\"req_a\"];\n    \
+             n1 [label=\"query \\\"users\\\"\"];\n    \
+             n2 [label=\"req_b\"];\n    \
+             n3 [label=\"query \\\"users\\\"\"];\n    \
+             n4 [label=\"respond\", shape=ellipse];\n    \
+             n0 -> n1;\n    \
+             n2 -> n3;\n    \
+             n0 -> n2 [style=dashed];\n    \
+             n2 -> n4 [style=dashed];\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filters_to_root() -> Result<()> {
+        let dot = to_dot(&sample_trace()?, Some("req_b"))?;
+        assert!(!dot.contains("req_a"));
+        assert!(dot.contains("n2 -> n3;"));
+        assert!(dot.contains("n2 -> n4 [style=dashed];"));
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use perfetto_protos::{
+    trace_packet::TracePacket,
+    trace_stats::{TraceStats, trace_stats::BufferStats},
+    track_event::track_event::Type,
+};
+
+use crate::Context;
+
+/// What a [`Context`] has written, lost and still holds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStats {
+    pub written_packets: u64,
+    pub written_bytes: u64,
+    pub dropped_packets: u64,
+    pub buffered_packets: u64,
+    pub buffered_bytes: u64,
+}
+
+impl Context {
+    /// Counts `packets` that never made it into the buffer, e.g. because it
+    /// was full or every sink failed. The next packet is marked with
+    /// `previous_packet_dropped` so the trace processor reports the gap.
+    pub fn record_dropped(&mut self, packets: u64) {
+        if packets == 0 {
+            return;
+        }
+        self.dropped_packets += packets;
+        self.packet_dropped = true;
+    }
+
+    /// Retracts the oldest buffered event to make room for newer ones, for
+    /// ring buffer style recording. A slice is retracted together with its
+    /// end, while slices that have not ended yet and ends of slices already
+    /// written are kept, so no slice is left half recorded. Descriptors,
+    /// interned data and packets that reset incremental state are kept too.
+    /// Returns false when no event is left to evict.
+    pub fn evict_oldest(&mut self) -> bool {
+        while self.evict_from < self.buffer.packet.len() {
+            let index = self.evict_from;
+            self.evict_from += 1;
+            let packet = &self.buffer.packet[index];
+            if self.retracted.contains(&index)
+                || !packet.has_track_event()
+                || packet.interned_data.is_some()
+                || packet.sequence_flags() != 0
+            {
+                continue;
+            }
+            let end = match packet.track_event().type_() {
+                Type::TYPE_SLICE_BEGIN => match self.buffered_end(index) {
+                    Some(end) => Some(end),
+                    None => continue,
+                },
+                Type::TYPE_SLICE_END => continue,
+                _ => None,
+            };
+            if self.retract_packet(self.flushed_packets + index as u64) {
+                self.dropped_packets += 1;
+                if let Some(end) = end
+                    && self.retract_packet(self.flushed_packets + end as u64)
+                {
+                    self.dropped_packets += 1;
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The index of the end of the slice begun at `begin`, if it is
+    /// buffered.
+    fn buffered_end(&self, begin: usize) -> Option<usize> {
+        let track = self.buffer.packet[begin].track_event().track_uuid();
+        let mut depth = 0usize;
+        for (index, packet) in self.buffer.packet.iter().enumerate().skip(begin + 1) {
+            if self.retracted.contains(&index)
+                || !packet.has_track_event()
+                || packet.track_event().track_uuid() != track
+            {
+                continue;
+            }
+            match packet.track_event().type_() {
+                Type::TYPE_SLICE_BEGIN => depth += 1,
+                Type::TYPE_SLICE_END if depth == 0 => return Some(index),
+                Type::TYPE_SLICE_END => depth -= 1,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    pub fn write_stats(&self) -> WriteStats {
+        WriteStats {
+            written_packets: self.written_packets,
+            written_bytes: self.written_bytes,
+            dropped_packets: self.dropped_packets,
+            buffered_packets: self.buffered_packets() as u64,
+            buffered_bytes: self.buffered_bytes() as u64,
+        }
+    }
+
+    /// Records a `TraceStats` packet with the counts so far, shown under
+    /// "Info and stats" in the UI. Written automatically by
+    /// [`Context::finalize_sinks`].
+    pub fn write_trace_stats(&mut self) {
+        let stats = self.write_stats();
+        let mut buffer = BufferStats::new();
+        buffer.set_bytes_written(stats.written_bytes + stats.buffered_bytes);
+        buffer.set_trace_writer_packet_loss(stats.dropped_packets);
+        let mut trace_stats = TraceStats::new();
+        trace_stats.buffer_stats.push(buffer);
+        let mut packet = TracePacket::new();
+        packet.set_trace_stats(trace_stats);
+        self.push_packet(packet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemorySink;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn marks_the_packet_after_a_drop() {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.record_dropped(3);
+        ctx.event()
+            .with_name("after")
+            .with_track_uuid(track)
+            .build();
+        ctx.event()
+            .with_name("later")
+            .with_track_uuid(track)
+            .build();
+
+        let trace = ctx.take_trace();
+        let marked: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.previous_packet_dropped())
+            .collect();
+        assert_eq!(marked.len(), 1);
+        assert_eq!(ctx.write_stats().dropped_packets, 3);
+    }
+
+    #[test]
+    fn evicts_the_oldest_events() {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for name in ["first", "second", "third"] {
+            ctx.event().with_name(name).with_track_uuid(track).build();
+        }
+        let before = ctx.buffered_packets();
+        assert!(ctx.evict_oldest());
+        assert_eq!(ctx.buffered_packets(), before - 1);
+        assert_eq!(ctx.write_stats().dropped_packets, 1);
+
+        let trace = ctx.take_trace();
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 2);
+        assert!(!ctx.evict_oldest());
+    }
+
+    #[test]
+    fn evicts_slices_with_their_end() {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_begin()
+            .with_name("open")
+            .with_track_uuid(track)
+            .build();
+        ctx.event()
+            .with_begin()
+            .with_name("done")
+            .with_track_uuid(track)
+            .build();
+        ctx.event()
+            .with_name("inside")
+            .with_track_uuid(track)
+            .build();
+        ctx.event().with_end().with_track_uuid(track).build();
+
+        assert!(ctx.evict_oldest());
+        assert_eq!(ctx.write_stats().dropped_packets, 2);
+        assert!(ctx.evict_oldest());
+        assert!(!ctx.evict_oldest(), "open slices are kept");
+
+        let trace = ctx.take_trace();
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(events, [Type::TYPE_SLICE_BEGIN]);
+    }
+
+    #[test]
+    fn finalize_writes_trace_stats() -> Result<()> {
+        let mut ctx = Context::new();
+        let sink = MemorySink::new();
+        ctx.add_sink(sink.clone());
+        let track = ctx.current_thread_track();
+        ctx.event().with_name("kept").with_track_uuid(track).build();
+        ctx.flush_sinks()?;
+        ctx.record_dropped(2);
+        let flushed = ctx.write_stats();
+        assert_eq!(flushed.buffered_packets, 0);
+        assert!(flushed.written_packets > 0);
+
+        ctx.finalize_sinks()?;
+        let trace = Trace::parse_from_bytes(&sink.contents())?;
+        let stats = trace
+            .packet
+            .iter()
+            .find(|p| p.has_trace_stats())
+            .map(|p| p.trace_stats().buffer_stats[0].clone())
+            .expect("trace stats packet");
+        assert_eq!(stats.trace_writer_packet_loss(), 2);
+        assert!(stats.bytes_written() >= flushed.written_bytes);
+        Ok(())
+    }
+
+    struct Broken;
+
+    impl std::io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_successful_writes_count_as_written() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.event().with_name("lost").with_track_uuid(track).build();
+        let buffered = ctx.buffered_packets() as u64;
+        assert!(ctx.write_to(&mut Broken).is_err());
+        let stats = ctx.write_stats();
+        assert_eq!((stats.written_packets, stats.written_bytes), (0, 0));
+        assert_eq!(stats.dropped_packets, buffered);
+
+        ctx.event()
+            .with_name("discarded")
+            .with_track_uuid(track)
+            .build();
+        ctx.rotate_sinks()?;
+        assert_eq!(ctx.write_stats().written_packets, 0);
+
+        ctx.event().with_name("kept").with_track_uuid(track).build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let stats = ctx.write_stats();
+        assert_eq!(stats.written_bytes, buf.len() as u64);
+        assert_eq!(
+            stats.written_packets,
+            Trace::parse_from_bytes(&buf)?.packet.len() as u64
+        );
+        Ok(())
+    }
+}
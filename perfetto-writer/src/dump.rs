@@ -0,0 +1,152 @@
+use anyhow::{Result, bail};
+use nix::{
+    errno::Errno,
+    sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction},
+};
+use std::{
+    ffi::c_int,
+    fs::File,
+    io::{BufWriter, ErrorKind, Read},
+    os::{
+        fd::{BorrowedFd, IntoRawFd},
+        unix::net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicI32, Ordering::Relaxed},
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Context, INDEX_FILE};
+
+/// The signal conventionally used to ask for a dump, `SIGUSR2`.
+pub const DUMP_SIGNAL: i32 = Signal::SIGUSR2 as i32;
+
+/// Where the handler reports signals, or -1 before a dumper is installed.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: c_int) {
+    let fd = WAKE_FD.load(Relaxed);
+    if fd < 0 {
+        return;
+    }
+    // Only async-signal-safe calls here: wake the dumping thread and put
+    // back the errno the interrupted code may be about to read.
+    let errno = Errno::last_raw();
+    let _ = nix::unistd::write(unsafe { BorrowedFd::borrow_raw(fd) }, &[1]);
+    Errno::set_raw(errno);
+}
+
+/// Writes what `ctx` buffered to a timestamped file in `dir` each time
+/// the process receives `signal`, e.g. `kill -USR2 <pid>` with
+/// [`DUMP_SIGNAL`], for on-demand captures from long-running daemons.
+///
+/// Each dump starts a new segment, so every file loads on its own and
+/// holds the events since the previous one. The files are named
+/// `trace-<unix seconds>-<millis>.pftrace` and listed with the time they
+/// cover in an [index](crate::IndexEntry) in `dir`, [`INDEX_FILE`]. Only
+/// one dumper can be installed per process; it lives as long as the
+/// process.
+pub fn dump_on_signal(
+    ctx: Arc<Mutex<Context>>,
+    signal: i32,
+    dir: impl Into<PathBuf>,
+) -> Result<JoinHandle<()>> {
+    let signal = Signal::try_from(signal)?;
+    let dir = dir.into();
+    let (mut wake, notify) = UnixStream::pair()?;
+    notify.set_nonblocking(true)?;
+    if WAKE_FD
+        .compare_exchange(-1, notify.into_raw_fd(), Relaxed, Relaxed)
+        .is_err()
+    {
+        bail!("a signal dumper is already installed in this process");
+    }
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only makes async-signal-safe calls.
+    unsafe { sigaction(signal, &action) }?;
+
+    Ok(thread::spawn(move || {
+        let mut byte = [0];
+        loop {
+            match wake.read(&mut byte) {
+                Ok(0) => return,
+                Ok(_) => {
+                    // A failed dump leaves the buffer for the next one.
+                    let _ = dump(&ctx, &dir);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        }
+    }))
+}
+
+fn dump(ctx: &Mutex<Context>, dir: &Path) -> Result<PathBuf> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let name = format!("trace-{}-{:03}.pftrace", now.as_secs(), now.subsec_millis());
+    let path = dir.join(&name);
+    let mut file = BufWriter::new(File::create(&path)?);
+    let entry = ctx
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .rotate_indexed(&mut file, name)?;
+    if let Some(entry) = entry {
+        entry.append(&dir.join(INDEX_FILE))?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn signals_dump_the_buffer() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("perfetto-dump-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        {
+            let mut ctx = ctx.lock().unwrap();
+            let track = ctx.current_thread_track();
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("before dump")
+                .build();
+        }
+        dump_on_signal(Arc::clone(&ctx), DUMP_SIGNAL, &dir)?;
+        assert!(dump_on_signal(Arc::clone(&ctx), DUMP_SIGNAL, &dir).is_err());
+
+        nix::sys::signal::raise(Signal::SIGUSR2)?;
+        // The index entry is added once the file is complete.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let entry = loop {
+            let index = std::fs::read_to_string(dir.join(INDEX_FILE)).unwrap_or_default();
+            if let Some(entry) = index.lines().next() {
+                break entry.to_string();
+            }
+            assert!(Instant::now() < deadline, "no dump was written");
+            thread::sleep(Duration::from_millis(10));
+        };
+        let file = entry.split('"').nth(3).unwrap();
+        let path = dir.join(file);
+
+        let trace = Trace::parse_from_bytes(&std::fs::read(&path)?)?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::Result;
+use protobuf::{CodedOutputStream, Message, rt::compute_raw_varint64_size};
+use std::{fmt, io::Write};
+
+use crate::Context;
+
+/// Returned by [`Context::write_to_slice`] when the buffered trace does not
+/// fit. Nothing is consumed, so the call can be retried with a buffer of at
+/// least `needed` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeedMore {
+    pub needed: usize,
+}
+
+impl fmt::Display for NeedMore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trace needs a buffer of {} bytes", self.needed)
+    }
+}
+
+impl std::error::Error for NeedMore {}
+
+impl Context {
+    /// Writes every packet recorded so far and releases them, returning how
+    /// many were written. Interning, tracks and open slices carry over, so
+    /// successive drains concatenate into one trace; use it to flush on
+    /// your own schedule.
+    pub fn drain_to<W: Write>(&mut self, w: &mut W) -> Result<usize> {
+        let trace = self.take_trace();
+        let packets = trace.packet.len();
+        self.write_trace_to(trace, w)?;
+        Ok(packets)
+    }
+
+    /// Appends the buffered trace to `buf`, encoding straight into it.
+    pub fn write_to_vec(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        let trace = self.take_trace();
+        let start = buf.len();
+        if let Err(e) = trace.write_to_vec(buf) {
+            self.record_dropped(trace.packet.len() as u64);
+            return Err(e.into());
+        }
+        self.record_written(&trace, (buf.len() - start) as u64);
+        Ok(())
+    }
+
+    /// Encodes the buffered trace into `buf` and returns the number of
+    /// bytes written, or how many are needed if `buf` is too small.
+    pub fn write_to_slice(&mut self, buf: &mut [u8]) -> Result<usize, NeedMore> {
+        let needed = self.encoded_len();
+        if needed > buf.len() {
+            return Err(NeedMore { needed });
+        }
+        let trace = self.take_trace();
+        let mut os = CodedOutputStream::bytes(buf);
+        trace
+            .write_to_with_cached_sizes(&mut os)
+            .and_then(|_| os.flush())
+            .expect("buffer was sized from the encoded length");
+        self.record_written(&trace, needed as u64);
+        Ok(needed)
+    }
+
+    /// Size of the buffered trace once encoded, leaving out retracted
+    /// packets. Caches the packet sizes used when encoding.
+    fn encoded_len(&self) -> usize {
+        let packets = self
+            .buffer
+            .packet
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !self.retracted.contains(i))
+            .map(|(_, packet)| {
+                let len = packet.compute_size();
+                1 + compute_raw_varint64_size(len) + len
+            })
+            .sum::<u64>();
+        packets as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+
+    fn record(ctx: &mut Context) {
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("tick")
+            .build();
+    }
+
+    #[test]
+    fn encoded_len_matches_writer() -> Result<()> {
+        let mut ctx = Context::new();
+        record(&mut ctx);
+        let len = ctx.encoded_len();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        assert_eq!(buf.len(), len);
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_slice_reports_needed_size() -> Result<()> {
+        let mut ctx = Context::new();
+        record(&mut ctx);
+        let mut small = [0u8; 8];
+        let needed = ctx.write_to_slice(&mut small).unwrap_err().needed;
+        assert!(needed > small.len());
+        assert!(ctx.buffered_packets() > 0);
+
+        let mut buf = vec![0u8; needed + 16];
+        assert_eq!(ctx.write_to_slice(&mut buf), Ok(needed));
+        assert_eq!(ctx.buffered_packets(), 0);
+        let trace = Trace::parse_from_bytes(&buf[..needed])?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+
+        record(&mut ctx);
+        let mut appended = vec![1, 2, 3];
+        ctx.write_to_vec(&mut appended)?;
+        let trace = Trace::parse_from_bytes(&appended[3..])?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        Ok(())
+    }
+
+    #[test]
+    fn drains_continue_the_trace() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("open")
+            .build();
+        let mut out = Vec::new();
+        assert!(ctx.drain_to(&mut out)? > 0);
+        assert_eq!(ctx.buffered_packets(), 0);
+
+        ctx.event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(track)
+            .build();
+        record(&mut ctx);
+        assert!(ctx.drain_to(&mut out)? > 0);
+        assert_eq!(ctx.drain_to(&mut out)?, 0);
+
+        let trace = Trace::parse_from_bytes(&out)?;
+        let inits = trace
+            .packet
+            .iter()
+            .filter(|p| p.sequence_flags() != 0)
+            .count();
+        assert_eq!(inits, 1);
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn retracted_packets_are_not_counted() -> Result<()> {
+        let mut ctx = Context::new();
+        record(&mut ctx);
+        let without = ctx.encoded_len();
+        record(&mut ctx);
+        let position = ctx.last_packet_position().unwrap();
+        assert!(ctx.retract_packet(position));
+        assert_eq!(ctx.encoded_len(), without);
+        Ok(())
+    }
+}
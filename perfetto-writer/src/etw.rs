@@ -0,0 +1,156 @@
+use perfetto_protos::track_event::{TrackEvent, track_event::Type};
+use smol_str::SmolStr;
+use std::{collections::HashMap, pin::Pin};
+use tracelogging_dynamic::{EventBuilder, Guid, Level, Opcode, OutType, Provider};
+
+use crate::Context;
+
+/// Writes the slices and instants of a [`Context`] to an ETW provider as
+/// they are recorded.
+pub(crate) struct EtwMirror {
+    provider: Pin<Box<Provider>>,
+    builder: EventBuilder,
+    // End events carry no name, so the begin's is remembered per track,
+    // along with the activity id of the slice.
+    open: HashMap<u64, Vec<(SmolStr, Guid)>>,
+    activities: u64,
+}
+
+impl EtwMirror {
+    fn new(name: &str) -> Self {
+        let provider = Box::pin(Provider::new(name, &Provider::options()));
+        // SAFETY: the provider is pinned and unregisters itself on drop.
+        unsafe { provider.as_ref().register() };
+        Self {
+            provider,
+            builder: EventBuilder::new(),
+            open: HashMap::new(),
+            // Keeps ids of slices on the same track distinct across
+            // processes.
+            activities: u64::from(std::process::id()) << 32,
+        }
+    }
+
+    /// A new activity id for a slice on `track`.
+    fn activity(&mut self, track: u64) -> Guid {
+        self.activities += 1;
+        Guid::from_u128(&((u128::from(track) << 64) | u128::from(self.activities)))
+    }
+
+    /// The activity id of the innermost open slice on `track`.
+    fn enclosing(&self, track: u64) -> Option<Guid> {
+        self.open.get(&track)?.last().map(|(_, id)| *id)
+    }
+
+    fn mirror(&mut self, event: &TrackEvent, name: Option<&SmolStr>, timestamp: Option<u64>) {
+        let track = event.track_uuid();
+        // Slices are activities, related to the slice they are nested in;
+        // instants belong to the slice they happen in.
+        let (opcode, name, activity, related) = match event.type_() {
+            Type::TYPE_SLICE_BEGIN => {
+                let name = name.cloned().unwrap_or_default();
+                let related = self.enclosing(track);
+                let activity = self.activity(track);
+                self.open
+                    .entry(track)
+                    .or_default()
+                    .push((name.clone(), activity));
+                (Opcode::ActivityStart, name, Some(activity), related)
+            }
+            Type::TYPE_SLICE_END => {
+                let Some((name, activity)) = self.open.get_mut(&track).and_then(Vec::pop) else {
+                    return;
+                };
+                (Opcode::ActivityStop, name, Some(activity), None)
+            }
+            Type::TYPE_INSTANT => (
+                Opcode::Info,
+                name.cloned().unwrap_or_default(),
+                self.enclosing(track),
+                None,
+            ),
+            _ => return,
+        };
+        if !self.provider.enabled(Level::Verbose, 0) {
+            return;
+        }
+        self.builder
+            .reset(&name, Level::Verbose, 0, 0)
+            .opcode(opcode)
+            .add_u64("track", track, OutType::Default, 0)
+            .add_u64(
+                "timestamp",
+                timestamp.unwrap_or_default(),
+                OutType::Default,
+                0,
+            )
+            .write(&self.provider, activity.as_ref(), related.as_ref());
+    }
+}
+
+impl Context {
+    /// Also writes slices and instants as TraceLogging events of the ETW
+    /// provider `name`, e.g. "MyCompany.MyApp", so they line up with system
+    /// events in WPA while the `.pftrace` is recorded as usual. Slices become
+    /// start/stop pairs sharing an activity id, related to the slice they
+    /// are nested in, so WPA can group and nest them. ETW only exists on Windows; elsewhere nothing is
+    /// mirrored.
+    pub fn mirror_to_etw(&mut self, name: &str) {
+        self.etw = Some(EtwMirror::new(name));
+    }
+
+    pub(crate) fn mirror_etw(
+        &mut self,
+        event: &TrackEvent,
+        name: Option<&SmolStr>,
+        timestamp: Option<u64>,
+    ) {
+        if let Some(etw) = &mut self.etw {
+            etw.mirror(event, name, timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_ends_with_their_begin() {
+        let mut ctx = Context::new();
+        ctx.mirror_to_etw("PerfettoWriter.Test");
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_begin()
+            .with_name("outer")
+            .with_track_uuid(track)
+            .build();
+        ctx.event()
+            .with_begin()
+            .with_name("inner")
+            .with_track_uuid(track)
+            .build();
+        ctx.event().with_end().with_track_uuid(track).build();
+
+        let open = &ctx.etw.as_ref().unwrap().open[&track];
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].0, "outer");
+        ctx.event().with_end().with_track_uuid(track).build();
+        assert!(ctx.etw.as_ref().unwrap().open[&track].is_empty());
+    }
+
+    #[test]
+    fn nested_slices_are_related_activities() {
+        let mut mirror = EtwMirror::new("PerfettoWriter.Test");
+        let outer = mirror.activity(1);
+        mirror
+            .open
+            .entry(1)
+            .or_default()
+            .push(("outer".into(), outer));
+        let inner = mirror.activity(1);
+        assert_ne!(outer, inner);
+        assert_eq!(mirror.enclosing(1), Some(outer));
+        assert_eq!(mirror.enclosing(2), None);
+    }
+}
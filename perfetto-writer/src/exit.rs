@@ -0,0 +1,80 @@
+#[cfg(unix)]
+use nix::sys::resource::{UsageWho, getrusage};
+
+use crate::{Context, InstantScope};
+
+/// Peak resident set size of the process in bytes, if the OS reports it.
+#[cfg(unix)]
+fn peak_rss_bytes() -> Option<u64> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF).ok()?;
+    let max_rss = u64::try_from(usage.max_rss()).ok()?;
+    if cfg!(target_vendor = "apple") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_rss_bytes() -> Option<u64> {
+    None
+}
+
+impl Context {
+    /// Records a "process exit" instant on the process track carrying the
+    /// exit code, the time since the context was created and the peak RSS,
+    /// so a batch job's trace carries its outcome. Call it just before the
+    /// final flush.
+    pub fn record_exit(&mut self, code: i32) {
+        let runtime = self.started.map(|s| s.elapsed()).unwrap_or_default();
+        let mut event = self
+            .event()
+            .with_now()
+            .with_instant_scope(InstantScope::Process)
+            .with_name("process exit")
+            .with_debug_int("exit_code", code as i64)
+            .with_debug_uint("runtime_ns", runtime.as_nanos() as u64);
+        if let Some(rss) = peak_rss_bytes() {
+            event.debug_uint("peak_rss_bytes", rss);
+        }
+        event.build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn exit_summary_is_recorded() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.record_exit(3);
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let process = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().process.is_some())
+            .unwrap()
+            .track_descriptor()
+            .uuid();
+        let event = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event())
+            .unwrap()
+            .track_event();
+        assert_eq!(event.track_uuid(), process);
+        assert_eq!(event.debug_annotations[0].int_value(), 3);
+        assert!(event.debug_annotations.len() >= 2);
+        if cfg!(target_os = "linux") {
+            assert!(event.debug_annotations[2].uint_value() > 0);
+        }
+        Ok(())
+    }
+}
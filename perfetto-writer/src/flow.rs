@@ -0,0 +1,106 @@
+use crate::EventBuilder;
+
+/// Which way a flow passes through an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowDirection {
+    /// The flow starts here or passes through and continues from this event,
+    /// e.g. a channel send or a task spawn.
+    Continue,
+    /// The flow ends at this event, e.g. the matching receive or first poll.
+    /// Later events can't extend it.
+    Terminate,
+}
+
+impl<'a> EventBuilder<'a> {
+    pub fn flow(&mut self, id: u64, direction: FlowDirection) {
+        match direction {
+            FlowDirection::Continue => self.flow_id(id),
+            FlowDirection::Terminate => self.terminating_flow_id(id),
+        }
+    }
+
+    pub fn flow_ids(&mut self, ids: impl IntoIterator<Item = u64>) {
+        self.event.flow_ids.extend(ids);
+    }
+
+    pub fn terminating_flow_ids(&mut self, ids: impl IntoIterator<Item = u64>) {
+        self.event.terminating_flow_ids.extend(ids);
+    }
+
+    pub fn with_flow(mut self, id: u64, direction: FlowDirection) -> Self {
+        self.flow(id, direction);
+        self
+    }
+
+    pub fn with_flow_ids(mut self, ids: impl IntoIterator<Item = u64>) -> Self {
+        self.flow_ids(ids);
+        self
+    }
+
+    pub fn with_terminating_flow_ids(mut self, ids: impl IntoIterator<Item = u64>) -> Self {
+        self.terminating_flow_ids(ids);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn fan_out_and_terminate() -> Result<()> {
+        let mut ctx = Context::new();
+        let (a, b) = (ctx.next_id(), ctx.next_id());
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(1)
+            .with_name("spawn")
+            .with_flow_ids([a, b])
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(2)
+            .with_name("poll a")
+            .with_flow(a, FlowDirection::Terminate)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(3)
+            .with_name("forward b")
+            .with_flow(b, FlowDirection::Continue)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(4)
+            .with_name("poll b")
+            .with_terminating_flow_ids([b])
+            .build();
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let flows: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| {
+                let e = p.track_event();
+                (e.flow_ids.clone(), e.terminating_flow_ids.clone())
+            })
+            .collect();
+        assert_eq!(
+            flows,
+            [
+                (vec![a, b], vec![]),
+                (vec![], vec![a]),
+                (vec![b], vec![]),
+                (vec![], vec![b])
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use perfetto_protos::{trace::Trace, trace_packet::TracePacket};
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+use web_time::Instant;
+
+use crate::{Context, InstantScope, current_pid};
+
+/// Bumped in the child after every `fork()`.
+static FORKS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(unix)]
+unsafe extern "C" {
+    fn pthread_atfork(
+        prepare: Option<extern "C" fn()>,
+        parent: Option<extern "C" fn()>,
+        child: Option<extern "C" fn()>,
+    ) -> std::ffi::c_int;
+}
+
+#[cfg(unix)]
+extern "C" fn forked() {
+    FORKS.fetch_add(1, Relaxed);
+}
+
+/// How many `fork()`s separate this process from the one that first called
+/// this, so state captured in a parent can tell it is now in a child, e.g.
+/// a file handle that belongs to the parent's trace.
+pub fn fork_generation() -> u64 {
+    generation()
+}
+
+/// Returns the current fork generation, registering the `fork()` handler
+/// on first use.
+pub(crate) fn generation() -> u64 {
+    #[cfg(unix)]
+    {
+        static REGISTER: std::sync::Once = std::sync::Once::new();
+        REGISTER.call_once(|| {
+            // SAFETY: the handler only touches an atomic, which is
+            // async-signal-safe as required in a forked child.
+            unsafe { pthread_atfork(None, None, Some(forked)) };
+        });
+    }
+    FORKS.load(Relaxed)
+}
+
+impl Context {
+    /// Reinitializes the context if the process forked since it was last
+    /// used. Called when recording starts, so the child of a `fork()` gets a
+    /// sequence, process and thread tracks of its own instead of
+    /// interleaving with the parent's.
+    pub(crate) fn check_fork(&mut self) {
+        let generation = generation();
+        if self.fork_generation != generation {
+            self.fork_generation = generation;
+            self.reinit_after_fork();
+        }
+    }
+
+    /// Makes a context inherited through `fork()` safe to use in the child.
+    /// This runs automatically; call it directly only when forking by other
+    /// means, e.g. a raw `clone` syscall.
+    ///
+    /// The packets buffered before the fork are the parent's to write and
+    /// are discarded, as are the sinks, which are shared with the parent and
+    /// are not flushed or finalized. The child starts a new packet sequence
+    /// and track uuid range, clears its interning and thread tracks, and
+    /// describes its own process. Tracks not tied to the parent's process
+    /// or threads are described again, nested under the child's process
+    /// track, and stay valid.
+    pub fn reinit_after_fork(&mut self) {
+        let pid = current_pid();
+        self.buffer = Trace::new();
+        self.buffered_bytes = 0;
+        self.retracted.clear();
+        self.evict_from = 0;
+        // Dropping a sink could flush the parent's buffered bytes twice.
+        std::mem::forget(std::mem::take(&mut self.sinks));
+        self.seq = pid;
+        *self.next_id.get_mut() = u64::from(pid) << 32;
+        self.thread_tracks.clear();
+        let global = self.scope_tracks.get(&InstantScope::Global).copied();
+        self.scope_tracks.clear();
+        self.lock_tracks.clear();
+        // Parents are created before their children, so one pass finds the
+        // tracks nested in the parent's process and thread tracks too.
+        let mut parents = HashSet::new();
+        let mut kept = Vec::new();
+        for track in std::mem::take(&mut self.tracks) {
+            if track.process.is_some()
+                || track.thread.is_some()
+                || (track.has_parent_uuid() && parents.contains(&track.parent_uuid()))
+            {
+                parents.insert(track.uuid());
+            } else {
+                kept.push(track);
+            }
+        }
+        self.reset_interning();
+
+        let mut init = self.init_packet();
+        init.set_clock_snapshot(self.clock_state());
+        self.last_clock_snapshot = Some(Instant::now());
+        self.push_packet(init);
+        if self.system_info_written {
+            self.write_system_info();
+        }
+        let process = self.scope_track(InstantScope::Process);
+        if let Some(global) = global {
+            self.scope_tracks.insert(InstantScope::Global, global);
+        }
+        for mut track in kept {
+            if !track.has_parent_uuid() && Some(track.uuid()) != global {
+                track.parent_uuid = process;
+            }
+            self.tracks.push(track.clone());
+            let mut tp = TracePacket::new();
+            tp.set_track_descriptor(track);
+            self.push_packet(tp);
+        }
+        if self.delta_base.is_some() {
+            self.set_delta_timestamps(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemorySink;
+
+    #[test]
+    fn child_gets_a_fresh_sequence() {
+        let mut ctx = Context::new();
+        let sink = MemorySink::new();
+        ctx.add_sink(sink.clone());
+        let shared = ctx.create_track("shared");
+        let thread = ctx.current_thread_track();
+        let locks = ctx.create_child_track(thread, "locks");
+        ctx.event()
+            .with_name("parent")
+            .with_track_uuid(thread)
+            .build();
+
+        ctx.reinit_after_fork();
+        let child_thread = ctx.current_thread_track();
+        assert_ne!(child_thread, thread);
+        ctx.event()
+            .with_name("child")
+            .with_track_uuid(shared)
+            .build();
+
+        let trace = ctx.take_trace();
+        ctx.flush_sinks().unwrap();
+        assert!(sink.contents().is_empty(), "sinks belong to the parent");
+        let head = &trace.packet[0];
+        assert_ne!(head.sequence_flags() & 1, 0);
+        assert_eq!(head.trusted_packet_sequence_id(), current_pid());
+        assert!(
+            trace
+                .packet
+                .iter()
+                .all(|p| !p.has_track_event() || p.track_event().track_uuid() == shared)
+        );
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .collect();
+        let process = tracks
+            .iter()
+            .find(|t| t.process.is_some() && t.thread.is_none())
+            .unwrap();
+        assert_eq!(process.process.pid(), current_pid() as i32);
+        let shared = tracks.iter().find(|t| t.uuid() == shared).unwrap();
+        assert_eq!(shared.parent_uuid(), process.uuid());
+        assert!(
+            tracks
+                .iter()
+                .all(|t| t.uuid() != thread && t.uuid() != locks)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use crate::{ClockId, Color, Context, CounterUnit, TrackUuid};
+
+/// One frame at 60 Hz.
+const DEFAULT_FRAME_BUDGET: Duration = Duration::from_nanos(16_666_667);
+
+/// Frame pacing state of a [`Context`].
+#[derive(Default)]
+pub(crate) struct Frames {
+    budget: Option<Duration>,
+    tracks: Option<(TrackUuid, TrackUuid)>,
+    open: Option<OpenFrame>,
+    long_frames: u64,
+}
+
+struct OpenFrame {
+    number: u64,
+    clock: ClockId,
+    start: u64,
+    /// Nanoseconds per unit of `clock`.
+    unit_ns: u64,
+}
+
+impl Context {
+    /// Sets how long a frame may take before it counts as long. Defaults to
+    /// one frame at 60 Hz.
+    pub fn set_frame_budget(&mut self, budget: Duration) {
+        self.frame_pacing.budget = Some(budget);
+    }
+
+    /// Marks the start of frame `frame_number`, ending the previous one.
+    /// Call it once per frame at the same point, e.g. right after present or
+    /// on vsync.
+    ///
+    /// Each frame becomes a slice on a "Frames" track, so frame pacing is
+    /// visible at a glance, and its duration goes to a "Frame time" counter.
+    /// Frames over the budget are colored and repeated on a "Long frames"
+    /// track; selecting one there shows the spans that ran during it. A
+    /// frame is written when the next one is marked.
+    pub fn mark_frame(&mut self, frame_number: u64) {
+        let clock = self.clock();
+        let frame = OpenFrame {
+            number: frame_number,
+            clock: clock.id(),
+            start: clock.now(),
+            unit_ns: clock.unit_multiplier_ns(),
+        };
+        if let Some(previous) = self.frame_pacing.open.replace(frame) {
+            let end = self.frame_pacing.open.as_ref().unwrap().start;
+            let elapsed = end
+                .saturating_sub(previous.start)
+                .saturating_mul(previous.unit_ns);
+            let elapsed = Duration::from_nanos(elapsed);
+            let skipped = frame_number.saturating_sub(previous.number + 1);
+            self.write_frame(previous, end, elapsed, skipped);
+        }
+    }
+
+    /// Frames that took longer than the budget so far.
+    pub fn long_frames(&self) -> u64 {
+        self.frame_pacing.long_frames
+    }
+
+    fn write_frame(&mut self, frame: OpenFrame, end: u64, elapsed: Duration, skipped: u64) {
+        let (frames, long) = match self.frame_pacing.tracks {
+            Some(tracks) => tracks,
+            None => {
+                let frames = self.create_track("Frames");
+                let long = self.create_track("Long frames");
+                self.frame_pacing.tracks = Some((frames, long));
+                (frames, long)
+            }
+        };
+        let over_budget = elapsed > self.frame_pacing.budget.unwrap_or(DEFAULT_FRAME_BUDGET);
+        let name = format!("Frame {}", frame.number);
+        let mut tracks = vec![frames];
+        if over_budget {
+            self.frame_pacing.long_frames += 1;
+            tracks.push(long);
+        }
+        for track in tracks {
+            let mut event = self.event();
+            event.begin();
+            event.clock(frame.clock);
+            event.timestamp_ns(frame.start);
+            event.name(name.as_str());
+            event.track_uuid(track);
+            event.debug_uint("frame_number", frame.number);
+            event.debug_double("duration_ms", elapsed.as_secs_f64() * 1e3);
+            if skipped > 0 {
+                event.debug_uint("skipped_frames", skipped);
+            }
+            if over_budget {
+                event.color(Color::Bad);
+            }
+            event.build();
+            self.event()
+                .with_end()
+                .with_clock(frame.clock)
+                .with_timestamp_ns(end)
+                .with_track_uuid(track)
+                .build();
+        }
+        let counter = self.named_counter_track("Frame time", CounterUnit::UNIT_TIME_NS);
+        self.event()
+            .with_counter()
+            .with_clock(frame.clock)
+            .with_timestamp_ns(end)
+            .with_track_uuid(counter)
+            .with_counter_value(elapsed.as_nanos() as i64)
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogicalClock;
+    use std::thread;
+
+    #[test]
+    fn long_frames_are_repeated_on_their_own_track() {
+        let mut ctx = Context::new();
+        ctx.set_frame_budget(Duration::from_millis(5));
+        ctx.mark_frame(1);
+        ctx.mark_frame(2);
+        thread::sleep(Duration::from_millis(10));
+        ctx.mark_frame(4);
+        assert_eq!(ctx.long_frames(), 1);
+        let (frames, long) = ctx.frame_pacing.tracks.unwrap();
+
+        let trace = ctx.take_trace();
+        let slices = |track| {
+            trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event() && p.track_event().track_uuid() == track)
+                .count()
+        };
+        assert_eq!(slices(frames), 4);
+        assert_eq!(slices(long), 2);
+        let long_frame = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event() && p.track_event().track_uuid() == long)
+            .unwrap();
+        let annotations = &long_frame.track_event().debug_annotations;
+        assert!(
+            annotations.iter().any(|a| a.uint_value() == 1),
+            "skipped frame"
+        );
+    }
+
+    #[test]
+    fn frame_times_follow_the_context_clock() {
+        let clock = LogicalClock::new(64).with_tick_duration(Duration::from_millis(1));
+        let mut ctx = Context::new();
+        ctx.set_clock(clock.clone());
+        ctx.set_frame_budget(Duration::from_millis(5));
+        ctx.mark_frame(1);
+        clock.advance(4);
+        ctx.mark_frame(2);
+        clock.advance(8);
+        ctx.mark_frame(3);
+        assert_eq!(ctx.long_frames(), 1);
+
+        let trace = ctx.take_trace();
+        let frame_times: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().has_counter_value())
+            .map(|p| p.track_event().counter_value())
+            .collect();
+        assert_eq!(frame_times, [4_000_000, 8_000_000]);
+    }
+}
@@ -0,0 +1,408 @@
+use perfetto_protos::{
+    ftrace_event::FtraceEvent,
+    ftrace_event_bundle::FtraceEventBundle,
+    irq::{
+        IrqHandlerEntryFtraceEvent, IrqHandlerExitFtraceEvent, SoftirqEntryFtraceEvent,
+        SoftirqExitFtraceEvent,
+    },
+    sched::{SchedSwitchFtraceEvent, SchedWakeupFtraceEvent, SchedWakingFtraceEvent},
+    trace_packet::TracePacket,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+};
+
+use crate::Context;
+
+/// The tracefs events [`capture_ftrace`] enables and
+/// [`Context::record_ftrace_text`] understands.
+pub const FTRACE_EVENTS: &[&str] = &[
+    "sched/sched_switch",
+    "sched/sched_wakeup",
+    "sched/sched_waking",
+    "irq/irq_handler_entry",
+    "irq/irq_handler_exit",
+    "irq/softirq_entry",
+    "irq/softirq_exit",
+];
+
+impl Context {
+    /// Converts tracefs text output, as read from `trace` or `trace_pipe`,
+    /// into ftrace event bundles, one per CPU. Scheduling and interrupt
+    /// events are kept, other lines are skipped. Returns how many events were
+    /// recorded.
+    ///
+    /// Timestamps are taken as they are, so the kernel should trace with
+    /// `trace_clock` set to `boot`, the clock Perfetto assumes for ftrace.
+    pub fn record_ftrace_text(&mut self, text: impl BufRead) -> io::Result<usize> {
+        let mut cpus: BTreeMap<u32, Vec<FtraceEvent>> = BTreeMap::new();
+        let mut count = 0;
+        for line in text.lines() {
+            if let Some((cpu, event)) = parse_line(&line?) {
+                cpus.entry(cpu).or_default().push(event);
+                count += 1;
+            }
+        }
+        for (cpu, events) in cpus {
+            let mut bundle = FtraceEventBundle::new();
+            bundle.set_cpu(cpu);
+            bundle.event = events;
+            let mut tp = TracePacket::new();
+            tp.set_ftrace_events(bundle);
+            self.push_packet(tp);
+        }
+        Ok(count)
+    }
+}
+
+/// Parses a line like
+/// `bash-1234 [002] d..3. 5081.123456: sched_switch: prev_comm=bash ...`.
+fn parse_line(line: &str) -> Option<(u32, FtraceEvent)> {
+    let open = line.find(" [")?;
+    let close = open + line[open..].find(']')?;
+    let task = line[..open].trim();
+    // Older kernels or the record-tgid option add "( 1234)" after the pid.
+    let task = task
+        .rsplit_once(" (")
+        .map_or(task, |(task, _)| task)
+        .trim_end();
+    let (_, pid) = task.rsplit_once('-')?;
+    let cpu = line[open + 2..close].trim().parse().ok()?;
+
+    let rest = &line[close + 1..];
+    let (head, rest) = rest.split_once(": ")?;
+    let timestamp = parse_timestamp(head.split_whitespace().last()?)?;
+    let (name, args) = rest
+        .split_once(": ")
+        .unwrap_or((rest.trim_end_matches(':'), ""));
+    let fields = Fields::parse(args);
+
+    let mut event = FtraceEvent::new();
+    event.set_timestamp(timestamp);
+    event.set_pid(pid.parse().ok()?);
+    match name {
+        "sched_switch" => {
+            let mut e = SchedSwitchFtraceEvent::new();
+            e.prev_comm = fields.get("prev_comm").map(str::to_string);
+            e.prev_pid = fields.num("prev_pid");
+            e.prev_prio = fields.num("prev_prio");
+            e.prev_state = fields.get("prev_state").map(task_state);
+            e.next_comm = fields.get("next_comm").map(str::to_string);
+            e.next_pid = fields.num("next_pid");
+            e.next_prio = fields.num("next_prio");
+            event.set_sched_switch(e);
+        }
+        "sched_wakeup" => {
+            let mut e = SchedWakeupFtraceEvent::new();
+            e.comm = fields.get("comm").map(str::to_string);
+            e.pid = fields.num("pid");
+            e.prio = fields.num("prio");
+            e.target_cpu = fields.num("target_cpu");
+            e.success = Some(1);
+            event.set_sched_wakeup(e);
+        }
+        "sched_waking" => {
+            let mut e = SchedWakingFtraceEvent::new();
+            e.comm = fields.get("comm").map(str::to_string);
+            e.pid = fields.num("pid");
+            e.prio = fields.num("prio");
+            e.target_cpu = fields.num("target_cpu");
+            e.success = Some(1);
+            event.set_sched_waking(e);
+        }
+        "irq_handler_entry" => {
+            let mut e = IrqHandlerEntryFtraceEvent::new();
+            e.irq = fields.num("irq");
+            e.name = fields.get("name").map(str::to_string);
+            event.set_irq_handler_entry(e);
+        }
+        "irq_handler_exit" => {
+            let mut e = IrqHandlerExitFtraceEvent::new();
+            e.irq = fields.num("irq");
+            e.ret = fields.get("ret").map(|ret| i32::from(ret == "handled"));
+            event.set_irq_handler_exit(e);
+        }
+        "softirq_entry" => {
+            let mut e = SoftirqEntryFtraceEvent::new();
+            e.vec = fields.num("vec");
+            event.set_softirq_entry(e);
+        }
+        "softirq_exit" => {
+            let mut e = SoftirqExitFtraceEvent::new();
+            e.vec = fields.num("vec");
+            event.set_softirq_exit(e);
+        }
+        _ => return None,
+    }
+    Some((cpu, event))
+}
+
+/// `5081.123456` seconds to nanoseconds.
+fn parse_timestamp(s: &str) -> Option<u64> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 9 {
+        return None;
+    }
+    let nanos = if frac.is_empty() {
+        0
+    } else {
+        frac.parse::<u64>().ok()? * 10u64.pow(9 - frac.len() as u32)
+    };
+    Some(secs.parse::<u64>().ok()? * 1_000_000_000 + nanos)
+}
+
+/// The kernel's task state bits for the letters `sched_switch` prints.
+fn task_state(state: &str) -> i64 {
+    let mut bits = 0;
+    for c in state.chars() {
+        bits |= match c {
+            'S' => 0x1,
+            'D' => 0x2,
+            'T' => 0x4,
+            't' => 0x8,
+            'X' => 0x10,
+            'Z' => 0x20,
+            'P' => 0x40,
+            'I' => 0x80,
+            // Preempted while runnable.
+            '+' => 0x100,
+            _ => 0,
+        };
+    }
+    bits
+}
+
+/// `key=value` pairs; a value runs until the next key, so command names
+/// with spaces survive.
+struct Fields<'a>(Vec<(&'a str, String)>);
+
+impl<'a> Fields<'a> {
+    fn parse(args: &'a str) -> Self {
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        for token in args.split_whitespace() {
+            match token.split_once('=') {
+                Some((key, value))
+                    if !key.is_empty()
+                        && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') =>
+                {
+                    fields.push((key, value.to_string()));
+                }
+                _ => {
+                    if let Some((_, value)) = fields.last_mut()
+                        && token != "==>"
+                    {
+                        value.push(' ');
+                        value.push_str(token);
+                    }
+                }
+            }
+        }
+        Self(fields)
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn num<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        // softirq_entry prints `vec=9 [action=RCU]`.
+        self.get(key)?.split_whitespace().next()?.parse().ok()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use capture::{FtraceCapture, capture_ftrace};
+
+#[cfg(target_os = "linux")]
+mod capture {
+    use std::{
+        fs::{self, File, OpenOptions},
+        io::{self, BufRead, BufReader, Write},
+        path::{Path, PathBuf},
+        sync::{
+            Arc, Mutex, PoisonError,
+            atomic::{AtomicBool, Ordering::Relaxed},
+        },
+        thread::{self, JoinHandle},
+    };
+
+    use super::FTRACE_EVENTS;
+    use crate::Context;
+
+    const TRACEFS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+    /// A running [`capture_ftrace`] session. Dropping it stops it like
+    /// [`FtraceCapture::stop`], ignoring errors.
+    pub struct FtraceCapture {
+        root: PathBuf,
+        stop: Arc<AtomicBool>,
+        /// The reader, until stopped.
+        thread: Option<JoinHandle<io::Result<()>>>,
+    }
+
+    /// Enables [`FTRACE_EVENTS`] in tracefs and streams them into `ctx` from
+    /// a background thread until [`FtraceCapture::stop`], producing a
+    /// combined app and kernel trace without running `traced`. Needs root or
+    /// access to tracefs, and takes over the global ftrace instance.
+    pub fn capture_ftrace(ctx: Arc<Mutex<Context>>) -> io::Result<FtraceCapture> {
+        let root = TRACEFS
+            .iter()
+            .map(PathBuf::from)
+            .find(|root| root.join("trace_pipe").exists())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"))?;
+        fs::write(root.join("tracing_on"), "0")?;
+        fs::write(root.join("trace_clock"), "boot")?;
+        fs::write(root.join("trace"), "")?;
+        for event in FTRACE_EVENTS {
+            fs::write(root.join("events").join(event).join("enable"), "1")?;
+        }
+        let pipe = File::open(root.join("trace_pipe"))?;
+        fs::write(root.join("tracing_on"), "1")?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || stream(BufReader::new(pipe), &ctx, &stop))
+        };
+        Ok(FtraceCapture {
+            root,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Hands lines to the context in batches of whatever is already
+    /// buffered, so the lock isn't taken per line.
+    fn stream(
+        mut pipe: BufReader<File>,
+        ctx: &Mutex<Context>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let mut batch = Vec::new();
+        while !stop.load(Relaxed) {
+            if pipe.read_until(b'\n', &mut batch)? == 0 {
+                break;
+            }
+            if pipe.buffer().is_empty() {
+                let mut ctx = ctx.lock().unwrap_or_else(PoisonError::into_inner);
+                ctx.record_ftrace_text(&batch[..])?;
+                batch.clear();
+            }
+        }
+        let mut ctx = ctx.lock().unwrap_or_else(PoisonError::into_inner);
+        ctx.record_ftrace_text(&batch[..])?;
+        Ok(())
+    }
+
+    fn disable(root: &Path) -> io::Result<()> {
+        fs::write(root.join("tracing_on"), "0")?;
+        for event in FTRACE_EVENTS {
+            fs::write(root.join("events").join(event).join("enable"), "0")?;
+        }
+        Ok(())
+    }
+
+    impl FtraceCapture {
+        /// Disables the events and waits for what was already read to be
+        /// recorded.
+        pub fn stop(mut self) -> io::Result<()> {
+            self.shutdown()
+        }
+
+        fn shutdown(&mut self) -> io::Result<()> {
+            let Some(thread) = self.thread.take() else {
+                return Ok(());
+            };
+            self.stop.store(true, Relaxed);
+            // Wakes the reader blocked on the pipe.
+            let woken = OpenOptions::new()
+                .write(true)
+                .open(self.root.join("trace_marker"))
+                .and_then(|mut marker| marker.write_all(b"perfetto-writer: stop\n"));
+            if let Err(e) = woken {
+                // The reader can't be woken, so leave it blocked rather
+                // than wait forever.
+                disable(&self.root)?;
+                return Err(e);
+            }
+            let result = thread.join().unwrap_or(Ok(()));
+            disable(&self.root)?;
+            result
+        }
+    }
+
+    impl Drop for FtraceCapture {
+        fn drop(&mut self) {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+
+    const TEXT: &str = "\
+# tracer: nop
+            bash-1234    [002] d..3.  5081.123456: sched_switch: prev_comm=bash prev_pid=1234 prev_prio=120 prev_state=S ==> next_comm=Web Content next_pid=88 next_prio=120
+          <idle>-0       [000] d.h2.  5081.200000: irq_handler_entry: irq=27 name=eth0
+          <idle>-0       [000] d.h2.  5081.200100: irq_handler_exit: irq=27 ret=handled
+     kworker/0:1-12      [000] ..s1.  5081.300000: softirq_entry: vec=9 [action=RCU]
+            bash-1234    [002] ....1  5081.400000: tracing_mark_write: hello
+";
+
+    #[test]
+    fn converts_sched_and_irq_events() -> io::Result<()> {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.record_ftrace_text(TEXT.as_bytes())?, 4);
+        let trace: Trace = ctx.take_trace();
+        let bundles: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_ftrace_events())
+            .map(|p| p.ftrace_events())
+            .collect();
+        assert_eq!(bundles.len(), 2);
+        assert_eq!(bundles[0].cpu(), 0);
+        assert_eq!(bundles[0].event.len(), 3);
+        assert_eq!(bundles[0].event[1].irq_handler_exit().ret(), 1);
+        assert_eq!(bundles[0].event[2].softirq_entry().vec(), 9);
+
+        let switch = &bundles[1].event[0];
+        assert_eq!(switch.timestamp(), 5_081_123_456_000);
+        assert_eq!(switch.pid(), 1234);
+        let switch = switch.sched_switch();
+        assert_eq!(switch.prev_state(), 1);
+        assert_eq!(switch.next_comm(), "Web Content");
+        assert_eq!(switch.next_pid(), 88);
+        Ok(())
+    }
+
+    #[test]
+    fn preempted_tasks_keep_their_flag() {
+        assert_eq!(task_state("R"), 0);
+        assert_eq!(task_state("R+"), 0x100);
+        assert_eq!(task_state("D"), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs root and a mounted tracefs"]
+    fn dropping_a_capture_turns_tracing_off() -> io::Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let capture = capture_ftrace(Arc::new(Mutex::new(Context::new())))?;
+        drop(capture);
+        let tracing_on = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+            .iter()
+            .find_map(|root| std::fs::read_to_string(format!("{root}/tracing_on")).ok())
+            .unwrap();
+        assert_eq!(tracing_on.trim(), "0");
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use smol_str::SmolStr;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, MutexGuard, PoisonError},
+    task::{Context as TaskContext, Poll},
+};
+
+use crate::{Context, global};
+
+fn lock(ctx: &Mutex<Context>) -> MutexGuard<'_, Context> {
+    ctx.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Records futures as slices without going through `tracing`.
+pub trait FutureExt: Future + Sized {
+    /// Records the future in the global context: an instant where it was
+    /// created, then a slice on its own track from its first poll until it
+    /// completes or is dropped, linked to the instant by a flow arrow. Does
+    /// nothing before [`init_global`](crate::init_global).
+    fn traced(self, name: impl Into<SmolStr>) -> Traced<'static, Self> {
+        Traced::new(self, global::context(), name.into(), None)
+    }
+
+    /// Like [`FutureExt::traced`], recording into `ctx`.
+    fn traced_in(self, ctx: &Mutex<Context>, name: impl Into<SmolStr>) -> Traced<'_, Self> {
+        Traced::new(self, Some(ctx), name.into(), None)
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+/// A future recorded as a slice. Created by [`FutureExt::traced`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Traced<'a, F> {
+    future: F,
+    ctx: Option<&'a Mutex<Context>>,
+    name: SmolStr,
+    category: Option<SmolStr>,
+    flow: u64,
+    /// The future's track while its slice is open.
+    track: Option<u64>,
+    started: bool,
+}
+
+impl<'a, F> Traced<'a, F> {
+    pub(crate) fn new(
+        future: F,
+        ctx: Option<&'a Mutex<Context>>,
+        name: SmolStr,
+        category: Option<SmolStr>,
+    ) -> Self {
+        let mut flow = 0;
+        if let Some(ctx) = ctx {
+            let mut ctx = lock(ctx);
+            flow = ctx.next_id();
+            let track = ctx.current_thread_track();
+            let mut event = ctx
+                .event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name(name.clone())
+                .with_flow_id(flow);
+            if let Some(category) = &category {
+                event.category(category.clone());
+            }
+            event.build();
+        }
+        Self {
+            future,
+            ctx,
+            name,
+            category,
+            flow,
+            track: None,
+            started: false,
+        }
+    }
+
+    fn begin(&mut self) {
+        self.started = true;
+        let Some(ctx) = self.ctx else {
+            return;
+        };
+        let mut ctx = lock(ctx);
+        let track = ctx
+            .track()
+            .name(self.name.as_str())
+            .current_process()
+            .build();
+        let mut event = ctx
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name(self.name.clone())
+            .with_terminating_flow_id(self.flow);
+        if let Some(category) = &self.category {
+            event.category(category.clone());
+        }
+        event.build();
+        self.track = Some(track);
+    }
+
+    fn end(&mut self) {
+        if let (Some(ctx), Some(track)) = (self.ctx, self.track.take()) {
+            let mut ctx = lock(ctx);
+            ctx.event()
+                .with_end()
+                .with_now()
+                .with_track_uuid(track)
+                .build();
+            ctx.release_track(track);
+        }
+    }
+}
+
+impl<F: Future> Future for Traced<'_, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is never moved out of `self`, and no other field
+        // is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        if !this.started {
+            this.begin();
+        }
+        // SAFETY: `this` is pinned, so its `future` field is too.
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let poll = future.poll(cx);
+        if poll.is_ready() {
+            this.end();
+        }
+        poll
+    }
+}
+
+impl<F> Drop for Traced<'_, F> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::task::Waker;
+
+    #[test]
+    fn traced_futures_are_slices() -> Result<()> {
+        let ctx = Mutex::new(Context::new());
+        let mut pending = true;
+        let future = std::future::poll_fn(|_| {
+            if std::mem::take(&mut pending) {
+                Poll::Pending
+            } else {
+                Poll::Ready(7)
+            }
+        })
+        .traced_in(&ctx, "load");
+        let mut future = std::pin::pin!(future);
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(7));
+
+        let mut buf = Vec::new();
+        lock(&ctx).write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let types: Vec<_> = events.iter().map(|e| e.type_()).collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_INSTANT,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        assert_eq!(events[0].flow_ids, events[1].terminating_flow_ids);
+        assert_eq!(events[1].track_uuid(), events[2].track_uuid());
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::Result;
+use perfetto_protos::{
+    debug_annotation::DebugAnnotation, trace::Trace, trace_packet::TracePacket,
+    track_event::track_event::Type,
+};
+use protobuf::Message;
+use std::{collections::HashMap, io::Write};
+
+use crate::{Context, INCREMENTAL_CLOCK_ID};
+
+/// First word of every FXT stream.
+const MAGIC: u64 = 0x0016_5478_4604_0010;
+
+const INITIALIZATION_RECORD: u64 = 1;
+const STRING_RECORD: u64 = 2;
+const EVENT_RECORD: u64 = 4;
+const KERNEL_OBJECT_RECORD: u64 = 7;
+
+const INSTANT_EVENT: u64 = 0;
+const COUNTER_EVENT: u64 = 1;
+const BEGIN_EVENT: u64 = 2;
+const END_EVENT: u64 = 3;
+
+const INT64_ARG: u64 = 3;
+const UINT64_ARG: u64 = 4;
+const DOUBLE_ARG: u64 = 5;
+const STRING_ARG: u64 = 6;
+const KOID_ARG: u64 = 8;
+const BOOL_ARG: u64 = 9;
+
+const PROCESS_OBJECT: u64 = 1;
+const THREAD_OBJECT: u64 = 2;
+
+/// Longest string FXT can hold.
+const MAX_STRING_LEN: usize = 32000;
+/// Highest index in the string table.
+const MAX_STRING_INDEX: u16 = 0x7fff;
+
+/// Where a track's events go: a process and a thread koid.
+#[derive(Debug, Clone, Copy, Default)]
+struct Thread {
+    pid: u64,
+    tid: u64,
+    counter: bool,
+}
+
+/// A string field: an index into the string table, or inline words.
+struct StringRef {
+    field: u64,
+    inline: Vec<u64>,
+}
+
+fn words(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks(8)
+        .map(|chunk| {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        })
+        .collect()
+}
+
+fn truncate(s: &str) -> &str {
+    let mut end = s.len().min(MAX_STRING_LEN);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[derive(Default)]
+struct Writer {
+    words: Vec<u64>,
+    strings: HashMap<String, u16>,
+}
+
+impl Writer {
+    fn record(&mut self, header: u64, body: &[u64]) {
+        let size = 1 + body.len() as u64;
+        self.words.push(header | size << 4);
+        self.words.extend_from_slice(body);
+    }
+
+    /// Refers to `s` through the string table, adding it when there's room.
+    fn string(&mut self, s: &str) -> StringRef {
+        let s = truncate(s);
+        if s.is_empty() {
+            return StringRef {
+                field: 0,
+                inline: Vec::new(),
+            };
+        }
+        if let Some(index) = self.strings.get(s) {
+            return StringRef {
+                field: u64::from(*index),
+                inline: Vec::new(),
+            };
+        }
+        let index = self.strings.len() as u16 + 1;
+        if index > MAX_STRING_INDEX {
+            return StringRef {
+                field: 0x8000 | s.len() as u64,
+                inline: words(s.as_bytes()),
+            };
+        }
+        self.strings.insert(s.to_string(), index);
+        let header = STRING_RECORD | u64::from(index) << 16 | (s.len() as u64) << 32;
+        self.record(header, &words(s.as_bytes()));
+        StringRef {
+            field: u64::from(index),
+            inline: Vec::new(),
+        }
+    }
+
+    /// Appends an argument to `body`.
+    fn arg(&mut self, body: &mut Vec<u64>, name: &str, kind: u64, value: u64, extra: &[u64]) {
+        let name = self.string(name);
+        let size = 1 + name.inline.len() + extra.len();
+        body.push(kind | (size as u64) << 4 | name.field << 16 | value << 32);
+        body.extend(name.inline);
+        body.extend_from_slice(extra);
+    }
+
+    fn kernel_object(&mut self, kind: u64, koid: u64, name: &str, process: Option<u64>) {
+        let name = self.string(name);
+        let mut body = vec![koid];
+        body.extend(name.inline);
+        if let Some(process) = process {
+            self.arg(&mut body, "process", KOID_ARG, 0, &[process]);
+        }
+        let argc = u64::from(process.is_some());
+        self.record(
+            KERNEL_OBJECT_RECORD | kind << 16 | name.field << 24 | argc << 40,
+            &body,
+        );
+    }
+}
+
+/// Interned strings of one packet sequence.
+#[derive(Default)]
+struct Interned {
+    names: HashMap<u64, String>,
+    categories: HashMap<u64, String>,
+    annotation_names: HashMap<u64, String>,
+    strings: HashMap<u64, String>,
+}
+
+impl Interned {
+    fn update(&mut self, packet: &TracePacket) {
+        let Some(data) = packet.interned_data.as_ref() else {
+            return;
+        };
+        for name in &data.event_names {
+            self.names.insert(name.iid(), name.name().to_string());
+        }
+        for category in &data.event_categories {
+            self.categories
+                .insert(category.iid(), category.name().to_string());
+        }
+        for name in &data.debug_annotation_names {
+            self.annotation_names
+                .insert(name.iid(), name.name().to_string());
+        }
+        for value in &data.debug_annotation_string_values {
+            self.strings.insert(
+                value.iid(),
+                String::from_utf8_lossy(value.str()).into_owned(),
+            );
+        }
+    }
+}
+
+/// Converts an encoded Perfetto trace to the Fuchsia trace format (FXT),
+/// which the Perfetto UI and Fuchsia tooling also read.
+///
+/// Slices, instants and counters are kept along with their names,
+/// categories and debug annotations. Thread tracks map to their thread and
+/// other tracks to a named thread of their own. Timestamps are written as
+/// nanoseconds in whichever clock each event used.
+pub fn to_fxt(trace: &[u8]) -> Result<Vec<u8>> {
+    let trace = Trace::parse_from_bytes(trace)?;
+    let mut out = Writer::default();
+    out.words.push(MAGIC);
+    out.record(INITIALIZATION_RECORD, &[1_000_000_000]);
+
+    let mut interned: HashMap<u32, Interned> = HashMap::new();
+    let mut incremental: HashMap<u32, u64> = HashMap::new();
+    let mut threads: HashMap<u64, Thread> = HashMap::new();
+    let mut counter_names: HashMap<u64, String> = HashMap::new();
+    let mut default_pid = 0;
+
+    for packet in &trace.packet {
+        let seq = packet.trusted_packet_sequence_id();
+        let strings = interned.entry(seq).or_default();
+        strings.update(packet);
+
+        if packet.has_clock_snapshot() {
+            for clock in &packet.clock_snapshot().clocks {
+                if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                    incremental.insert(seq, clock.timestamp());
+                }
+            }
+        }
+
+        if packet.has_track_descriptor() {
+            let track = packet.track_descriptor();
+            let thread = if let Some(thread) = track.thread.as_ref() {
+                let pid = thread.pid() as u64;
+                let tid = thread.tid() as u64;
+                let name = if track.has_name() {
+                    track.name()
+                } else {
+                    thread.thread_name()
+                };
+                out.kernel_object(THREAD_OBJECT, tid, name, Some(pid));
+                Thread {
+                    pid,
+                    tid,
+                    counter: false,
+                }
+            } else if let Some(process) = track.process.as_ref() {
+                let pid = process.pid() as u64;
+                default_pid = pid;
+                let name = if track.has_name() {
+                    track.name()
+                } else {
+                    process.process_name()
+                };
+                out.kernel_object(PROCESS_OBJECT, pid, name, None);
+                Thread {
+                    pid,
+                    tid: pid,
+                    counter: false,
+                }
+            } else {
+                let pid = threads
+                    .get(&track.parent_uuid())
+                    .map_or(default_pid, |parent| parent.pid);
+                out.kernel_object(THREAD_OBJECT, track.uuid(), track.name(), Some(pid));
+                if track.counter.is_some() {
+                    counter_names.insert(track.uuid(), track.name().to_string());
+                }
+                Thread {
+                    pid,
+                    tid: track.uuid(),
+                    counter: track.counter.is_some(),
+                }
+            };
+            threads.insert(track.uuid(), thread);
+        }
+
+        if !packet.has_track_event() {
+            continue;
+        }
+        let event = packet.track_event();
+        let mut timestamp = packet.timestamp();
+        if packet.timestamp_clock_id() == INCREMENTAL_CLOCK_ID {
+            let last = incremental.entry(seq).or_default();
+            *last += timestamp;
+            timestamp = *last;
+        }
+        let thread = threads.get(&event.track_uuid()).copied().unwrap_or(Thread {
+            pid: default_pid,
+            tid: event.track_uuid(),
+            counter: false,
+        });
+        let strings = &interned[&seq];
+        let name = if event.has_name_iid() {
+            strings.names.get(&event.name_iid()).cloned()
+        } else {
+            event.has_name().then(|| event.name().to_string())
+        };
+        let category = event
+            .category_iids
+            .first()
+            .and_then(|iid| strings.categories.get(iid).cloned())
+            .or_else(|| event.categories.first().cloned());
+
+        let mut args = Vec::new();
+        let mut argc = 0;
+        let kind = match event.type_() {
+            Type::TYPE_SLICE_BEGIN => BEGIN_EVENT,
+            Type::TYPE_SLICE_END => END_EVENT,
+            Type::TYPE_INSTANT => INSTANT_EVENT,
+            Type::TYPE_COUNTER => {
+                if event.has_double_counter_value() {
+                    let value = event.double_counter_value().to_bits();
+                    out.arg(&mut args, "value", DOUBLE_ARG, 0, &[value]);
+                } else {
+                    let value = event.counter_value() as u64;
+                    out.arg(&mut args, "value", INT64_ARG, 0, &[value]);
+                }
+                argc += 1;
+                COUNTER_EVENT
+            }
+            _ => continue,
+        };
+        for annotation in &event.debug_annotations {
+            if argc < 15 && annotation_arg(&mut out, &mut args, strings, annotation) {
+                argc += 1;
+            }
+        }
+
+        // Counter tracks are named after what they count.
+        let name = match (kind, thread.counter) {
+            (COUNTER_EVENT, true) => counter_names.get(&event.track_uuid()).cloned(),
+            _ => name,
+        };
+        let name = name.unwrap_or_default();
+        let name = out.string(&name);
+        let category = out.string(&category.unwrap_or_default());
+        let mut body = vec![timestamp, thread.pid, thread.tid];
+        body.extend(category.inline);
+        body.extend(name.inline);
+        body.extend(args);
+        if kind == COUNTER_EVENT {
+            body.push(event.track_uuid());
+        }
+        out.record(
+            EVENT_RECORD | kind << 16 | argc << 20 | category.field << 32 | name.field << 48,
+            &body,
+        );
+    }
+
+    Ok(out.words.iter().flat_map(|w| w.to_le_bytes()).collect())
+}
+
+/// Appends `annotation` as an argument, returning whether it was written.
+fn annotation_arg(
+    out: &mut Writer,
+    args: &mut Vec<u64>,
+    strings: &Interned,
+    annotation: &DebugAnnotation,
+) -> bool {
+    let name = if annotation.has_name_iid() {
+        strings.annotation_names.get(&annotation.name_iid())
+    } else {
+        None
+    };
+    let name = name.map_or(annotation.name(), String::as_str);
+    if annotation.has_bool_value() {
+        out.arg(args, name, BOOL_ARG, annotation.bool_value().into(), &[]);
+    } else if annotation.has_uint_value() {
+        out.arg(args, name, UINT64_ARG, 0, &[annotation.uint_value()]);
+    } else if annotation.has_int_value() {
+        out.arg(args, name, INT64_ARG, 0, &[annotation.int_value() as u64]);
+    } else if annotation.has_double_value() {
+        let value = annotation.double_value().to_bits();
+        out.arg(args, name, DOUBLE_ARG, 0, &[value]);
+    } else {
+        let value = if annotation.has_string_value_iid() {
+            strings.strings.get(&annotation.string_value_iid()).cloned()
+        } else {
+            annotation
+                .has_string_value()
+                .then(|| annotation.string_value().to_string())
+        };
+        let Some(value) = value else {
+            return false;
+        };
+        let value = out.string(&value);
+        out.arg(args, name, STRING_ARG, value.field, &value.inline);
+    }
+    true
+}
+
+impl Context {
+    /// Writes everything buffered in the Fuchsia trace format instead of
+    /// protobuf. See [`to_fxt`]. Each call produces a complete FXT stream,
+    /// so call it once with the whole trace.
+    pub fn write_fxt<W: Write>(&mut self, w: &mut W) -> Result<()> {
+        let mut buf = Vec::new();
+        self.write_to_vec(&mut buf)?;
+        w.write_all(&to_fxt(&buf)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits an FXT stream into records of words.
+    fn records(bytes: &[u8]) -> Vec<Vec<u64>> {
+        let words: Vec<_> = bytes
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[0], MAGIC);
+        let mut records = Vec::new();
+        let mut rest = &words[1..];
+        while let Some(header) = rest.first() {
+            let size = (header >> 4 & 0xfff) as usize;
+            records.push(rest[..size].to_vec());
+            rest = &rest[size..];
+        }
+        records
+    }
+
+    fn string(records: &[Vec<u64>], index: u64) -> String {
+        let record = records
+            .iter()
+            .find(|r| r[0] & 0xf == STRING_RECORD && r[0] >> 16 & 0x7fff == index)
+            .unwrap();
+        let len = (record[0] >> 32 & 0x7fff) as usize;
+        let bytes: Vec<_> = record[1..].iter().flat_map(|w| w.to_le_bytes()).collect();
+        String::from_utf8(bytes[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn events_become_fxt_records() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        let counter = ctx.counter_track("queue");
+        ctx.event()
+            .with_begin()
+            .with_timestamp_ns(100)
+            .with_track_uuid(track)
+            .with_name("parse")
+            .with_category("io")
+            .with_debug_str("file", "a.txt")
+            .with_debug_int("size", 12)
+            .build();
+        ctx.event()
+            .with_counter()
+            .with_timestamp_ns(150)
+            .with_track_uuid(counter)
+            .with_counter_value(3)
+            .build();
+        ctx.event()
+            .with_end()
+            .with_timestamp_ns(200)
+            .with_track_uuid(track)
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_fxt(&mut buf)?;
+
+        let records = records(&buf);
+        assert_eq!(records[0], [INITIALIZATION_RECORD | 2 << 4, 1_000_000_000]);
+        let events: Vec<_> = records
+            .iter()
+            .filter(|r| r[0] & 0xf == EVENT_RECORD)
+            .collect();
+        let kinds: Vec<_> = events.iter().map(|r| r[0] >> 16 & 0xf).collect();
+        assert_eq!(kinds, [BEGIN_EVENT, COUNTER_EVENT, END_EVENT]);
+        let timestamps: Vec<_> = events.iter().map(|r| r[1]).collect();
+        assert_eq!(timestamps, [100, 150, 200]);
+
+        let begin = events[0];
+        assert_eq!(begin[0] >> 20 & 0xf, 2);
+        assert_eq!(string(&records, begin[0] >> 48), "parse");
+        assert_eq!(string(&records, begin[0] >> 32 & 0xffff), "io");
+        assert_eq!(begin[3], crate::current_thread() as u64);
+        let counter_record = events[1];
+        assert_eq!(*counter_record.last().unwrap(), counter);
+        assert_eq!(string(&records, counter_record[0] >> 48), "queue");
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::Result;
+use std::{
+    io::Write,
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use crate::Context;
+
+struct Global {
+    ctx: Mutex<Context>,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+static GLOBAL: OnceLock<Global> = OnceLock::new();
+
+/// Installs a process-wide context written to `sink` by [`flush_global`],
+/// so libraries can emit events with [`instant`] and [`counter`] without
+/// being handed a [`Context`]. Returns false if one was already installed.
+pub fn init_global(sink: impl Write + Send + 'static) -> bool {
+    GLOBAL
+        .set(Global {
+            ctx: Mutex::new(Context::new()),
+            sink: Mutex::new(Box::new(sink)),
+        })
+        .is_ok()
+}
+
+pub(crate) fn context() -> Option<&'static Mutex<Context>> {
+    GLOBAL.get().map(|global| &global.ctx)
+}
+
+/// Runs `f` with the global context, or does nothing before
+/// [`init_global`].
+pub fn with_global<R>(f: impl FnOnce(&mut Context) -> R) -> Option<R> {
+    let mut ctx = context()?.lock().unwrap_or_else(PoisonError::into_inner);
+    Some(f(&mut ctx))
+}
+
+/// Records an instant on the calling thread's track of the global context.
+pub fn instant(name: &str) {
+    with_global(|ctx| {
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name(name)
+            .build();
+    });
+}
+
+/// Sets the global context's counter track called `name` to `value`.
+pub fn counter(name: &str, value: f64) {
+    with_global(|ctx| {
+        let track = ctx.counter_track(name);
+        ctx.event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(track)
+            .with_double_counter_value(value)
+            .build();
+    });
+}
+
+/// Writes everything buffered in the global context to its sink.
+pub fn flush_global() -> Result<()> {
+    let Some(global) = GLOBAL.get() else {
+        return Ok(());
+    };
+    let mut sink = global.sink.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut ctx = global.ctx.lock().unwrap_or_else(PoisonError::into_inner);
+    ctx.write_to(&mut *sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn free_functions_use_the_global_context() -> Result<()> {
+        let sink = SharedBuf::default();
+        assert!(init_global(sink.clone()));
+        assert!(!init_global(SharedBuf::default()));
+        instant("started");
+        counter("queue", 3.0);
+        {
+            let _slice = crate::perfetto_span!("global");
+            crate::perfetto_counter!("queue", 2);
+        }
+        flush_global()?;
+
+        let trace = Trace::parse_from_bytes(&sink.0.lock().unwrap())?;
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use smol_str::SmolStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{Context, Traced, global};
+
+/// Ends a slice on the current thread's track when dropped. Created by
+/// [`perfetto_span!`](crate::perfetto_span).
+#[must_use = "the slice ends when the guard is dropped"]
+pub struct SliceGuard<'a> {
+    ctx: &'a Mutex<Context>,
+    track: u64,
+}
+
+fn lock(ctx: &Mutex<Context>) -> MutexGuard<'_, Context> {
+    ctx.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<'a> SliceGuard<'a> {
+    /// Begins a slice named `name` on the calling thread's track.
+    pub fn new(ctx: &'a Mutex<Context>, name: impl Into<SmolStr>) -> Self {
+        Self::begin(ctx, name.into(), None)
+    }
+
+    fn begin(ctx: &'a Mutex<Context>, name: SmolStr, category: Option<&str>) -> Self {
+        let mut guard = lock(ctx);
+        let track = guard.current_thread_track();
+        let mut event = guard
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name(name);
+        if let Some(category) = category {
+            event.category(category);
+        }
+        event.build();
+        Self { ctx, track }
+    }
+
+    /// Like [`SliceGuard::new`] against the global context, or `None`
+    /// before [`init_global`](crate::init_global).
+    pub fn global(name: impl Into<SmolStr>) -> Option<SliceGuard<'static>> {
+        global::context().map(|ctx| SliceGuard::new(ctx, name))
+    }
+}
+
+impl Drop for SliceGuard<'_> {
+    fn drop(&mut self) {
+        lock(self.ctx)
+            .event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(self.track)
+            .build();
+    }
+}
+
+#[doc(hidden)]
+pub fn global_slice(name: &str, category: Option<&str>) -> Option<SliceGuard<'static>> {
+    let ctx = global::context()?;
+    if let Some(category) = category
+        && !lock(ctx).is_category_enabled(category)
+    {
+        return None;
+    }
+    Some(SliceGuard::begin(ctx, name.into(), category))
+}
+
+#[doc(hidden)]
+pub fn global_future<F>(future: F, name: &str, category: Option<&str>) -> Traced<'static, F> {
+    let ctx = global::context()
+        .filter(|ctx| category.is_none_or(|category| lock(ctx).is_category_enabled(category)));
+    Traced::new(future, ctx, name.into(), category.map(SmolStr::from))
+}
+
+#[doc(hidden)]
+pub fn counter_in(ctx: &Mutex<Context>, name: &str, value: f64) {
+    let mut ctx = lock(ctx);
+    let track = ctx.counter_track(name);
+    ctx.event()
+        .with_counter()
+        .with_now()
+        .with_track_uuid(track)
+        .with_double_counter_value(value)
+        .build();
+}
+
+/// Records a slice on the current thread's track for the rest of the
+/// enclosing scope, without going through `tracing`. Takes a
+/// `Mutex<Context>` (or anything dereferencing to one), or no context to use
+/// the global one.
+///
+/// `perfetto_span!(category = "db", name)` records the slice in a category
+/// of the global context, and compiles to nothing when the category is in
+/// [`COMPILED_OUT_CATEGORIES`](crate::COMPILED_OUT_CATEGORIES).
+///
+/// ```
+/// use perfetto_writer::{Context, perfetto_span};
+/// use std::sync::Mutex;
+///
+/// let ctx = Mutex::new(Context::new());
+/// let _slice = perfetto_span!(ctx, "parse");
+/// let _query = perfetto_span!(category = "db", "query");
+/// ```
+#[macro_export]
+macro_rules! perfetto_span {
+    (category = $category:literal, $name:expr) => {
+        if const { $crate::category_compiled_in($category) } {
+            $crate::guard::global_slice($name, ::core::option::Option::Some($category))
+        } else {
+            ::core::option::Option::None
+        }
+    };
+    ($name:expr) => {
+        $crate::SliceGuard::global($name)
+    };
+    ($ctx:expr, $name:expr) => {
+        $crate::SliceGuard::new(&$ctx, $name)
+    };
+}
+
+/// Sets the counter track called `name` to `value`, in the given
+/// `Mutex<Context>` or the global context.
+///
+/// ```
+/// use perfetto_writer::{Context, perfetto_counter};
+/// use std::sync::Mutex;
+///
+/// let ctx = Mutex::new(Context::new());
+/// perfetto_counter!(ctx, "queue_depth", 12);
+/// ```
+#[macro_export]
+macro_rules! perfetto_counter {
+    ($name:expr, $value:expr) => {
+        $crate::counter($name, $value as f64)
+    };
+    ($ctx:expr, $name:expr, $value:expr) => {
+        $crate::guard::counter_in(&$ctx, $name, $value as f64)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::sync::Arc;
+
+    #[test]
+    fn guards_balance_slices() -> anyhow::Result<()> {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        {
+            let _outer = perfetto_span!(ctx, "outer");
+            let _inner = perfetto_span!(ctx, "inner");
+            perfetto_counter!(ctx, "items", 3);
+        }
+
+        let mut buf = Vec::new();
+        ctx.lock().unwrap().write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_COUNTER,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        Ok(())
+    }
+}
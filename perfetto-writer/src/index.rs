@@ -0,0 +1,153 @@
+use anyhow::Result;
+use perfetto_protos::trace::Trace;
+use std::{fmt::Write as _, fs::OpenOptions, io::Write, path::Path};
+
+use crate::{Context, trim::EventTimes};
+
+/// The index [`dump_on_signal`](crate::dump_on_signal) keeps next to the
+/// files it writes.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// What one file of a trace cut into several holds, so a time window can be
+/// stitched back together without opening every file, e.g. with
+/// `perfetto-rs merge --index`.
+///
+/// An index is a file of JSON lines, one entry per trace file:
+/// `{"file":"trace-1.pftrace","start":100,"end":250,"sessions":[...]}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The trace file, relative to the index.
+    pub file: String,
+    /// The first and last event timestamps in the file, in the events' own
+    /// clock, nanoseconds for the default one.
+    pub start: u64,
+    pub end: u64,
+    /// The trace uuids of the [sessions](Context::write_session) in the
+    /// file, as 32 hex digits.
+    pub sessions: Vec<String>,
+}
+
+impl IndexEntry {
+    /// Describes `trace`, stored as `file`. Returns None if it has no
+    /// events.
+    pub fn new(file: impl Into<String>, trace: &Trace) -> Option<Self> {
+        let mut times = EventTimes::default();
+        let mut span: Option<(u64, u64)> = None;
+        let mut sessions = Vec::new();
+        for packet in &trace.packet {
+            if packet.has_trace_uuid() {
+                let uuid = packet.trace_uuid();
+                let uuid = (uuid.msb() as u64 as u128) << 64 | uuid.lsb() as u64 as u128;
+                sessions.push(format!("{uuid:032x}"));
+            }
+            if let Some(ts) = times.resolve(packet) {
+                span = Some(span.map_or((ts, ts), |(start, end)| (start.min(ts), end.max(ts))));
+            }
+        }
+        let (start, end) = span?;
+        Some(Self {
+            file: file.into(),
+            start,
+            end,
+            sessions,
+        })
+    }
+
+    /// The entry as one line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"file\":");
+        push_json_str(&mut json, &self.file);
+        let _ = write!(
+            json,
+            ",\"start\":{},\"end\":{},\"sessions\":[",
+            self.start, self.end
+        );
+        for (i, session) in self.sessions.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_str(&mut json, session);
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Adds the entry to the index at `path`, creating it if needed.
+    pub fn append(&self, path: &Path) -> Result<()> {
+        let mut index = OpenOptions::new().create(true).append(true).open(path)?;
+        index.write_all(format!("{}\n", self.to_json()).as_bytes())?;
+        Ok(())
+    }
+}
+
+fn push_json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+impl Context {
+    /// Like [`Context::rotate`], also returning the index entry of the
+    /// finished segment, stored as `file`, unless it had no events.
+    pub fn rotate_indexed<W: Write>(
+        &mut self,
+        w: &mut W,
+        file: impl Into<String>,
+    ) -> Result<Option<IndexEntry>> {
+        let trace = self.take_trace();
+        let entry = IndexEntry::new(file, &trace);
+        self.write_trace_to(trace, w)?;
+        self.start_segment();
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SessionMetadata;
+
+    #[test]
+    fn entries_cover_the_events_of_a_segment() -> Result<()> {
+        let mut ctx = Context::new();
+        let uuid = ctx.write_session(&SessionMetadata::new().uuid(0xab));
+        let track = ctx.current_thread_track();
+        for ts in [300, 100, 200] {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(ts)
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+        }
+        let entry = ctx
+            .rotate_indexed(&mut Vec::new(), "a \"b\".pftrace")?
+            .unwrap();
+        assert_eq!(
+            entry,
+            IndexEntry {
+                file: "a \"b\".pftrace".into(),
+                start: 100,
+                end: 300,
+                sessions: vec![format!("{uuid:032x}")],
+            }
+        );
+        assert_eq!(
+            entry.to_json(),
+            format!(
+                r#"{{"file":"a \"b\".pftrace","start":100,"end":300,"sessions":["{uuid:032x}"]}}"#
+            )
+        );
+        assert!(ctx.rotate_indexed(&mut Vec::new(), "empty")?.is_none());
+        Ok(())
+    }
+}
@@ -3,11 +3,12 @@ use dashmap::DashMap;
 use protobuf::{Message, MessageField};
 use smol_str::SmolStr;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::Write,
     sync::atomic::{AtomicU64, Ordering::Relaxed},
-    time::{SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
+use web_time::Instant;
 
 use perfetto_protos::{
     counter_descriptor::{CounterDescriptor, counter_descriptor::Unit},
@@ -21,8 +22,140 @@ use perfetto_protos::{
     track_event::{EventCategory, EventName, TrackEvent, track_event::Type},
 };
 
+#[cfg(feature = "unstable")]
+mod actor;
+mod alloc;
+mod blob;
+#[cfg(feature = "unstable")]
+mod callstack;
+mod category;
+mod clock;
+mod color;
+#[cfg(feature = "unstable")]
+pub mod dot;
+mod drops;
+#[cfg(all(unix, feature = "signal"))]
+mod dump;
+mod encode;
+#[cfg(feature = "etw")]
+mod etw;
+mod exit;
+mod flow;
+mod fork;
+mod frame;
+#[cfg(feature = "ftrace")]
+mod ftrace;
+mod future;
+pub mod fxt;
+mod global;
+#[doc(hidden)]
+pub mod guard;
+mod index;
+mod link;
+#[cfg(feature = "live")]
+mod live;
+mod logging;
+mod merge;
+#[cfg(unix)]
+mod mmap;
+pub mod prelude;
+#[cfg(all(
+    feature = "profiler",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod profiler;
+mod raw;
+mod redact;
+mod remote;
+mod scope;
+mod segment;
+mod serve;
+mod session;
+mod sink;
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(all(feature = "sched", target_os = "linux"))]
+mod switches;
+pub mod sync;
+mod system;
+mod thread_time;
+#[cfg(feature = "unstable")]
+pub mod timestamp;
+mod traceparent;
+mod trim;
+mod validate;
+#[cfg(feature = "unstable")]
+mod wasm;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod web;
+
+#[cfg(feature = "unstable")]
+pub use actor::{ActorTracer, Envelope};
+pub use alloc::{AllocSite, AllocStats, TracingAllocator};
+pub use blob::{Blob, extract_blobs};
+#[cfg(feature = "unstable")]
+pub use callstack::{StackFrame, StackMapping};
+pub use category::{COMPILED_OUT_CATEGORIES, CategoryRegistry, category_compiled_in};
+pub use clock::{Clock, ClockId, INCREMENTAL_CLOCK_ID, LogicalClock, SystemClock};
+pub use color::Color;
+pub use drops::WriteStats;
+#[cfg(all(unix, feature = "signal"))]
+pub use dump::{DUMP_SIGNAL, dump_on_signal};
+pub use encode::NeedMore;
+pub use flow::FlowDirection;
+pub use fork::fork_generation;
+#[cfg(feature = "ftrace")]
+pub use ftrace::FTRACE_EVENTS;
+#[cfg(all(feature = "ftrace", target_os = "linux"))]
+pub use ftrace::{FtraceCapture, capture_ftrace};
+pub use future::{FutureExt, Traced};
+pub use global::{counter, flush_global, init_global, instant, with_global};
+pub use guard::SliceGuard;
+pub use index::{INDEX_FILE, IndexEntry};
+#[cfg(feature = "live")]
+pub use live::{LIVE_PORT, serve_live};
+pub use merge::merge;
+#[cfg(unix)]
+pub use mmap::MmapSink;
+#[cfg(feature = "macros")]
+pub use perfetto_macros::trace;
+#[cfg(all(
+    feature = "profiler",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use profiler::Profiler;
+pub use redact::Redactor;
+pub use remote::{Collector, RemoteSink};
+pub use scope::InstantScope;
+pub use serve::{UI_PORT, serve, ui_link};
+pub use session::SessionMetadata;
+pub use sink::{FlushPolicy, MemorySink, TraceSink};
+#[cfg(feature = "futures")]
+pub use stream::{TraceSinkExt, TraceStreamExt, TracedSink, TracedStream};
+#[cfg(all(feature = "sched", target_os = "linux"))]
+pub use switches::ContextSwitches;
+pub use traceparent::{InvalidTraceParent, TraceParent};
+pub use trim::trim;
+pub use validate::{Diagnostic, InternedKind, Problem, validate};
+#[cfg(feature = "unstable")]
+pub use wasm::GuestTracer;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub use web::PerformanceClock;
+
+/// The generated `perfetto.protos` message types, for building packets by
+/// hand and passing them to [`Context::write_raw_packet`].
+pub use perfetto_protos as protos;
+/// The protobuf runtime the [`protos`] types are generated for.
+pub use protobuf;
+
 // Re-export Unit enum for counter tracks
 pub use perfetto_protos::counter_descriptor::counter_descriptor::Unit as CounterUnit;
+// Re-export Priority enum for log messages
+pub use perfetto_protos::log_message::log_message::Priority as LogPriority;
+// Re-export ChildTracksOrdering enum for track hierarchies
+pub use perfetto_protos::track_descriptor::track_descriptor::ChildTracksOrdering as ChildOrdering;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub(crate) enum InternID {
@@ -30,26 +163,26 @@ pub(crate) enum InternID {
     Existing(u64),
 }
 
-impl Into<u64> for InternID {
-    fn into(self) -> u64 {
-        match self {
+impl From<InternID> for u64 {
+    fn from(id: InternID) -> u64 {
+        match id {
             InternID::New(i) => i,
             InternID::Existing(i) => i,
         }
     }
 }
 
-impl Into<MessageField<EventName>> for InternID {
-    fn into(self) -> MessageField<EventName> {
+impl From<InternID> for MessageField<EventName> {
+    fn from(id: InternID) -> MessageField<EventName> {
         MessageField::some(EventName {
-            iid: Some(self.into()),
+            iid: Some(id.into()),
             ..Default::default()
         })
     }
 }
 
 impl InternID {
-    fn new(self) -> bool {
+    fn is_new(self) -> bool {
         match self {
             InternID::New(_) => true,
             InternID::Existing(_) => false,
@@ -82,6 +215,30 @@ impl<T: Clone + std::hash::Hash + Eq> Intern<T> {
     fn next_id(&self) -> u64 {
         self.next_id.fetch_add(1, Relaxed) + 1
     }
+
+    /// Forgets `value`, so it gets a new id when interned again.
+    fn forget<Q>(&self, value: &Q)
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: std::hash::Hash + Eq + ?Sized,
+    {
+        self.items.remove(value);
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            items: DashMap::with_capacity(capacity),
+        }
+    }
+}
+
+/// Sizes preallocated by a [`ContextBuilder`].
+#[derive(Debug, Default, Clone, Copy)]
+struct Capacity {
+    events: usize,
+    threads: usize,
+    interned: usize,
 }
 
 #[derive(Default)]
@@ -91,24 +248,87 @@ pub struct Context {
     debug_annotation_str_values: Intern<SmolStr>,
     categories: Intern<SmolStr>,
     source_locations: Intern<(SmolStr, u32)>,
+    log_bodies: Intern<SmolStr>,
+    #[cfg(feature = "unstable")]
+    function_names: Intern<SmolStr>,
+    #[cfg(feature = "unstable")]
+    frames: Intern<(u64, u64, u64)>,
+    #[cfg(feature = "unstable")]
+    callstacks: Intern<Vec<u64>>,
+    #[cfg(feature = "unstable")]
+    mappings: Intern<StackMapping>,
     buffer: Trace,
+    buffered_bytes: usize,
+    flushed_packets: u64,
+    written_packets: u64,
+    written_bytes: u64,
+    dropped_packets: u64,
+    packet_dropped: bool,
+    evict_from: usize,
+    fork_generation: u64,
+    #[cfg(feature = "etw")]
+    etw: Option<etw::EtwMirror>,
+    retracted: HashSet<usize>,
+    retracted_delta: u64,
+    staged: Option<Vec<TracePacket>>,
+    suppressed_slices: HashMap<u64, category::SuppressedSlices>,
     seq: u32,
     next_id: AtomicU64,
-    thread_tracks: HashMap<i32, u64>,
+    thread_tracks: HashMap<i64, u64>,
+    tracks: Vec<TrackDescriptor>,
+    counter_tracks: HashMap<SmolStr, u64>,
+    category_colors: HashMap<SmolStr, Color>,
+    category_registry: CategoryRegistry,
+    link_templates: HashMap<SmolStr, String>,
+    scope_tracks: HashMap<InstantScope, u64>,
+    lock_tracks: HashMap<i64, u64>,
+    frame_pacing: frame::Frames,
+    attachments_track: Option<u64>,
+    clock: Option<Box<dyn Clock>>,
+    clock_snapshot_interval: Option<Duration>,
+    last_clock_snapshot: Option<Instant>,
+    delta_base: Option<(ClockId, u64)>,
+    capacity: Capacity,
+    started: Option<Instant>,
+    sinks: Vec<Box<dyn TraceSink>>,
+    flush_policy: FlushPolicy,
+    flush_error: Option<anyhow::Error>,
+    system_info_written: bool,
+    thread_time: bool,
 }
 
+/// Identifies a track; events refer to it with
+/// [`EventBuilder::track_uuid`].
+pub type TrackUuid = u64;
+
+const DEFAULT_CLOCK_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
 impl Context {
     pub fn new() -> Self {
-        let mut s = Self::default();
-        let init = s.init_packet();
+        let mut s = Self {
+            clock_snapshot_interval: Some(DEFAULT_CLOCK_SNAPSHOT_INTERVAL),
+            last_clock_snapshot: Some(Instant::now()),
+            started: Some(Instant::now()),
+            fork_generation: fork::generation(),
+            ..Default::default()
+        };
+        let mut init = s.init_packet();
+        init.set_clock_snapshot(clock::snapshot_now(None));
         s.buffer.packet.push(init);
         s
     }
 
+    /// Returns a builder for a context with preallocated buffers.
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    // Skips the clock snapshot so golden output stays deterministic.
     #[cfg(test)]
     pub(crate) fn new_with_seq(seq: u32) -> Self {
         let mut s = Self {
             seq,
+            fork_generation: fork::generation(),
             ..Default::default()
         };
         let init = s.init_packet();
@@ -117,6 +337,7 @@ impl Context {
     }
 
     pub fn current_thread_track(&mut self) -> u64 {
+        self.check_fork();
         let current = current_thread();
         if let Some(track) = self.thread_tracks.get(&current) {
             return *track;
@@ -126,6 +347,111 @@ impl Context {
         track
     }
 
+    /// Creates a top level track for grouping related events, e.g. "GC" or
+    /// "Network".
+    pub fn create_track(&mut self, name: impl Into<String>) -> TrackUuid {
+        self.track().name(name).build()
+    }
+
+    /// Creates a track nested under `parent` in the UI.
+    pub fn create_child_track(&mut self, parent: TrackUuid, name: impl Into<String>) -> TrackUuid {
+        self.track().name(name).parent_uuid(parent).build()
+    }
+
+    /// Like [`Context::current_thread_track`], but names the track when it is
+    /// first created.
+    pub fn current_thread_track_named(&mut self, name: impl Into<String>) -> u64 {
+        self.check_fork();
+        let current = current_thread();
+        if let Some(track) = self.thread_tracks.get(&current) {
+            return *track;
+        }
+        let track = self
+            .track()
+            .name(name)
+            .current_process()
+            .current_thread()
+            .build();
+        self.thread_tracks.insert(current, track);
+        track
+    }
+
+    fn carry_delta(&mut self, from: usize, delta: u64) {
+        for (index, packet) in self.buffer.packet.iter_mut().enumerate().skip(from) {
+            if self.retracted.contains(&index) {
+                continue;
+            }
+            if packet.has_clock_snapshot() {
+                return;
+            }
+            if packet.timestamp_clock_id() == INCREMENTAL_CLOCK_ID {
+                packet.set_timestamp(packet.timestamp() + delta);
+                return;
+            }
+        }
+        self.retracted_delta += delta;
+    }
+
+    /// Number of packets waiting to be written.
+    pub fn buffered_packets(&self) -> usize {
+        self.buffer.packet.len() - self.retracted.len()
+    }
+
+    /// Position of the most recently recorded packet, for use with
+    /// [`Context::retract_packet`]. Positions keep counting across flushes.
+    pub fn last_packet_position(&self) -> Option<u64> {
+        (self.flushed_packets + self.buffer.packet.len() as u64).checked_sub(1)
+    }
+
+    /// Drops a packet that has not been written yet, e.g. the begin of a
+    /// slice that turned out to be too short to keep. Returns false if it
+    /// was already flushed.
+    ///
+    /// Only packets that nothing else refers to should be retracted; the
+    /// interned data a packet introduced lives in separate packets and is
+    /// kept.
+    pub fn retract_packet(&mut self, position: u64) -> bool {
+        let Some(index) = position
+            .checked_sub(self.flushed_packets)
+            .map(|i| i as usize)
+            .filter(|i| *i < self.buffer.packet.len())
+        else {
+            return false;
+        };
+        if !self.retracted.insert(index) {
+            return false;
+        }
+        let packet = &self.buffer.packet[index];
+        self.buffered_bytes = self
+            .buffered_bytes
+            .saturating_sub(packet.compute_size() as usize);
+        // Later deltas are relative to this packet, so hand its delta on.
+        if packet.timestamp_clock_id() == INCREMENTAL_CLOCK_ID {
+            let delta = packet.timestamp();
+            self.carry_delta(index + 1, delta);
+        }
+        true
+    }
+
+    /// Approximate encoded size of the packets waiting to be written.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// The counter track called `name`, created on first use.
+    pub fn counter_track(&mut self, name: &str) -> TrackUuid {
+        self.named_counter_track(name, Unit::UNIT_UNSPECIFIED)
+    }
+
+    pub(crate) fn named_counter_track(&mut self, name: &str, unit: Unit) -> u64 {
+        if let Some(track) = self.counter_tracks.get(name) {
+            return *track;
+        }
+        let track = self.track().name(name).counter().unit(unit).build();
+        self.counter_tracks.insert(name.into(), track);
+        track
+    }
+
     fn init_packet(&self) -> TracePacket {
         let mut tp = TracePacket::new();
         tp.set_sequence_flags(SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32);
@@ -133,13 +459,72 @@ impl Context {
         tp
     }
 
+    /// Clears the interning state, keeping the preallocated capacity.
+    fn reset_interning(&mut self) {
+        let capacity = self.capacity.interned;
+        self.event_names = Intern::with_capacity(capacity);
+        self.debug_annotation_names = Intern::with_capacity(capacity);
+        self.debug_annotation_str_values = Intern::with_capacity(capacity);
+        self.categories = Intern::with_capacity(capacity);
+        self.source_locations = Intern::with_capacity(capacity);
+        self.log_bodies = Intern::with_capacity(capacity);
+        self.category_registry.invalidate();
+        #[cfg(feature = "unstable")]
+        {
+            self.function_names = Intern::with_capacity(capacity);
+            self.frames = Intern::with_capacity(capacity);
+            self.callstacks = Intern::with_capacity(capacity);
+            self.mappings = Intern::with_capacity(capacity);
+        }
+    }
+
     pub fn write_to<W: Write>(&mut self, w: &mut W) -> Result<()> {
-        let trace = std::mem::take(&mut self.buffer);
-        trace.write_to_writer(w)?;
-        w.flush()?;
+        let trace = self.take_trace();
+        self.write_trace_to(trace, w)
+    }
+
+    /// Writes `trace`, taken from the buffer, counting it as written or
+    /// dropped.
+    fn write_trace_to<W: Write>(&mut self, trace: Trace, w: &mut W) -> Result<()> {
+        let written = trace
+            .write_to_writer(w)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(w.flush()?));
+        if let Err(e) = written {
+            self.record_dropped(trace.packet.len() as u64);
+            return Err(e);
+        }
+        self.record_written(&trace, trace.cached_size().into());
         Ok(())
     }
+
+    /// Counts the packets of `trace` as written, once `bytes` of it were.
+    pub(crate) fn record_written(&mut self, trace: &Trace, bytes: u64) {
+        self.written_packets += trace.packet.len() as u64;
+        self.written_bytes += bytes;
+    }
+
+    /// Empties the buffer, returning what is left of it once retracted
+    /// packets are dropped.
+    fn take_trace(&mut self) -> Trace {
+        let mut trace = std::mem::take(&mut self.buffer);
+        self.buffer.packet.reserve(self.capacity.events);
+        self.buffered_bytes = 0;
+        self.evict_from = 0;
+        self.flushed_packets += trace.packet.len() as u64;
+        if !self.retracted.is_empty() {
+            let retracted = std::mem::take(&mut self.retracted);
+            let mut index = 0;
+            trace.packet.retain(|_| {
+                index += 1;
+                !retracted.contains(&(index - 1))
+            });
+        }
+        trace
+    }
+
     pub fn event<'a>(&'a mut self) -> EventBuilder<'a> {
+        self.check_fork();
         EventBuilder::new(self)
     }
 
@@ -152,7 +537,7 @@ impl Context {
         TrackBuilder::new(self).uuid(id)
     }
 
-    fn source_location<'a>(&'a mut self, file: impl Into<SmolStr>, line: u32) -> u64 {
+    fn source_location(&mut self, file: impl Into<SmolStr>, line: u32) -> u64 {
         let file = file.into();
         let id = self.source_locations.intern((file.clone(), line));
         match id {
@@ -167,7 +552,7 @@ impl Context {
                         line_number: Some(line),
                         ..Default::default()
                     });
-                self.push_packet(tp);
+                self.push_interned(tp);
                 id
             }
             InternID::Existing(id) => id,
@@ -177,7 +562,7 @@ impl Context {
     fn intern_event_name(&mut self, name: impl Into<SmolStr>) -> InternID {
         let name = name.into();
         let id = self.event_names.intern(name.clone());
-        if id.new() {
+        if id.is_new() {
             let mut tp = TracePacket::new();
             let mut itd = InternedData::new();
             itd.event_names.push(EventName {
@@ -186,7 +571,7 @@ impl Context {
                 ..Default::default()
             });
             tp.interned_data = MessageField::some(itd);
-            self.push_packet(tp);
+            self.push_interned(tp);
         }
         id
     }
@@ -194,7 +579,7 @@ impl Context {
     fn intern_debug_annotation_name(&mut self, name: impl Into<SmolStr>) -> InternID {
         let name = name.into();
         let id = self.debug_annotation_names.intern(name.clone());
-        if id.new() {
+        if id.is_new() {
             let mut tp = TracePacket::new();
             let mut itd = InternedData::new();
             itd.debug_annotation_names.push(DebugAnnotationName {
@@ -203,7 +588,7 @@ impl Context {
                 ..Default::default()
             });
             tp.interned_data = MessageField::some(itd);
-            self.push_packet(tp);
+            self.push_interned(tp);
         }
         id
     }
@@ -211,7 +596,7 @@ impl Context {
     fn intern_debug_annotation_str_value(&mut self, value: impl Into<SmolStr>) -> InternID {
         let value = value.into();
         let id = self.debug_annotation_str_values.intern(value.clone());
-        if id.new() {
+        if id.is_new() {
             let mut tp = TracePacket::new();
             let mut itd = InternedData::new();
             itd.debug_annotation_string_values.push(InternedString {
@@ -220,7 +605,7 @@ impl Context {
                 ..Default::default()
             });
             tp.interned_data = MessageField::some(itd);
-            self.push_packet(tp);
+            self.push_interned(tp);
         }
         id
     }
@@ -228,7 +613,7 @@ impl Context {
     fn intern_category(&mut self, category: impl Into<SmolStr>) -> InternID {
         let category = category.into();
         let id = self.categories.intern(category.clone());
-        if id.new() {
+        if id.is_new() {
             let mut tp = TracePacket::new();
             let mut itd = InternedData::new();
             itd.event_categories.push(EventCategory {
@@ -237,7 +622,8 @@ impl Context {
                 ..Default::default()
             });
             tp.interned_data = MessageField::some(itd);
-            self.push_packet(tp);
+            self.push_interned(tp);
+            self.category_registry.interned(&category, id.as_u64());
         }
         id
     }
@@ -248,19 +634,190 @@ impl<'a> Context {
         if !packet.has_trusted_packet_sequence_id() {
             packet.set_trusted_packet_sequence_id(self.seq);
         }
+        if std::mem::take(&mut self.packet_dropped) {
+            packet.set_previous_packet_dropped(true);
+        }
+        self.buffered_bytes += packet.compute_size() as usize;
         self.buffer.packet.push(packet);
     }
+
+    /// Pushes newly interned data, or holds it back while an event is being
+    /// built, so an event that is dropped leaves nothing in the trace.
+    fn push_interned(&mut self, packet: TracePacket) {
+        match &mut self.staged {
+            Some(staged) => staged.push(packet),
+            None => self.push_packet(packet),
+        }
+    }
+
+    /// Pushes the data interned for the event being built.
+    fn push_staged(&mut self) {
+        for packet in self.staged.take().into_iter().flatten() {
+            self.push_packet(packet);
+        }
+    }
+
+    /// Forgets the data interned for a dropped event, so whatever uses it
+    /// next interns it again.
+    fn drop_staged(&mut self) {
+        for packet in self.staged.take().into_iter().flatten() {
+            let Some(data) = packet.interned_data.as_ref() else {
+                continue;
+            };
+            for name in &data.event_names {
+                self.event_names.forget(name.name());
+            }
+            for name in &data.debug_annotation_names {
+                self.debug_annotation_names.forget(name.name());
+            }
+            for value in &data.debug_annotation_string_values {
+                let value = String::from_utf8_lossy(value.str());
+                self.debug_annotation_str_values.forget(value.as_ref());
+            }
+            for category in &data.event_categories {
+                self.categories.forget(category.name());
+                self.category_registry.interned(category.name(), 0);
+            }
+            for loc in &data.source_locations {
+                let key = (SmolStr::from(loc.file_name()), loc.line_number());
+                self.source_locations.forget(&key);
+            }
+            for body in &data.log_message_body {
+                self.log_bodies.forget(body.body());
+            }
+        }
+    }
 }
 
-pub fn current_thread() -> i32 {
+/// The OS id of the calling thread, as shown by `top`, Activity Monitor or
+/// Process Explorer. macOS ids are 64 bits wide.
+pub fn current_thread() -> i64 {
     #[cfg(target_os = "linux")]
     {
-        nix::unistd::gettid().as_raw()
+        nix::unistd::gettid().as_raw().into()
+    }
+
+    #[cfg(target_vendor = "apple")]
+    {
+        unsafe extern "C" {
+            fn pthread_threadid_np(thread: *mut std::ffi::c_void, id: *mut u64) -> std::ffi::c_int;
+        }
+        let mut id = 0;
+        // SAFETY: a null thread means the calling one, and `id` is valid
+        // for writes.
+        unsafe { pthread_threadid_np(std::ptr::null_mut(), &mut id) };
+        id as i64
+    }
+
+    #[cfg(target_os = "freebsd")]
+    {
+        unsafe extern "C" {
+            fn pthread_getthreadid_np() -> std::ffi::c_int;
+        }
+        // SAFETY: takes no arguments and cannot fail.
+        unsafe { pthread_getthreadid_np() }.into()
     }
 
-    #[cfg(not(target_os = "linux"))]
+    #[cfg(all(
+        unix,
+        not(any(target_os = "linux", target_vendor = "apple", target_os = "freebsd"))
+    ))]
     {
-        (nix::sys::pthread::pthread_self() as i32).abs()
+        (nix::sys::pthread::pthread_self() as i32).abs().into()
+    }
+
+    #[cfg(windows)]
+    {
+        #[link(name = "kernel32")]
+        unsafe extern "system" {
+            fn GetCurrentThreadId() -> u32;
+        }
+        // SAFETY: takes no arguments and cannot fail.
+        unsafe { GetCurrentThreadId() }.into()
+    }
+
+    // Browsers run the module on a single thread per instance.
+    #[cfg(not(any(unix, windows)))]
+    {
+        1
+    }
+}
+
+pub(crate) fn current_pid() -> u32 {
+    #[cfg(any(unix, windows))]
+    {
+        std::process::id()
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        1
+    }
+}
+
+/// Configures a [`Context`]. Created with [`Context::builder`].
+///
+/// The capacity hints preallocate the packet buffer and intern tables, so the
+/// first seconds of a capture aren't distorted by reallocations.
+#[derive(Debug, Default)]
+pub struct ContextBuilder {
+    capacity: Capacity,
+    session: Option<SessionMetadata>,
+    system_info: bool,
+    #[cfg(feature = "unstable")]
+    timestamp_format: timestamp::TimestampFormat,
+}
+
+impl ContextBuilder {
+    /// Number of packets expected between flushes.
+    pub fn expected_events(mut self, n: usize) -> Self {
+        self.capacity.events = n;
+        self
+    }
+
+    /// Number of threads expected to record events.
+    pub fn expected_threads(mut self, n: usize) -> Self {
+        self.capacity.threads = n;
+        self
+    }
+
+    /// Number of distinct names, categories and annotation values expected
+    /// per interning table.
+    pub fn expected_interned(mut self, n: usize) -> Self {
+        self.capacity.interned = n;
+        self
+    }
+
+    /// Writes a trace uuid and `session` metadata at the start of the trace.
+    pub fn session(mut self, session: SessionMetadata) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Whether to start the trace with a `SystemInfo` packet, which
+    /// trace_processor needs for machine dependent metrics. Off by default,
+    /// like [`Context::new`].
+    pub fn system_info(mut self, enabled: bool) -> Self {
+        self.system_info = enabled;
+        self
+    }
+
+    pub fn build(self) -> Context {
+        let mut ctx = Context::new();
+        if self.system_info {
+            ctx.write_system_info();
+        }
+        ctx.capacity = self.capacity;
+        ctx.reset_interning();
+        ctx.buffer.packet.reserve(self.capacity.events);
+        ctx.thread_tracks.reserve(self.capacity.threads);
+        ctx.tracks.reserve(self.capacity.threads);
+        if let Some(session) = &self.session {
+            ctx.write_session(session);
+        }
+        #[cfg(feature = "unstable")]
+        self.timestamp_format.apply(&mut ctx);
+        ctx
     }
 }
 
@@ -300,12 +857,15 @@ impl<'a> TrackBuilder<'a> {
     }
 
     pub fn current_process(self) -> Self {
-        let pid = std::process::id();
+        let pid = current_pid();
         self.pid(pid as i32)
     }
 
+    /// Tags the track with the calling thread. `ThreadDescriptor` only has
+    /// room for 32 bits, so wider macOS ids keep their low half there, while
+    /// the context still keys thread tracks by the full id.
     pub fn current_thread(self) -> Self {
-        self.tid(current_thread())
+        self.tid(current_thread() as i32)
     }
 
     pub fn counter(mut self) -> Self {
@@ -313,6 +873,19 @@ impl<'a> TrackBuilder<'a> {
         self
     }
 
+    /// How the UI sorts this track's children.
+    pub fn child_ordering(mut self, ordering: ChildOrdering) -> Self {
+        self.track.set_child_ordering(ordering);
+        self
+    }
+
+    /// Position among siblings when the parent uses
+    /// [`ChildOrdering::EXPLICIT`]; lower ranks come first.
+    pub fn sibling_order_rank(mut self, rank: i32) -> Self {
+        self.track.set_sibling_order_rank(rank);
+        self
+    }
+
     pub fn unit(mut self, unit: Unit) -> Self {
         self.track.counter.mut_or_insert_default().set_unit(unit);
         self
@@ -349,14 +922,28 @@ impl<'a> TrackBuilder<'a> {
             self.track.has_uuid(),
             "track_uuid is required for a track event"
         );
+        self.ctx.tracks.push(self.track.clone());
         tp.set_track_descriptor(self.track);
         self.ctx.push_packet(tp);
         id
     }
 }
 
+type LazyAnnotation<'a> = (SmolStr, Box<dyn FnOnce() -> String + 'a>);
+
 pub struct EventBuilder<'a> {
     event: TrackEvent,
+    timestamp: Option<u64>,
+    clock: Option<ClockId>,
+    /// Whether the timestamp was taken by [`EventBuilder::now`], to be
+    /// taken again if the clock changes.
+    now: bool,
+    color: Option<Color>,
+    scope: Option<InstantScope>,
+    lazy: Vec<LazyAnnotation<'a>>,
+    dropped: bool,
+    #[cfg(feature = "etw")]
+    name: Option<SmolStr>,
     ctx: &'a mut Context,
 }
 
@@ -364,20 +951,58 @@ impl<'a> EventBuilder<'a> {
     fn new(ctx: &'a mut Context) -> Self {
         Self {
             event: TrackEvent::new(),
-            ctx,
+            timestamp: None,
+            clock: None,
+            now: false,
+            color: None,
+            scope: None,
+            lazy: Vec::new(),
+            dropped: false,
+            #[cfg(feature = "etw")]
+            name: None,
+            ctx: {
+                ctx.staged = Some(Vec::new());
+                ctx
+            },
         }
     }
 
-    pub fn timestamp_us(&mut self, us: i64) {
-        self.event.set_timestamp_absolute_us(us);
+    /// Sets the timestamp in microseconds, saturating at the largest
+    /// timestamp in nanoseconds.
+    pub fn timestamp_us(&mut self, us: u64) {
+        self.timestamp_ns(us.saturating_mul(1000));
+    }
+
+    /// Sets the timestamp in nanoseconds, or in the units of the event's
+    /// clock when it doesn't count nanoseconds, e.g. simulation ticks for a
+    /// [`LogicalClock`].
+    pub fn timestamp_ns(&mut self, ns: u64) {
+        self.timestamp = Some(ns);
+        self.now = false;
+    }
+
+    /// Declares which clock domain the event's timestamp is in. Defaults to
+    /// the domain of the context's [`Clock`]. A timestamp taken with
+    /// [`EventBuilder::now`] is taken again from `clock`.
+    pub fn clock(&mut self, clock: ClockId) {
+        self.clock = Some(clock);
+        if self.now {
+            self.now();
+        }
     }
 
+    /// Sets the timestamp to the current time of the event's clock, before
+    /// or after it is declared with [`EventBuilder::clock`].
     pub fn now(&mut self) {
-        let us = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros() as i64;
-        self.timestamp_us(us);
+        let clock = self.ctx.clock();
+        match self.clock {
+            Some(id) if id != clock.id() => self.timestamp = id.now_ns(),
+            _ => {
+                self.timestamp = Some(clock.now());
+                self.clock = Some(clock.id());
+            }
+        }
+        self.now = true;
     }
 
     pub fn begin(&mut self) {
@@ -396,22 +1021,44 @@ impl<'a> EventBuilder<'a> {
         self.event.set_type(Type::TYPE_COUNTER);
     }
 
+    /// Adds a category. If it is a disabled category of the
+    /// [`CategoryRegistry`], the event is dropped on build.
     pub fn category(&mut self, category: impl Into<SmolStr>) {
+        let category = category.into();
+        if !self.ctx.is_category_enabled(&category) {
+            self.dropped = true;
+            return;
+        }
+        if let Some(color) = self.ctx.category_colors.get(&category) {
+            self.color.get_or_insert(*color);
+        }
         let id = self.ctx.intern_category(category);
         self.event.category_iids.push(id.into());
     }
 
+    /// Sets where the event was emitted. Each file and line pair is
+    /// interned once per trace segment; events carry only its id.
     pub fn source_location(&mut self, file: impl Into<SmolStr>, line: u32) {
         let loc = self.ctx.source_location(file, line);
         self.event.set_source_location_iid(loc);
     }
 
     pub fn name(&mut self, name: impl Into<SmolStr>) {
+        let name = name.into();
+        #[cfg(feature = "etw")]
+        if self.ctx.etw.is_some() {
+            self.name = Some(name.clone());
+        }
         let id = self.ctx.intern_event_name(name);
         self.event.set_name_iid(id.into());
     }
 
+    /// Adds a string annotation. Values are interned, so a large string such
+    /// as a SQL query or stack dump is written once per trace segment and
+    /// referenced by id from every event that repeats it.
     pub fn debug_str(&mut self, name: impl Into<SmolStr>, value: impl Into<SmolStr>) {
+        let name = name.into();
+        let value = value.into();
         let id = self.ctx.intern_debug_annotation_name(name);
         let vid = self.ctx.intern_debug_annotation_str_value(value);
         let mut da = DebugAnnotation::new();
@@ -460,6 +1107,12 @@ impl<'a> EventBuilder<'a> {
         self.event.debug_annotations.push(da);
     }
 
+    /// Adds a string annotation whose value is only computed if the event is
+    /// actually built, so expensive formatting is skipped for dropped events.
+    pub fn debug_lazy(&mut self, name: impl Into<SmolStr>, value: impl FnOnce() -> String + 'a) {
+        self.lazy.push((name.into(), Box::new(value)));
+    }
+
     pub fn track_uuid(&mut self, id: u64) {
         self.event.set_track_uuid(id);
     }
@@ -490,11 +1143,34 @@ impl<'a> EventBuilder<'a> {
         self.event.extra_double_counter_values.push(value);
     }
 
-    pub fn with_timestamp_us(mut self, us: i64) -> Self {
+    /// Sets the counter track called `name` (created on first use) as of
+    /// this event, e.g. bytes processed by a slice, so the counter steps in
+    /// line with the slices.
+    pub fn extra_counter_named(&mut self, name: &str, value: i64) {
+        let track = self.ctx.counter_track(name);
+        self.extra_counter(track, value);
+    }
+
+    pub fn extra_double_counter_named(&mut self, name: &str, value: f64) {
+        let track = self.ctx.counter_track(name);
+        self.extra_double_counter(track, value);
+    }
+
+    pub fn with_timestamp_us(mut self, us: u64) -> Self {
         self.timestamp_us(us);
         self
     }
 
+    pub fn with_timestamp_ns(mut self, ns: u64) -> Self {
+        self.timestamp_ns(ns);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: ClockId) -> Self {
+        self.clock(clock);
+        self
+    }
+
     pub fn with_now(mut self) -> Self {
         self.now();
         self
@@ -570,6 +1246,15 @@ impl<'a> EventBuilder<'a> {
         self
     }
 
+    pub fn with_debug_lazy(
+        mut self,
+        name: impl Into<SmolStr>,
+        value: impl FnOnce() -> String + 'a,
+    ) -> Self {
+        self.debug_lazy(name, value);
+        self
+    }
+
     pub fn with_counter_value(mut self, value: i64) -> Self {
         self.counter_value(value);
         self
@@ -600,18 +1285,61 @@ impl<'a> EventBuilder<'a> {
         self
     }
 
-    pub fn build(self) {
-        let mut tp = TracePacket::new();
+    pub fn with_extra_counter_named(mut self, name: &str, value: i64) -> Self {
+        self.extra_counter_named(name, value);
+        self
+    }
+
+    pub fn with_extra_double_counter_named(mut self, name: &str, value: f64) -> Self {
+        self.extra_double_counter_named(name, value);
+        self
+    }
+
+    pub fn build(mut self) {
+        if let Some(scope) = self.scope
+            && let Some(track) = self.ctx.scope_track(scope)
+        {
+            self.event.set_track_uuid(track);
+        }
         assert!(
             self.event.has_track_uuid(),
             "track_uuid is required for a track event"
         );
-        tp.set_track_event(self.event);
+        if self.is_dropped() {
+            return;
+        }
+        for (name, value) in std::mem::take(&mut self.lazy) {
+            self.debug_str(name, value());
+        }
+        if let Some(color) = self.color {
+            self.debug_str(color::COLOR_ANNOTATION, color.as_str());
+        }
+        self.link_log_source_location();
+        self.maybe_thread_time();
+        self.ctx.push_staged();
+        let mut tp = TracePacket::new();
+        if let Some(ts) = self.timestamp {
+            let clock = self.clock.unwrap_or_else(|| self.ctx.clock().id());
+            self.ctx.set_packet_timestamp(&mut tp, ts, clock);
+        }
+        self.ctx.maybe_clock_snapshot();
+        #[cfg(feature = "etw")]
+        self.ctx
+            .mirror_etw(&self.event, self.name.as_ref(), self.timestamp);
+        tp.set_track_event(std::mem::take(&mut self.event));
         self.ctx.push_packet(tp);
+        self.ctx.maybe_auto_flush();
+    }
+}
+
+impl Drop for EventBuilder<'_> {
+    fn drop(&mut self) {
+        self.ctx.drop_staged();
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use super::*;
     use anyhow::Result;
@@ -812,6 +1540,154 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn named_custom_tracks() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let render = ctx.create_track("Render");
+        let raster = ctx.create_child_track(render, "Raster");
+        assert_ne!(render, raster);
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(raster)
+            .with_name("tile")
+            .build();
+        ctx.write_to(&mut buf)?;
+        let trace: Trace = Trace::parse_from_bytes(&buf)?;
+
+        let parent = trace.packet[1].track_descriptor();
+        assert_eq!(parent.uuid(), render);
+        assert_eq!(parent.name(), "Render");
+        assert!(!parent.has_parent_uuid());
+
+        let child = trace.packet[2].track_descriptor();
+        assert_eq!(child.uuid(), raster);
+        assert_eq!(child.name(), "Raster");
+        assert_eq!(child.parent_uuid(), render);
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_strings_are_stored_once() -> Result<()> {
+        let query = "SELECT * FROM events WHERE ".repeat(200);
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for _ in 0..10 {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_debug_str("sql", query.as_str())
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        assert!(buf.len() < 2 * query.len());
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let iids: HashSet<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().debug_annotations[0].string_value_iid())
+            .collect();
+        assert_eq!(iids.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn source_locations_are_interned() -> Result<()> {
+        let file = "src/some/deeply/nested/module/with/a/long/path.rs";
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for line in [10, 10, 10, 20] {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_source_location(file, line)
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let locations: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.source_locations.iter())
+            .map(|l| (l.file_name(), l.line_number()))
+            .collect();
+        assert_eq!(locations, [(file, 10), (file, 20)]);
+        let iids: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().source_location_iid())
+            .collect();
+        assert_eq!(iids[0], iids[1]);
+        assert_eq!(iids[1], iids[2]);
+        assert_ne!(iids[2], iids[3]);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_preallocates() -> Result<()> {
+        let mut ctx = Context::builder()
+            .expected_events(1024)
+            .expected_threads(8)
+            .expected_interned(64)
+            .build();
+        assert!(ctx.buffer.packet.capacity() >= 1024);
+        assert!(ctx.thread_tracks.capacity() >= 8);
+        assert!(ctx.event_names.items.capacity() >= 64);
+
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_track_uuid(track)
+            .with_name("first")
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        assert!(ctx.buffer.packet.capacity() >= 1024);
+
+        let trace: Trace = Trace::parse_from_bytes(&buf)?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        Ok(())
+    }
+
+    #[test]
+    fn track_hierarchy_ordering() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let workers = ctx
+            .track()
+            .name("Workers")
+            .child_ordering(ChildOrdering::EXPLICIT)
+            .build();
+        for (name, rank) in [("worker 2", 2), ("worker 1", 1)] {
+            ctx.track()
+                .name(name)
+                .parent_uuid(workers)
+                .sibling_order_rank(rank)
+                .build();
+        }
+        ctx.write_to(&mut buf)?;
+        let trace: Trace = Trace::parse_from_bytes(&buf)?;
+
+        let parent = trace.packet[1].track_descriptor();
+        assert_eq!(parent.child_ordering(), ChildOrdering::EXPLICIT);
+        let children: Vec<_> = trace.packet[2..]
+            .iter()
+            .map(|p| p.track_descriptor())
+            .inspect(|t| assert_eq!(t.parent_uuid(), workers))
+            .map(|t| (t.name(), t.sibling_order_rank()))
+            .collect();
+        assert_eq!(children, [("worker 2", 2), ("worker 1", 1)]);
+        Ok(())
+    }
+
     #[test]
     fn track_current_process() -> Result<()> {
         let mut buf = Vec::new();
@@ -840,7 +1716,7 @@ mod tests {
     fn track_current_thread() -> Result<()> {
         let mut buf = Vec::new();
         let mut ctx = Context::new();
-        let expected_tid = current_thread();
+        let expected_tid = current_thread() as i32;
 
         ctx.track()
             .uuid(201)
@@ -855,7 +1731,7 @@ mod tests {
         let track = trace.packet[1].track_descriptor();
         assert_eq!(track.uuid(), 201);
         assert_eq!(track.name(), "thread_track");
-        assert_eq!(track.thread.tid(), expected_tid as i32);
+        assert_eq!(track.thread.tid(), expected_tid);
 
         Ok(())
     }
@@ -865,7 +1741,7 @@ mod tests {
         let mut buf = Vec::new();
         let mut ctx = Context::new();
         let expected_pid = std::process::id();
-        let expected_tid = current_thread();
+        let expected_tid = current_thread() as i32;
 
         ctx.track()
             .uuid(202)
@@ -882,7 +1758,7 @@ mod tests {
         assert_eq!(track.uuid(), 202);
         assert_eq!(track.name(), "full_track");
         assert_eq!(track.thread.pid(), expected_pid as i32);
-        assert_eq!(track.thread.tid(), expected_tid as i32);
+        assert_eq!(track.thread.tid(), expected_tid);
 
         Ok(())
     }
@@ -922,15 +1798,15 @@ mod tests {
         let mut category_networking_found = false;
 
         for packet in &trace.packet {
-            if let Some(interned) = packet.interned_data.as_ref() {
-                if !interned.event_categories.is_empty() {
-                    if interned.event_categories[0].name() == "rendering" {
-                        category_rendering_found = true;
-                        assert_eq!(interned.event_categories[0].iid(), 1);
-                    } else if interned.event_categories[0].name() == "networking" {
-                        category_networking_found = true;
-                        assert_eq!(interned.event_categories[0].iid(), 2);
-                    }
+            if let Some(interned) = packet.interned_data.as_ref()
+                && !interned.event_categories.is_empty()
+            {
+                if interned.event_categories[0].name() == "rendering" {
+                    category_rendering_found = true;
+                    assert_eq!(interned.event_categories[0].iid(), 1);
+                } else if interned.event_categories[0].name() == "networking" {
+                    category_networking_found = true;
+                    assert_eq!(interned.event_categories[0].iid(), 2);
                 }
             }
             if packet.has_track_event() {
@@ -1019,6 +1895,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn named_extra_counters() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for bytes in [10, 20] {
+            ctx.event()
+                .with_begin()
+                .with_track_uuid(track)
+                .with_extra_counter_named("bytes", bytes)
+                .with_extra_double_counter_named("ratio", 0.5)
+                .build();
+        }
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert_eq!(
+            events[0].extra_counter_track_uuids,
+            events[1].extra_counter_track_uuids
+        );
+        assert_eq!(events[1].extra_counter_values, [20]);
+        assert_eq!(events[1].extra_double_counter_values, [0.5]);
+        let bytes = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().name() == "bytes")
+            .unwrap();
+        assert_eq!(
+            bytes.track_descriptor().uuid(),
+            events[0].extra_counter_track_uuids[0]
+        );
+        Ok(())
+    }
+
     #[test]
     fn extra_counters() -> Result<()> {
         let mut buf = Vec::new();
@@ -1074,7 +1989,7 @@ mod tests {
         assert_eq!(event.debug_annotations.len(), 5);
 
         // Check bool annotation
-        assert_eq!(event.debug_annotations[0].bool_value(), true);
+        assert!(event.debug_annotations[0].bool_value());
         // Check int annotation
         assert_eq!(event.debug_annotations[1].int_value(), -42);
         // Check uint annotation
@@ -1087,6 +2002,50 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn lazy_annotations_only_run_on_build() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let calls = std::cell::Cell::new(0);
+
+        let dropped = ctx
+            .event()
+            .with_instant()
+            .with_track_uuid(1)
+            .with_debug_lazy("skipped", || {
+                calls.set(calls.get() + 1);
+                "never".to_string()
+            });
+        drop(dropped);
+        assert_eq!(calls.get(), 0);
+
+        ctx.event()
+            .with_instant()
+            .with_name("lazy_event")
+            .with_track_uuid(1)
+            .with_debug_lazy("expensive", || {
+                calls.set(calls.get() + 1);
+                format!("{:?}", vec![1, 2, 3])
+            })
+            .build();
+        assert_eq!(calls.get(), 1);
+
+        ctx.write_to(&mut buf)?;
+        let trace: Trace = Trace::parse_from_bytes(&buf)?;
+        let values: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| s.str().to_vec())
+            .collect();
+        assert_eq!(values, [b"[1, 2, 3]".to_vec()]);
+        let event = trace.packet.iter().find(|p| p.has_track_event()).unwrap();
+        assert_eq!(event.track_event().debug_annotations.len(), 1);
+
+        Ok(())
+    }
+
     #[test]
     fn counter_track_basic() -> Result<()> {
         let mut buf = Vec::new();
@@ -1215,7 +2174,7 @@ mod tests {
         assert_eq!(track.name(), "packet_count_delta");
         assert!(track.counter.is_some());
         assert_eq!(track.counter.unit(), Unit::UNIT_COUNT);
-        assert_eq!(track.counter.is_incremental(), true);
+        assert!(track.counter.is_incremental());
 
         Ok(())
     }
@@ -1248,7 +2207,7 @@ mod tests {
         assert_eq!(track.counter.unit(), Unit::UNIT_SIZE_BYTES);
         assert_eq!(track.counter.unit_name(), "bytes_per_second");
         assert_eq!(track.counter.unit_multiplier(), 1);
-        assert_eq!(track.counter.is_incremental(), false);
+        assert!(!track.counter.is_incremental());
 
         Ok(())
     }
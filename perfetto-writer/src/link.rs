@@ -0,0 +1,116 @@
+use smol_str::SmolStr;
+use std::fmt::Write as _;
+
+use crate::{Context, EventBuilder};
+
+impl Context {
+    /// Sets the link [`EventBuilder::debug_link`] renders annotations named
+    /// `name` as, by substituting the percent-encoded value for `{}` in
+    /// `template`, e.g.
+    /// `set_link_template("trace_id", "https://jaeger.example/trace/{}")`.
+    /// The UI makes annotation values that are URLs clickable.
+    pub fn set_link_template(&mut self, name: impl Into<SmolStr>, template: impl Into<String>) {
+        self.link_templates.insert(name.into(), template.into());
+    }
+
+    fn linkify(&self, name: &str, value: SmolStr) -> SmolStr {
+        match self.link_templates.get(name) {
+            Some(template) => template.replace("{}", &percent_encode(&value)).into(),
+            None => value,
+        }
+    }
+}
+
+/// Escapes everything but the characters URLs leave unreserved.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+impl<'a> EventBuilder<'a> {
+    /// Adds an annotation the UI renders as a clickable link to `url`,
+    /// e.g. the dashboard for the request a slice served. A `url` that
+    /// isn't http(s) is left out, so untrusted input can't become e.g. a
+    /// `javascript:` link.
+    pub fn debug_url(&mut self, name: impl Into<SmolStr>, url: impl Into<SmolStr>) {
+        let url = url.into();
+        if url.starts_with("http://") || url.starts_with("https://") {
+            self.debug_str(name, url);
+        }
+    }
+
+    pub fn with_debug_url(mut self, name: impl Into<SmolStr>, url: impl Into<SmolStr>) -> Self {
+        self.debug_url(name, url);
+        self
+    }
+
+    /// Adds a string annotation rendered through the link template set for
+    /// `name` with [`Context::set_link_template`], or as is if there is
+    /// none.
+    pub fn debug_link(&mut self, name: impl Into<SmolStr>, value: impl Into<SmolStr>) {
+        let name = name.into();
+        let value = self.ctx.linkify(&name, value.into());
+        self.debug_str(name, value);
+    }
+
+    pub fn with_debug_link(mut self, name: impl Into<SmolStr>, value: impl Into<SmolStr>) -> Self {
+        self.debug_link(name, value);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn templated_annotations_become_urls() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.set_link_template("trace_id", "https://jaeger.example/trace/{}");
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_debug_link("trace_id", "abc 1/2&3")
+            .with_debug_str("trace_id", "raw")
+            .with_debug_link("user", "bob")
+            .with_debug_url("dashboard", "https://grafana.example/d/api")
+            .with_debug_url("script", "javascript:alert(1)")
+            .build();
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let values: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| String::from_utf8_lossy(s.str()).into_owned())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                "https://jaeger.example/trace/abc%201%2F2%263",
+                "raw",
+                "bob",
+                "https://grafana.example/d/api"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::Result;
+use perfetto_core::wire::{Fields, Message as Encoder, Value};
+use perfetto_protos::{
+    trace::Trace,
+    trace_config::TraceConfig,
+    trace_stats::{TraceStats, trace_stats::BufferStats},
+};
+use protobuf::Message as _;
+use std::{
+    io::{self, ErrorKind},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tungstenite::{
+    Message, WebSocket,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+
+use crate::{Context, serve::UI_ORIGIN};
+
+/// The port the Perfetto UI's WebSocket target connects to.
+pub const LIVE_PORT: u16 = 8037;
+
+/// The consumer methods served, numbered from 1 in this order.
+const METHODS: [&str; 8] = [
+    "EnableTracing",
+    "DisableTracing",
+    "ReadBuffers",
+    "FreeBuffers",
+    "Flush",
+    "GetTraceStats",
+    "QueryServiceState",
+    "QueryCapabilities",
+];
+
+/// Packets are sent in replies of about this many bytes.
+const CHUNK: usize = 128 * 1024;
+
+fn lock(ctx: &Mutex<Context>) -> MutexGuard<'_, Context> {
+    ctx.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lets the Perfetto UI record from this process, as if it were the
+/// `traced` service behind `websocket_bridge`: pick "WebSocket" as the
+/// target on the UI's record page, then start and stop recordings from the
+/// browser while the app runs.
+///
+/// Each recording starts a new segment of `ctx`, flushing what was buffered
+/// before to its [sinks](Context::add_sink), and the packets are drained to
+/// the UI as it reads them, so nothing else should write `ctx` meanwhile.
+/// Serves one connection at a time on `127.0.0.1:port` from a background
+/// thread; use [`LIVE_PORT`] for the UI to find it. Only ui.perfetto.dev
+/// may connect, so other pages open in the browser can't record the app.
+pub fn serve_live(ctx: Arc<Mutex<Context>>, port: u16) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    Ok(spawn(listener, ctx))
+}
+
+fn spawn(listener: TcpListener, ctx: Arc<Mutex<Context>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A broken connection only ends its own session.
+            let _ = Consumer::accept(stream, &ctx).and_then(|mut c| c.run());
+        }
+    })
+}
+
+/// A recording started by `EnableTracing`, which is answered once it ends.
+struct Session {
+    request: u64,
+    deadline: Option<Instant>,
+    buffer_size: u64,
+}
+
+/// One connected consumer, speaking traced's IPC protocol: frames of a
+/// little endian length and an `IPCFrame`, carried in binary messages.
+struct Consumer<'a> {
+    ws: WebSocket<TcpStream>,
+    ctx: &'a Mutex<Context>,
+    inbox: Vec<u8>,
+    session: Option<Session>,
+}
+
+impl<'a> Consumer<'a> {
+    fn accept(stream: TcpStream, ctx: &'a Mutex<Context>) -> Result<Self> {
+        let ws =
+            tungstenite::accept_hdr(stream, check_origin).map_err(|e| anyhow::anyhow!("{e}"))?;
+        // Wake up now and then to end timed recordings.
+        ws.get_ref()
+            .set_read_timeout(Some(Duration::from_millis(100)))?;
+        Ok(Self {
+            ws,
+            ctx,
+            inbox: Vec::new(),
+            session: None,
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        loop {
+            if let Some(session) = &self.session
+                && session.deadline.is_some_and(|d| Instant::now() >= d)
+            {
+                self.disable()?;
+            }
+            match self.ws.read() {
+                Ok(Message::Binary(data)) => {
+                    self.inbox.extend_from_slice(&data);
+                    while let Some(frame) = self.next_frame() {
+                        self.handle(&frame)?;
+                    }
+                }
+                Ok(Message::Close(_)) => return Ok(()),
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let header: [u8; 4] = self.inbox.get(..4)?.try_into().ok()?;
+        let len = u32::from_le_bytes(header) as usize;
+        if self.inbox.len() < 4 + len {
+            return None;
+        }
+        let frame = self.inbox[4..4 + len].to_vec();
+        self.inbox.drain(..4 + len);
+        Some(frame)
+    }
+
+    fn send(&mut self, frame: Encoder) -> Result<()> {
+        let mut message = (frame.0.len() as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&frame.0);
+        self.ws.send(Message::Binary(message.into()))?;
+        Ok(())
+    }
+
+    fn reply(&mut self, request: u64, reply: Encoder, has_more: bool) -> Result<()> {
+        let reply = Encoder::default()
+            .varint(1, 1)
+            .varint(2, has_more.into())
+            .bytes(3, &reply.0);
+        self.send(Encoder::default().varint(2, request).bytes(6, &reply.0))
+    }
+
+    fn handle(&mut self, frame: &[u8]) -> Result<()> {
+        let mut request = 0;
+        for (field, value) in Fields(frame) {
+            match (field, value) {
+                (2, Value::Varint(id)) => request = id,
+                (3, Value::Bytes(bind)) => return self.bind(request, bind),
+                (5, Value::Bytes(invoke)) => return self.invoke(request, invoke),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, request: u64, bind: &[u8]) -> Result<()> {
+        let name = Fields(bind).find_map(|f| match f {
+            (1, Value::Bytes(name)) => Some(name),
+            _ => None,
+        });
+        let mut reply = Encoder::default();
+        if name == Some(b"ConsumerPort".as_slice()) {
+            reply = reply.varint(1, 1).varint(2, 1);
+            for (id, method) in (1..).zip(METHODS) {
+                let method = Encoder::default().varint(1, id).bytes(2, method.as_bytes());
+                reply = reply.bytes(3, &method.0);
+            }
+        }
+        self.send(Encoder::default().varint(2, request).bytes(4, &reply.0))
+    }
+
+    fn invoke(&mut self, request: u64, invoke: &[u8]) -> Result<()> {
+        let mut method = 0;
+        let mut args: &[u8] = &[];
+        for (field, value) in Fields(invoke) {
+            match (field, value) {
+                (2, Value::Varint(id)) => method = id,
+                (3, Value::Bytes(bytes)) => args = bytes,
+                _ => {}
+            }
+        }
+        let name = method
+            .checked_sub(1)
+            .and_then(|i| METHODS.get(i as usize).copied());
+        match name {
+            Some("EnableTracing") => self.enable(request, args),
+            Some("DisableTracing") => {
+                self.reply(request, Encoder::default(), false)?;
+                self.disable()
+            }
+            Some("ReadBuffers") => self.read_buffers(request),
+            Some("FreeBuffers") => {
+                self.session = None;
+                self.reply(request, Encoder::default(), false)
+            }
+            Some("GetTraceStats") => {
+                let mut buffer = BufferStats::new();
+                buffer.set_buffer_size(self.session.as_ref().map_or(0, |s| s.buffer_size));
+                buffer.set_bytes_written(lock(self.ctx).buffered_bytes() as u64);
+                let mut stats = TraceStats::new();
+                stats.buffer_stats.push(buffer);
+                let reply = Encoder::default().bytes(1, &stats.write_to_bytes()?);
+                self.reply(request, reply, false)
+            }
+            Some("QueryServiceState") => {
+                let producer = Encoder::default()
+                    .varint(1, 1)
+                    .bytes(2, b"perfetto-writer")
+                    .varint(5, std::process::id().into());
+                let descriptor = Encoder::default().bytes(1, b"track_event");
+                let source = Encoder::default().bytes(1, &descriptor.0).varint(2, 1);
+                let state = Encoder::default()
+                    .bytes(1, &producer.0)
+                    .bytes(2, &source.0)
+                    .bytes(
+                        5,
+                        concat!("perfetto-writer ", env!("CARGO_PKG_VERSION")).as_bytes(),
+                    );
+                self.reply(request, Encoder::default().bytes(1, &state.0), false)
+            }
+            Some(_) => self.reply(request, Encoder::default(), false),
+            None => {
+                let reply = Encoder::default().varint(1, 0);
+                self.send(Encoder::default().varint(2, request).bytes(6, &reply.0))
+            }
+        }
+    }
+
+    fn enable(&mut self, request: u64, args: &[u8]) -> Result<()> {
+        let config = Fields(args)
+            .find_map(|f| match f {
+                (1, Value::Bytes(config)) => Some(config),
+                _ => None,
+            })
+            .map(TraceConfig::parse_from_bytes)
+            .transpose()?
+            .unwrap_or_default();
+        let duration = config.duration_ms();
+        self.session = Some(Session {
+            request,
+            deadline: (duration > 0)
+                .then(|| Instant::now() + Duration::from_millis(duration.into())),
+            buffer_size: config.buffers.first().map_or(0, |b| b.size_kb()) as u64 * 1024,
+        });
+        lock(self.ctx).rotate_sinks()
+    }
+
+    /// Ends the recording by answering its `EnableTracing` call.
+    fn disable(&mut self) -> Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(());
+        };
+        session.deadline = None;
+        let request = session.request;
+        self.reply(request, Encoder::default().varint(1, 1), false)
+    }
+
+    fn read_buffers(&mut self, request: u64) -> Result<()> {
+        let trace = lock(self.ctx).take_trace();
+        let sent = self.send_packets(request, &trace);
+        let mut ctx = lock(self.ctx);
+        match sent {
+            Ok(bytes) => {
+                ctx.record_written(&trace, bytes);
+                Ok(())
+            }
+            Err(e) => {
+                ctx.record_dropped(trace.packet.len() as u64);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends the packets of `trace` in `ReadBuffers` replies, returning how
+    /// many bytes of packets were sent.
+    fn send_packets(&mut self, request: u64, trace: &Trace) -> Result<u64> {
+        let mut reply = Encoder::default();
+        let mut sent = 0;
+        for packet in &trace.packet {
+            let packet = packet.write_to_bytes()?;
+            sent += packet.len() as u64;
+            let slice = Encoder::default().bytes(1, &packet).varint(2, 1);
+            reply = reply.bytes(2, &slice.0);
+            if reply.0.len() >= CHUNK {
+                self.reply(request, std::mem::take(&mut reply), true)?;
+            }
+        }
+        self.reply(request, reply, false)?;
+        Ok(sent)
+    }
+}
+
+/// Accepts the WebSocket handshake of the Perfetto UI only. Browsers send
+/// the page's origin, which other sites can't forge.
+// The signature is the one `accept_hdr` expects.
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    let origin = request.headers().get("Origin");
+    if origin.is_some_and(|origin| origin == UI_ORIGIN) {
+        return Ok(response);
+    }
+    let mut forbidden = ErrorResponse::new(Some("only the Perfetto UI may connect".into()));
+    *forbidden.status_mut() = StatusCode::FORBIDDEN;
+    Err(forbidden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemorySink;
+    use perfetto_protos::{trace::Trace, trace_packet::TracePacket};
+    use tungstenite::client::IntoClientRequest;
+
+    type Client = WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+    fn send(client: &mut Client, frame: Encoder) -> Result<()> {
+        let mut message = (frame.0.len() as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&frame.0);
+        client.send(Message::Binary(message.into()))?;
+        Ok(())
+    }
+
+    fn invoke(client: &mut Client, request: u64, method: &str, args: &[u8]) -> Result<()> {
+        let id = METHODS.iter().position(|m| *m == method).unwrap() as u64 + 1;
+        let invoke = Encoder::default().varint(1, 1).varint(2, id).bytes(3, args);
+        send(
+            client,
+            Encoder::default().varint(2, request).bytes(5, &invoke.0),
+        )
+    }
+
+    /// The next frame's request id and message.
+    fn receive(client: &mut Client) -> Result<(u64, u32, Vec<u8>)> {
+        let data = client.read()?.into_data();
+        let frame = &data[4..];
+        let mut request = 0;
+        for (field, value) in Fields(frame) {
+            match (field, value) {
+                (2, Value::Varint(id)) => request = id,
+                (field, Value::Bytes(msg)) => return Ok((request, field, msg.to_vec())),
+                _ => {}
+            }
+        }
+        anyhow::bail!("empty frame")
+    }
+
+    fn field(msg: &[u8], number: u32) -> Option<Value<'_>> {
+        Fields(msg).find(|(f, _)| *f == number).map(|(_, v)| v)
+    }
+
+    fn connect(port: u16, origin: &str) -> Result<Client> {
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        let mut request = format!("ws://127.0.0.1:{port}/traced").into_client_request()?;
+        request.headers_mut().insert("Origin", origin.parse()?);
+        let (client, _) =
+            tungstenite::client(request, tungstenite::stream::MaybeTlsStream::Plain(stream))
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(client)
+    }
+
+    #[test]
+    fn only_the_ui_may_connect() -> Result<()> {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let port = listener.local_addr()?.port();
+        spawn(listener, ctx);
+
+        assert!(connect(port, "https://evil.example").is_err());
+        assert!(connect(port, UI_ORIGIN).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn the_ui_can_record_a_session() -> Result<()> {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let sink = MemorySink::new();
+        {
+            let mut ctx = lock(&ctx);
+            ctx.add_sink(sink.clone());
+            let track = ctx.current_thread_track();
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("before")
+                .build();
+        }
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let port = listener.local_addr()?.port();
+        spawn(listener, Arc::clone(&ctx));
+        let mut client = connect(port, UI_ORIGIN)?;
+
+        let bind = Encoder::default().bytes(1, b"ConsumerPort");
+        send(
+            &mut client,
+            Encoder::default().varint(2, 1).bytes(3, &bind.0),
+        )?;
+        let (request, kind, reply) = receive(&mut client)?;
+        assert_eq!((request, kind), (1, 4));
+        let methods = Fields(&reply).filter(|(f, _)| *f == 3).count();
+        assert_eq!(methods, METHODS.len());
+
+        invoke(&mut client, 2, "EnableTracing", &[])?;
+        // Wait for the recording to start before writing to it.
+        invoke(&mut client, 5, "GetTraceStats", &[])?;
+        assert_eq!(receive(&mut client)?.0, 5);
+        let before = Trace::parse_from_bytes(&sink.contents())?;
+        assert!(before.packet.iter().any(|p| p.has_track_event()));
+        {
+            let mut ctx = lock(&ctx);
+            let track = ctx.current_thread_track();
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("live")
+                .build();
+        }
+        invoke(&mut client, 3, "ReadBuffers", &[])?;
+        let mut packets = Vec::new();
+        loop {
+            let (request, kind, reply) = receive(&mut client)?;
+            assert_eq!((request, kind), (3, 6));
+            let Some(Value::Bytes(response)) = field(&reply, 3) else {
+                panic!("no reply");
+            };
+            for (_, slice) in Fields(response) {
+                if let Value::Bytes(slice) = slice
+                    && let Some(Value::Bytes(data)) = field(slice, 1)
+                {
+                    packets.push(TracePacket::parse_from_bytes(data)?);
+                }
+            }
+            if !matches!(field(&reply, 2), Some(Value::Varint(1))) {
+                break;
+            }
+        }
+        assert!(packets.iter().any(|p| p.has_track_event()));
+        assert!(packets.iter().any(|p| p.sequence_flags() != 0));
+
+        invoke(&mut client, 4, "DisableTracing", &[])?;
+        assert_eq!(receive(&mut client)?.0, 4);
+        let (request, _, reply) = receive(&mut client)?;
+        assert_eq!(request, 2);
+        let Some(Value::Bytes(response)) = field(&reply, 3) else {
+            panic!("no reply");
+        };
+        assert!(matches!(field(response, 1), Some(Value::Varint(1))));
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use perfetto_protos::{
+    interned_data::InternedData, log_message::LogMessageBody, trace_packet::TracePacket,
+};
+use protobuf::MessageField;
+use smol_str::SmolStr;
+
+use crate::{Context, EventBuilder, LogPriority};
+
+impl Context {
+    fn intern_log_body(&mut self, body: SmolStr) -> u64 {
+        let id = self.log_bodies.intern(body.clone());
+        if id.is_new() {
+            let mut tp = TracePacket::new();
+            let mut itd = InternedData::new();
+            itd.log_message_body.push(LogMessageBody {
+                iid: Some(id.as_u64()),
+                body: Some(body.to_string()),
+                ..Default::default()
+            });
+            tp.interned_data = MessageField::some(itd);
+            self.push_interned(tp);
+        }
+        id.into()
+    }
+}
+
+impl<'a> EventBuilder<'a> {
+    /// Attaches a log line to the event, which the UI lists in its logs
+    /// panel. The body is interned, and the event's source location is
+    /// reused for the message.
+    pub fn log_message(&mut self, body: impl Into<SmolStr>, priority: LogPriority) {
+        let body_iid = self.ctx.intern_log_body(body.into());
+        let message = self.event.log_message.mut_or_insert_default();
+        message.set_body_iid(body_iid);
+        message.set_prio(priority);
+    }
+
+    pub fn with_log_message(mut self, body: impl Into<SmolStr>, priority: LogPriority) -> Self {
+        self.log_message(body, priority);
+        self
+    }
+
+    /// Points the log message at the event's source location, whichever
+    /// order the two were set in.
+    pub(crate) fn link_log_source_location(&mut self) {
+        if !self.event.has_source_location_iid() {
+            return;
+        }
+        let iid = self.event.source_location_iid();
+        if let Some(message) = self.event.log_message.as_mut() {
+            message.source_location_iid.get_or_insert(iid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn log_messages_are_interned() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for _ in 0..2 {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_log_message("cache miss", LogPriority::PRIO_WARN)
+                .with_source_location("src/cache.rs", 12)
+                .build();
+        }
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let bodies: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.log_message_body.iter())
+            .map(|b| b.body().to_string())
+            .collect();
+        assert_eq!(bodies, ["cache miss"]);
+
+        let messages: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().log_message.clone().unwrap())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], messages[1]);
+        assert_eq!(messages[0].prio(), LogPriority::PRIO_WARN);
+        assert!(messages[0].has_source_location_iid());
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::Result;
+use perfetto_protos::{trace::Trace, trace_packet::TracePacket};
+use protobuf::Message;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+};
+
+/// Assigns track uuids and flow ids to one input, keeping those that don't
+/// collide.
+struct IdMap {
+    remapped: HashMap<u64, u64>,
+    flows: HashMap<u64, u64>,
+}
+
+impl IdMap {
+    fn get(&self, uuid: u64) -> u64 {
+        self.remapped.get(&uuid).copied().unwrap_or(uuid)
+    }
+
+    fn flow(&self, id: u64) -> u64 {
+        self.flows.get(&id).copied().unwrap_or(id)
+    }
+
+    fn apply(&self, packet: &mut TracePacket) {
+        if !self.flows.is_empty() && packet.has_track_event() {
+            let event = packet.mut_track_event();
+            for id in event
+                .flow_ids
+                .iter_mut()
+                .chain(event.terminating_flow_ids.iter_mut())
+                .chain(event.flow_ids_old.iter_mut())
+                .chain(event.terminating_flow_ids_old.iter_mut())
+            {
+                *id = self.flow(*id);
+            }
+        }
+        if self.remapped.is_empty() {
+            return;
+        }
+        if packet.has_track_descriptor() {
+            let track = packet.mut_track_descriptor();
+            track.set_uuid(self.get(track.uuid()));
+            if track.has_parent_uuid() {
+                track.set_parent_uuid(self.get(track.parent_uuid()));
+            }
+        }
+        if packet.has_track_event() {
+            let event = packet.mut_track_event();
+            if event.has_track_uuid() {
+                event.set_track_uuid(self.get(event.track_uuid()));
+            }
+            for uuid in event
+                .extra_counter_track_uuids
+                .iter_mut()
+                .chain(event.extra_double_counter_track_uuids.iter_mut())
+            {
+                *uuid = self.get(*uuid);
+            }
+        }
+        if let Some(defaults) = packet
+            .trace_packet_defaults
+            .as_mut()
+            .and_then(|d| d.track_event_defaults.as_mut())
+        {
+            if defaults.has_track_uuid() {
+                defaults.set_track_uuid(self.get(defaults.track_uuid()));
+            }
+            for uuid in defaults
+                .extra_counter_track_uuids
+                .iter_mut()
+                .chain(defaults.extra_double_counter_track_uuids.iter_mut())
+            {
+                *uuid = self.get(*uuid);
+            }
+        }
+    }
+}
+
+/// Combines several encoded traces, e.g. from multiple processes or
+/// shards, into one written to `out`.
+///
+/// Each input's packet sequences get sequence ids of their own, so their
+/// interning stays apart. A track uuid an earlier input described
+/// differently is remapped, as is a flow id an earlier input used, so flows
+/// only connect events of the same input. Identical track descriptors and
+/// clock snapshots are written once.
+pub fn merge<R: Read, W: Write>(inputs: impl IntoIterator<Item = R>, out: &mut W) -> Result<()> {
+    let mut merged = Trace::new();
+    let mut next_seq = 0u32;
+    let mut tracks: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut snapshots: HashSet<Vec<u8>> = HashSet::new();
+    let mut flows: HashSet<u64> = HashSet::new();
+
+    for mut input in inputs {
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let mut map = IdMap {
+            remapped: HashMap::new(),
+            flows: HashMap::new(),
+        };
+        for packet in trace.packet.iter().filter(|p| p.has_track_descriptor()) {
+            let track = packet.track_descriptor();
+            let encoded = track.write_to_bytes()?;
+            match tracks.get(&track.uuid()) {
+                Some(existing) if *existing != encoded => {
+                    let mut uuid = track.uuid();
+                    while tracks.contains_key(&uuid) || map.remapped.values().any(|u| *u == uuid) {
+                        uuid = uuid.wrapping_mul(0x9e37_79b9_7f4a_7c15).wrapping_add(1);
+                    }
+                    map.remapped.insert(track.uuid(), uuid);
+                }
+                _ => {}
+            }
+        }
+
+        let own_flows: HashSet<u64> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .flat_map(|p| {
+                let event = p.track_event();
+                event
+                    .flow_ids
+                    .iter()
+                    .chain(&event.terminating_flow_ids)
+                    .chain(&event.flow_ids_old)
+                    .chain(&event.terminating_flow_ids_old)
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        for &id in &own_flows {
+            if flows.contains(&id) {
+                let mut new = id;
+                while flows.contains(&new)
+                    || own_flows.contains(&new)
+                    || map.flows.values().any(|f| *f == new)
+                {
+                    new = new.wrapping_mul(0x9e37_79b9_7f4a_7c15).wrapping_add(1);
+                }
+                map.flows.insert(id, new);
+            }
+        }
+        flows.extend(own_flows.iter().map(|id| map.flow(*id)));
+
+        let mut seqs: HashMap<u32, u32> = HashMap::new();
+        for mut packet in trace.packet {
+            if packet.has_trusted_packet_sequence_id() {
+                let seq = *seqs
+                    .entry(packet.trusted_packet_sequence_id())
+                    .or_insert_with(|| {
+                        next_seq += 1;
+                        next_seq
+                    });
+                packet.set_trusted_packet_sequence_id(seq);
+            }
+            map.apply(&mut packet);
+            // The packet may carry more than the snapshot, e.g. sequence
+            // flags, so only the repeated snapshot is dropped.
+            if packet.has_clock_snapshot()
+                && !snapshots.insert(packet.clock_snapshot().write_to_bytes()?)
+            {
+                packet.clear_clock_snapshot();
+            }
+            if packet.has_track_descriptor() {
+                let track = packet.track_descriptor();
+                let encoded = track.write_to_bytes()?;
+                if tracks.get(&track.uuid()) == Some(&encoded) {
+                    continue;
+                }
+                tracks.insert(track.uuid(), encoded);
+            }
+            merged.packet.push(packet);
+        }
+    }
+
+    merged.write_to_writer(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+
+    fn shard(name: &str) -> Result<Vec<u8>> {
+        let mut ctx = Context::new_with_seq(1);
+        let process = ctx.track().uuid(10).name("server").build();
+        let track = ctx
+            .track()
+            .uuid(200)
+            .name(name)
+            .parent_uuid(process)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_timestamp_ns(1)
+            .with_track_uuid(track)
+            .with_name(name)
+            .with_flow_id(7)
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn conflicting_ids_are_remapped() -> Result<()> {
+        let (a, b) = (shard("a")?, shard("b")?);
+        let mut out = Vec::new();
+        merge([a.as_slice(), b.as_slice()], &mut out)?;
+        let trace = Trace::parse_from_bytes(&out)?;
+
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .collect();
+        let names: Vec<_> = tracks.iter().map(|t| t.name()).collect();
+        assert_eq!(names, ["server", "a", "b"]);
+        assert_eq!(tracks[1].uuid(), 200);
+        assert_ne!(tracks[2].uuid(), 200);
+        assert_eq!(tracks[2].parent_uuid(), 10);
+
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.trusted_packet_sequence_id(), p.track_event().track_uuid()))
+            .collect();
+        assert_eq!(events, [(1, 200), (2, tracks[2].uuid())]);
+
+        let flows: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().flow_ids.clone())
+            .collect();
+        assert_eq!(flows[0], [7]);
+        assert_ne!(flows[1], [7]);
+        Ok(())
+    }
+}
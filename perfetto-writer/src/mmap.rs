@@ -0,0 +1,224 @@
+use nix::sys::mman::{MapFlags, MsFlags, ProtFlags, mmap, msync, munmap};
+use perfetto_core::wire::read_varint;
+use perfetto_protos::trace_packet::TracePacket;
+use protobuf::Message;
+use std::{
+    ffi::c_void,
+    fs::{File, OpenOptions},
+    io,
+    num::NonZeroUsize,
+    path::Path,
+    ptr::NonNull,
+};
+
+use crate::TraceSink;
+
+/// Appends the trace into a memory-mapped file of fixed capacity. Writes
+/// are plain copies into the page cache, so they cost no syscalls, and the
+/// data survives the process crashing.
+///
+/// The file is truncated to what was written when the sink is finalized
+/// or dropped. After a crash it still has its full size; use
+/// [`MmapSink::recover`] to cut it back to its complete packets.
+pub struct MmapSink {
+    file: File,
+    map: NonNull<c_void>,
+    capacity: usize,
+    len: usize,
+    closed: bool,
+}
+
+// SAFETY: the mapping is owned by the sink and only accessed through
+// `&mut self`.
+unsafe impl Send for MmapSink {}
+
+impl MmapSink {
+    /// Creates (or overwrites) `path` with room for `capacity` bytes.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let length = NonZeroUsize::new(capacity)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "capacity is zero"))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(capacity as u64)?;
+        // SAFETY: a fresh shared mapping of a file we sized ourselves.
+        let map = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                &file,
+                0,
+            )
+        }?;
+        Ok(Self {
+            file,
+            map,
+            capacity,
+            len: 0,
+            closed: false,
+        })
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Truncates a file left behind by a crashed process after its last
+    /// complete packet, so it opens as a valid trace. Returns the new length.
+    pub fn recover(path: impl AsRef<Path>) -> io::Result<u64> {
+        let data = std::fs::read(&path)?;
+        let len = complete_packets_len(&data) as u64;
+        OpenOptions::new().write(true).open(path)?.set_len(len)?;
+        Ok(len)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        // SAFETY: the mapping is live until this call and not used after.
+        unsafe {
+            msync(self.map, self.capacity, MsFlags::MS_SYNC)?;
+            munmap(self.map, self.capacity)?;
+        }
+        self.file.set_len(self.len as u64)
+    }
+}
+
+impl TraceSink for MmapSink {
+    fn write_trace(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::other("sink was finalized"));
+        }
+        if bytes.len() > self.capacity - self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "memory-mapped trace file is full",
+            ));
+        }
+        // SAFETY: the range was checked to lie inside the live mapping.
+        unsafe {
+            let dst = self.map.cast::<u8>().as_ptr().add(self.len);
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+        }
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn finalize_trace(&mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for MmapSink {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Length of the prefix of `data` made of whole, decodable `Trace.packet`
+/// fields. The untouched tail of the file is zeros, which never decode, so
+/// a packet torn by the crash is cut along with it.
+fn complete_packets_len(data: &[u8]) -> usize {
+    let mut pos = 0;
+    while data.get(pos) == Some(&0x0a) {
+        let Some((len, varint)) = read_varint(&data[pos + 1..]) else {
+            break;
+        };
+        let start = pos + 1 + varint;
+        let Some(packet) = usize::try_from(len)
+            .ok()
+            .and_then(|len| start.checked_add(len))
+            .and_then(|end| data.get(start..end))
+        else {
+            break;
+        };
+        if TracePacket::parse_from_bytes(packet).is_err() {
+            break;
+        }
+        pos = start + packet.len();
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+
+    fn record(ctx: &mut Context) {
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("mapped")
+            .build();
+    }
+
+    #[test]
+    fn finalized_file_is_a_trace() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("perfetto-mmap-{}.pftrace", std::process::id()));
+        let mut ctx = Context::new();
+        ctx.add_sink(MmapSink::create(&path, 1 << 16)?);
+        record(&mut ctx);
+        ctx.flush_sinks()?;
+        record(&mut ctx);
+        ctx.finalize_sinks()?;
+
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert!(data.len() < 1 << 16);
+        let trace = Trace::parse_from_bytes(&data)?;
+        assert_eq!(
+            trace.packet.iter().filter(|p| p.has_track_event()).count(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn crashed_files_are_recovered() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("perfetto-crash-{}.pftrace", std::process::id()));
+        let mut ctx = Context::new();
+        record(&mut ctx);
+        let mut bytes = Vec::new();
+        ctx.write_to(&mut bytes)?;
+        let mut sink = MmapSink::create(&path, 1 << 12)?;
+        sink.write_trace(&bytes)?;
+        // A torn packet at the end, as if the process died mid-copy.
+        sink.write_trace(&[0x0a, 0x40, 0x08, 0x01])?;
+        assert!(sink.write_trace(&[0; 1 << 12]).is_err());
+        std::mem::forget(sink);
+
+        assert_eq!(std::fs::metadata(&path)?.len(), 1 << 12);
+        assert_eq!(MmapSink::recover(&path)?, bytes.len() as u64);
+        let trace = Trace::parse_from_bytes(&std::fs::read(&path)?)?;
+        std::fs::remove_file(&path)?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_lengths_end_the_complete_packets() {
+        let mut data = vec![0x0a];
+        data.extend([0xff; 9]);
+        data.push(0x01);
+        assert_eq!(complete_packets_len(&data), 0);
+    }
+}
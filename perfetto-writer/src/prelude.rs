@@ -0,0 +1,21 @@
+//! The stable surface of the crate.
+//!
+//! Everything reachable from here follows semver: it only changes in
+//! breaking ways across major versions. Newer APIs are reachable from the
+//! crate root but stay out of the prelude until they settle, and the most
+//! experimental ones need the `unstable` feature.
+//!
+//! ```
+//! use perfetto_writer::prelude::*;
+//!
+//! let mut ctx = Context::new();
+//! let track = ctx.current_thread_track();
+//! ctx.event()
+//!     .with_instant()
+//!     .with_now()
+//!     .with_track_uuid(track)
+//!     .with_name("hello")
+//!     .build();
+//! ```
+
+pub use crate::{Context, CounterUnit, EventBuilder, TrackBuilder};
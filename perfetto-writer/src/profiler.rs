@@ -0,0 +1,375 @@
+use anyhow::{Result, bail};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    ffi::c_void,
+    sync::atomic::{
+        AtomicBool, AtomicU8, AtomicU64, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+
+use crate::{Context, StackFrame, StackMapping};
+
+const MAX_DEPTH: usize = 64;
+const SLOTS: usize = 4096;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const FULL: u8 = 2;
+
+/// A sample captured inside the signal handler. Only raw instruction pointers
+/// are recorded there; symbolization happens later in [`Profiler::drain`].
+/// The first is where the thread was interrupted, the rest are the return
+/// addresses of its callers.
+struct Slot {
+    state: AtomicU8,
+    timestamp_ns: UnsafeCell<u64>,
+    tid: UnsafeCell<i32>,
+    len: UnsafeCell<usize>,
+    ips: UnsafeCell<[usize; MAX_DEPTH]>,
+}
+
+// Access to the cells is serialized by `state`.
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            timestamp_ns: UnsafeCell::new(0),
+            tid: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+            ips: UnsafeCell::new([0; MAX_DEPTH]),
+        }
+    }
+}
+
+static RING: [Slot; SLOTS] = [const { Slot::new() }; SLOTS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static LOST: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigprof(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut c_void) {
+    let errno = unsafe { *libc::__errno_location() };
+    let slot = &RING[NEXT_SLOT.fetch_add(1, Relaxed) % SLOTS];
+    if slot
+        .state
+        .compare_exchange(EMPTY, WRITING, Acquire, Relaxed)
+        .is_err()
+    {
+        LOST.fetch_add(1, Relaxed);
+        return;
+    }
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+        *slot.timestamp_ns.get() = ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64;
+        *slot.tid.get() = libc::syscall(libc::SYS_gettid) as i32;
+        *slot.len.get() = walk_frames(ucontext.cast(), &mut *slot.ips.get());
+        *libc::__errno_location() = errno;
+    }
+    slot.state.store(FULL, Release);
+}
+
+/// The program counter, stack pointer and frame pointer of the interrupted
+/// code.
+#[cfg(target_arch = "x86_64")]
+unsafe fn registers(ucontext: *const libc::ucontext_t) -> (usize, usize, usize) {
+    let gregs = unsafe { &(*ucontext).uc_mcontext.gregs };
+    (
+        gregs[libc::REG_RIP as usize] as usize,
+        gregs[libc::REG_RSP as usize] as usize,
+        gregs[libc::REG_RBP as usize] as usize,
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn registers(ucontext: *const libc::ucontext_t) -> (usize, usize, usize) {
+    let mcontext = unsafe { &(*ucontext).uc_mcontext };
+    (
+        mcontext.pc as usize,
+        mcontext.sp as usize,
+        mcontext.regs[29] as usize,
+    )
+}
+
+/// Follows the frame pointer chain of the interrupted code into `ips`,
+/// innermost first, and returns how many were written.
+///
+/// Only what is async-signal-safe happens here: every frame record is read
+/// with `process_vm_readv`, which fails rather than faults on a bad pointer,
+/// so code built without frame pointers just ends the walk early. Build with
+/// `-C force-frame-pointers=yes` for full stacks.
+unsafe fn walk_frames(ucontext: *const libc::ucontext_t, ips: &mut [usize; MAX_DEPTH]) -> usize {
+    let (pc, sp, mut fp) = unsafe { registers(ucontext) };
+    ips[0] = pc;
+    let mut len = 1;
+    // Callers' frames live at higher addresses than their callees'.
+    let mut lowest = sp;
+    while len < MAX_DEPTH && fp >= lowest && fp % align_of::<usize>() == 0 {
+        // A frame record holds the caller's frame pointer, then the return
+        // address.
+        let mut record = [0usize; 2];
+        if !read_own_memory(fp, &mut record) || record[1] == 0 {
+            break;
+        }
+        ips[len] = record[1];
+        len += 1;
+        lowest = fp + size_of_val(&record);
+        fp = record[0];
+    }
+    len
+}
+
+fn read_own_memory(address: usize, out: &mut [usize; 2]) -> bool {
+    let size = size_of_val(out);
+    let local = libc::iovec {
+        iov_base: out.as_mut_ptr().cast(),
+        iov_len: size,
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut c_void,
+        iov_len: size,
+    };
+    let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+    read == size as isize
+}
+
+/// A `SIGPROF` driven sampling profiler for the current process.
+///
+/// While running, the kernel interrupts whichever thread is burning CPU at
+/// roughly `frequency_hz` and the handler records its raw callstack into a
+/// fixed-size ring. [`Profiler::drain`] symbolizes the pending samples and
+/// writes them to a [`Context`] as `PerfSample` packets, attributed to the
+/// files mapped into the process, which the Perfetto UI renders as a
+/// flamegraph next to the regular slices.
+///
+/// Only one profiler can run per process; stopping happens on drop.
+pub struct Profiler {
+    symbols: HashMap<usize, StackFrame>,
+    mappings: Vec<StackMapping>,
+    previous: SigAction,
+}
+
+impl Profiler {
+    pub fn start(frequency_hz: u32) -> Result<Self> {
+        if frequency_hz == 0 || frequency_hz > 1_000_000 {
+            bail!("sampling frequency must be within 1..=1000000 Hz");
+        }
+        if ACTIVE.swap(true, Acquire) {
+            bail!("a profiler is already running in this process");
+        }
+
+        let action = SigAction::new(
+            SigHandler::SigAction(on_sigprof),
+            SaFlags::SA_RESTART | SaFlags::SA_SIGINFO,
+            SigSet::empty(),
+        );
+        let previous = match unsafe { sigaction(Signal::SIGPROF, &action) } {
+            Ok(previous) => previous,
+            Err(e) => {
+                ACTIVE.store(false, Release);
+                return Err(e.into());
+            }
+        };
+
+        let profiler = Self {
+            symbols: HashMap::new(),
+            mappings: read_mappings(),
+            previous,
+        };
+        let period_us = 1_000_000 / frequency_hz as libc::suseconds_t;
+        set_timer(period_us)?;
+        Ok(profiler)
+    }
+
+    /// Number of samples discarded because the ring was full.
+    pub fn lost(&self) -> u64 {
+        LOST.load(Relaxed)
+    }
+
+    /// Writes every pending sample to `ctx` and returns how many were written.
+    pub fn drain(&mut self, ctx: &mut Context) -> usize {
+        let mut pending = Vec::new();
+        for slot in RING.iter() {
+            if slot.state.load(Acquire) != FULL {
+                continue;
+            }
+            let (ts, tid, ips) = unsafe {
+                let len = *slot.len.get();
+                (
+                    *slot.timestamp_ns.get(),
+                    *slot.tid.get(),
+                    (&*slot.ips.get())[..len].to_vec(),
+                )
+            };
+            slot.state.store(EMPTY, Release);
+            pending.push((ts, tid, ips));
+        }
+        pending.sort_by_key(|(ts, _, _)| *ts);
+
+        for (ts, tid, ips) in &pending {
+            let frames = self.symbolize(ips);
+            ctx.perf_sample(*ts, *tid, &frames);
+        }
+        pending.len()
+    }
+
+    fn symbolize(&mut self, ips: &[usize]) -> Vec<StackFrame> {
+        ips.iter().map(|ip| self.resolve(*ip)).collect()
+    }
+
+    fn resolve(&mut self, ip: usize) -> StackFrame {
+        if let Some(frame) = self.symbols.get(&ip) {
+            return frame.clone();
+        }
+        let mut name = None;
+        backtrace::resolve(ip as *mut c_void, |symbol| {
+            if name.is_none() {
+                name = symbol.name().map(|n| format!("{:#}", n));
+            }
+        });
+        let name = name.unwrap_or_else(|| format!("{:#x}", ip));
+        let frame = match self.mapping(ip as u64) {
+            Some(mapping) => StackFrame::new(name, ip as u64 - mapping.start + mapping.offset)
+                .with_mapping(mapping),
+            None => StackFrame::new(name, ip as u64),
+        };
+        self.symbols.insert(ip, frame.clone());
+        frame
+    }
+
+    /// The mapping `ip` falls in, rereading the mappings once if it is in
+    /// none of them, e.g. in a library loaded since.
+    fn mapping(&mut self, ip: u64) -> Option<StackMapping> {
+        let find = |mappings: &[StackMapping]| {
+            mappings
+                .iter()
+                .find(|m| (m.start..m.end).contains(&ip))
+                .cloned()
+        };
+        find(&self.mappings).or_else(|| {
+            self.mappings = read_mappings();
+            find(&self.mappings)
+        })
+    }
+}
+
+/// The executable mappings listed in `/proc/self/maps`.
+fn read_mappings() -> Vec<StackMapping> {
+    let Ok(maps) = std::fs::read_to_string("/proc/self/maps") else {
+        return Vec::new();
+    };
+    maps.lines()
+        .filter_map(|line| {
+            // address perms offset dev inode [path]
+            let mut fields = line.splitn(6, ' ');
+            let (range, perms, offset) = (fields.next()?, fields.next()?, fields.next()?);
+            if !perms.contains('x') {
+                return None;
+            }
+            let path = fields.nth(2).map(str::trim_start).unwrap_or_default();
+            let (start, end) = range.split_once('-')?;
+            Some(StackMapping {
+                path: if path.is_empty() { "[anon]" } else { path }.into(),
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+            })
+        })
+        .collect()
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let _ = set_timer(0);
+        let _ = unsafe { sigaction(Signal::SIGPROF, &self.previous) };
+        ACTIVE.store(false, Release);
+    }
+}
+
+// Not exposed by the `libc` crate on every target.
+unsafe extern "C" {
+    fn setitimer(
+        which: libc::c_int,
+        new: *const libc::itimerval,
+        old: *mut libc::itimerval,
+    ) -> libc::c_int;
+}
+
+fn set_timer(period_us: libc::suseconds_t) -> Result<()> {
+    let interval = libc::timeval {
+        tv_sec: period_us / 1_000_000,
+        tv_usec: period_us % 1_000_000,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { setitimer(libc::ITIMER_PROF, &timer, std::ptr::null_mut()) } != 0 {
+        bail!("setitimer failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn samples_a_busy_thread() -> Result<()> {
+        let mut profiler = Profiler::start(1000)?;
+        assert!(Profiler::start(1000).is_err());
+
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let mut acc = 0u64;
+        while Instant::now() < deadline {
+            acc = acc.wrapping_mul(31).wrapping_add(7);
+        }
+        std::hint::black_box(acc);
+
+        let mut buf = Vec::new();
+        let mut ctx = Context::new();
+        let written = profiler.drain(&mut ctx);
+        drop(profiler);
+        assert!(written > 0);
+
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        assert_eq!(
+            trace.packet.iter().filter(|p| p.has_perf_sample()).count(),
+            written
+        );
+        let test_binary = std::env::current_exe()?;
+        let paths: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.mapping_paths.iter())
+            .map(|p| String::from_utf8_lossy(p.str()).into_owned())
+            .collect();
+        assert!(paths.iter().any(|p| test_binary.ends_with(p)), "{paths:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn reads_executable_mappings() {
+        let mappings = read_mappings();
+        let ip = reads_executable_mappings as *const () as usize as u64;
+        let own = mappings
+            .iter()
+            .find(|m| (m.start..m.end).contains(&ip))
+            .unwrap();
+        assert!(own.path.starts_with('/'), "{own:?}");
+    }
+}
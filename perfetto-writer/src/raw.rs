@@ -0,0 +1,131 @@
+use anyhow::{Result, bail};
+use perfetto_protos::trace_packet::TracePacket;
+use protobuf::Message;
+
+use crate::Context;
+
+impl Context {
+    /// Buffers a packet built by hand from the [`protos`](crate::protos)
+    /// types, for data the builders do not cover. The packet is written on
+    /// this context's sequence unless it sets its own
+    /// `trusted_packet_sequence_id`.
+    ///
+    /// Interned ids and track uuids the packet refers to must exist on the
+    /// sequence; nothing is checked until you run [`validate`](crate::validate).
+    pub fn write_raw_packet(&mut self, packet: TracePacket) {
+        self.push_packet(packet);
+    }
+
+    /// Like [`Context::write_raw_packet`] for a `TracePacket` encoded
+    /// elsewhere, e.g. by a custom data source. Fails if `bytes` is not a
+    /// valid packet.
+    ///
+    /// Packets that carry interned data, refer to interned ids or set
+    /// incremental-state flags are rejected unless they set their own
+    /// `trusted_packet_sequence_id`: on this context's sequence they would
+    /// clash with the ids and state it interns itself.
+    pub fn inject_packet(&mut self, bytes: &[u8]) -> Result<()> {
+        let packet = TracePacket::parse_from_bytes(bytes)?;
+        let own_sequence = !packet.has_trusted_packet_sequence_id()
+            || packet.trusted_packet_sequence_id() == self.seq;
+        if own_sequence && uses_incremental_state(&packet) {
+            bail!(
+                "injected packets with interned data or incremental-state flags \
+                 need their own trusted_packet_sequence_id"
+            );
+        }
+        self.write_raw_packet(packet);
+        Ok(())
+    }
+}
+
+/// Whether `packet` reads or resets the interning state of its sequence.
+fn uses_incremental_state(packet: &TracePacket) -> bool {
+    if packet.interned_data.is_some()
+        || packet.has_sequence_flags()
+        || packet.has_incremental_state_cleared()
+        || packet.has_first_packet_on_sequence()
+    {
+        return true;
+    }
+    if !packet.has_track_event() {
+        return false;
+    }
+    let event = packet.track_event();
+    event.has_name_iid()
+        || !event.category_iids.is_empty()
+        || event.has_source_location_iid()
+        || event.debug_annotations.iter().any(|a| a.has_name_iid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::TrackEvent};
+
+    #[test]
+    fn raw_packets_join_the_sequence() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        let mut event = TrackEvent::new();
+        event.set_track_uuid(track);
+        event.set_name("handmade".to_string());
+        let mut packet = TracePacket::new();
+        packet.set_timestamp(42);
+        packet.set_track_event(event);
+        ctx.write_raw_packet(packet);
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let packet = trace.packet.last().unwrap();
+        assert_eq!(packet.track_event().name(), "handmade");
+        assert_eq!(packet.timestamp(), 42);
+        assert_eq!(
+            packet.trusted_packet_sequence_id(),
+            trace.packet[0].trusted_packet_sequence_id()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn injected_packets_are_buffered() -> Result<()> {
+        let mut ctx = Context::new();
+        let mut packet = TracePacket::new();
+        packet.set_timestamp(7);
+        packet.set_trusted_packet_sequence_id(99);
+        let bytes = packet.write_to_bytes()?;
+        let before = ctx.buffered_bytes();
+        ctx.inject_packet(&bytes)?;
+        assert!(ctx.buffered_bytes() > before);
+        assert!(ctx.inject_packet(&[0xff]).is_err());
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let packet = trace.packet.last().unwrap();
+        assert_eq!(packet.timestamp(), 7);
+        assert_eq!(packet.trusted_packet_sequence_id(), 99);
+        Ok(())
+    }
+
+    #[test]
+    fn injected_incremental_state_needs_its_own_sequence() -> Result<()> {
+        let mut ctx = Context::new();
+        let mut event = TrackEvent::new();
+        event.set_name_iid(1);
+        let mut packet = TracePacket::new();
+        packet.set_track_event(event);
+        assert!(ctx.inject_packet(&packet.write_to_bytes()?).is_err());
+
+        let mut cleared = TracePacket::new();
+        cleared.set_sequence_flags(1);
+        assert!(ctx.inject_packet(&cleared.write_to_bytes()?).is_err());
+
+        packet.set_trusted_packet_sequence_id(99);
+        ctx.inject_packet(&packet.write_to_bytes()?)?;
+        cleared.set_trusted_packet_sequence_id(99);
+        ctx.inject_packet(&cleared.write_to_bytes()?)?;
+        Ok(())
+    }
+}
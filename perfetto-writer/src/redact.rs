@@ -0,0 +1,389 @@
+use perfetto_protos::{
+    debug_annotation::DebugAnnotation, source_location::SourceLocation, trace::Trace,
+    trace_packet::trace_packet::SequenceFlags,
+    track_descriptor::track_descriptor::Static_or_dynamic_name as TrackName,
+};
+use siphasher::sip::SipHasher13;
+use std::{
+    collections::{HashMap, HashSet, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+};
+
+/// Interned ids are scoped to a sequence and reset with its incremental
+/// state, so they are keyed by sequence, reset count and iid.
+type Iid = (u32, u32, u64);
+
+/// Rewrites user data in a parsed trace so it can be shared, e.g. attached
+/// to a public bug report.
+///
+/// Matching strings are replaced by a keyed hash, so equal values still
+/// compare equal in the redacted trace but can't be recovered by hashing
+/// guesses. The key is random unless [`salt`](Redactor::salt) fixes it.
+/// Patterns are matched against the whole string, with `*` standing for any
+/// run of characters.
+///
+/// ```
+/// use perfetto_writer::{Redactor, protos::trace::Trace};
+///
+/// let mut trace = Trace::new();
+/// Redactor::new()
+///     .annotation("user.*")
+///     .event_name("GET /users/*")
+///     .log_body("login failed for *")
+///     .file_paths()
+///     .redact(&mut trace);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    salt: u128,
+    annotations: Vec<String>,
+    event_names: Vec<String>,
+    log_bodies: Vec<String>,
+    file_paths: bool,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        let random = || RandomState::new().build_hasher().finish();
+        Self {
+            salt: (u128::from(random()) << 64) | u128::from(random()),
+            annotations: Vec::new(),
+            event_names: Vec::new(),
+            log_bodies: Vec::new(),
+            file_paths: false,
+        }
+    }
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keys the hashes with `salt` instead of a random key, so traces
+    /// redacted separately with the same salt still match up. Keep it
+    /// secret: anyone with it can hash guesses and compare.
+    pub fn salt(mut self, salt: u128) -> Self {
+        self.salt = salt;
+        self
+    }
+
+    /// Hashes the string values of annotations whose name matches
+    /// `pattern`, including the entries nested under them.
+    pub fn annotation(mut self, pattern: impl Into<String>) -> Self {
+        self.annotations.push(pattern.into());
+        self
+    }
+
+    /// Hashes event and track names matching `pattern`.
+    pub fn event_name(mut self, pattern: impl Into<String>) -> Self {
+        self.event_names.push(pattern.into());
+        self
+    }
+
+    /// Hashes log messages matching `pattern`.
+    pub fn log_body(mut self, pattern: impl Into<String>) -> Self {
+        self.log_bodies.push(pattern.into());
+        self
+    }
+
+    /// Strips the directories from source file paths, keeping the file
+    /// name, e.g. `/home/alice/app/src/db.rs` becomes `db.rs`.
+    pub fn file_paths(mut self) -> Self {
+        self.file_paths = true;
+        self
+    }
+
+    fn hash(&self, value: &[u8]) -> String {
+        // SipHash, which is stable across builds unlike `DefaultHasher`.
+        let mut hasher = SipHasher13::new_with_key(&self.salt.to_le_bytes());
+        hasher.write(value);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn annotation_matches(&self, name: &str) -> bool {
+        self.annotations.iter().any(|p| glob(p, name))
+    }
+
+    fn rename(&self, name: &mut String) {
+        if self.event_names.iter().any(|p| glob(p, name)) {
+            *name = self.hash(name.as_bytes());
+        }
+    }
+
+    fn redact_log_body(&self, body: &mut String) {
+        if self.log_bodies.iter().any(|p| glob(p, body)) {
+            *body = self.hash(body.as_bytes());
+        }
+    }
+
+    fn source_location(&self, location: &mut SourceLocation) {
+        if self.file_paths && location.has_file_name() {
+            let path = location.file_name();
+            let file = path.rsplit(['/', '\\']).next().unwrap_or(path);
+            location.set_file_name(file.to_string());
+        }
+    }
+
+    /// Rewrites `trace` in place.
+    pub fn redact(&self, trace: &mut Trace) {
+        // Interned annotation values are written before the events using
+        // them, so find the ones used under a matching name first.
+        let mut resets: HashMap<u32, u32> = HashMap::new();
+        let mut names: HashMap<Iid, String> = HashMap::new();
+        let mut values: HashSet<Iid> = HashSet::new();
+        for packet in &trace.packet {
+            let seq = packet.trusted_packet_sequence_id();
+            let reset = resets.entry(seq).or_default();
+            if packet.sequence_flags() & SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32 != 0 {
+                *reset += 1;
+            }
+            let scope = (seq, *reset);
+            if let Some(data) = packet.interned_data.as_ref() {
+                for name in &data.debug_annotation_names {
+                    names.insert((scope.0, scope.1, name.iid()), name.name().to_string());
+                }
+            }
+            if packet.has_track_event() {
+                for annotation in &packet.track_event().debug_annotations {
+                    self.find_values(annotation, scope, false, &names, &mut values);
+                }
+            }
+        }
+
+        resets.clear();
+        for packet in &mut trace.packet {
+            let seq = packet.trusted_packet_sequence_id();
+            let reset = resets.entry(seq).or_default();
+            if packet.sequence_flags() & SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32 != 0 {
+                *reset += 1;
+            }
+            let scope = (seq, *reset);
+            if let Some(data) = packet.interned_data.as_mut() {
+                for name in &mut data.event_names {
+                    if let Some(name) = name.name.as_mut() {
+                        self.rename(name);
+                    }
+                }
+                for value in &mut data.debug_annotation_string_values {
+                    if values.contains(&(scope.0, scope.1, value.iid())) {
+                        let hashed = self.hash(value.str());
+                        value.set_str(hashed.into_bytes());
+                    }
+                }
+                for location in &mut data.source_locations {
+                    self.source_location(location);
+                }
+                for body in &mut data.log_message_body {
+                    if let Some(body) = body.body.as_mut() {
+                        self.redact_log_body(body);
+                    }
+                }
+            }
+            if packet.has_track_descriptor()
+                && let Some(
+                    TrackName::Name(name)
+                    | TrackName::StaticName(name)
+                    | TrackName::AtraceName(name),
+                ) = &mut packet.mut_track_descriptor().static_or_dynamic_name
+            {
+                self.rename(name);
+            }
+            if packet.has_track_event() {
+                let event = packet.mut_track_event();
+                if event.has_name() {
+                    let mut name = event.take_name();
+                    self.rename(&mut name);
+                    event.set_name(name);
+                }
+                if event.has_source_location() {
+                    self.source_location(event.mut_source_location());
+                }
+                for annotation in &mut event.debug_annotations {
+                    self.redact_inline(annotation, scope, false, &names);
+                }
+            }
+        }
+    }
+
+    fn name<'a>(
+        annotation: &'a DebugAnnotation,
+        scope: (u32, u32),
+        names: &'a HashMap<Iid, String>,
+    ) -> &'a str {
+        if annotation.has_name_iid() {
+            names
+                .get(&(scope.0, scope.1, annotation.name_iid()))
+                .map_or("", String::as_str)
+        } else {
+            annotation.name()
+        }
+    }
+
+    fn find_values(
+        &self,
+        annotation: &DebugAnnotation,
+        scope: (u32, u32),
+        parent: bool,
+        names: &HashMap<Iid, String>,
+        values: &mut HashSet<Iid>,
+    ) {
+        let matched = parent || self.annotation_matches(Self::name(annotation, scope, names));
+        if matched && annotation.has_string_value_iid() {
+            values.insert((scope.0, scope.1, annotation.string_value_iid()));
+        }
+        for child in annotation
+            .dict_entries
+            .iter()
+            .chain(annotation.array_values.iter())
+        {
+            self.find_values(child, scope, matched, names, values);
+        }
+    }
+
+    fn redact_inline(
+        &self,
+        annotation: &mut DebugAnnotation,
+        scope: (u32, u32),
+        parent: bool,
+        names: &HashMap<Iid, String>,
+    ) {
+        let matched = parent || self.annotation_matches(Self::name(annotation, scope, names));
+        if matched && annotation.has_string_value() {
+            let hashed = self.hash(annotation.string_value().as_bytes());
+            annotation.set_string_value(hashed);
+        }
+        for child in annotation
+            .dict_entries
+            .iter_mut()
+            .chain(annotation.array_values.iter_mut())
+        {
+            self.redact_inline(child, scope, matched, names);
+        }
+    }
+}
+
+/// Whether `pattern` matches all of `s`, with `*` matching any run of
+/// characters.
+fn glob(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, LogPriority};
+    use anyhow::Result;
+    use protobuf::Message;
+
+    #[test]
+    fn globs() {
+        assert!(glob("user.*", "user.email"));
+        assert!(glob("*", ""));
+        assert!(glob("a*c*e", "abcde"));
+        assert!(!glob("a*c*e", "abcd"));
+        assert!(!glob("user", "user.email"));
+        assert!(!glob("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn matching_strings_are_hashed() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.create_track("GET /users/alice");
+        for user in ["alice", "bob", "alice"] {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name(format!("GET /users/{user}"))
+                .with_debug_str("user.name", user)
+                .with_debug_str("route", "/users")
+                .with_source_location("/home/alice/app/src/db.rs", 7)
+                .with_log_message(format!("login failed for {user}"), LogPriority::PRIO_WARN)
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let mut trace = Trace::parse_from_bytes(&buf)?;
+
+        Redactor::new()
+            .salt(42)
+            .annotation("user.*")
+            .event_name("GET /users/*")
+            .log_body("login failed for *")
+            .file_paths()
+            .redact(&mut trace);
+
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().name())
+            .collect();
+        assert!(tracks.iter().all(|t| !t.contains("alice")), "{tracks:?}");
+        let data: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .collect();
+        let names: Vec<_> = data
+            .iter()
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name())
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|n| n.len() == 16 && !n.contains('/')));
+        let values: Vec<_> = data
+            .iter()
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| String::from_utf8_lossy(s.str()).into_owned())
+            .collect();
+        assert_eq!(values.len(), 3);
+        assert!(!values.contains(&"alice".to_string()));
+        assert!(values.contains(&"/users".to_string()));
+        let files: Vec<_> = data
+            .iter()
+            .flat_map(|i| i.source_locations.iter())
+            .map(|l| l.file_name())
+            .collect();
+        assert_eq!(files, ["db.rs"]);
+        let bodies: Vec<_> = data
+            .iter()
+            .flat_map(|i| i.log_message_body.iter())
+            .map(|b| b.body())
+            .collect();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies.iter().all(|b| b.len() == 16), "{bodies:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_are_keyed() {
+        let hash = |redactor: &Redactor| redactor.hash(b"alice");
+        assert_eq!(
+            hash(&Redactor::new().salt(1)),
+            hash(&Redactor::new().salt(1))
+        );
+        assert_ne!(
+            hash(&Redactor::new().salt(1)),
+            hash(&Redactor::new().salt(2))
+        );
+        assert_ne!(hash(&Redactor::new()), hash(&Redactor::new()));
+    }
+}
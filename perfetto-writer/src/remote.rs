@@ -0,0 +1,199 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use crate::{TraceSink, session};
+
+/// Frames larger than this are refused, so a bad peer can't make the
+/// collector allocate without bound.
+const MAX_FRAME: usize = 64 << 20;
+
+fn write_frame(w: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+/// Reads the next frame, or `None` at a clean end of stream.
+fn read_frame(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(header) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes"),
+        ));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Streams the trace to a [`Collector`] on another machine.
+///
+/// The protocol is a stream of frames, each a little endian `u32` length
+/// and its payload. The first names the sending host and every other one
+/// holds whole encoded packets, as handed to the sink.
+pub struct RemoteSink {
+    stream: BufWriter<TcpStream>,
+}
+
+impl RemoteSink {
+    /// Connects to the collector at `addr`, naming this machine by its
+    /// hostname.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let host = session::hostname().unwrap_or_else(|| "unknown".into());
+        Self::connect_as(addr, &host)
+    }
+
+    /// Connects to the collector at `addr` as `host`, e.g. to tell apart
+    /// several processes on one machine.
+    pub fn connect_as(addr: impl ToSocketAddrs, host: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut stream = BufWriter::new(stream);
+        write_frame(&mut stream, host.as_bytes())?;
+        Ok(Self { stream })
+    }
+}
+
+impl TraceSink for RemoteSink {
+    fn write_trace(&mut self, bytes: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.stream, bytes)
+    }
+
+    fn flush_trace(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Receives traces from [`RemoteSink`]s and writes each connection to a
+/// `.pftrace` file named after its host in one directory.
+///
+/// A host that connects again, e.g. after a restart, gets a numbered file
+/// next to the first, e.g. `web-3.1.pftrace`; `merge` combines them.
+pub struct Collector {
+    listener: TcpListener,
+    dir: PathBuf,
+    taken: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Collector {
+    pub fn bind(addr: impl ToSocketAddrs, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            dir: dir.into(),
+            taken: Arc::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections forever, writing each from its own thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let dir = self.dir.clone();
+            let taken = Arc::clone(&self.taken);
+            // A broken connection keeps what it sent and ends on its own.
+            thread::spawn(move || receive(stream, &dir, &taken));
+        }
+        Ok(())
+    }
+}
+
+/// Writes one connection's trace, returning its file.
+fn receive(
+    mut stream: TcpStream,
+    dir: &Path,
+    taken: &Mutex<HashSet<PathBuf>>,
+) -> io::Result<PathBuf> {
+    let Some(host) = read_frame(&mut stream)? else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    let host: String = String::from_utf8_lossy(&host)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let path = {
+        let mut taken = taken.lock().unwrap_or_else(PoisonError::into_inner);
+        let path = (0..)
+            .map(|i| match i {
+                0 => dir.join(format!("{host}.pftrace")),
+                i => dir.join(format!("{host}.{i}.pftrace")),
+            })
+            .find(|p| !taken.contains(p) && !p.exists())
+            .expect("some file name is free");
+        taken.insert(path.clone());
+        path
+    };
+    let mut file = BufWriter::new(File::create(&path)?);
+    while let Some(packets) = read_frame(&mut stream)? {
+        file.write_all(&packets)?;
+        Write::flush(&mut file)?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn hosts_get_their_own_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("perfetto-remote-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let collector = Collector::bind("127.0.0.1:0", &dir)?;
+        let addr = collector.local_addr()?;
+        let taken = Arc::clone(&collector.taken);
+
+        let mut receivers = Vec::new();
+        for (host, events) in [("web-1", 2), ("db/1", 3)] {
+            let mut ctx = Context::new();
+            ctx.add_sink(RemoteSink::connect_as(addr, host)?);
+            let track = ctx.current_thread_track();
+            for _ in 0..events {
+                ctx.event()
+                    .with_instant()
+                    .with_now()
+                    .with_track_uuid(track)
+                    .with_name("tick")
+                    .build();
+                ctx.flush_sinks()?;
+            }
+            ctx.finalize_sinks()?;
+            drop(ctx);
+            let (stream, _) = collector.listener.accept()?;
+            receivers.push((events, receive(stream, &dir, &taken)?));
+        }
+
+        for (events, path) in receivers {
+            let trace = Trace::parse_from_bytes(&std::fs::read(&path)?)?;
+            let count = trace.packet.iter().filter(|p| p.has_track_event()).count();
+            assert_eq!(count, events);
+        }
+        assert!(dir.join("web-1.pftrace").exists());
+        assert!(dir.join("db_1.pftrace").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use crate::{Context, EventBuilder, TrackUuid};
+
+/// How widely an instant event applies, and so where the UI draws it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum InstantScope {
+    /// Drawn on the event's own track.
+    #[default]
+    Thread,
+    /// Drawn on the process track, e.g. a GC pause.
+    Process,
+    /// Drawn on a global track spanning the trace, e.g. a deploy marker.
+    Global,
+}
+
+impl FromStr for InstantScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(InstantScope::Thread),
+            "process" => Ok(InstantScope::Process),
+            "global" => Ok(InstantScope::Global),
+            _ => Err(format!("unknown instant scope {:?}", s)),
+        }
+    }
+}
+
+impl Context {
+    /// The track that instants with `scope` are moved to, or `None` for
+    /// thread scoped instants which stay on their own track.
+    pub(crate) fn scope_track(&mut self, scope: InstantScope) -> Option<TrackUuid> {
+        if scope == InstantScope::Thread {
+            return None;
+        }
+        if let Some(track) = self.scope_tracks.get(&scope) {
+            return Some(*track);
+        }
+        let mut builder = self.track();
+        match scope {
+            InstantScope::Process => builder
+                .track
+                .process
+                .mut_or_insert_default()
+                .set_pid(crate::current_pid() as i32),
+            _ => builder.track.set_name("Global".to_string()),
+        }
+        let track = builder.build();
+        self.scope_tracks.insert(scope, track);
+        Some(track)
+    }
+}
+
+impl<'a> EventBuilder<'a> {
+    /// Marks the event as an instant with `scope`. Process and global
+    /// instants are moved off the event's track so they render across the
+    /// whole process or trace.
+    pub fn instant_scope(&mut self, scope: InstantScope) {
+        self.instant();
+        self.scope = Some(scope);
+    }
+
+    pub fn with_instant_scope(mut self, scope: InstantScope) -> Self {
+        self.instant_scope(scope);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[test]
+    fn scoped_instants_move_tracks() -> Result<()> {
+        let mut ctx = Context::new();
+        let thread = ctx.current_thread_track();
+        for scope in [
+            InstantScope::Thread,
+            InstantScope::Process,
+            InstantScope::Global,
+            InstantScope::Global,
+        ] {
+            ctx.event()
+                .with_track_uuid(thread)
+                .with_instant_scope(scope)
+                .with_name("marker")
+                .build();
+        }
+        ctx.event()
+            .with_instant_scope(InstantScope::Global)
+            .with_name("no track")
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .collect();
+        let process = tracks.iter().find(|t| t.process.is_some()).unwrap();
+        assert_eq!(process.process.pid(), std::process::id() as i32);
+        let global = tracks.iter().find(|t| t.name() == "Global").unwrap();
+
+        let placed: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .inspect(|p| assert_eq!(p.track_event().type_(), Type::TYPE_INSTANT))
+            .map(|p| p.track_event().track_uuid())
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                thread,
+                process.uuid(),
+                global.uuid(),
+                global.uuid(),
+                global.uuid()
+            ]
+        );
+        assert_eq!("global".parse(), Ok(InstantScope::Global));
+        assert!("galaxy".parse::<InstantScope>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use perfetto_protos::trace_packet::TracePacket;
+use std::{collections::HashSet, io::Write};
+use web_time::Instant;
+
+use crate::{Context, TrackUuid};
+
+impl Context {
+    /// Writes everything recorded so far and starts a new segment that can be
+    /// loaded on its own.
+    ///
+    /// Interned data is cleared and the clock snapshot and every track
+    /// descriptor are emitted again, so continuous services can cut their
+    /// trace into e.g. hourly files without losing the events in between.
+    /// Track uuids stay valid across segments, unless
+    /// [released](Context::release_track).
+    pub fn rotate<W: Write>(&mut self, w: &mut W) -> Result<()> {
+        self.write_to(w)?;
+        self.start_segment();
+        Ok(())
+    }
+
+    /// Like [`Context::rotate`], but the finished segment goes to the
+    /// [sinks](Context::add_sink). Without sinks it is discarded.
+    pub fn rotate_sinks(&mut self) -> Result<()> {
+        let result = if self.sinks.is_empty() {
+            let discarded = self.take_trace();
+            self.record_dropped(discarded.packet.len() as u64);
+            Ok(())
+        } else {
+            self.flush_sinks()
+        };
+        self.start_segment();
+        result
+    }
+
+    /// Stops describing `track`, and the tracks nested in it, in later
+    /// segments. Since every track is described again in each segment,
+    /// release the ones made for short-lived work, e.g. a task or an actor,
+    /// so a long-running context doesn't accumulate them.
+    pub fn release_track(&mut self, track: TrackUuid) {
+        // Parents are created before their children, so one pass finds the
+        // nested tracks too.
+        let mut released = HashSet::from([track]);
+        self.tracks.retain(|t| {
+            let gone = released.contains(&t.uuid())
+                || (t.has_parent_uuid() && released.contains(&t.parent_uuid()));
+            if gone {
+                released.insert(t.uuid());
+            }
+            !gone
+        });
+    }
+
+    /// Makes the next packets a segment of their own, after the buffer was
+    /// written.
+    pub(crate) fn start_segment(&mut self) {
+        self.reset_interning();
+
+        let mut init = self.init_packet();
+        init.set_clock_snapshot(self.clock_state());
+        self.last_clock_snapshot = Some(Instant::now());
+        self.push_packet(init);
+        if self.system_info_written {
+            self.write_system_info();
+        }
+        for track in self.tracks.clone() {
+            let mut tp = TracePacket::new();
+            tp.set_track_descriptor(track);
+            self.push_packet(tp);
+        }
+        if self.delta_base.is_some() {
+            self.set_delta_timestamps(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    fn record(ctx: &mut Context, track: u64) {
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("tick")
+            .build();
+    }
+
+    #[test]
+    fn segments_are_self_contained() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.track().name("worker").build();
+        record(&mut ctx, track);
+
+        let mut first = Vec::new();
+        ctx.rotate(&mut first)?;
+        record(&mut ctx, track);
+        let mut second = Vec::new();
+        ctx.write_to(&mut second)?;
+
+        for segment in [first, second] {
+            let trace = Trace::parse_from_bytes(&segment)?;
+            let head = &trace.packet[0];
+            assert_ne!(head.sequence_flags() & 1, 0);
+            assert!(head.has_clock_snapshot());
+            assert!(
+                trace
+                    .packet
+                    .iter()
+                    .any(|p| p.has_track_descriptor() && p.track_descriptor().uuid() == track)
+            );
+            let names: Vec<_> = trace
+                .packet
+                .iter()
+                .filter_map(|p| p.interned_data.as_ref())
+                .flat_map(|i| i.event_names.iter())
+                .map(|n| (n.iid(), n.name().to_string()))
+                .collect();
+            assert_eq!(names, [(1, "tick".to_string())]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn released_tracks_are_not_described_again() -> Result<()> {
+        let mut ctx = Context::new();
+        let task = ctx.create_track("task");
+        let child = ctx.create_child_track(task, "step");
+        let kept = ctx.create_track("kept");
+        record(&mut ctx, child);
+        ctx.release_track(task);
+
+        let mut first = Vec::new();
+        ctx.rotate(&mut first)?;
+        let mut second = Vec::new();
+        ctx.write_to(&mut second)?;
+        let described = |segment: &[u8]| -> Result<Vec<u64>> {
+            Ok(Trace::parse_from_bytes(segment)?
+                .packet
+                .iter()
+                .filter(|p| p.has_track_descriptor())
+                .map(|p| p.track_descriptor().uuid())
+                .collect())
+        };
+        assert_eq!(described(&first)?, [task, child, kept]);
+        assert_eq!(described(&second)?, [kept]);
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::Result;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+};
+
+use crate::Context;
+
+/// The port ui.perfetto.dev is allowed to fetch traces from.
+pub const UI_PORT: u16 = 9001;
+
+pub(crate) const UI_ORIGIN: &str = "https://ui.perfetto.dev";
+
+/// The path the trace is served on.
+const TRACE_PATH: &str = "/trace.pftrace";
+
+/// A link that opens the trace served on `port` in ui.perfetto.dev.
+pub fn ui_link(port: u16) -> String {
+    format!("{UI_ORIGIN}/#!/?url=http://127.0.0.1:{port}{TRACE_PATH}")
+}
+
+/// Serves the buffered trace on `127.0.0.1:port` from a background thread,
+/// returning a link that opens it in ui.perfetto.dev and the thread, which
+/// finishes once the UI has fetched the trace.
+///
+/// The UI only fetches from [`UI_PORT`], so use that unless you're running
+/// your own UI.
+pub fn serve(ctx: &mut Context, port: u16) -> Result<(String, JoinHandle<Result<()>>)> {
+    let mut trace = Vec::new();
+    ctx.write_to(&mut trace)?;
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let link = ui_link(listener.local_addr()?.port());
+    Ok((link, thread::spawn(move || serve_on(&listener, &trace))))
+}
+
+fn serve_on(listener: &TcpListener, trace: &[u8]) -> Result<()> {
+    for stream in listener.incoming() {
+        if respond(stream?, trace)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Answers one request, returning whether it fetched the trace.
+fn respond(mut stream: TcpStream, trace: &[u8]) -> Result<bool> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut request = request.split_whitespace();
+    let method = request.next().unwrap_or_default();
+    let path = request.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let cors = format!(
+        "Access-Control-Allow-Origin: {UI_ORIGIN}\r\n\
+         Access-Control-Allow-Methods: GET, OPTIONS\r\n\
+         Access-Control-Allow-Headers: *\r\n"
+    );
+    match method {
+        _ if path != TRACE_PATH => {
+            write!(
+                stream,
+                "HTTP/1.1 404 Not Found\r\n{cors}Content-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            Ok(false)
+        }
+        "GET" => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n{cors}Content-Type: application/octet-stream\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                trace.len()
+            )?;
+            stream.write_all(trace)?;
+            stream.flush()?;
+            Ok(true)
+        }
+        "OPTIONS" => {
+            write!(
+                stream,
+                "HTTP/1.1 204 No Content\r\n{cors}Content-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            Ok(false)
+        }
+        _ => {
+            write!(
+                stream,
+                "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::io::Read;
+
+    fn request(port: u16, method: &str, path: &str) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nOrigin: {UI_ORIGIN}\r\n\r\n"
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn serves_the_trace_with_cors() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("tick")
+            .build();
+        let (link, server) = serve(&mut ctx, 0)?;
+        let port: u16 = link
+            .trim_end_matches(TRACE_PATH)
+            .rsplit(':')
+            .next()
+            .unwrap()
+            .parse()?;
+
+        let other = String::from_utf8(request(port, "GET", "/etc/passwd")?)?;
+        assert!(other.starts_with("HTTP/1.1 404"));
+        let preflight = String::from_utf8(request(port, "OPTIONS", TRACE_PATH)?)?;
+        assert!(preflight.starts_with("HTTP/1.1 204"));
+        assert!(preflight.contains("Access-Control-Allow-Origin: https://ui.perfetto.dev"));
+
+        let response = request(port, "GET", TRACE_PATH)?;
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]);
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains("Access-Control-Allow-Origin: https://ui.perfetto.dev"));
+        let trace = Trace::parse_from_bytes(&response[split + 4..])?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+
+        server.join().unwrap()?;
+        assert_eq!(
+            ui_link(UI_PORT),
+            "https://ui.perfetto.dev/#!/?url=http://127.0.0.1:9001/trace.pftrace"
+        );
+        Ok(())
+    }
+}
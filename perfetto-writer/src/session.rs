@@ -0,0 +1,182 @@
+use perfetto_protos::{
+    chrome_trace_event::{ChromeEventBundle, ChromeMetadata},
+    trace_packet::TracePacket,
+    trace_uuid::TraceUuid,
+};
+use std::hash::{BuildHasher, Hasher};
+
+use crate::{ClockId, Context};
+
+/// Identifies a trace and the process that recorded it, so traces
+/// collected from a fleet can be told apart and correlated. Written with
+/// [`Context::write_session`] or [`ContextBuilder::session`].
+///
+/// [`ContextBuilder::session`]: crate::ContextBuilder::session
+#[derive(Debug, Clone, Default)]
+pub struct SessionMetadata {
+    uuid: Option<u128>,
+    entries: Vec<(String, String)>,
+    command_line: bool,
+}
+
+impl SessionMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn app_name(self, name: impl Into<String>) -> Self {
+        self.entry("app_name", name)
+    }
+
+    pub fn app_version(self, version: impl Into<String>) -> Self {
+        self.entry("app_version", version)
+    }
+
+    pub fn git_hash(self, hash: impl Into<String>) -> Self {
+        self.entry("git_hash", hash)
+    }
+
+    /// Adds a custom metadata entry, e.g. the deployment or region.
+    pub fn entry(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.entries.push((name.into(), value.into()));
+        self
+    }
+
+    /// Uses `uuid` instead of a random one, e.g. to share one id between
+    /// the traces of cooperating processes.
+    pub fn uuid(mut self, uuid: u128) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Also records the process's command line. Off by default, since
+    /// arguments may carry secrets.
+    pub fn command_line(mut self) -> Self {
+        self.command_line = true;
+        self
+    }
+}
+
+/// A random version 4 uuid, keyed by std's random hasher seeds.
+fn random_uuid() -> u128 {
+    let half = || {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(ClockId::Realtime.now_ns().unwrap_or_default());
+        hasher.write_u32(crate::current_pid());
+        hasher.finish()
+    };
+    let uuid = (u128::from(half()) << 64) | u128::from(half());
+    uuid & !(0xf << 76 | 0x3 << 62) | (0x4 << 76 | 0x2 << 62)
+}
+
+pub(crate) fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    {
+        nix::unistd::gethostname().ok()?.into_string().ok()
+    }
+
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+impl Context {
+    /// Writes the trace uuid and a metadata packet with `session`'s
+    /// entries, plus the hostname, wall-clock start time and, if asked for,
+    /// the command line.
+    /// Returns the trace uuid.
+    pub fn write_session(&mut self, session: &SessionMetadata) -> u128 {
+        let uuid = session.uuid.unwrap_or_else(random_uuid);
+        let mut tp = TracePacket::new();
+        tp.set_trace_uuid(TraceUuid {
+            msb: Some((uuid >> 64) as i64),
+            lsb: Some(uuid as i64),
+            ..Default::default()
+        });
+        self.push_packet(tp);
+
+        let mut bundle = ChromeEventBundle::new();
+        let mut add = |name: &str, value: String| {
+            let mut entry = ChromeMetadata::new();
+            entry.set_name(name.to_string());
+            entry.set_string_value(value);
+            bundle.metadata.push(entry);
+        };
+        add("trace_uuid", format!("{:032x}", uuid));
+        for (name, value) in &session.entries {
+            add(name, value.clone());
+        }
+        if let Some(hostname) = hostname() {
+            add("hostname", hostname);
+        }
+        if session.command_line {
+            add(
+                "command_line",
+                std::env::args().collect::<Vec<_>>().join(" "),
+            );
+        }
+        if let Some(now) = ClockId::Realtime.now_ns() {
+            add("start_time_ns", now.to_string());
+        }
+        let mut tp = TracePacket::new();
+        tp.set_chrome_events(bundle);
+        self.push_packet(tp);
+        uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn session_packets_identify_the_trace() -> Result<()> {
+        let mut ctx = Context::builder()
+            .session(SessionMetadata::new().app_name("api").git_hash("abc123"))
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let uuid = trace.packet.iter().find(|p| p.has_trace_uuid()).unwrap();
+        let uuid = uuid.trace_uuid();
+        assert_eq!((uuid.msb() >> 12) & 0xf, 4);
+        let metadata: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_chrome_events())
+            .flat_map(|p| p.chrome_events().metadata.iter())
+            .map(|m| (m.name(), m.string_value()))
+            .collect();
+        let get = |name| metadata.iter().find(|m| m.0 == name).map(|m| m.1);
+        assert_eq!(get("app_name"), Some("api"));
+        assert_eq!(get("git_hash"), Some("abc123"));
+        assert_eq!(
+            get("trace_uuid").map(|s| s.len()),
+            Some(32),
+            "uuid is written in hex"
+        );
+        assert!(get("command_line").is_none());
+        assert!(get("start_time_ns").is_some());
+
+        let mut ctx = Context::new();
+        assert_eq!(ctx.write_session(&SessionMetadata::new().uuid(7)), 7);
+        ctx.write_session(&SessionMetadata::new().command_line());
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_chrome_events())
+            .flat_map(|p| p.chrome_events().metadata.iter())
+            .map(|m| m.name())
+            .collect();
+        assert_eq!(names.iter().filter(|n| **n == "command_line").count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use protobuf::Message;
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::Context;
+
+/// A destination for encoded trace bytes. Every `Write` is a sink, e.g. a
+/// `File` or a `TcpStream`. The methods are named apart from those of
+/// `Write`, so both traits can be in scope.
+pub trait TraceSink: Send {
+    fn write_trace(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    fn flush_trace(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the trace is complete, after a last flush.
+    fn finalize_trace(&mut self) -> io::Result<()> {
+        self.flush_trace()
+    }
+}
+
+impl<W: Write + Send> TraceSink for W {
+    fn write_trace(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
+
+    fn flush_trace(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// An in-memory sink whose clones share one buffer, so the trace can be
+/// read back after the sink was handed to a [`Context`].
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Write for MemorySink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// When recorded events are drained to the sinks without an explicit
+/// flush. Checked as each event is built, so no background thread is
+/// involved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Drain once this many packets are buffered.
+    pub max_packets: Option<usize>,
+    /// Drain once the buffered packets reach this many encoded bytes.
+    pub max_bytes: Option<usize>,
+}
+
+impl FlushPolicy {
+    fn exceeded(&self, packets: usize, bytes: usize) -> bool {
+        self.max_packets.is_some_and(|max| packets >= max)
+            || self.max_bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+impl Context {
+    /// Drains to the sinks whenever `policy` is exceeded. Can be changed at
+    /// any time; the new thresholds apply from the next event. Has no
+    /// effect while no sink is registered.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    /// The error of the last automatic flush that failed, if any.
+    pub fn take_flush_error(&mut self) -> Option<anyhow::Error> {
+        self.flush_error.take()
+    }
+
+    pub(crate) fn maybe_auto_flush(&mut self) {
+        if self.sinks.is_empty()
+            || !self
+                .flush_policy
+                .exceeded(self.buffered_packets(), self.buffered_bytes())
+        {
+            return;
+        }
+        if let Err(e) = self.flush_sinks() {
+            self.flush_error = Some(e);
+        }
+    }
+
+    /// Adds a sink that receives every [`Context::flush_sinks`]. Any number
+    /// of sinks can be registered, e.g. a file, a socket and a
+    /// [`MemorySink`].
+    pub fn add_sink(&mut self, sink: impl TraceSink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Whether any sink is registered.
+    pub fn has_sinks(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    /// Encodes the buffered packets once and writes them to every sink.
+    /// A failing sink does not keep the others from being written; the
+    /// first error is returned. If every sink fails the packets are counted
+    /// as dropped. Without sinks the packets stay buffered.
+    pub fn flush_sinks(&mut self) -> Result<()> {
+        self.check_fork();
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+        let trace = self.take_trace();
+        let bytes = match trace.write_to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.record_dropped(trace.packet.len() as u64);
+                return Err(e.into());
+            }
+        };
+        let mut result = Ok(());
+        let mut failed = 0;
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.write_trace(&bytes).and_then(|_| sink.flush_trace()) {
+                failed += 1;
+                if result.is_ok() {
+                    result = Err(e.into());
+                }
+            }
+        }
+        if failed > 0 && failed == self.sinks.len() {
+            self.record_dropped(trace.packet.len() as u64);
+        } else {
+            self.record_written(&trace, bytes.len() as u64);
+        }
+        result
+    }
+
+    /// Writes a [`TraceStats`](Context::write_trace_stats) packet and
+    /// flushes, then finalizes and removes every sink.
+    pub fn finalize_sinks(&mut self) -> Result<()> {
+        self.write_trace_stats();
+        let mut result = self.flush_sinks();
+        for mut sink in self.sinks.drain(..) {
+            if let Err(e) = sink.finalize_trace()
+                && result.is_ok()
+            {
+                result = Err(e.into());
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+
+    struct Broken;
+
+    impl TraceSink for Broken {
+        fn write_trace(&mut self, _: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn policy_drains_as_events_are_built() -> Result<()> {
+        let mut ctx = Context::new();
+        let sink = MemorySink::new();
+        ctx.add_sink(sink.clone());
+        ctx.set_flush_policy(FlushPolicy {
+            max_packets: Some(4),
+            ..Default::default()
+        });
+        let track = ctx.current_thread_track();
+        for _ in 0..10 {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+            assert!(ctx.buffered_packets() < 4);
+        }
+        assert!(!sink.contents().is_empty());
+
+        ctx.set_flush_policy(FlushPolicy::default());
+        let flushed = sink.contents().len();
+        for _ in 0..10 {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .build();
+        }
+        assert_eq!(sink.contents().len(), flushed);
+        assert!(ctx.take_flush_error().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn every_sink_gets_the_trace() -> Result<()> {
+        let mut ctx = Context::new();
+        let first = MemorySink::new();
+        let second = MemorySink::new();
+        ctx.add_sink(Broken);
+        ctx.add_sink(first.clone());
+        ctx.add_sink(second.clone());
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("fanout")
+            .build();
+
+        assert!(ctx.finalize_sinks().is_err());
+        assert_eq!(first.contents(), second.contents());
+        let trace = Trace::parse_from_bytes(&first.contents())?;
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        assert!(ctx.finalize_sinks().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_is_flushed_without_sinks() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .build();
+        let buffered = ctx.buffered_packets();
+        ctx.flush_sinks()?;
+        assert_eq!(ctx.buffered_packets(), buffered);
+        assert_eq!(ctx.write_stats().dropped_packets, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use futures_core::Stream;
+use futures_sink::Sink;
+use smol_str::SmolStr;
+use std::{
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{Context as TaskContext, Poll},
+};
+
+use crate::{Context, CounterUnit, global};
+
+/// Records an instant named `name` on the calling thread's track and sets
+/// the "`name` items" counter track to `count`.
+fn record_item(ctx: Option<&Mutex<Context>>, name: &str, count: u64) {
+    let Some(ctx) = ctx else {
+        return;
+    };
+    let mut ctx = ctx.lock().unwrap_or_else(PoisonError::into_inner);
+    let thread = ctx.current_thread_track();
+    ctx.event()
+        .with_instant()
+        .with_now()
+        .with_track_uuid(thread)
+        .with_name(name)
+        .build();
+    let counter = ctx.named_counter_track(&format!("{} items", name), CounterUnit::UNIT_COUNT);
+    ctx.event()
+        .with_counter()
+        .with_now()
+        .with_track_uuid(counter)
+        .with_counter_value(count as i64)
+        .build();
+}
+
+/// Records each item of a stream. See [`TraceStreamExt::traced_stream`].
+pub trait TraceStreamExt: Stream + Sized {
+    /// Records an instant in the global context for every item the stream
+    /// yields, and the running item count on a "`name` items" counter
+    /// track. Does nothing before [`init_global`](crate::init_global).
+    fn traced_stream(self, name: impl Into<SmolStr>) -> TracedStream<'static, Self> {
+        TracedStream::new(self, global::context(), name.into())
+    }
+
+    /// Like [`TraceStreamExt::traced_stream`], recording into `ctx`.
+    fn traced_stream_in(
+        self,
+        ctx: &Mutex<Context>,
+        name: impl Into<SmolStr>,
+    ) -> TracedStream<'_, Self> {
+        TracedStream::new(self, Some(ctx), name.into())
+    }
+}
+
+impl<S: Stream> TraceStreamExt for S {}
+
+/// A stream whose items are recorded. Created by
+/// [`TraceStreamExt::traced_stream`].
+#[must_use = "streams do nothing unless polled"]
+pub struct TracedStream<'a, S> {
+    stream: S,
+    ctx: Option<&'a Mutex<Context>>,
+    name: SmolStr,
+    items: u64,
+}
+
+impl<'a, S> TracedStream<'a, S> {
+    fn new(stream: S, ctx: Option<&'a Mutex<Context>>, name: SmolStr) -> Self {
+        Self {
+            stream,
+            ctx,
+            name,
+            items: 0,
+        }
+    }
+}
+
+impl<S: Stream> Stream for TracedStream<'_, S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<S::Item>> {
+        // SAFETY: `stream` is never moved out of `self`, and no other field
+        // is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        let poll = stream.poll_next(cx);
+        if let Poll::Ready(Some(_)) = poll {
+            this.items += 1;
+            record_item(this.ctx, &this.name, this.items);
+        }
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+/// Records each item sent into a sink. See [`TraceSinkExt::traced_sink`].
+pub trait TraceSinkExt<Item>: Sink<Item> + Sized {
+    /// Records an instant in the global context for every item sent, and
+    /// the running item count on a "`name` items" counter track. Does
+    /// nothing before [`init_global`](crate::init_global).
+    fn traced_sink(self, name: impl Into<SmolStr>) -> TracedSink<'static, Self> {
+        TracedSink::new(self, global::context(), name.into())
+    }
+
+    /// Like [`TraceSinkExt::traced_sink`], recording into `ctx`.
+    fn traced_sink_in(
+        self,
+        ctx: &Mutex<Context>,
+        name: impl Into<SmolStr>,
+    ) -> TracedSink<'_, Self> {
+        TracedSink::new(self, Some(ctx), name.into())
+    }
+}
+
+impl<Item, S: Sink<Item>> TraceSinkExt<Item> for S {}
+
+/// A sink whose items are recorded. Created by [`TraceSinkExt::traced_sink`].
+#[must_use = "sinks do nothing unless polled"]
+pub struct TracedSink<'a, S> {
+    sink: S,
+    ctx: Option<&'a Mutex<Context>>,
+    name: SmolStr,
+    items: u64,
+}
+
+impl<'a, S> TracedSink<'a, S> {
+    fn new(sink: S, ctx: Option<&'a Mutex<Context>>, name: SmolStr) -> Self {
+        Self {
+            sink,
+            ctx,
+            name,
+            items: 0,
+        }
+    }
+
+    fn sink(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // SAFETY: `sink` is never moved out of `self`, and no other field
+        // is pinned.
+        unsafe { self.map_unchecked_mut(|this| &mut this.sink) }
+    }
+}
+
+impl<Item, S: Sink<Item>> Sink<Item> for TracedSink<'_, S> {
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.sink().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), S::Error> {
+        self.as_mut().sink().start_send(item)?;
+        // SAFETY: only unpinned fields are touched.
+        let this = unsafe { self.get_unchecked_mut() };
+        this.items += 1;
+        record_item(this.ctx, &this.name, this.items);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.sink().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.sink().poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::{convert::Infallible, task::Waker};
+
+    /// Yields 1, 2, 3.
+    struct Count(u32);
+
+    impl Stream for Count {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Option<u32>> {
+            self.0 += 1;
+            Poll::Ready((self.0 <= 3).then_some(self.0))
+        }
+    }
+
+    /// Collects whatever is sent into it.
+    #[derive(Default)]
+    struct Collect(Vec<u32>);
+
+    impl Sink<u32> for Collect {
+        type Error = Infallible;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u32) -> Result<(), Infallible> {
+            self.0.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn items_are_counted() -> Result<()> {
+        let ctx = Mutex::new(Context::new());
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        let mut stream = std::pin::pin!(Count(0).traced_stream_in(&ctx, "read"));
+        let mut sink = std::pin::pin!(Collect::default().traced_sink_in(&ctx, "write"));
+        while let Poll::Ready(Some(item)) = stream.as_mut().poll_next(&mut cx) {
+            assert!(sink.as_mut().poll_ready(&mut cx).is_ready());
+            sink.as_mut().start_send(item)?;
+        }
+        assert_eq!(sink.sink.0, [1, 2, 3]);
+
+        let mut buf = Vec::new();
+        ctx.lock().unwrap().write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .filter(|t| t.counter.is_some())
+            .map(|t| t.name().to_string())
+            .collect();
+        assert_eq!(tracks, ["read items", "write items"]);
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let instants = events
+            .iter()
+            .filter(|e| e.type_() == Type::TYPE_INSTANT)
+            .count();
+        assert_eq!(instants, 6);
+        let counts: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == Type::TYPE_COUNTER)
+            .map(|e| e.counter_value())
+            .collect();
+        assert_eq!(counts, [1, 1, 2, 2, 3, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::{Result, bail};
+use std::{
+    ffi::c_void,
+    io,
+    sync::atomic::{Ordering, fence},
+};
+
+use crate::{ClockId, Context, TrackUuid};
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SWITCH: u32 = 14;
+const PERF_RECORD_SWITCH_CPU_WIDE: u32 = 15;
+const PERF_RECORD_MISC_SWITCH_OUT: u16 = 1 << 13;
+const PERF_RECORD_MISC_SWITCH_OUT_PREEMPT: u16 = 1 << 14;
+
+// Flag bits of `perf_event_attr`.
+const EXCLUDE_KERNEL: u64 = 1 << 5;
+const EXCLUDE_HV: u64 = 1 << 6;
+const SAMPLE_ID_ALL: u64 = 1 << 18;
+const USE_CLOCKID: u64 = 1 << 25;
+const CONTEXT_SWITCH: u64 = 1 << 26;
+
+// Offsets into `perf_event_mmap_page`.
+const DATA_HEAD: usize = 1024;
+const DATA_TAIL: usize = 1032;
+
+/// Ring buffer pages, after the metadata page. Must be a power of two.
+const DATA_PAGES: usize = 16;
+
+/// `perf_event_attr` as of `PERF_ATTR_SIZE_VER5`.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+/// A context switch of the traced thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Switch {
+    time: u64,
+    out: bool,
+    preempted: bool,
+    /// The pid and tid of the thread switched to when switching out, known
+    /// from CPU-wide records only.
+    next: Option<(u32, u32)>,
+}
+
+/// Decodes a switch record into the tid it was sampled on and the switch.
+fn parse_switch(type_: u32, misc: u16, body: &[u8]) -> Option<(u32, Switch)> {
+    let u32_at = |at: usize| Some(u32::from_ne_bytes(body.get(at..at + 4)?.try_into().ok()?));
+    let u64_at = |at: usize| Some(u64::from_ne_bytes(body.get(at..at + 8)?.try_into().ok()?));
+    let out = misc & PERF_RECORD_MISC_SWITCH_OUT != 0;
+    // CPU-wide records lead with the next or previous pid and tid, then
+    // both kinds end in the sample id trailer: pid, tid, then time.
+    let (trailer, other) = match type_ {
+        PERF_RECORD_SWITCH => (0, None),
+        PERF_RECORD_SWITCH_CPU_WIDE => (8, Some((u32_at(0)?, u32_at(4)?))),
+        _ => return None,
+    };
+    let switch = Switch {
+        time: u64_at(trailer + 8)?,
+        out,
+        preempted: misc & PERF_RECORD_MISC_SWITCH_OUT_PREEMPT != 0,
+        next: other.filter(|_| out),
+    };
+    Some((u32_at(trailer + 4)?, switch))
+}
+
+/// A perf ring buffer.
+struct Ring {
+    fd: i32,
+    mmap: *mut u8,
+    mmap_len: usize,
+    page_size: usize,
+}
+
+impl Ring {
+    /// Opens a ring for `attr` on `pid` and `cpu`, as `perf_event_open`
+    /// takes them.
+    fn open(attr: &PerfEventAttr, pid: i32, cpu: i32) -> Result<Self> {
+        // SAFETY: `attr` outlives the call.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                attr as *const PerfEventAttr,
+                pid,
+                cpu,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        } as i32;
+        if fd < 0 {
+            bail!("perf_event_open failed: {}", io::Error::last_os_error());
+        }
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mmap_len = page_size * (1 + DATA_PAGES);
+        // SAFETY: maps the event's ring buffer, checked below.
+        let mmap = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mmap_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if mmap == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            // SAFETY: `fd` was opened above and is not used again.
+            unsafe { libc::close(fd) };
+            bail!("mapping the perf ring buffer failed: {e}");
+        }
+        Ok(Self {
+            fd,
+            mmap: mmap.cast(),
+            mmap_len,
+            page_size,
+        })
+    }
+
+    /// Consumes the records written since the last call, adding the
+    /// switches of `tid` to `switches`. Returns how many records were lost.
+    fn drain(&mut self, tid: u32, switches: &mut Vec<Switch>) -> u64 {
+        let data_len = self.page_size * DATA_PAGES;
+        // SAFETY: the metadata page is mapped for the lifetime of `self`.
+        let head = unsafe { (self.mmap.add(DATA_HEAD) as *const u64).read_volatile() };
+        fence(Ordering::Acquire);
+        let mut tail = unsafe { (self.mmap.add(DATA_TAIL) as *const u64).read_volatile() };
+
+        let mut lost = 0;
+        while tail < head {
+            let header = self.read(tail, 8, data_len);
+            let type_ = u32::from_ne_bytes(header[..4].try_into().unwrap());
+            let misc = u16::from_ne_bytes(header[4..6].try_into().unwrap());
+            let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as u64;
+            if size < 8 {
+                break;
+            }
+            let body = self.read(tail + 8, size as usize - 8, data_len);
+            match type_ {
+                PERF_RECORD_LOST if body.len() >= 16 => {
+                    lost += u64::from_ne_bytes(body[8..16].try_into().unwrap());
+                }
+                _ => {
+                    if let Some((switched, switch)) = parse_switch(type_, misc, &body)
+                        && switched == tid
+                    {
+                        switches.push(switch);
+                    }
+                }
+            }
+            tail += size;
+        }
+        fence(Ordering::Release);
+        // SAFETY: as above; the kernel only reads the tail.
+        unsafe { (self.mmap.add(DATA_TAIL) as *mut u64).write_volatile(tail) };
+        lost
+    }
+
+    /// Copies `len` bytes at ring position `at`, which may wrap around.
+    fn read(&self, at: u64, len: usize, data_len: usize) -> Vec<u8> {
+        // SAFETY: the data pages follow the metadata page in the mapping.
+        let data = unsafe { std::slice::from_raw_parts(self.mmap.add(self.page_size), data_len) };
+        (0..len)
+            .map(|i| data[(at as usize + i) % data_len])
+            .collect()
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: both were created in `open` and are not used after this.
+        unsafe {
+            libc::munmap(self.mmap as *mut c_void, self.mmap_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Turns the switches of a thread into off-CPU slices.
+struct OffCpu {
+    track: TrackUuid,
+    since: Option<Switch>,
+}
+
+impl OffCpu {
+    /// Returns how many slices `switch` completed.
+    fn record(&mut self, ctx: &mut Context, switch: Switch) -> usize {
+        if switch.out {
+            self.since = Some(switch);
+            return 0;
+        }
+        let Some(out) = self.since.take() else {
+            return 0;
+        };
+        let mut event = ctx
+            .event()
+            .with_begin()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(out.time)
+            .with_name(if out.preempted {
+                "preempted"
+            } else {
+                "blocked"
+            })
+            .with_track_uuid(self.track);
+        if let Some((pid, tid)) = out.next {
+            event.debug_uint("on_cpu_pid", pid.into());
+            event.debug_uint("on_cpu_tid", tid.into());
+        }
+        event.build();
+        ctx.event()
+            .with_end()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(switch.time)
+            .with_track_uuid(self.track)
+            .build();
+        1
+    }
+}
+
+/// Records when the calling thread is switched off and back onto a CPU,
+/// using `perf_event_open` context switch records.
+///
+/// [`ContextSwitches::drain`] writes each off-CPU interval as a slice on an
+/// "off-CPU" track nested under the thread's track, named "preempted" when
+/// the scheduler took the CPU away and "blocked" when the thread waited, so
+/// gaps inside the thread's own slices are explained.
+///
+/// Where CPU-wide events are allowed, e.g. with `perf_event_paranoid` of 0
+/// or `CAP_PERFMON`, each slice also has the pid and tid of the thread that
+/// got the CPU as `on_cpu_pid` and `on_cpu_tid`. Otherwise only the calling
+/// thread is watched, which works unprivileged with the default
+/// `perf_event_paranoid` of 2.
+pub struct ContextSwitches {
+    rings: Vec<Ring>,
+    tid: u32,
+    off_cpu: OffCpu,
+    lost: u64,
+}
+
+// The rings are only read through `&mut self`.
+unsafe impl Send for ContextSwitches {}
+
+impl ContextSwitches {
+    pub fn for_current_thread(ctx: &mut Context) -> Result<Self> {
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: size_of::<PerfEventAttr>() as u32,
+            config: PERF_COUNT_SW_CONTEXT_SWITCHES,
+            sample_type: PERF_SAMPLE_TID | PERF_SAMPLE_TIME,
+            flags: EXCLUDE_KERNEL | EXCLUDE_HV | SAMPLE_ID_ALL | USE_CLOCKID | CONTEXT_SWITCH,
+            clockid: libc::CLOCK_BOOTTIME,
+            ..Default::default()
+        };
+        // SAFETY: neither call has preconditions.
+        let (tid, cpus) = unsafe { (libc::gettid(), libc::sysconf(libc::_SC_NPROCESSORS_CONF)) };
+        // A ring per CPU for every thread there, or else one following the
+        // calling thread (pid 0) on every CPU (-1).
+        let rings = (0..cpus as i32)
+            .map(|cpu| Ring::open(&attr, -1, cpu))
+            .collect::<Result<Vec<_>>>()
+            .or_else(|_| Ring::open(&attr, 0, -1).map(|ring| vec![ring]))?;
+        let thread = ctx.current_thread_track();
+        let track = ctx.create_child_track(thread, "off-CPU");
+        Ok(Self {
+            rings,
+            tid: tid as u32,
+            off_cpu: OffCpu { track, since: None },
+            lost: 0,
+        })
+    }
+
+    /// Switch records the kernel dropped because a ring was full.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Writes the off-CPU intervals recorded since the last call to `ctx`
+    /// and returns how many were written. An interval still open is written
+    /// once the thread is back on a CPU.
+    pub fn drain(&mut self, ctx: &mut Context) -> usize {
+        let mut switches = Vec::new();
+        for ring in &mut self.rings {
+            self.lost += ring.drain(self.tid, &mut switches);
+        }
+        // A thread switched out on one CPU may come back on another.
+        switches.sort_by_key(|switch| switch.time);
+        switches
+            .into_iter()
+            .map(|switch| self.off_cpu.record(ctx, switch))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    fn record(fields: &[u32], time: u64) -> Vec<u8> {
+        let mut body: Vec<u8> = fields.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        body.extend(time.to_ne_bytes());
+        body
+    }
+
+    #[test]
+    fn switches_name_the_thread_that_got_the_cpu() {
+        let preempt = PERF_RECORD_MISC_SWITCH_OUT | PERF_RECORD_MISC_SWITCH_OUT_PREEMPT;
+        let (tid, out) = parse_switch(
+            PERF_RECORD_SWITCH_CPU_WIDE,
+            preempt,
+            &record(&[9, 10, 1, 2], 100),
+        )
+        .unwrap();
+        assert_eq!(tid, 2);
+        assert_eq!(out.next, Some((9, 10)));
+        let (_, back) =
+            parse_switch(PERF_RECORD_SWITCH_CPU_WIDE, 0, &record(&[9, 10, 1, 2], 250)).unwrap();
+        assert_eq!(back.next, None);
+        let (_, own) = parse_switch(PERF_RECORD_SWITCH, 0, &record(&[1, 2], 300)).unwrap();
+        assert_eq!((own.time, own.next), (300, None));
+
+        let mut ctx = Context::new();
+        let mut off_cpu = OffCpu {
+            track: ctx.current_thread_track(),
+            since: None,
+        };
+        assert_eq!(off_cpu.record(&mut ctx, out), 0);
+        assert_eq!(off_cpu.record(&mut ctx, back), 1);
+        let trace = ctx.take_trace();
+        let slice = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event())
+            .unwrap()
+            .track_event();
+        let tids: Vec<_> = slice
+            .debug_annotations
+            .iter()
+            .map(|a| a.uint_value())
+            .collect();
+        assert_eq!(tids, [9, 10]);
+    }
+
+    #[test]
+    #[ignore = "needs perf_event_open, which containers commonly filter"]
+    fn records_blocked_intervals() -> Result<()> {
+        let mut ctx = Context::new();
+        let mut switches = ContextSwitches::for_current_thread(&mut ctx)?;
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(2));
+        }
+        let written = switches.drain(&mut ctx);
+        assert!(written >= 3, "{written} off-CPU intervals");
+
+        let trace = ctx.take_trace();
+        let blocked = trace
+            .packet
+            .iter()
+            .filter(|p| {
+                p.has_track_event() && p.track_event().track_uuid() == switches.off_cpu.track
+            })
+            .count();
+        assert_eq!(blocked, written * 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Drop-in replacements for [`std::sync::Mutex`] and [`std::sync::RwLock`]
+//! that make lock contention visible.
+//!
+//! Each lock has a name. When acquiring it has to wait, the wait is
+//! recorded on the global context as a slice with that name, on a "locks"
+//! track under the waiting thread. Uncontended acquires and processes
+//! without [`init_global`](crate::init_global) pay only for a `try_lock`.
+
+use std::{
+    collections::hash_map::Entry,
+    fmt,
+    sync::{
+        LockResult, MutexGuard, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult,
+    },
+};
+
+use crate::{ClockId, Context, TrackUuid, current_thread, global};
+
+/// Times a contended acquire, if there is a global context to record it.
+struct Wait(Option<u64>);
+
+impl Wait {
+    fn start() -> Self {
+        Self(
+            global::context()
+                .is_some()
+                .then(|| ClockId::Boottime.now_ns())
+                .flatten(),
+        )
+    }
+
+    fn record(self, name: &str) {
+        let (Some(start), Some(end)) = (self.0, ClockId::Boottime.now_ns()) else {
+            return;
+        };
+        global::with_global(|ctx| ctx.record_lock_wait(name, start, end));
+    }
+}
+
+/// Runs `acquire` unless `try_acquire` already succeeded, recording the wait.
+fn acquire<G>(
+    name: &str,
+    try_acquire: impl FnOnce() -> TryLockResult<G>,
+    acquire: impl FnOnce() -> LockResult<G>,
+) -> LockResult<G> {
+    match try_acquire() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(e)) => Err(e),
+        Err(TryLockError::WouldBlock) => {
+            let wait = Wait::start();
+            let guard = acquire();
+            wait.record(name);
+            guard
+        }
+    }
+}
+
+impl Context {
+    /// Records a wait for the lock `name` from `start` to `end`, boottime
+    /// nanoseconds, on the calling thread's "locks" track.
+    pub(crate) fn record_lock_wait(&mut self, name: &str, start: u64, end: u64) {
+        let track = self.lock_track();
+        self.event()
+            .with_begin()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(start)
+            .with_name(name)
+            .with_track_uuid(track)
+            .build();
+        self.event()
+            .with_end()
+            .with_clock(ClockId::Boottime)
+            .with_timestamp_ns(end)
+            .with_track_uuid(track)
+            .build();
+    }
+
+    fn lock_track(&mut self) -> TrackUuid {
+        let thread = self.current_thread_track();
+        match self.lock_tracks.entry(current_thread()) {
+            Entry::Occupied(track) => *track.get(),
+            Entry::Vacant(_) => {
+                let track = self.create_child_track(thread, "locks");
+                self.lock_tracks.insert(current_thread(), track);
+                track
+            }
+        }
+    }
+}
+
+/// A [`std::sync::Mutex`] that records contended acquires.
+pub struct Mutex<T: ?Sized> {
+    name: &'static str,
+    inner: std::sync::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            inner: std::sync::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        acquire(self.name, || self.inner.try_lock(), || self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("name", &self.name)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// A [`std::sync::RwLock`] that records contended acquires.
+pub struct RwLock<T: ?Sized> {
+    name: &'static str,
+    inner: std::sync::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            inner: std::sync::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        acquire(self.name, || self.inner.try_read(), || self.inner.read())
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        acquire(self.name, || self.inner.try_write(), || self.inner.write())
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.inner.try_read()
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.inner.try_write()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RwLock")
+            .field("name", &self.name)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn contended_acquires_wait_for_the_holder() {
+        let lock = Arc::new(Mutex::new("queue", 0));
+        let guard = lock.lock().unwrap();
+        let waiter = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || *lock.lock().unwrap() += 1)
+        };
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        waiter.join().unwrap();
+        assert_eq!(*lock.lock().unwrap(), 1);
+
+        let rw = RwLock::new("config", 1);
+        let read = rw.read().unwrap();
+        assert!(rw.try_write().is_err());
+        drop(read);
+        *rw.write().unwrap() += 1;
+        assert_eq!(rw.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn waits_go_on_a_locks_track_per_thread() {
+        let mut ctx = Context::new();
+        ctx.record_lock_wait("queue", 100, 250);
+        ctx.record_lock_wait("queue", 300, 400);
+        let other = thread::scope(|s| {
+            s.spawn(|| {
+                ctx.record_lock_wait("queue", 120, 260);
+                ctx.lock_track()
+            })
+            .join()
+            .unwrap()
+        });
+        assert_ne!(ctx.lock_track(), other);
+
+        let trace = ctx.take_trace();
+        let locks = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor() && p.track_descriptor().name() == "locks")
+            .count();
+        assert_eq!(locks, 2);
+        let waits = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(waits, 6);
+    }
+}
@@ -0,0 +1,86 @@
+use perfetto_protos::{
+    system_info::{SystemInfo, Utsname},
+    trace_packet::TracePacket,
+};
+
+use crate::Context;
+
+/// Describes the machine: kernel, architecture, CPU count and page size.
+fn system_info() -> SystemInfo {
+    let mut info = SystemInfo::new();
+    #[cfg(unix)]
+    if let Ok(uts) = nix::sys::utsname::uname() {
+        let field = |s: &std::ffi::OsStr| Some(s.to_string_lossy().into_owned());
+        info.utsname = Some(Utsname {
+            sysname: field(uts.sysname()),
+            version: field(uts.version()),
+            release: field(uts.release()),
+            machine: field(uts.machine()),
+            ..Default::default()
+        })
+        .into();
+    }
+    if info.utsname.is_none() {
+        info.utsname = Some(Utsname {
+            sysname: Some(std::env::consts::OS.to_string()),
+            machine: Some(std::env::consts::ARCH.to_string()),
+            ..Default::default()
+        })
+        .into();
+    }
+    if let Ok(cpus) = std::thread::available_parallelism() {
+        info.set_num_cpus(cpus.get() as u32);
+    }
+    #[cfg(unix)]
+    if let Ok(Some(page_size)) = nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE) {
+        info.set_page_size(page_size as u32);
+    }
+    info
+}
+
+impl Context {
+    /// Emits the `SystemInfo` packet (kernel, architecture, CPU count, page
+    /// size) trace_processor reads machine details from. Enable
+    /// [`ContextBuilder::system_info`](crate::ContextBuilder::system_info) to
+    /// write it up front; once written it is repeated at the start of every
+    /// segment.
+    pub fn write_system_info(&mut self) {
+        self.system_info_written = true;
+        let mut tp = TracePacket::new();
+        tp.set_system_info(system_info());
+        self.push_packet(tp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn traces_start_with_system_info() -> Result<()> {
+        let mut ctx = Context::builder().system_info(true).build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let info = trace
+            .packet
+            .iter()
+            .find(|p| p.has_system_info())
+            .unwrap()
+            .system_info();
+        assert!(info.num_cpus() > 0);
+        assert!(!info.utsname.machine().is_empty());
+        #[cfg(unix)]
+        assert!(info.page_size() > 0);
+
+        let mut ctx = Context::builder().build();
+        buf.clear();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        assert!(!trace.packet.iter().any(|p| p.has_system_info()));
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use perfetto_protos::track_event::track_event::Type;
+
+use crate::{Context, EventBuilder};
+
+/// CPU time consumed by the calling thread, in nanoseconds.
+fn thread_cpu_time_ns() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        crate::clock::clock_gettime(nix::time::ClockId::CLOCK_THREAD_CPUTIME_ID)
+    }
+
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+impl Context {
+    /// Stamps every slice begin and end with the calling thread's CPU time,
+    /// so the UI shows each slice's CPU time next to its wall time. Slices
+    /// must be recorded on the thread whose track they are on.
+    pub fn set_thread_time(&mut self, enabled: bool) {
+        self.thread_time = enabled;
+    }
+}
+
+impl<'a> EventBuilder<'a> {
+    /// Records the calling thread's CPU time on the event.
+    pub fn thread_time(&mut self) {
+        if let Some(ns) = thread_cpu_time_ns() {
+            self.event.set_thread_time_absolute_us((ns / 1000) as i64);
+        }
+    }
+
+    pub fn with_thread_time(mut self) -> Self {
+        self.thread_time();
+        self
+    }
+
+    pub(crate) fn maybe_thread_time(&mut self) {
+        let slice = matches!(
+            self.event.type_(),
+            Type::TYPE_SLICE_BEGIN | Type::TYPE_SLICE_END
+        );
+        if self.ctx.thread_time && slice && !self.event.has_thread_time_absolute_us() {
+            self.thread_time();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn slices_carry_thread_time() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.set_thread_time(true);
+        let track = ctx.current_thread_track();
+        ctx.event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_name("spin")
+            .build();
+        let mut x = 0u64;
+        for i in 0..1_000_000 {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+        }
+        ctx.event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(track)
+            .build();
+        ctx.event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track)
+            .build();
+
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        assert!(events[0].has_thread_time_absolute_us());
+        assert!(events[1].thread_time_absolute_us() >= events[0].thread_time_absolute_us());
+        assert!(!events[2].has_thread_time_absolute_us());
+        Ok(())
+    }
+}
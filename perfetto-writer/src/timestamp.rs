@@ -0,0 +1,170 @@
+//! Timestamp encoding experiments.
+//!
+//! [`Context`](crate::Context) writes either absolute timestamps or, with
+//! [`set_delta_timestamps`](crate::Context::set_delta_timestamps), deltas on
+//! an incremental clock, the two schemes trace processor can decode. The
+//! [`TimestampEncoding`] strategies here let other schemes be measured on
+//! real timestamp streams before any of them is worth proposing upstream;
+//! see `benches/timestamp_bench.rs` for the size comparison. The schemes a
+//! context can write are picked per session with
+//! [`ContextBuilder::timestamp_format`].
+
+use perfetto_core::wire::varint_len;
+
+use crate::{Context, ContextBuilder};
+
+/// The timestamp encodings a [`Context`] can write, i.e. those trace
+/// processor decodes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// Full timestamps on the context's clock.
+    #[default]
+    Absolute,
+    /// Deltas on an incremental clock, see
+    /// [`Context::set_delta_timestamps`].
+    Delta,
+}
+
+impl TimestampFormat {
+    /// The strategy computing the values this format stores, for comparing
+    /// it against the experimental ones.
+    pub fn encoding(self) -> Box<dyn TimestampEncoding> {
+        match self {
+            TimestampFormat::Absolute => Box::new(Absolute),
+            TimestampFormat::Delta => Box::new(Delta::default()),
+        }
+    }
+
+    pub(crate) fn apply(self, ctx: &mut Context) {
+        ctx.set_delta_timestamps(self == TimestampFormat::Delta);
+    }
+}
+
+impl ContextBuilder {
+    /// How the session's timestamps are encoded. Absolute by default.
+    pub fn timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.timestamp_format = format;
+        self
+    }
+}
+
+/// Turns a stream of timestamps into the integers that would be stored.
+pub trait TimestampEncoding {
+    fn name(&self) -> &'static str;
+
+    /// The value stored for `ts`, given every earlier timestamp.
+    fn encode(&mut self, ts: u64) -> u64;
+}
+
+/// Full timestamps, as written by default.
+#[derive(Debug, Default)]
+pub struct Absolute;
+
+impl TimestampEncoding for Absolute {
+    fn name(&self) -> &'static str {
+        "absolute"
+    }
+
+    fn encode(&mut self, ts: u64) -> u64 {
+        ts
+    }
+}
+
+/// Distance to the previous timestamp, as written on the incremental clock.
+#[derive(Debug, Default)]
+pub struct Delta {
+    last: u64,
+}
+
+impl TimestampEncoding for Delta {
+    fn name(&self) -> &'static str {
+        "delta"
+    }
+
+    fn encode(&mut self, ts: u64) -> u64 {
+        let delta = ts.wrapping_sub(self.last);
+        self.last = ts;
+        delta
+    }
+}
+
+/// Change in the distance between timestamps, zigzag encoded. Regular
+/// sampling collapses to zeros. Perfetto has no clock that decodes this, so
+/// it is measurement only.
+#[derive(Debug, Default)]
+pub struct DeltaOfDelta {
+    last: u64,
+    last_delta: i64,
+}
+
+impl TimestampEncoding for DeltaOfDelta {
+    fn name(&self) -> &'static str {
+        "delta-of-delta"
+    }
+
+    fn encode(&mut self, ts: u64) -> u64 {
+        let delta = ts.wrapping_sub(self.last) as i64;
+        let dod = delta.wrapping_sub(self.last_delta);
+        self.last = ts;
+        self.last_delta = delta;
+        ((dod << 1) ^ (dod >> 63)) as u64
+    }
+}
+
+/// Bytes the varints produced by `encoding` for `timestamps` take up.
+pub fn encoded_len(encoding: &mut dyn TimestampEncoding, timestamps: &[u64]) -> usize {
+    timestamps
+        .iter()
+        .map(|ts| varint_len(encoding.encode(*ts)))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INCREMENTAL_CLOCK_ID;
+    use anyhow::Result;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn dense_timestamps_shrink() {
+        let start = 1_700_000_000_000_000_000u64;
+        let timestamps: Vec<_> = (0..1000).map(|i| start + i * 1_000).collect();
+        let absolute = encoded_len(&mut Absolute, &timestamps);
+        let delta = encoded_len(&mut Delta::default(), &timestamps[1..]) + varint_len(start);
+        let dod = encoded_len(&mut DeltaOfDelta::default(), &timestamps);
+        assert!(delta < absolute);
+        assert!(dod < delta);
+    }
+
+    #[test]
+    fn sessions_pick_their_format() -> Result<()> {
+        for (format, name) in [
+            (TimestampFormat::Absolute, "absolute"),
+            (TimestampFormat::Delta, "delta"),
+        ] {
+            let mut ctx = Context::builder().timestamp_format(format).build();
+            let track = ctx.current_thread_track();
+            for _ in 0..2 {
+                ctx.event()
+                    .with_instant()
+                    .with_now()
+                    .with_track_uuid(track)
+                    .with_name("tick")
+                    .build();
+            }
+            let mut buf = Vec::new();
+            ctx.write_to(&mut buf)?;
+            let trace = Trace::parse_from_bytes(&buf)?;
+            let incremental = trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event())
+                .all(|p| p.timestamp_clock_id() == INCREMENTAL_CLOCK_ID);
+            assert_eq!(incremental, format == TimestampFormat::Delta);
+            assert_eq!(format.encoding().name(), name);
+        }
+        Ok(())
+    }
+}
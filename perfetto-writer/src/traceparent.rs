@@ -0,0 +1,143 @@
+use std::{fmt, str::FromStr};
+
+use crate::SessionMetadata;
+
+/// A W3C trace context `traceparent` value, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+///
+/// Maps OpenTelemetry ids onto Perfetto ones and back: the trace id is the
+/// trace uuid and the parent span id is the flow id, so traces recorded by
+/// services sharing a trace context line up with each other and with the
+/// OpenTelemetry spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceParent {
+    pub trace_id: u128,
+    pub parent_id: u64,
+    pub flags: u8,
+}
+
+/// Returned when parsing a malformed `traceparent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTraceParent;
+
+impl fmt::Display for InvalidTraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid traceparent")
+    }
+}
+
+impl std::error::Error for InvalidTraceParent {}
+
+impl TraceParent {
+    /// The sampled flag.
+    pub const SAMPLED: u8 = 0x01;
+
+    /// A sampled trace context for the Perfetto trace `trace_uuid`, whose
+    /// parent is the slice carrying `flow_id`.
+    pub fn from_flow(trace_uuid: u128, flow_id: u64) -> Self {
+        Self {
+            trace_id: trace_uuid,
+            parent_id: flow_id,
+            flags: Self::SAMPLED,
+        }
+    }
+
+    /// The flow id linking the parent span to its continuation.
+    pub fn flow_id(&self) -> u64 {
+        self.parent_id
+    }
+
+    /// The trace uuid to record with [`SessionMetadata::uuid`].
+    pub fn trace_uuid(&self) -> u128 {
+        self.trace_id
+    }
+
+    /// Session metadata carrying this context's trace id as the trace uuid.
+    pub fn session(&self) -> SessionMetadata {
+        SessionMetadata::new().uuid(self.trace_id)
+    }
+}
+
+/// `field` if it is `len` hex digits.
+fn hex(field: &str, len: usize) -> Result<&str, InvalidTraceParent> {
+    (field.len() == len && field.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then_some(field)
+        .ok_or(InvalidTraceParent)
+}
+
+impl FromStr for TraceParent {
+    type Err = InvalidTraceParent;
+
+    fn from_str(s: &str) -> Result<Self, InvalidTraceParent> {
+        let mut parts = s.trim().split('-');
+        let (Some(version), Some(trace_id), Some(parent_id), Some(flags)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(InvalidTraceParent);
+        };
+        // Later versions may append fields, which are ignored.
+        if hex(version, 2)? == "ff" || (version == "00" && parts.next().is_some()) {
+            return Err(InvalidTraceParent);
+        }
+        let parsed = Self {
+            trace_id: u128::from_str_radix(hex(trace_id, 32)?, 16)
+                .map_err(|_| InvalidTraceParent)?,
+            parent_id: u64::from_str_radix(hex(parent_id, 16)?, 16)
+                .map_err(|_| InvalidTraceParent)?,
+            flags: u8::from_str_radix(hex(flags, 2)?, 16).map_err(|_| InvalidTraceParent)?,
+        };
+        if parsed.trace_id == 0 || parsed.parent_id == 0 {
+            return Err(InvalidTraceParent);
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn round_trips_through_flow_ids() {
+        let parent: TraceParent = EXAMPLE.parse().unwrap();
+        assert_eq!(parent.trace_uuid(), 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(parent.flow_id(), 0x00f067aa0ba902b7);
+        assert_eq!(parent.flags, TraceParent::SAMPLED);
+        let rebuilt = TraceParent::from_flow(parent.trace_uuid(), parent.flow_id());
+        assert_eq!(rebuilt.to_string(), EXAMPLE);
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(
+                value.parse::<TraceParent>(),
+                Err(InvalidTraceParent),
+                "{}",
+                value
+            );
+        }
+        let future = format!("01-{}-extra", &EXAMPLE[3..]);
+        assert!(future.parse::<TraceParent>().is_ok());
+    }
+}
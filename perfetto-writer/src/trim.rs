@@ -0,0 +1,260 @@
+use perfetto_protos::{trace::Trace, trace_packet::TracePacket, track_event::track_event::Type};
+use std::{collections::HashMap, ops::Range};
+
+use crate::INCREMENTAL_CLOCK_ID;
+
+/// Resolves the timestamps of track events, following the incremental
+/// clock of each sequence through its snapshots and deltas.
+#[derive(Default)]
+pub(crate) struct EventTimes(HashMap<u32, u64>);
+
+impl EventTimes {
+    /// The timestamp of `packet`'s track event in the event's own clock, or
+    /// None for packets without one. Every packet has to be passed in order.
+    pub(crate) fn resolve(&mut self, packet: &TracePacket) -> Option<u64> {
+        let seq = packet.trusted_packet_sequence_id();
+        if packet.has_clock_snapshot() {
+            for clock in &packet.clock_snapshot().clocks {
+                if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                    self.0.insert(seq, clock.timestamp());
+                }
+            }
+        }
+        if !packet.has_track_event() {
+            return None;
+        }
+        if packet.timestamp_clock_id() != INCREMENTAL_CLOCK_ID {
+            return Some(packet.timestamp());
+        }
+        let last = self.0.entry(seq).or_default();
+        *last += packet.timestamp();
+        Some(*last)
+    }
+}
+
+/// Clips `trace` to the track events timestamped within `window`, e.g. to
+/// share just the interesting seconds of a long capture.
+///
+/// Descriptors, interned data, clock snapshots and other packets without
+/// an event are kept, so the rest still decodes. Slices that began before
+/// the window lose their end, slices still open when it closes end at its
+/// boundary, and delta encoded timestamps are rewritten against the events
+/// that remain. The window is in the events' own clock,
+/// nanoseconds for the default one.
+pub fn trim(trace: &mut Trace, window: Range<u64>) {
+    let mut times = EventTimes::default();
+    // Absolute time of the last packet kept on each sequence's incremental
+    // clock.
+    let mut kept: HashMap<u32, u64> = HashMap::new();
+    // Whether each open slice was kept, per track.
+    let mut open: HashMap<u64, Vec<bool>> = HashMap::new();
+
+    trace.packet.retain_mut(|packet| {
+        let seq = packet.trusted_packet_sequence_id();
+        if packet.has_clock_snapshot() {
+            for clock in &packet.clock_snapshot().clocks {
+                if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                    kept.insert(seq, clock.timestamp());
+                }
+            }
+        }
+        let Some(timestamp) = times.resolve(packet) else {
+            return true;
+        };
+
+        let incremental = packet.timestamp_clock_id() == INCREMENTAL_CLOCK_ID;
+        let event = packet.track_event();
+        let stack = open.entry(event.track_uuid()).or_default();
+        let in_window = window.contains(&timestamp);
+        let mut timestamp = timestamp;
+        let keep = match event.type_() {
+            Type::TYPE_SLICE_BEGIN => {
+                stack.push(in_window);
+                in_window
+            }
+            Type::TYPE_SLICE_END => {
+                let begun = stack.pop().unwrap_or(false);
+                if begun && timestamp >= window.end {
+                    timestamp = window.end;
+                    if !incremental {
+                        packet.set_timestamp(timestamp);
+                    }
+                }
+                begun
+            }
+            _ => in_window,
+        };
+
+        if keep {
+            if incremental {
+                let last = kept.entry(seq).or_default();
+                packet.set_timestamp(timestamp - *last);
+                *last = timestamp;
+            }
+            return true;
+        }
+        // Interning and sequence state ride along on some event packets.
+        if packet.interned_data.is_some() || packet.sequence_flags() != 0 {
+            packet.clear_track_event();
+            packet.clear_timestamp();
+            packet.clear_timestamp_clock_id();
+            return true;
+        }
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use anyhow::Result;
+    use protobuf::Message;
+
+    fn events(trace: &Trace) -> Vec<(Type, u64)> {
+        trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.track_event().type_(), p.timestamp()))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_only_the_window() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for (ts, kind) in [
+            (100, Type::TYPE_SLICE_BEGIN),
+            (150, Type::TYPE_INSTANT),
+            (200, Type::TYPE_SLICE_BEGIN),
+            (250, Type::TYPE_SLICE_END),
+            (300, Type::TYPE_SLICE_END),
+            (350, Type::TYPE_SLICE_BEGIN),
+            (400, Type::TYPE_SLICE_END),
+        ] {
+            let mut event = ctx.event().with_timestamp_ns(ts).with_track_uuid(track);
+            match kind {
+                Type::TYPE_SLICE_BEGIN => event.begin(),
+                Type::TYPE_SLICE_END => event.end(),
+                _ => event.instant(),
+            }
+            event.name("work");
+            event.build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let mut trace = Trace::parse_from_bytes(&buf)?;
+        let descriptors = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .count();
+
+        trim(&mut trace, 150..375);
+        assert_eq!(
+            events(&trace),
+            [
+                (Type::TYPE_INSTANT, 150),
+                (Type::TYPE_SLICE_BEGIN, 200),
+                (Type::TYPE_SLICE_END, 250),
+                (Type::TYPE_SLICE_BEGIN, 350),
+                (Type::TYPE_SLICE_END, 375)
+            ]
+        );
+        let remaining = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .count();
+        assert_eq!(remaining, descriptors);
+        assert!(trace.packet.iter().any(|p| p.interned_data.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites_delta_timestamps() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.set_delta_timestamps(true);
+        let track = ctx.current_thread_track();
+        for _ in 0..5 {
+            ctx.event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+        }
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let mut trace = Trace::parse_from_bytes(&buf)?;
+        let mut absolute = Vec::new();
+        let mut last = 0;
+        for packet in &trace.packet {
+            if packet.has_clock_snapshot() {
+                for clock in &packet.clock_snapshot().clocks {
+                    if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                        last = clock.timestamp();
+                    }
+                }
+            }
+            if packet.has_track_event() {
+                last += packet.timestamp();
+                absolute.push(last);
+            }
+        }
+
+        trim(&mut trace, absolute[2]..u64::MAX);
+        let deltas: Vec<_> = events(&trace).into_iter().map(|(_, ts)| ts).collect();
+        let base = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_clock_snapshot())
+            .flat_map(|p| p.clock_snapshot().clocks.iter())
+            .filter(|c| c.clock_id() == INCREMENTAL_CLOCK_ID)
+            .map(|c| c.timestamp())
+            .next_back()
+            .unwrap();
+        assert_eq!(deltas[0], absolute[2] - base);
+        assert_eq!(deltas[1], absolute[3] - absolute[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn ends_delta_slices_at_the_boundary() -> Result<()> {
+        let mut ctx = Context::new();
+        ctx.set_delta_timestamps(true);
+        let track = ctx.current_thread_track();
+        let begin = ctx.clock().now();
+        ctx.event()
+            .with_begin()
+            .with_timestamp_ns(begin)
+            .with_track_uuid(track)
+            .with_name("long")
+            .build();
+        ctx.event()
+            .with_end()
+            .with_timestamp_ns(begin + 1_000)
+            .with_track_uuid(track)
+            .build();
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        let mut trace = Trace::parse_from_bytes(&buf)?;
+
+        trim(&mut trace, begin..begin + 400);
+        let mut times = EventTimes::default();
+        let resolved: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| Some((p.track_event().type_(), times.resolve(p)?)))
+            .collect();
+        assert_eq!(
+            resolved,
+            [
+                (Type::TYPE_SLICE_BEGIN, begin),
+                (Type::TYPE_SLICE_END, begin + 400)
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use perfetto_protos::{
+    debug_annotation::DebugAnnotation,
+    trace::Trace,
+    trace_packet::{TracePacket, trace_packet::SequenceFlags},
+    track_event::track_event::Type,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::INCREMENTAL_CLOCK_ID;
+
+/// The kinds of interned data a packet can refer to by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InternedKind {
+    EventName,
+    Category,
+    SourceLocation,
+    AnnotationName,
+    AnnotationString,
+}
+
+/// Something wrong with a trace, found by [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An event on a track no descriptor declares.
+    MissingTrack(u64),
+    /// A track whose parent no descriptor declares.
+    MissingParent { track: u64, parent: u64 },
+    /// A slice end with no open slice on its track.
+    EndWithoutBegin(u64),
+    /// An event earlier than the one before it on the same sequence and
+    /// clock.
+    NonMonotonic {
+        sequence: u32,
+        previous: u64,
+        timestamp: u64,
+    },
+    /// An interned id not defined on the packet's sequence since its
+    /// incremental state was last cleared.
+    DanglingIid { kind: InternedKind, iid: u64 },
+    /// A delta encoded timestamp before any snapshot of its clock.
+    IncrementalWithoutSnapshot(u32),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingTrack(track) => write!(f, "event on undeclared track {track}"),
+            Self::MissingParent { track, parent } => {
+                write!(f, "track {track} has undeclared parent {parent}")
+            }
+            Self::EndWithoutBegin(track) => write!(f, "slice end without a begin on track {track}"),
+            Self::NonMonotonic {
+                sequence,
+                previous,
+                timestamp,
+            } => write!(
+                f,
+                "timestamp {timestamp} is before {previous} on sequence {sequence}"
+            ),
+            Self::DanglingIid { kind, iid } => write!(f, "undefined {kind:?} iid {iid}"),
+            Self::IncrementalWithoutSnapshot(sequence) => write!(
+                f,
+                "incremental timestamp before a clock snapshot on sequence {sequence}"
+            ),
+        }
+    }
+}
+
+/// A [`Problem`] and the index of the packet it was found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub packet: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet {}: {}", self.packet, self.problem)
+    }
+}
+
+/// Interned ids defined on one sequence.
+#[derive(Default)]
+struct Defined(HashSet<(InternedKind, u64)>);
+
+impl Defined {
+    fn update(&mut self, packet: &TracePacket) {
+        let Some(data) = packet.interned_data.as_ref() else {
+            return;
+        };
+        let ids = [
+            (
+                InternedKind::EventName,
+                data.event_names.iter().map(|n| n.iid()).collect::<Vec<_>>(),
+            ),
+            (
+                InternedKind::Category,
+                data.event_categories.iter().map(|c| c.iid()).collect(),
+            ),
+            (
+                InternedKind::SourceLocation,
+                data.source_locations.iter().map(|l| l.iid()).collect(),
+            ),
+            (
+                InternedKind::AnnotationName,
+                data.debug_annotation_names
+                    .iter()
+                    .map(|n| n.iid())
+                    .collect(),
+            ),
+            (
+                InternedKind::AnnotationString,
+                data.debug_annotation_string_values
+                    .iter()
+                    .map(|s| s.iid())
+                    .collect(),
+            ),
+        ];
+        for (kind, iids) in ids {
+            self.0.extend(iids.into_iter().map(|iid| (kind, iid)));
+        }
+    }
+
+    fn check(&self, kind: InternedKind, iid: u64, problems: &mut Vec<Problem>) {
+        if !self.0.contains(&(kind, iid)) {
+            problems.push(Problem::DanglingIid { kind, iid });
+        }
+    }
+
+    fn check_annotation(&self, annotation: &DebugAnnotation, problems: &mut Vec<Problem>) {
+        if annotation.has_name_iid() {
+            self.check(
+                InternedKind::AnnotationName,
+                annotation.name_iid(),
+                problems,
+            );
+        }
+        if annotation.has_string_value_iid() {
+            let iid = annotation.string_value_iid();
+            self.check(InternedKind::AnnotationString, iid, problems);
+        }
+        for child in annotation
+            .dict_entries
+            .iter()
+            .chain(annotation.array_values.iter())
+        {
+            self.check_annotation(child, problems);
+        }
+    }
+}
+
+/// Checks a parsed trace for the mistakes that make trace processor drop or
+/// misplace data: events on undeclared tracks, slice ends without a begin,
+/// timestamps going backwards within a sequence, dangling interned ids and
+/// delta timestamps without a clock snapshot.
+///
+/// Useful in tests of code that writes packets by hand, e.g. with
+/// [`Context::write_raw_packet`](crate::Context::write_raw_packet).
+pub fn validate(trace: &Trace) -> Vec<Diagnostic> {
+    let tracks: HashSet<u64> = trace
+        .packet
+        .iter()
+        .filter(|p| p.has_track_descriptor())
+        .map(|p| p.track_descriptor().uuid())
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    let mut defined: HashMap<u32, Defined> = HashMap::new();
+    let mut clocks: HashMap<u32, u64> = HashMap::new();
+    let mut last: HashMap<(u32, u32), u64> = HashMap::new();
+    let mut open: HashMap<u64, usize> = HashMap::new();
+    for (i, packet) in trace.packet.iter().enumerate() {
+        let mut problems = Vec::new();
+        let seq = packet.trusted_packet_sequence_id();
+        if packet.sequence_flags() & SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32 != 0 {
+            defined.remove(&seq);
+        }
+        let interned = defined.entry(seq).or_default();
+        interned.update(packet);
+        if packet.has_clock_snapshot() {
+            for clock in &packet.clock_snapshot().clocks {
+                if clock.clock_id() == INCREMENTAL_CLOCK_ID {
+                    clocks.insert(seq, clock.timestamp());
+                }
+            }
+        }
+        let clock = packet.timestamp_clock_id();
+        let timestamp = if clock == INCREMENTAL_CLOCK_ID && packet.has_timestamp() {
+            match clocks.get_mut(&seq) {
+                Some(base) => {
+                    *base += packet.timestamp();
+                    Some(*base)
+                }
+                None => {
+                    problems.push(Problem::IncrementalWithoutSnapshot(seq));
+                    None
+                }
+            }
+        } else {
+            packet.timestamp
+        };
+
+        if packet.has_track_descriptor() {
+            let track = packet.track_descriptor();
+            if track.has_parent_uuid() && !tracks.contains(&track.parent_uuid()) {
+                problems.push(Problem::MissingParent {
+                    track: track.uuid(),
+                    parent: track.parent_uuid(),
+                });
+            }
+        }
+
+        if packet.has_track_event() {
+            let event = packet.track_event();
+            let track = event.track_uuid();
+            if event.has_track_uuid() && !tracks.contains(&track) {
+                problems.push(Problem::MissingTrack(track));
+            }
+            if let Some(timestamp) = timestamp
+                && let Some(previous) = last.insert((seq, clock), timestamp)
+                && timestamp < previous
+            {
+                problems.push(Problem::NonMonotonic {
+                    sequence: seq,
+                    previous,
+                    timestamp,
+                });
+            }
+            if event.has_name_iid() {
+                interned.check(InternedKind::EventName, event.name_iid(), &mut problems);
+            }
+            for iid in &event.category_iids {
+                interned.check(InternedKind::Category, *iid, &mut problems);
+            }
+            if event.has_source_location_iid() {
+                let iid = event.source_location_iid();
+                interned.check(InternedKind::SourceLocation, iid, &mut problems);
+            }
+            for annotation in &event.debug_annotations {
+                interned.check_annotation(annotation, &mut problems);
+            }
+            match event.type_() {
+                Type::TYPE_SLICE_BEGIN => *open.entry(track).or_default() += 1,
+                Type::TYPE_SLICE_END => match open.get_mut(&track) {
+                    Some(depth) if *depth > 0 => *depth -= 1,
+                    _ => problems.push(Problem::EndWithoutBegin(track)),
+                },
+                _ => {}
+            }
+        }
+        diagnostics.extend(
+            problems
+                .into_iter()
+                .map(|problem| Diagnostic { packet: i, problem }),
+        );
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, FlowDirection};
+    use anyhow::Result;
+    use protobuf::Message;
+
+    fn written(ctx: &mut Context) -> Result<Trace> {
+        let mut buf = Vec::new();
+        ctx.write_to(&mut buf)?;
+        Ok(Trace::parse_from_bytes(&buf)?)
+    }
+
+    #[test]
+    fn the_writer_produces_valid_traces() -> Result<()> {
+        for delta in [false, true] {
+            let mut ctx = Context::new();
+            ctx.set_delta_timestamps(delta);
+            let track = ctx.current_thread_track();
+            let counter = ctx.counter_track("queue");
+            ctx.event()
+                .with_begin()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("work")
+                .with_category("io")
+                .with_debug_str("key", "value")
+                .with_source_location("src/lib.rs", 1)
+                .with_flow(7, FlowDirection::Continue)
+                .build();
+            ctx.event()
+                .with_counter()
+                .with_now()
+                .with_track_uuid(counter)
+                .with_counter_value(3)
+                .build();
+            ctx.event()
+                .with_end()
+                .with_now()
+                .with_track_uuid(track)
+                .build();
+            assert_eq!(validate(&written(&mut ctx)?), []);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn problems_are_reported() -> Result<()> {
+        let mut ctx = Context::new();
+        let track = ctx.current_thread_track();
+        for ts in [200, 100] {
+            ctx.event()
+                .with_instant()
+                .with_timestamp_ns(ts)
+                .with_track_uuid(track)
+                .with_name("tick")
+                .build();
+        }
+        ctx.event()
+            .with_end()
+            .with_timestamp_ns(300)
+            .with_track_uuid(track)
+            .build();
+        let mut trace = written(&mut ctx)?;
+        trace
+            .packet
+            .retain(|p| p.interned_data.is_none() && !p.has_track_descriptor());
+
+        let problems: Vec<_> = validate(&trace).into_iter().map(|d| d.problem).collect();
+        assert!(problems.contains(&Problem::MissingTrack(track)));
+        assert!(problems.contains(&Problem::EndWithoutBegin(track)));
+        assert!(problems.contains(&Problem::NonMonotonic {
+            sequence: trace.packet[0].trusted_packet_sequence_id(),
+            previous: 200,
+            timestamp: 100,
+        }));
+        assert!(problems.iter().any(|p| matches!(
+            p,
+            Problem::DanglingIid {
+                kind: InternedKind::EventName,
+                ..
+            }
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use smol_str::SmolStr;
+use std::sync::{Arc, Mutex};
+
+use crate::Context;
+
+/// Records the time a WebAssembly instance spends in guest code and in host
+/// calls as slices on a dedicated track.
+///
+/// Embedders report boundary crossings with [`GuestTracer::enter_guest`] /
+/// [`GuestTracer::exit_guest`] and [`GuestTracer::enter_host`] /
+/// [`GuestTracer::exit_host`]. With the `wasmtime` feature,
+/// [`GuestTracer::install`] wires these up to a store's call hook.
+pub struct GuestTracer {
+    ctx: Arc<Mutex<Context>>,
+    track: u64,
+    next_guest_name: Mutex<Option<SmolStr>>,
+}
+
+impl GuestTracer {
+    pub fn new(ctx: Arc<Mutex<Context>>, instance: &str) -> Self {
+        let track = ctx
+            .lock()
+            .unwrap()
+            .track()
+            .name(format!("wasm {}", instance))
+            .build();
+        Self {
+            ctx,
+            track,
+            next_guest_name: Mutex::new(None),
+        }
+    }
+
+    pub fn track_uuid(&self) -> u64 {
+        self.track
+    }
+
+    /// Runs `f`, naming the guest slice it opens `name` instead of the
+    /// generic "wasm". Use it around typed calls into the instance.
+    pub fn call<R>(&self, name: impl Into<SmolStr>, f: impl FnOnce() -> R) -> R {
+        *self.next_guest_name.lock().unwrap() = Some(name.into());
+        let result = f();
+        self.next_guest_name.lock().unwrap().take();
+        result
+    }
+
+    pub fn enter_guest(&self) {
+        let name = self
+            .next_guest_name
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| SmolStr::new_static("wasm"));
+        self.begin(name);
+    }
+
+    pub fn exit_guest(&self) {
+        self.end();
+    }
+
+    pub fn enter_host(&self, name: impl Into<SmolStr>) {
+        self.begin(name.into());
+    }
+
+    pub fn exit_host(&self) {
+        self.end();
+    }
+
+    fn begin(&self, name: SmolStr) {
+        self.ctx
+            .lock()
+            .unwrap()
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(self.track)
+            .with_category("wasm")
+            .with_name(name)
+            .build();
+    }
+
+    fn end(&self) {
+        self.ctx
+            .lock()
+            .unwrap()
+            .event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(self.track)
+            .build();
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl GuestTracer {
+    /// Maps a wasmtime call hook transition onto guest/host slices.
+    pub fn on_call_hook(&self, hook: wasmtime::CallHook) {
+        match hook {
+            wasmtime::CallHook::CallingWasm => self.enter_guest(),
+            wasmtime::CallHook::ReturningFromWasm => self.exit_guest(),
+            wasmtime::CallHook::CallingHost => self.enter_host("host"),
+            wasmtime::CallHook::ReturningFromHost => self.exit_host(),
+        }
+    }
+
+    /// Installs the tracer as `store`'s call hook, replacing any existing one.
+    pub fn install<T: 'static>(self: &Arc<Self>, store: &mut wasmtime::Store<T>) {
+        let tracer = Arc::clone(self);
+        store.call_hook(move |_, hook| {
+            tracer.on_call_hook(hook);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[test]
+    fn guest_and_host_slices() -> Result<()> {
+        let ctx = Arc::new(Mutex::new(Context::new()));
+        let tracer = GuestTracer::new(Arc::clone(&ctx), "plugin");
+
+        tracer.call("run", || {
+            tracer.enter_guest();
+            tracer.enter_host("fd_write");
+            tracer.exit_host();
+            tracer.exit_guest();
+        });
+        tracer.enter_guest();
+        tracer.exit_guest();
+
+        let mut buf = Vec::new();
+        ctx.lock().unwrap().write_to(&mut buf)?;
+        let trace = Trace::parse_from_bytes(&buf)?;
+
+        let track = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor())
+            .unwrap()
+            .track_descriptor();
+        assert_eq!(track.name(), "wasm plugin");
+
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["run", "fd_write", "wasm"]);
+
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .inspect(|p| assert_eq!(p.track_event().track_uuid(), tracer.track_uuid()))
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_END,
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{Clock, ClockId};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    fn performance_now() -> f64;
+
+    #[wasm_bindgen(thread_local_v2, js_namespace = performance, js_name = timeOrigin)]
+    static TIME_ORIGIN: f64;
+}
+
+/// `performance.now()` in nanoseconds.
+pub(crate) fn performance_now_ns() -> u64 {
+    (performance_now() * 1e6) as u64
+}
+
+/// Wall clock time with `performance.now()` resolution, rather than the
+/// millisecond resolution of `Date.now()`.
+pub(crate) fn realtime_ns() -> u64 {
+    ((TIME_ORIGIN.with(|origin| *origin) + performance_now()) * 1e6) as u64
+}
+
+/// Reads `performance.now()`, the high resolution timer of browsers and
+/// workers, in the monotonic domain. Install it with
+/// [`Context::set_clock`](crate::Context::set_clock) to timestamp events
+/// relative to page load.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PerformanceClock;
+
+impl Clock for PerformanceClock {
+    fn now(&self) -> u64 {
+        performance_now_ns()
+    }
+
+    fn id(&self) -> ClockId {
+        ClockId::Monotonic
+    }
+}
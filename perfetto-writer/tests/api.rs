@@ -0,0 +1,9 @@
+//! Compile tests pinning the stable API. A failure here means a change
+//! breaks downstream crates and needs a major version bump.
+
+#[test]
+fn stable_api() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/prelude.rs");
+    t.pass("tests/ui/builders.rs");
+}
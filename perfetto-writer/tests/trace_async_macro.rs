@@ -0,0 +1,81 @@
+#![cfg(feature = "macros")]
+
+use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+use perfetto_writer as perfetto;
+use protobuf::Message;
+use std::{
+    future::Future,
+    io::Write,
+    pin::pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pending on the first poll, so futures interleave.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if std::mem::replace(&mut self.0, true) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[perfetto::trace(category = "net", crate = "perfetto")]
+async fn fetch(n: u32) -> u32 {
+    YieldOnce(false).await;
+    n * 2
+}
+
+#[test]
+fn interleaved_calls_get_a_track_each() {
+    let sink = SharedBuf::default();
+    perfetto::init_global(sink.clone());
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut a = pin!(fetch(1));
+    let mut b = pin!(fetch(2));
+    assert!(a.as_mut().poll(&mut cx).is_pending());
+    assert!(b.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(a.as_mut().poll(&mut cx), Poll::Ready(2));
+    assert_eq!(b.as_mut().poll(&mut cx), Poll::Ready(4));
+    perfetto::flush_global().unwrap();
+
+    let trace = Trace::parse_from_bytes(&sink.0.lock().unwrap()).unwrap();
+    let slices: Vec<_> = trace
+        .packet
+        .iter()
+        .filter(|p| p.has_track_event())
+        .map(|p| p.track_event())
+        .filter(|e| matches!(e.type_(), Type::TYPE_SLICE_BEGIN | Type::TYPE_SLICE_END))
+        .map(|e| (e.type_(), e.track_uuid()))
+        .collect();
+    let [
+        (Type::TYPE_SLICE_BEGIN, a),
+        (Type::TYPE_SLICE_BEGIN, b),
+        (Type::TYPE_SLICE_END, a_end),
+        (Type::TYPE_SLICE_END, b_end),
+    ] = slices[..]
+    else {
+        panic!("{slices:?}");
+    };
+    assert_ne!(a, b);
+    assert_eq!((a, b), (a_end, b_end));
+}
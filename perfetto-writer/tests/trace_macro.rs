@@ -0,0 +1,59 @@
+#![cfg(feature = "macros")]
+
+use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+use perfetto_writer as perfetto;
+use protobuf::Message;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[perfetto::trace(category = "db")]
+fn query(rows: u32) -> u32 {
+    rows * 2
+}
+
+#[perfetto::trace]
+fn handle() -> u32 {
+    query(2) + query(3)
+}
+
+#[test]
+fn traced_functions_record_slices() {
+    let sink = SharedBuf::default();
+    perfetto::init_global(sink.clone());
+    assert_eq!(handle(), 10);
+    perfetto::flush_global().unwrap();
+
+    let trace = Trace::parse_from_bytes(&sink.0.lock().unwrap()).unwrap();
+    let names: Vec<_> = trace
+        .packet
+        .iter()
+        .filter_map(|p| p.interned_data.as_ref())
+        .flat_map(|i| i.event_names.iter())
+        .map(|n| n.name().to_string())
+        .collect();
+    assert_eq!(names, ["handle", "query"]);
+    let types: Vec<_> = trace
+        .packet
+        .iter()
+        .filter(|p| p.has_track_event())
+        .map(|p| p.track_event().type_())
+        .collect();
+    assert_eq!(types.len(), 6);
+    assert_eq!(types[0], Type::TYPE_SLICE_BEGIN);
+    assert_eq!(types[5], Type::TYPE_SLICE_END);
+}
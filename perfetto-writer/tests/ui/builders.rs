@@ -0,0 +1,32 @@
+use perfetto_writer::prelude::*;
+
+fn main() {
+    let mut ctx = Context::new();
+    let track: u64 = ctx
+        .track()
+        .name("requests")
+        .current_process()
+        .counter()
+        .unit(CounterUnit::UNIT_COUNT)
+        .build();
+
+    let event: EventBuilder<'_> = ctx
+        .event()
+        .with_begin()
+        .with_now()
+        .with_track_uuid(track)
+        .with_category("io")
+        .with_name("read")
+        .with_source_location("main.rs", 1)
+        .with_flow_id(1)
+        .with_debug_str("path", "/tmp")
+        .with_debug_int("len", 1)
+        .with_debug_bool("cached", false);
+    event.build();
+
+    ctx.event()
+        .with_end()
+        .with_timestamp_us(1)
+        .with_track_uuid(track)
+        .build();
+}
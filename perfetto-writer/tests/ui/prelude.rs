@@ -0,0 +1,12 @@
+use perfetto_writer::prelude::*;
+
+fn main() {
+    let mut ctx: Context = Context::new();
+    let _: &mut Context = &mut ctx;
+    let _: fn(&mut Context) -> u64 = Context::current_thread_track;
+    let _: fn(&Context) -> u64 = Context::next_id;
+    let _: CounterUnit = CounterUnit::UNIT_COUNT;
+
+    let mut buf = Vec::new();
+    ctx.write_to(&mut buf).unwrap();
+}
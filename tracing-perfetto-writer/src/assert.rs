@@ -0,0 +1,75 @@
+/// Target of the events emitted by [`perfetto_assert!`]; the layer draws
+/// them in red, named after their message.
+pub const ASSERT_TARGET: &str = "perfetto.assert";
+
+/// A soft `debug_assert!`: when `cond` is false, records an instant named
+/// after the message with the expression text attached, so invariant
+/// violations show up on the timeline. Like `debug_assert!` it panics in
+/// debug builds, but in release builds execution carries on.
+///
+/// ```
+/// use tracing_perfetto_writer::perfetto_assert;
+///
+/// let (len, cap) = (3, 4);
+/// perfetto_assert!(len <= cap, "len {} exceeds cap {}", len, cap);
+/// ```
+#[macro_export]
+macro_rules! perfetto_assert {
+    ($cond:expr $(,)?) => {
+        $crate::perfetto_assert!($cond, "assertion failed: {}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            let message = format!($($arg)+);
+            $crate::__private::tracing::error!(
+                target: $crate::ASSERT_TARGET,
+                assertion = stringify!($cond),
+                "{}",
+                message
+            );
+            if cfg!(debug_assertions) {
+                panic!("{}", message);
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PerfettoLayer;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn failed_assertions_are_recorded() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("work").entered();
+            let (len, cap) = (5, 4);
+            perfetto_assert!(len >= 1);
+            let failed = catch_unwind(AssertUnwindSafe(|| {
+                perfetto_assert!(len <= cap, "len {} exceeds cap {}", len, cap);
+            }));
+            assert_eq!(failed.is_err(), cfg!(debug_assertions));
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert!(names.contains(&"len 5 exceeds cap 4".to_string()));
+        let instants = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().type_() == Type::TYPE_INSTANT)
+            .count();
+        assert_eq!(instants, 1);
+    }
+}
@@ -0,0 +1,86 @@
+use perfetto_writer::fork_generation;
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::{Error, PerfettoLayer, PerfettoLayerBuilder};
+
+pub(crate) type AsyncSink = Arc<Mutex<Option<Box<dyn AsyncWrite + Send + Unpin>>>>;
+
+impl PerfettoLayerBuilder {
+    /// Where [`PerfettoLayer::flush_async`] writes the encoded trace, e.g. a
+    /// `tokio::fs::File` or `TcpStream`, without blocking worker threads.
+    /// Automatic flushes on a full buffer still go to the blocking
+    /// [`sink`](PerfettoLayerBuilder::sink).
+    pub fn async_sink(mut self, sink: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        self.async_sink = Some(Box::new(sink));
+        self
+    }
+}
+
+impl PerfettoLayer {
+    /// Encodes the buffered trace and writes it to the async sink, if any.
+    /// The context lock is only held while encoding, so events keep being
+    /// recorded while the write is in flight.
+    pub async fn flush_async(&self) -> Result<(), Error> {
+        let mut sink = self.async_sink.lock().await;
+        self.forget_if_forked(&mut sink);
+        let Some(sink) = sink.as_mut() else {
+            return Ok(());
+        };
+        let mut buf = Vec::new();
+        self.lock()
+            .write_to_vec(&mut buf)
+            .map_err(|e| Error::Write(e.into()))?;
+        self.drained();
+        let written = async {
+            sink.write_all(&buf).await?;
+            sink.flush().await
+        };
+        written.await.map_err(|e| Error::Write(e.into()))
+    }
+
+    /// Forgets a sink inherited through `fork()`. It belongs to the parent's
+    /// trace, and dropping it could flush the parent's buffered bytes twice.
+    /// The context forgets its own sinks.
+    fn forget_if_forked<T>(&self, sink: &mut Option<T>) {
+        if self.fork_generation != fork_generation() {
+            std::mem::forget(sink.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn flushes_to_an_async_writer() {
+        let (writer, mut reader) = tokio::io::duplex(1 << 16);
+        let layer = PerfettoLayer::builder().async_sink(writer).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("streamed").in_scope(|| {});
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let bytes = runtime.block_on(async {
+            layer.flush_async().await.unwrap();
+            drop(layer);
+            let mut bytes = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut bytes)
+                .await
+                .unwrap();
+            bytes
+        });
+        let trace = Trace::parse_from_bytes(&bytes).unwrap();
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+    }
+}
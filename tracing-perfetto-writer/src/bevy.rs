@@ -0,0 +1,92 @@
+use bevy_app::{App, First, Plugin};
+use bevy_ecs::{
+    resource::Resource,
+    system::{Local, Res},
+};
+
+use crate::PerfettoLayer;
+
+/// A Bevy plugin marking every frame of the app on a [`PerfettoLayer`], so
+/// frame pacing and long frames show up as described in
+/// [`Context::mark_frame`](perfetto_writer::Context::mark_frame).
+///
+/// Install the layer itself as usual, e.g. through `LogPlugin::custom_layer`,
+/// and build it with
+/// [`bevy_systems`](crate::PerfettoLayerBuilder::bevy_systems) so the
+/// system spans Bevy emits get their names and per-schedule tracks.
+pub struct PerfettoPlugin {
+    layer: PerfettoLayer,
+}
+
+impl PerfettoPlugin {
+    pub fn new(layer: PerfettoLayer) -> Self {
+        Self { layer }
+    }
+}
+
+#[derive(Resource)]
+struct FrameMarks(PerfettoLayer);
+
+fn mark_frame(marks: Res<FrameMarks>, mut frame: Local<u64>) {
+    marks.0.mark_frame(*frame);
+    *frame += 1;
+}
+
+impl Plugin for PerfettoPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(FrameMarks(self.layer.clone()))
+            .add_systems(First, mark_frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_app::Update;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    fn move_player() {}
+
+    #[test]
+    fn marks_frames_and_names_systems() {
+        let layer = PerfettoLayer::builder().bevy_systems(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut app = App::new();
+            app.add_plugins(PerfettoPlugin::new(layer.clone()))
+                .add_systems(Update, move_player);
+            for _ in 0..3 {
+                app.update();
+            }
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let track = |name: &str| {
+            trace
+                .packet
+                .iter()
+                .find(|p| p.has_track_descriptor() && p.track_descriptor().name() == name)
+                .map(|p| p.track_descriptor().uuid())
+                .unwrap()
+        };
+        let slices = |track| {
+            trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event() && p.track_event().track_uuid() == track)
+                .count()
+        };
+        assert_eq!(slices(track("Frames")), 2 * 2);
+        assert!(slices(track("Update")) >= 3 * 2);
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert!(names.contains(&"move_player".to_string()), "{names:?}");
+    }
+}
@@ -0,0 +1,340 @@
+#[cfg(feature = "tokio")]
+use perfetto_writer::fork_generation;
+use perfetto_writer::{Clock, Context, SessionMetadata, TraceSink};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::AtomicBool},
+    time::Duration,
+};
+use tracing::Metadata;
+
+use crate::{Error, ErrorHandler, OverflowPolicy, PerfettoLayer};
+
+pub(crate) type Filter = Arc<dyn Fn(&Metadata<'_>) -> bool + Send + Sync>;
+
+/// Limits applied to the fields recorded as debug annotations.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AnnotationLimits {
+    pub(crate) max_annotations: Option<usize>,
+    pub(crate) max_len: Option<usize>,
+}
+
+/// Settings shared by every clone of a [`PerfettoLayer`].
+#[derive(Default)]
+pub(crate) struct Config {
+    pub(crate) max_buffered_packets: Option<usize>,
+    pub(crate) max_buffered_bytes: Option<usize>,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) block_timeout: Option<Duration>,
+    pub(crate) filter: Option<Filter>,
+    pub(crate) thread_names: bool,
+    pub(crate) separate_event_tracks: bool,
+    pub(crate) log_messages: bool,
+    pub(crate) min_span_duration: Option<Duration>,
+    pub(crate) sample_root_spans: Option<u32>,
+    pub(crate) callsite_backtraces: bool,
+    pub(crate) busy_idle_time: bool,
+    pub(crate) poll_slices: bool,
+    pub(crate) bevy_systems: bool,
+    pub(crate) statistics_targets: Vec<String>,
+    pub(crate) statistics_interval: Option<Duration>,
+    pub(crate) annotation_limits: AnnotationLimits,
+    pub(crate) link_fields: Vec<String>,
+    pub(crate) session_id: Option<u128>,
+    pub(crate) trace_dir: Option<PathBuf>,
+}
+
+/// Configures a [`PerfettoLayer`]. Created with [`PerfettoLayer::builder`].
+pub struct PerfettoLayerBuilder {
+    context: Context,
+    #[cfg(feature = "tokio")]
+    pub(crate) async_sink: Option<Box<dyn tokio::io::AsyncWrite + Send + Unpin>>,
+    config: Config,
+    enabled: bool,
+    flush_on_exit: bool,
+    on_error: ErrorHandler,
+    routes: Vec<(String, PerfettoLayer)>,
+}
+
+impl Default for PerfettoLayerBuilder {
+    fn default() -> Self {
+        Self {
+            context: Context::builder().system_info(true).build(),
+            #[cfg(feature = "tokio")]
+            async_sink: None,
+            config: Config::default(),
+            enabled: true,
+            flush_on_exit: false,
+            on_error: Arc::new(|_| {}),
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl PerfettoLayerBuilder {
+    /// Where [`PerfettoLayer::flush_to_sink`] and automatic flushes write the
+    /// encoded trace. Successive flushes append to the same stream. Adds
+    /// to the context's [sinks](Context::add_sink), so calling it again
+    /// writes the trace to each.
+    pub fn sink(mut self, sink: impl TraceSink + 'static) -> Self {
+        self.context.add_sink(sink);
+        self
+    }
+
+    /// Writes the trace to `{dir}/{pid}.pftrace`, creating `dir` if needed.
+    /// Child processes started with [`PerfettoLayer::pass_to_child`] write
+    /// next to it, so the directory can be merged into one timeline.
+    pub fn trace_dir(mut self, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let file = File::create(dir.join(format!("{}.pftrace", std::process::id())))?;
+        self.context.add_sink(BufWriter::new(file));
+        self.config.trace_dir = Some(dir);
+        Ok(self)
+    }
+
+    /// Caps how many packets are held in memory. Once reached the buffer is
+    /// flushed to the sink, or new events are dropped when there is none.
+    pub fn max_buffered_packets(mut self, max: usize) -> Self {
+        self.config.max_buffered_packets = Some(max);
+        self
+    }
+
+    /// Like [`PerfettoLayerBuilder::max_buffered_packets`], but caps the
+    /// approximate encoded size of the buffer instead.
+    pub fn max_buffered_bytes(mut self, max: usize) -> Self {
+        self.config.max_buffered_bytes = Some(max);
+        self
+    }
+
+    /// Chooses what happens when the buffer is full and flushing did not
+    /// free any room. Defaults to [`OverflowPolicy::DropNewest`].
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = policy;
+        self
+    }
+
+    /// How long [`OverflowPolicy::Block`] waits for a flush before dropping
+    /// the new span or event. Defaults to one second.
+    pub fn block_timeout(mut self, timeout: Duration) -> Self {
+        self.config.block_timeout = Some(timeout);
+        self
+    }
+
+    /// Replaces the clock used to timestamp spans and events.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.context.set_clock(clock);
+        self
+    }
+
+    pub fn delta_timestamps(mut self, enabled: bool) -> Self {
+        self.context.set_delta_timestamps(enabled);
+        self
+    }
+
+    pub fn clock_snapshot_interval(mut self, interval: Option<Duration>) -> Self {
+        self.context.set_clock_snapshot_interval(interval);
+        self
+    }
+
+    /// Records each span's thread CPU time next to its wall time. Only
+    /// meaningful for spans that open and close on the same thread.
+    pub fn thread_time(mut self, enabled: bool) -> Self {
+        self.context.set_thread_time(enabled);
+        self
+    }
+
+    /// Writes a trace uuid and session metadata (app name, version, host,
+    /// and the command line if asked for) at the start of the trace. Child processes inherit the
+    /// uuid through [`PerfettoLayer::pass_to_child`].
+    pub fn session(mut self, session: SessionMetadata) -> Self {
+        self.config.session_id = Some(self.context.write_session(&session));
+        self
+    }
+
+    /// Renders the field `name` as a link built from `template`, e.g.
+    /// `link_template("request_id", "https://grafana.example/explore?id={}")`.
+    /// See [`Context::set_link_template`].
+    pub fn link_template(mut self, name: &str, template: impl Into<String>) -> Self {
+        self.context.set_link_template(name, template);
+        self.config.link_fields.push(name.to_string());
+        self
+    }
+
+    /// Only records spans and events for which `filter` returns true.
+    pub fn filter(
+        mut self,
+        filter: impl Fn(&Metadata<'_>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Names thread tracks after `std::thread::current().name()`.
+    pub fn thread_names(mut self, enabled: bool) -> Self {
+        self.config.thread_names = enabled;
+        self
+    }
+
+    /// Puts events on a "logs" track nested under each thread's track instead
+    /// of interleaving them with that thread's slices.
+    pub fn separate_event_tracks(mut self, enabled: bool) -> Self {
+        self.config.separate_event_tracks = enabled;
+        self
+    }
+
+    /// Also writes each event's message as a Perfetto log message, with its
+    /// level and source location, so it shows up in the UI's logs panel.
+    pub fn log_messages(mut self, enabled: bool) -> Self {
+        self.config.log_messages = enabled;
+        self
+    }
+
+    /// Drops spans that close sooner than `min`, removing their begin from
+    /// the buffer, so hot tight-loop spans don't bloat the trace. Spans whose
+    /// begin was already flushed are kept.
+    pub fn min_span_duration(mut self, min: Duration) -> Self {
+        self.config.min_span_duration = Some(min);
+        self
+    }
+
+    /// Records a random 1-in-`n` root spans together with all of their
+    /// children and events, bounding overhead and trace size for always-on
+    /// tracing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn sample_root_spans(mut self, n: u32) -> Self {
+        assert!(n > 0, "sampling rate must be at least 1");
+        self.config.sample_root_spans = Some(n);
+        self
+    }
+
+    /// Captures a backtrace the first time each span or event callsite fires
+    /// and attaches it to every span and event from that callsite. The
+    /// backtrace is interned, so after the first capture this costs a lookup.
+    pub fn callsite_backtraces(mut self, enabled: bool) -> Self {
+        self.config.callsite_backtraces = enabled;
+        self
+    }
+
+    /// Annotates each span's end with how long it was entered (`busy_ns`)
+    /// and how long it was open but not entered (`idle_ns`), showing how
+    /// much of an async span was spent executing rather than awaiting.
+    pub fn busy_idle_time(mut self, enabled: bool) -> Self {
+        self.config.busy_idle_time = enabled;
+        self
+    }
+
+    /// Writes a "poll" slice on the executing thread's track for every
+    /// enter and exit of a span, so each poll of an instrumented future
+    /// shows up, revealing scheduling gaps and poll storms. Spans then go on
+    /// an async track of their own instead of the thread's, so futures
+    /// interleaved on one thread keep their nesting.
+    pub fn poll_slices(mut self, enabled: bool) -> Self {
+        self.config.poll_slices = enabled;
+        self
+    }
+
+    /// Understands the spans Bevy emits with its `trace` feature: each run
+    /// of a system becomes a slice named after the system, on a track per
+    /// schedule under the thread's track, and schedule slices are named
+    /// after the schedule. Without this, every system is one slice named
+    /// "system" lasting the whole app, since Bevy enters the same span on
+    /// every run.
+    pub fn bevy_systems(mut self, enabled: bool) -> Self {
+        self.config.bevy_systems = enabled;
+        self
+    }
+
+    /// Aggregates spans whose target starts with `target` into duration
+    /// histograms, one per callsite, instead of recording every slice. The
+    /// aggregates are written as counter tracks once per statistics interval
+    /// and on every flush, so ultra-hot paths can stay instrumented at
+    /// almost no trace-size cost.
+    pub fn statistics_only(mut self, target: impl Into<String>) -> Self {
+        self.config.statistics_targets.push(target.into());
+        self
+    }
+
+    /// How often aggregates of statistics-only spans are written, one
+    /// second by default.
+    pub fn statistics_interval(mut self, interval: Duration) -> Self {
+        self.config.statistics_interval = Some(interval);
+        self
+    }
+
+    /// Records at most `max` fields per span or event.
+    pub fn max_annotations(mut self, max: usize) -> Self {
+        self.config.annotation_limits.max_annotations = Some(max);
+        self
+    }
+
+    /// Truncates recorded field values to `max` bytes.
+    pub fn max_annotation_len(mut self, max: usize) -> Self {
+        self.config.annotation_limits.max_len = Some(max);
+        self
+    }
+
+    /// Sends spans and events whose target starts with `target` to `layer`
+    /// instead, so subsystems can be written to sinks with their own buffer
+    /// limits and retention, e.g. verbose IO events to a small in-memory
+    /// buffer and request spans to a file. Keep a clone of `layer` to flush
+    /// it. Routes are tried in the order they were added.
+    pub fn route(mut self, target: impl Into<String>, layer: PerfettoLayer) -> Self {
+        self.routes.push((target.into(), layer));
+        self
+    }
+
+    /// Whether the layer starts out recording. See [`PerfettoLayer::set_enabled`].
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Flushes to the sink when the process exits. See
+    /// [`PerfettoLayer::flush_on_exit`].
+    pub fn flush_on_exit(mut self, enabled: bool) -> Self {
+        self.flush_on_exit = enabled;
+        self
+    }
+
+    /// Sets the callback invoked when the layer fails to record or write the
+    /// trace. By default errors are ignored; the layer can't report them
+    /// through `tracing` itself, since it may be holding its own lock.
+    pub fn on_error(mut self, f: impl Fn(Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Arc::new(f);
+        self
+    }
+
+    pub fn build(mut self) -> PerfettoLayer {
+        if self.config.trace_dir.is_some() && self.config.session_id.is_none() {
+            self = self.session(SessionMetadata::new());
+        }
+        let layer = PerfettoLayer {
+            context: Arc::new(Mutex::new(self.context)),
+            #[cfg(feature = "tokio")]
+            async_sink: Arc::new(tokio::sync::Mutex::new(self.async_sink)),
+            config: Arc::new(self.config),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            overflow: Arc::default(),
+            enabled: Arc::new(AtomicBool::new(self.enabled)),
+            backtraces: Arc::default(),
+            event_tracks: Arc::default(),
+            schedules: Arc::default(),
+            async_tracks: Arc::default(),
+            routes: Arc::new(self.routes),
+            stats: Arc::default(),
+            on_error: self.on_error,
+            #[cfg(feature = "tokio")]
+            fork_generation: fork_generation(),
+        };
+        if self.flush_on_exit {
+            layer.flush_on_exit();
+        }
+        layer
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+use web_time::Instant;
+
+/// Annotation holding how long a span spent entered, i.e. executing.
+pub(crate) const BUSY_ANNOTATION: &str = "busy_ns";
+
+/// Annotation holding how long a span spent open but not entered, e.g. an
+/// async span awaiting between polls.
+pub(crate) const IDLE_ANNOTATION: &str = "idle_ns";
+
+/// Busy and idle time accumulated over a span's enters and exits. A span
+/// entered again while entered, e.g. recursively or on another thread, is
+/// busy until the last of them exits.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SpanTimings {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+    depth: u32,
+}
+
+impl SpanTimings {
+    pub(crate) fn new() -> Self {
+        Self {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last: Instant::now(),
+            depth: 0,
+        }
+    }
+
+    pub(crate) fn enter(&mut self) {
+        if self.depth == 0 {
+            let now = Instant::now();
+            self.idle += now - self.last;
+            self.last = now;
+        }
+        self.depth += 1;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            let now = Instant::now();
+            self.busy += now - self.last;
+            self.last = now;
+        }
+    }
+
+    /// Busy and idle time in nanoseconds, counting the time since the last
+    /// exit as idle, or as busy if the span is still entered.
+    pub(crate) fn close(mut self) -> (u64, u64) {
+        let elapsed = Instant::now() - self.last;
+        match self.depth {
+            0 => self.idle += elapsed,
+            _ => self.busy += elapsed,
+        }
+        (self.busy.as_nanos() as u64, self.idle.as_nanos() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PerfettoLayer;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn busy_and_idle_time_are_annotated() {
+        let layer = PerfettoLayer::builder().busy_idle_time(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("poll");
+            for _ in 0..2 {
+                let _entered = span.enter();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_names.iter())
+            .map(|n| (n.iid(), n.name().to_string()))
+            .collect();
+        let value = |name: &str| {
+            let iid = names.iter().find(|(_, n)| n == name).unwrap().0;
+            trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event())
+                .flat_map(|p| p.track_event().debug_annotations.iter())
+                .find(|a| a.name_iid() == iid)
+                .unwrap()
+                .uint_value()
+        };
+        let busy = value(BUSY_ANNOTATION);
+        let idle = value(IDLE_ANNOTATION);
+        assert!(busy >= 10_000_000);
+        assert!(idle >= 10_000_000);
+    }
+
+    #[test]
+    fn nested_enters_are_busy_once() {
+        let mut timings = SpanTimings::new();
+        timings.enter();
+        std::thread::sleep(Duration::from_millis(5));
+        timings.enter();
+        timings.exit();
+        std::thread::sleep(Duration::from_millis(5));
+        timings.exit();
+        let (busy, idle) = timings.close();
+        assert!(busy >= 10_000_000);
+        assert!(idle < 5_000_000);
+    }
+}
@@ -0,0 +1,99 @@
+use perfetto_writer::SessionMetadata;
+use std::{io, process::Command};
+
+use crate::PerfettoLayer;
+
+/// Hex trace uuid shared by every process of a session.
+pub const SESSION_ID_VAR: &str = "PERFETTO_SESSION_ID";
+/// Directory each process of a session writes its `{pid}.pftrace` to.
+pub const TRACE_DIR_VAR: &str = "PERFETTO_TRACE_DIR";
+
+impl PerfettoLayer {
+    /// Sets the environment variables [`PerfettoLayer::from_parent_env`]
+    /// reads on `command`, so the child joins this layer's session and
+    /// [`trace_dir`](crate::PerfettoLayerBuilder::trace_dir).
+    pub fn pass_to_child<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        if let Some(id) = self.config.session_id {
+            command.env(SESSION_ID_VAR, format!("{:032x}", id));
+        }
+        if let Some(dir) = &self.config.trace_dir {
+            command.env(TRACE_DIR_VAR, dir);
+        }
+        command
+    }
+
+    /// Builds a layer for a process started with
+    /// [`PerfettoLayer::pass_to_child`], or returns `None` when
+    /// `PERFETTO_TRACE_DIR` is unset. The trace is flushed on exit; merge the
+    /// directory with `perfetto_writer::merge` or `perfetto-rs merge`.
+    pub fn from_parent_env() -> io::Result<Option<Self>> {
+        Self::from_parent_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_parent_vars(var: impl Fn(&str) -> Option<String>) -> io::Result<Option<Self>> {
+        let Some(dir) = var(TRACE_DIR_VAR) else {
+            return Ok(None);
+        };
+        let mut session = SessionMetadata::new();
+        if let Some(id) = var(SESSION_ID_VAR) {
+            let id = u128::from_str_radix(id.trim(), 16).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid {} {:?}: {}", SESSION_ID_VAR, id, e),
+                )
+            })?;
+            session = session.uuid(id);
+        }
+        let layer = Self::builder()
+            .session(session)
+            .trace_dir(dir)?
+            .flush_on_exit(true)
+            .build();
+        Ok(Some(layer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::{collections::HashMap, ffi::OsStr, fs};
+
+    #[test]
+    fn child_joins_the_parent_session() {
+        let dir = std::env::temp_dir().join(format!("perfetto-child-{}", std::process::id()));
+        let parent = PerfettoLayer::builder().trace_dir(&dir).unwrap().build();
+        let mut command = Command::new("true");
+        parent.pass_to_child(&mut command);
+        let vars: HashMap<&OsStr, &OsStr> = command
+            .get_envs()
+            .filter_map(|(k, v)| Some((k, v?)))
+            .collect();
+        let get = |key: &str| {
+            vars.get(OsStr::new(key))
+                .map(|v| v.to_string_lossy().into_owned())
+        };
+        assert_eq!(get(TRACE_DIR_VAR), Some(dir.to_string_lossy().into_owned()));
+
+        let child = PerfettoLayer::from_parent_vars(get).unwrap().unwrap();
+        assert_eq!(child.config.session_id, parent.config.session_id);
+        child.flush_to_sink().unwrap();
+
+        let path = dir.join(format!("{}.pftrace", std::process::id()));
+        let trace = Trace::parse_from_bytes(&fs::read(&path).unwrap()).unwrap();
+        let uuid = trace.packet.iter().find(|p| p.has_trace_uuid()).unwrap();
+        let id = parent.config.session_id.unwrap();
+        assert_eq!(uuid.trace_uuid().lsb(), id as i64);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_session_id() {
+        let vars = |key: &str| match key {
+            TRACE_DIR_VAR => Some(std::env::temp_dir().to_string_lossy().into_owned()),
+            _ => Some("not hex".to_string()),
+        };
+        assert!(PerfettoLayer::from_parent_vars(vars).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::{fs::File, io, io::BufWriter};
+
+use crate::PerfettoLayer;
+
+const DEFAULT_BUFFER_KB: usize = 1024;
+
+impl PerfettoLayer {
+    /// Builds a layer from environment variables, or returns `None` when
+    /// `PERFETTO_TRACE_FILE` is unset so the layer can be installed
+    /// unconditionally as an `Option<PerfettoLayer>`.
+    ///
+    /// - `PERFETTO_TRACE_FILE`: path the trace is written to.
+    /// - `PERFETTO_BUFFER_KB`: how much is buffered before flushing to the
+    ///   file, 1024 by default.
+    /// - `PERFETTO_CATEGORIES`: comma separated list of levels (`info`,
+    ///   `debug`, ...) or target prefixes to record. Everything is recorded
+    ///   when unset.
+    ///
+    /// Call [`PerfettoLayer::flush_to_sink`] before exiting to write out what
+    /// is still buffered.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> io::Result<Option<Self>> {
+        let Some(path) = var("PERFETTO_TRACE_FILE") else {
+            return Ok(None);
+        };
+        let buffer_kb = match var("PERFETTO_BUFFER_KB") {
+            Some(kb) => kb.trim().parse::<usize>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid PERFETTO_BUFFER_KB {:?}: {}", kb, e),
+                )
+            })?,
+            None => DEFAULT_BUFFER_KB,
+        };
+        let mut builder = Self::builder()
+            .sink(BufWriter::new(File::create(path)?))
+            .max_buffered_bytes(buffer_kb * 1024);
+        if let Some(categories) = var("PERFETTO_CATEGORIES") {
+            let categories: Vec<String> = categories
+                .split(',')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect();
+            builder = builder.filter(move |meta| {
+                categories.iter().any(|c| {
+                    meta.level().as_str().eq_ignore_ascii_case(c) || meta.target().starts_with(c)
+                })
+            });
+        }
+        Ok(Some(builder.build()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use std::collections::HashMap;
+    use tracing_subscriber::prelude::*;
+
+    fn vars(pairs: &[(&str, String)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        move |key| map.get(key).cloned()
+    }
+
+    #[test]
+    fn disabled_without_trace_file() {
+        assert!(PerfettoLayer::from_vars(vars(&[])).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_buffer_size() {
+        let path = std::env::temp_dir().join("perfetto-env-invalid.pftrace");
+        let err = PerfettoLayer::from_vars(vars(&[
+            ("PERFETTO_TRACE_FILE", path.display().to_string()),
+            ("PERFETTO_BUFFER_KB", "lots".to_string()),
+        ]))
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn writes_filtered_trace_to_file() {
+        let path =
+            std::env::temp_dir().join(format!("perfetto-env-{}.pftrace", std::process::id()));
+        let layer = PerfettoLayer::from_vars(vars(&[
+            ("PERFETTO_TRACE_FILE", path.display().to_string()),
+            ("PERFETTO_BUFFER_KB", "1".to_string()),
+            ("PERFETTO_CATEGORIES", "warn, db".to_string()),
+        ]))
+        .unwrap()
+        .unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..50 {
+                let _skipped = tracing::info_span!("skipped").entered();
+                let _query = tracing::info_span!(target: "db::pool", "query").entered();
+                let _slow = tracing::warn_span!("slow").entered();
+            }
+        });
+        layer.flush_to_sink().unwrap();
+
+        let trace = Trace::parse_from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["query", "slow"]);
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 200);
+    }
+}
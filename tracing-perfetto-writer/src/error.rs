@@ -0,0 +1,34 @@
+use std::fmt;
+
+/// Failures reported to the callback registered with
+/// [`PerfettoLayer::on_error`](crate::PerfettoLayer::on_error).
+#[derive(Debug)]
+pub enum Error {
+    /// Writing the encoded trace to its destination failed.
+    Write(Box<dyn std::error::Error + Send + Sync>),
+    /// The in-memory buffer reached its configured limit and no sink could
+    /// take the overflow, so new spans and events are being dropped.
+    BufferFull,
+    /// A thread panicked while holding the context lock. The layer keeps
+    /// recording, but the event that was being written may be incomplete.
+    Poisoned,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Write(e) => write!(f, "failed to write trace: {}", e),
+            Error::BufferFull => write!(f, "trace buffer is full, dropping events"),
+            Error::Poisoned => write!(f, "perfetto context lock was poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Write(e) => Some(e.as_ref()),
+            Error::BufferFull | Error::Poisoned => None,
+        }
+    }
+}
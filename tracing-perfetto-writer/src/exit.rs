@@ -0,0 +1,193 @@
+use perfetto_writer::{Context, LogPriority};
+use std::{
+    panic::{self, PanicHookInfo},
+    sync::{Arc, Mutex, PoisonError, TryLockError, Weak},
+};
+
+use crate::{Error, ErrorHandler, PerfettoLayer};
+
+/// A layer to flush when the process exits. Only its context is referenced,
+/// and weakly, so registering a layer doesn't keep it alive.
+#[cfg_attr(target_family = "wasm", allow(dead_code))]
+struct ExitLayer {
+    context: Weak<Mutex<Context>>,
+    on_error: ErrorHandler,
+}
+
+#[cfg_attr(target_family = "wasm", allow(dead_code))]
+impl ExitLayer {
+    fn flush(&self) {
+        let Some(context) = self.context.upgrade() else {
+            return;
+        };
+        let mut context = match context.try_lock() {
+            Ok(context) => context,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        if !context.has_sinks() {
+            return;
+        }
+        context.write_trace_stats();
+        if let Err(e) = context.flush_sinks() {
+            (self.on_error)(Error::Write(e.into()));
+        }
+    }
+}
+
+/// Layers flushed when the process exits.
+static EXIT_LAYERS: Mutex<Vec<ExitLayer>> = Mutex::new(Vec::new());
+
+#[cfg(not(target_family = "wasm"))]
+unsafe extern "C" {
+    fn atexit(callback: extern "C" fn()) -> std::ffi::c_int;
+}
+
+#[cfg(not(target_family = "wasm"))]
+extern "C" fn flush_at_exit() {
+    let layers = EXIT_LAYERS.lock().unwrap_or_else(PoisonError::into_inner);
+    for layer in layers.iter() {
+        layer.flush();
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn register_at_exit() {
+    static REGISTER: std::sync::Once = std::sync::Once::new();
+    REGISTER.call_once(|| {
+        // SAFETY: `atexit` only stores the callback, which can't unwind
+        // into C since a panic in an `extern "C"` function aborts.
+        unsafe { atexit(flush_at_exit) };
+    });
+}
+
+#[cfg(target_family = "wasm")]
+fn register_at_exit() {}
+
+impl PerfettoLayer {
+    /// Installs a panic hook that records the panic as a fatal "panic"
+    /// instant on the panicking thread's track, with its message and
+    /// location, then flushes to the sink before running the previous hook.
+    /// Without it a panic that aborts, or unwinds out of `main`, loses
+    /// whatever was still buffered.
+    pub fn install_panic_hook(&self) {
+        let layer = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            layer.record_panic(info);
+            previous(info);
+        }));
+    }
+
+    /// Flushes to the sink when the process exits, whether by returning
+    /// from `main` or through `std::process::exit`, which skips
+    /// destructors. Does nothing on `wasm`.
+    pub fn flush_on_exit(&self) {
+        let mut layers = EXIT_LAYERS.lock().unwrap_or_else(PoisonError::into_inner);
+        layers.retain(|layer| layer.context.strong_count() > 0);
+        layers.push(ExitLayer {
+            context: Arc::downgrade(&self.context),
+            on_error: self.on_error.clone(),
+        });
+        drop(layers);
+        register_at_exit();
+    }
+
+    fn record_panic(&self, info: &PanicHookInfo<'_>) {
+        // The panic may have started while this thread held the lock.
+        let mut context = match self.context.try_lock() {
+            Ok(context) => context,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        let message = info.payload_as_str().unwrap_or("Box<dyn Any>");
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        let track = self.thread_track(&mut context);
+        context
+            .event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track.into())
+            .with_name("panic")
+            .with_debug_str("message", message)
+            .with_debug_str("location", location.as_str())
+            .with_log_message(
+                format!("panicked at {location}: {message}"),
+                LogPriority::PRIO_FATAL,
+            )
+            .build();
+        drop(context);
+        self.flush_unless_locked();
+    }
+
+    /// Flushes to the sink unless another thread, or this one, holds the
+    /// context, which would otherwise deadlock.
+    fn flush_unless_locked(&self) -> bool {
+        let mut context = match self.context.try_lock() {
+            Ok(context) => context,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return false,
+        };
+        context.write_trace_stats();
+        self.write_to_sink(&mut context).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn exit_flushes_registered_layers() {
+        let sink = Shared::default();
+        let layer = PerfettoLayer::builder()
+            .sink(sink.clone())
+            .flush_on_exit(true)
+            .build();
+        layer.record_exit(0).unwrap();
+        sink.0.lock().unwrap().clear();
+        {
+            let mut context = layer.lock();
+            let track = context.current_thread_track();
+            context
+                .event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track)
+                .with_name("last words")
+                .build();
+        }
+
+        flush_at_exit();
+        assert!(!sink.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn registered_layers_are_not_kept_alive() {
+        let layer = PerfettoLayer::builder()
+            .sink(Shared::default())
+            .flush_on_exit(true)
+            .build();
+        let context = Arc::downgrade(&layer.context);
+        drop(layer);
+        assert!(context.upgrade().is_none());
+    }
+}
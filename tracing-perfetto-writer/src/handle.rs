@@ -0,0 +1,115 @@
+use perfetto_writer::EventBuilder;
+use smol_str::SmolStr;
+
+use crate::PerfettoLayer;
+
+/// Emits events into a [`PerfettoLayer`]'s trace directly, for things
+/// `tracing` can't express such as counters or slices that cross function
+/// boundaries. Cheap to clone; events respect the layer's enabled flag and
+/// buffer limits, and land on the calling thread's track.
+#[derive(Clone)]
+pub struct ContextHandle {
+    layer: PerfettoLayer,
+}
+
+impl PerfettoLayer {
+    /// Returns a handle for emitting events alongside the layer.
+    pub fn context_handle(&self) -> ContextHandle {
+        ContextHandle {
+            layer: self.clone(),
+        }
+    }
+}
+
+impl ContextHandle {
+    pub fn instant(&self, name: impl Into<SmolStr>) {
+        self.emit(|builder| builder.instant(), name.into());
+    }
+
+    /// Opens a slice on the calling thread's track, closed by
+    /// [`ContextHandle::end`] on the same thread.
+    pub fn begin(&self, name: impl Into<SmolStr>) {
+        self.emit(|builder| builder.begin(), name.into());
+    }
+
+    pub fn end(&self, name: impl Into<SmolStr>) {
+        self.emit(|builder| builder.end(), name.into());
+    }
+
+    /// Sets the counter track called `name` to `value`.
+    pub fn counter(&self, name: &str, value: f64) {
+        if !self.layer.is_enabled() {
+            return;
+        }
+        let Some(mut context) = self.layer.writable() else {
+            return;
+        };
+        let track = context.counter_track(name);
+        context
+            .event()
+            .with_counter()
+            .with_now()
+            .with_track_uuid(track)
+            .with_double_counter_value(value)
+            .build();
+    }
+
+    fn emit(&self, kind: impl FnOnce(&mut EventBuilder<'_>), name: SmolStr) {
+        if !self.layer.is_enabled() {
+            return;
+        }
+        let Some(mut context) = self.layer.writable() else {
+            return;
+        };
+        let track = self.layer.thread_track(&mut context);
+        let mut builder = context.event();
+        kind(&mut builder);
+        builder
+            .with_now()
+            .with_track_uuid(track.into())
+            .with_name(name)
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PerfettoLayer;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+
+    #[test]
+    fn handle_emits_events() {
+        let layer = PerfettoLayer::new();
+        let handle = layer.context_handle();
+        handle.begin("frame");
+        handle.instant("vsync");
+        handle.clone().counter("fps", 59.9);
+        handle.end("frame");
+        layer.set_enabled(false);
+        handle.instant("dropped");
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                Type::TYPE_SLICE_BEGIN,
+                Type::TYPE_INSTANT,
+                Type::TYPE_COUNTER,
+                Type::TYPE_SLICE_END
+            ]
+        );
+        assert!(
+            trace
+                .packet
+                .iter()
+                .any(|p| p.has_track_descriptor() && p.track_descriptor().name() == "fps")
+        );
+    }
+}
@@ -1,15 +1,70 @@
-use perfetto_writer::{Context, EventBuilder};
-use std::sync::{Arc, Mutex};
+use dashmap::DashMap;
+use perfetto_writer::{Color, Context, EventBuilder, InstantScope, LogPriority, WriteStats};
+use smol_str::SmolStr;
+use std::backtrace::Backtrace;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, MutexGuard};
 use tracing::field::Visit;
-use tracing::{Subscriber, span};
+use tracing::{Metadata, Subscriber, callsite, span};
+#[cfg(feature = "log")]
+use tracing_log::NormalizeEvent;
 use tracing_subscriber::{Layer, layer::Context as LayerContext, registry::LookupSpan};
+use web_time::Instant;
+
+mod assert;
+#[cfg(feature = "tokio")]
+mod async_sink;
+#[cfg(feature = "bevy")]
+mod bevy;
+mod builder;
+mod busy;
+mod child;
+mod env;
+mod error;
+mod exit;
+mod handle;
+#[cfg(feature = "log")]
+mod log_bridge;
+mod overflow;
+mod poll;
+mod route;
+#[cfg(feature = "tokio")]
+mod runtime;
+mod stats;
+mod systems;
+#[cfg(feature = "tokio")]
+mod task;
+
+pub use assert::ASSERT_TARGET;
+#[cfg(feature = "bevy")]
+pub use bevy::PerfettoPlugin;
+pub use builder::PerfettoLayerBuilder;
+use builder::{AnnotationLimits, Config};
+use busy::{BUSY_ANNOTATION, IDLE_ANNOTATION, SpanTimings};
+pub use child::{SESSION_ID_VAR, TRACE_DIR_VAR};
+pub use error::Error;
+pub use handle::ContextHandle;
+#[cfg(feature = "log")]
+pub use log_bridge::init_log_bridge;
+use overflow::Overflow;
+pub use overflow::{OverflowPolicy, OverflowStats};
+use poll::{AsyncTrack, AsyncTracks};
+use stats::{Statistics, StatsStart};
+use systems::{BevySpan, Schedules};
+#[cfg(feature = "tokio")]
+pub use task::spawn;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use tracing;
+}
 
 #[derive(Debug, Clone, Copy)]
 struct SliceId(u64);
 
-impl Into<u64> for SliceId {
-    fn into(self) -> u64 {
-        self.0
+impl From<SliceId> for u64 {
+    fn from(id: SliceId) -> u64 {
+        id.0
     }
 }
 
@@ -22,9 +77,9 @@ impl From<u64> for SliceId {
 #[derive(Debug, Clone, Copy)]
 struct TrackId(u64);
 
-impl Into<u64> for TrackId {
-    fn into(self) -> u64 {
-        self.0
+impl From<TrackId> for u64 {
+    fn from(id: TrackId) -> u64 {
+        id.0
     }
 }
 
@@ -34,43 +89,429 @@ impl From<u64> for TrackId {
     }
 }
 
-struct EventBuilderVisitor<'a>(EventBuilder<'a>);
+/// Where a span's begin packet was recorded, kept so that spans closing
+/// under the duration threshold can retract it.
+#[derive(Debug, Clone, Copy)]
+struct BeginPacket {
+    position: u64,
+    start: Instant,
+}
+
+/// Flows started by `follows_from` that end when the span is first entered.
+#[derive(Debug, Default)]
+struct PendingFlows(Vec<u64>);
+
+/// Whether the trace a span belongs to was picked by root span sampling.
+#[derive(Debug, Clone, Copy)]
+struct Sampled(bool);
+
+/// Returns true with probability 1/`n`, drawing entropy from std's randomly
+/// keyed hasher.
+fn one_in(n: u32) -> bool {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    random.is_multiple_of(n as u64)
+}
+
+struct EventBuilderVisitor<'a> {
+    builder: EventBuilder<'a>,
+    limits: AnnotationLimits,
+    /// Fields with a link template, recorded unquoted so they form a URL.
+    link_fields: &'a [String],
+    recorded: usize,
+    skip_log_fields: bool,
+}
+
+impl<'a> EventBuilderVisitor<'a> {
+    fn new(builder: EventBuilder<'a>, config: &'a Config) -> Self {
+        Self {
+            builder,
+            limits: config.annotation_limits,
+            link_fields: &config.link_fields,
+            recorded: 0,
+            skip_log_fields: false,
+        }
+    }
+
+    fn build(self) {
+        self.builder.build();
+    }
+}
+
+/// Reserved field selecting an event's [`InstantScope`], e.g.
+/// `info!(perfetto.scope = "global", "deploy started")`.
+const SCOPE_FIELD: &str = "perfetto.scope";
+
+/// Prefix of numeric fields recorded as extra counter values instead of
+/// annotations, e.g. `info_span!("read", counter.bytes = n)` sets the
+/// "bytes" counter track at the start of the slice.
+const COUNTER_FIELD_PREFIX: &str = "counter.";
+
+/// Annotation holding the backtrace of a span or event's callsite.
+const BACKTRACE_ANNOTATION: &str = "backtrace";
 
 impl<'a> Visit for EventBuilderVisitor<'a> {
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        match field.name().strip_prefix(COUNTER_FIELD_PREFIX) {
+            Some(name) => self.builder.extra_counter_named(name, value),
+            None => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        match field.name().strip_prefix(COUNTER_FIELD_PREFIX) {
+            // Counter values are signed, so larger ones stay an annotation.
+            Some(name) => match i64::try_from(value) {
+                Ok(counter) => self.builder.extra_counter_named(name, counter),
+                Err(_) => self.builder.debug_uint(field.name(), value),
+            },
+            None => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        match field.name().strip_prefix(COUNTER_FIELD_PREFIX) {
+            Some(name) => self.builder.extra_double_counter_named(name, value),
+            None => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == SCOPE_FIELD {
+            match value.parse::<InstantScope>() {
+                Ok(scope) => self.builder.instant_scope(scope),
+                Err(_) => self.record_debug(field, &value),
+            }
+            return;
+        }
+        if self.link_fields.iter().any(|name| name == field.name()) {
+            self.annotate(field.name(), value.to_string());
+            return;
+        }
+        self.record_debug(field, &value);
+    }
+
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.0.debug_str(field.name(), &format!("{:?}", value));
+        if self.skip_log_fields && field.name().starts_with("log.") {
+            return;
+        }
+        if self
+            .limits
+            .max_annotations
+            .is_some_and(|max| self.recorded >= max)
+        {
+            return;
+        }
+        self.annotate(field.name(), format!("{:?}", value));
+    }
+}
+
+impl<'a> EventBuilderVisitor<'a> {
+    /// Adds a debug annotation, subject to the configured limits.
+    fn annotate(&mut self, name: &'static str, mut value: String) {
+        if self
+            .limits
+            .max_annotations
+            .is_some_and(|max| self.recorded >= max)
+        {
+            return;
+        }
+        if let Some(mut max) = self.limits.max_len
+            && value.len() > max
+        {
+            while !value.is_char_boundary(max) {
+                max -= 1;
+            }
+            value.truncate(max);
+        }
+        if self.link_fields.iter().any(|field| field == name) {
+            self.builder.debug_link(name, value);
+        } else {
+            self.builder.debug_str(name, value);
+        }
+        self.recorded += 1;
     }
 }
 
+/// Fields recorded with `Span::record` after a span was created, attached to
+/// its end event.
+#[derive(Debug, Default)]
+struct LateFields(Vec<(&'static str, String)>);
+
+impl Visit for LateFields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        let value = format!("{:?}", value);
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+/// Pulls the message out of an event, which names its instant.
+#[derive(Default)]
+struct LogMessage(Option<String>);
+
+impl Visit for LogMessage {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+fn log_priority(level: &tracing::Level) -> LogPriority {
+    match *level {
+        tracing::Level::TRACE => LogPriority::PRIO_VERBOSE,
+        tracing::Level::DEBUG => LogPriority::PRIO_DEBUG,
+        tracing::Level::INFO => LogPriority::PRIO_INFO,
+        tracing::Level::WARN => LogPriority::PRIO_WARN,
+        tracing::Level::ERROR => LogPriority::PRIO_ERROR,
+    }
+}
+
+type ErrorHandler = Arc<dyn Fn(Error) + Send + Sync>;
+
 /// A tracing layer that writes trace events to Perfetto format
+#[derive(Clone)]
 pub struct PerfettoLayer {
     context: Arc<Mutex<Context>>,
+    #[cfg(feature = "tokio")]
+    async_sink: async_sink::AsyncSink,
+    config: Arc<Config>,
+    overflowed: Arc<AtomicBool>,
+    overflow: Arc<Overflow>,
+    enabled: Arc<AtomicBool>,
+    backtraces: Arc<DashMap<callsite::Identifier, SmolStr>>,
+    event_tracks: Arc<DashMap<u64, u64>>,
+    schedules: Arc<Schedules>,
+    async_tracks: Arc<AsyncTracks>,
+    routes: Arc<Vec<(String, PerfettoLayer)>>,
+    stats: Arc<Mutex<Statistics>>,
+    on_error: ErrorHandler,
+    /// The fork generation the async sink was opened in.
+    #[cfg(feature = "tokio")]
+    fork_generation: u64,
 }
 
-impl Clone for PerfettoLayer {
-    fn clone(&self) -> Self {
-        Self {
-            context: Arc::clone(&self.context),
-        }
+impl Default for PerfettoLayer {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl PerfettoLayer {
     /// Creates a new PerfettoLayer
     pub fn new() -> Self {
-        let ctx = Context::new();
+        Self::builder().build()
+    }
 
-        Self {
-            context: Arc::new(Mutex::new(ctx)),
-        }
+    /// Returns a builder for configuring the layer
+    pub fn builder() -> PerfettoLayerBuilder {
+        PerfettoLayerBuilder::default()
+    }
+
+    /// Sets the callback invoked when the layer fails to record or write the
+    /// trace. By default errors are ignored; the layer can't report them
+    /// through `tracing` itself, since it may be holding its own lock.
+    pub fn on_error(mut self, f: impl Fn(Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Arc::new(f);
+        self
+    }
+
+    /// Turns recording on or off. While disabled new spans and events return
+    /// before taking any lock, so the layer can stay installed in production
+    /// and be enabled only around interesting windows. Spans that were open
+    /// when recording stopped still get their end.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Relaxed)
     }
 
     /// Flushes the underlying Perfetto context to a Vec
     pub fn flush(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.emit_statistics();
         let mut buf = Vec::new();
-        self.context.lock().unwrap().write_to(&mut buf)?;
+        if let Err(e) = self.lock().write_to(&mut buf) {
+            (self.on_error)(Error::Write(e.to_string().into()));
+            return Err(e.into());
+        }
+        self.drained();
         Ok(buf)
     }
+
+    /// Returns everything recorded so far and atomically starts a new,
+    /// self-contained trace segment, so continuous services can ship e.g.
+    /// hourly segments without losing events in the gap. Spans open across
+    /// the cut begin in one segment and end in the next. See
+    /// [`PerfettoLayer::rotate_to_sink`] to write the segment to the sink
+    /// instead.
+    pub fn rotate(&self) -> Vec<u8> {
+        self.emit_statistics();
+        let mut buf = Vec::new();
+        let mut context = self.lock();
+        if let Err(e) = context.rotate(&mut buf) {
+            (self.on_error)(Error::Write(e.into()));
+        }
+        // The next segment describes its own event tracks.
+        self.event_tracks.clear();
+        drop(context);
+        self.drained();
+        buf
+    }
+
+    /// Flushes the underlying Perfetto context to the configured sink, if any
+    pub fn flush_to_sink(&self) -> Result<(), Error> {
+        self.emit_statistics();
+        let mut context = self.lock();
+        self.write_to_sink(&mut context)
+    }
+
+    /// Like [`PerfettoLayer::rotate`], but the finished segment goes to the
+    /// configured sink, if any. Without one nothing happens.
+    pub fn rotate_to_sink(&self) -> Result<(), Error> {
+        self.emit_statistics();
+        let mut context = self.lock();
+        if !context.has_sinks() {
+            return Ok(());
+        }
+        let result = context.rotate_sinks().map_err(|e| Error::Write(e.into()));
+        self.event_tracks.clear();
+        drop(context);
+        self.drained();
+        result
+    }
+
+    /// The context the layer records into, for writers that take a shared
+    /// `Arc<Mutex<Context>>` such as `perfetto-metrics`, `perfetto-tower`,
+    /// `perfetto-otel`, `ActorTracer` or
+    /// `GuestTracer`, so everything ends up in one trace. Writes through it
+    /// bypass the layer's enabled flag and buffer limits.
+    pub fn shared_context(&self) -> Arc<Mutex<Context>> {
+        Arc::clone(&self.context)
+    }
+
+    /// Packets written and dropped so far, e.g. spans and events lost to
+    /// [`max_buffered_packets`](PerfettoLayerBuilder::max_buffered_packets).
+    pub fn stats(&self) -> WriteStats {
+        self.lock().write_stats()
+    }
+
+    /// Marks the start of frame `frame_number`, see [`Context::mark_frame`].
+    pub fn mark_frame(&self, frame_number: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(mut context) = self.writable() {
+            context.mark_frame(frame_number);
+        }
+    }
+
+    /// Shutdown hook for batch jobs: records the exit code, runtime and peak
+    /// RSS as a final summary event and a `TraceStats` packet, then flushes
+    /// to the sink. Call it just before `std::process::exit(code)`.
+    pub fn record_exit(&self, code: i32) -> Result<(), Error> {
+        let Some(mut context) = self.writable() else {
+            // The exit record is dropped like any event over the limit, but
+            // what is buffered still goes out.
+            return self.write_to_sink(&mut self.lock());
+        };
+        context.record_exit(code);
+        context.write_trace_stats();
+        self.write_to_sink(&mut context)
+    }
+
+    fn write_to_sink(&self, context: &mut Context) -> Result<(), Error> {
+        if !context.has_sinks() {
+            return Ok(());
+        }
+        context.flush_sinks().map_err(|e| Error::Write(e.into()))?;
+        self.drained();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Context> {
+        self.context.lock().unwrap_or_else(|poisoned| {
+            (self.on_error)(Error::Poisoned);
+            self.context.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// Locks the context for a new span or event, enforcing the buffer limit.
+    fn writable(&self) -> Option<MutexGuard<'_, Context>> {
+        let mut context = self.lock();
+        if !self.over_limit(&context) {
+            return Some(context);
+        }
+        if let Err(e) = self.write_to_sink(&mut context) {
+            (self.on_error)(e);
+        }
+        if !self.over_limit(&context) {
+            return Some(context);
+        }
+        self.make_room(context)
+    }
+
+    fn over_limit(&self, context: &Context) -> bool {
+        self.config
+            .max_buffered_packets
+            .is_some_and(|max| context.buffered_packets() >= max)
+            || self
+                .config
+                .max_buffered_bytes
+                .is_some_and(|max| context.buffered_bytes() >= max)
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        self.is_enabled()
+            && self
+                .config
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter(meta))
+    }
+
+    /// The backtrace captured the first time `meta`'s callsite fired. The
+    /// string is interned by the context, so later events only carry a
+    /// reference to it.
+    fn callsite_backtrace(&self, meta: &Metadata<'_>) -> Option<SmolStr> {
+        if !self.config.callsite_backtraces {
+            return None;
+        }
+        let backtrace = self
+            .backtraces
+            .entry(meta.callsite())
+            .or_insert_with(|| Backtrace::force_capture().to_string().into());
+        Some(backtrace.clone())
+    }
+
+    fn thread_track(&self, context: &mut Context) -> TrackId {
+        if self.config.thread_names
+            && let Some(name) = std::thread::current().name()
+        {
+            return context.current_thread_track_named(name).into();
+        }
+        context.current_thread_track().into()
+    }
+
+    /// The track events on `track` are written to, which is a "logs" child
+    /// of it when events are kept apart from slices.
+    fn event_track(&self, context: &mut Context, track: TrackId) -> TrackId {
+        if !self.config.separate_event_tracks {
+            return track;
+        }
+        let logs = *self
+            .event_tracks
+            .entry(track.0)
+            .or_insert_with(|| context.create_child_track(track.0, "logs"));
+        logs.into()
+    }
 }
 
 impl<S> Layer<S> for PerfettoLayer
@@ -78,15 +519,65 @@ where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
     fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
-        let mut context = self.context.lock().unwrap();
-        let thread_track: TrackId = context.current_thread_track().into();
+        if let Some(route) = self.route(attrs.metadata()) {
+            return route.on_new_span(attrs, id, ctx);
+        }
+        if !self.enabled(attrs.metadata()) {
+            return;
+        }
+        if self.statistics_only(attrs.metadata()) {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(StatsStart(Instant::now()));
+            }
+            return;
+        }
+        let bevy = self.bevy_span(attrs);
+        if let Some(system @ BevySpan::System(_)) = bevy {
+            if let Some(span) = ctx.span(id) {
+                let mut exe = span.extensions_mut();
+                exe.insert(system);
+                exe.insert(self.owner());
+            }
+            return;
+        }
+        if let Some(n) = self.config.sample_root_spans
+            && let Some(span) = ctx.span(id)
+        {
+            let inherited = span
+                .scope()
+                .skip(1)
+                .find_map(|s| s.extensions().get::<Sampled>().copied());
+            let sampled = inherited.unwrap_or_else(|| Sampled(one_in(n)));
+            span.extensions_mut().insert(sampled);
+            if !sampled.0 {
+                return;
+            }
+        }
+        let backtrace = self.callsite_backtrace(attrs.metadata());
+        let Some(mut context) = self.writable() else {
+            return;
+        };
+        let thread_track = match self.config.poll_slices {
+            true => self.async_track(&mut context, attrs.metadata().name()),
+            false => self.thread_track(&mut context),
+        };
         let slice_id: SliceId = context.next_id().into();
         if let Some(span) = ctx.span(id) {
             let mut exe = span.extensions_mut();
             exe.insert(thread_track);
+            if self.config.poll_slices {
+                exe.insert(AsyncTrack(attrs.metadata().name()));
+            }
             exe.insert(slice_id);
+            exe.insert(self.owner());
+            if self.config.busy_idle_time {
+                exe.insert(SpanTimings::new());
+            }
+            if let Some(bevy) = bevy.clone() {
+                exe.insert(bevy);
+            }
             let meta = span.metadata();
-            let mut ev = EventBuilderVisitor(
+            let mut ev = EventBuilderVisitor::new(
                 context
                     .event()
                     .with_begin()
@@ -98,92 +589,304 @@ where
                     )
                     .with_now()
                     .with_category(meta.level().as_str())
-                    .with_name(attrs.metadata().name()),
+                    .with_name(match &bevy {
+                        Some(BevySpan::Schedule(name)) => name.clone(),
+                        _ => attrs.metadata().name().into(),
+                    }),
+                &self.config,
             );
+            // A parent recorded by another route is in another context,
+            // where the flow would lead nowhere.
             if let Some(parent) = span.parent() {
-                if let Some(parent_slice) = parent.extensions().get::<SliceId>() {
-                    ev.0.flow_id(parent_slice.0);
+                let parent = parent.extensions();
+                if let Some(parent_slice) = parent.get::<SliceId>()
+                    && self.owns(&parent)
+                {
+                    ev.builder.flow_id(parent_slice.0);
                 }
             }
+            if let Some(backtrace) = backtrace {
+                ev.builder.debug_str(BACKTRACE_ANNOTATION, backtrace);
+            }
             attrs.record(&mut ev);
-            ev.0.build();
-        }
-    }
-
-    // fn on_enter(&self, id: &span::Id, ctx: LayerContext<'_, S>) {
-    //     let mut context = self.context.lock().unwrap();
-    //     if let Some(span) = ctx.span(id) {
-    //         let exe = span.extensions();
-    //         let track = exe.get::<TrackId>().unwrap();
-    //         context
-    //             .event()
-    //             .begin()
-    //             .now()
-    //             .track_uuid((*track).into())
-    //             .name("active")
-    //             .build();
-    //     }
-    // }
-
-    // fn on_exit(&self, id: &span::Id, ctx: LayerContext<'_, S>) {
-    //     let mut context = self.context.lock().unwrap();
-    //     if let Some(span) = ctx.span(id) {
-    //         let exe = span.extensions();
-    //         let track = exe.get::<TrackId>().unwrap();
-    //         context
-    //             .event()
-    //             .end()
-    //             .now()
-    //             .track_uuid((*track).into())
-    //             .name("active")
-    //             .build();
-    //     }
-    // }
+            ev.build();
+            if self.config.min_span_duration.is_some()
+                && let Some(position) = context.last_packet_position()
+            {
+                exe.insert(BeginPacket {
+                    position,
+                    start: Instant::now(),
+                });
+            }
+        }
+    }
 
     fn on_close(&self, id: span::Id, ctx: LayerContext<'_, S>) {
-        let mut context = self.context.lock().unwrap();
+        if let Some(route) = ctx.metadata(&id).and_then(|meta| self.route(meta)) {
+            return route.on_close(id, ctx);
+        }
+        // Ends bypass the buffer limit so slices that were begun stay balanced.
         if let Some(span) = ctx.span(&id) {
             let exe = span.extensions();
-            let track = exe.get::<TrackId>().unwrap();
+            if let Some(start) = exe.get::<StatsStart>() {
+                self.record_statistic(span.metadata(), start.0.elapsed());
+                return;
+            }
+            let Some(track) = exe.get::<TrackId>() else {
+                return;
+            };
+            let mut context = self.lock();
+            if let Some(async_track) = exe.get::<AsyncTrack>() {
+                self.release_async_track(*async_track, *track);
+            }
+            if let Some(min) = self.config.min_span_duration
+                && let Some(begin) = exe.get::<BeginPacket>()
+                && begin.start.elapsed() < min
+                && context.retract_packet(begin.position)
+            {
+                return;
+            }
+            let mut ev = EventBuilderVisitor::new(
+                context
+                    .event()
+                    .with_end()
+                    .with_now()
+                    .with_track_uuid((*track).into())
+                    .with_name(span.name()),
+                &self.config,
+            );
+            if let Some(fields) = exe.get::<LateFields>() {
+                for (name, value) in &fields.0 {
+                    ev.annotate(name, value.clone());
+                }
+            }
+            if let Some(timings) = exe.get::<SpanTimings>() {
+                let (busy, idle) = timings.close();
+                ev.builder.debug_uint(BUSY_ANNOTATION, busy);
+                ev.builder.debug_uint(IDLE_ANNOTATION, idle);
+            }
+            ev.build();
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(route) = ctx.metadata(id).and_then(|meta| self.route(meta)) {
+            return route.on_record(id, values, ctx);
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if span.extensions().get::<TrackId>().is_none() {
+            return;
+        }
+        let mut exe = span.extensions_mut();
+        match exe.get_mut::<LateFields>() {
+            Some(fields) => values.record(fields),
+            None => {
+                let mut fields = LateFields::default();
+                values.record(&mut fields);
+                exe.insert(fields);
+            }
+        }
+    }
+
+    fn on_follows_from(&self, id: &span::Id, follows: &span::Id, ctx: LayerContext<'_, S>) {
+        if let Some(route) = ctx.metadata(id).and_then(|meta| self.route(meta)) {
+            return route.on_follows_from(id, follows, ctx);
+        }
+        let (Some(span), Some(cause)) = (ctx.span(id), ctx.span(follows)) else {
+            return;
+        };
+        if !self.owns(&span.extensions()) || !self.owns(&cause.extensions()) {
+            return;
+        }
+        let Some(track) = cause.extensions().get::<TrackId>().copied() else {
+            return;
+        };
+        let Some(mut context) = self.writable() else {
+            return;
+        };
+        let flow = context.next_id();
+        context
+            .event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track.into())
+            .with_name(span.name())
+            .with_flow_id(flow)
+            .build();
+        // A span that is already running won't be entered again soon, so the
+        // arrow ends right away instead of at its next enter.
+        if ctx.current_span().id() == Some(id) {
+            let thread_track = self.thread_track(&mut context);
             context
                 .event()
-                .with_end()
+                .with_instant()
                 .with_now()
-                .with_track_uuid((*track).into())
+                .with_track_uuid(thread_track.into())
                 .with_name(span.name())
+                .with_terminating_flow_id(flow)
                 .build();
+            return;
+        }
+        let mut exe = span.extensions_mut();
+        match exe.get_mut::<PendingFlows>() {
+            Some(pending) => pending.0.push(flow),
+            None => exe.insert(PendingFlows(vec![flow])),
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if let Some(route) = ctx.metadata(id).and_then(|meta| self.route(meta)) {
+            return route.on_enter(id, ctx);
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if self.enter_bevy_span(id, &span) {
+            return;
+        }
+        let mut exe = span.extensions_mut();
+        if let Some(timings) = exe.get_mut::<SpanTimings>() {
+            timings.enter();
+        }
+        let pending = exe.remove::<PendingFlows>();
+        drop(exe);
+        self.begin_poll(&span);
+        let Some(PendingFlows(flows)) = pending else {
+            return;
+        };
+        let mut context = self.lock();
+        let track = self.thread_track(&mut context);
+        context
+            .event()
+            .with_instant()
+            .with_now()
+            .with_track_uuid(track.into())
+            .with_name(span.name())
+            .with_terminating_flow_ids(flows)
+            .build();
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if let Some(route) = ctx.metadata(id).and_then(|meta| self.route(meta)) {
+            return route.on_exit(id, ctx);
         }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if self.exit_bevy_span(id, &span) {
+            return;
+        }
+        if let Some(timings) = span.extensions_mut().get_mut::<SpanTimings>() {
+            timings.exit();
+        }
+        self.end_poll(&span);
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: LayerContext<'_, S>) {
-        let mut context = self.context.lock().unwrap();
-        if let Some(span) = ctx.event_span(event) {
-            let exe = span.extensions();
-            let track = exe.get::<TrackId>().unwrap();
-            let meta = event.metadata();
-            let mut ev = EventBuilderVisitor(
-                context
-                    .event()
-                    .with_instant()
-                    .with_now()
-                    .with_track_uuid((*track).into())
-                    .with_category(meta.target())
-                    .with_source_location(
-                        meta.file().unwrap_or_default(),
-                        meta.line().unwrap_or_default(),
-                    )
-                    .with_category(meta.level().as_str())
-                    .with_name(event.metadata().name()),
-            );
-            event.record(&mut ev);
-            ev.0.build();
+        // Events bridged by tracing-log carry placeholder metadata; the real
+        // target, file and line are recovered from their fields.
+        #[cfg(feature = "log")]
+        let normalized = event.normalized_metadata();
+        #[cfg(not(feature = "log"))]
+        let normalized: Option<Metadata<'_>> = None;
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        if let Some(route) = self.route(meta) {
+            return route.on_event(event, ctx);
+        }
+        if !self.enabled(meta) {
+            return;
+        }
+        // Bridged `log` records outside any span, as most are, go on the
+        // thread's track unless only sampled traces are being kept; other
+        // events outside spans are left out. Events inside a span recorded
+        // by another route go on the thread's track too.
+        let span_track = match ctx.event_span(event) {
+            Some(span) => {
+                let exe = span.extensions();
+                match exe.get::<TrackId>().copied() {
+                    Some(_) if !self.owns(&exe) => None,
+                    Some(track) => Some(track),
+                    None => return,
+                }
+            }
+            None if normalized.is_none() || self.config.sample_root_spans.is_some() => return,
+            None => None,
+        };
+        let backtrace = self.callsite_backtrace(event.metadata());
+        let Some(mut context) = self.writable() else {
+            return;
+        };
+        let track = match span_track {
+            Some(track) => track,
+            None => self.thread_track(&mut context),
+        };
+        let track = self.event_track(&mut context, track);
+        let assertion = meta.target() == ASSERT_TARGET;
+        let message = if normalized.is_some() || assertion || self.config.log_messages {
+            let mut message = LogMessage::default();
+            event.record(&mut message);
+            message.0
+        } else {
+            None
+        };
+        // Other events are named after their callsite, which keeps the
+        // names interned once however much their messages vary; the
+        // message is an annotation.
+        let name = if normalized.is_some() {
+            match &message {
+                Some(message) => format!("{}: {}", meta.target(), message),
+                None => meta.target().to_string(),
+            }
+        } else if assertion && let Some(message) = &message {
+            message.clone()
+        } else {
+            meta.name().to_string()
+        };
+        let mut ev = EventBuilderVisitor::new(
+            context
+                .event()
+                .with_instant()
+                .with_now()
+                .with_track_uuid(track.into())
+                .with_category(meta.target())
+                .with_source_location(
+                    meta.file().unwrap_or_default(),
+                    meta.line().unwrap_or_default(),
+                )
+                .with_category(meta.level().as_str())
+                .with_name(name.as_str()),
+            &self.config,
+        );
+        if normalized.is_some() {
+            ev.skip_log_fields = true;
+            if let Some(module_path) = meta.module_path() {
+                ev.builder.debug_str("module_path", module_path);
+            }
+        }
+        if assertion {
+            ev.builder.color(Color::Terrible);
+        }
+        if self.config.log_messages {
+            let body = message.unwrap_or_else(|| name.clone());
+            ev.builder.log_message(body, log_priority(meta.level()));
         }
+        if let Some(backtrace) = backtrace {
+            ev.builder.debug_str(BACKTRACE_ANNOTATION, backtrace);
+        }
+        event.record(&mut ev);
+        ev.build();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use perfetto_protos::trace::Trace;
+    use perfetto_writer::SessionMetadata;
+    use protobuf::Message;
+    use std::io::Write;
     use tracing_subscriber::prelude::*;
 
     #[test]
@@ -193,6 +896,731 @@ mod tests {
         drop(layer);
     }
 
+    #[test]
+    fn test_counter_fields() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("read", counter.bytes = 4096u64, path = "/tmp").in_scope(|| {});
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let track = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().name() == "bytes")
+            .unwrap()
+            .track_descriptor()
+            .uuid();
+        let begin = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event() && !p.track_event().extra_counter_values.is_empty())
+            .unwrap()
+            .track_event();
+        assert_eq!(begin.extra_counter_track_uuids, [track]);
+        assert_eq!(begin.extra_counter_values, [4096]);
+        assert_eq!(begin.debug_annotations.len(), 1);
+    }
+
+    #[test]
+    fn test_oversized_counter_fields_stay_unsigned() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("read", counter.bytes = u64::MAX).in_scope(|| {});
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let begin = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event())
+            .unwrap()
+            .track_event();
+        assert!(begin.extra_counter_values.is_empty());
+        assert_eq!(begin.debug_annotations[0].uint_value(), u64::MAX);
+    }
+
+    #[test]
+    fn test_session_metadata() {
+        let layer = PerfettoLayer::builder()
+            .session(SessionMetadata::new().app_name("worker").uuid(42))
+            .build();
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let uuid = trace.packet.iter().find(|p| p.has_trace_uuid()).unwrap();
+        assert_eq!(uuid.trace_uuid().lsb(), 42);
+        assert!(trace.packet.iter().any(|p| {
+            p.has_chrome_events()
+                && p.chrome_events()
+                    .metadata
+                    .iter()
+                    .any(|m| m.string_value() == "worker")
+        }));
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_reported() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        let layer =
+            PerfettoLayer::new().on_error(move |e| sink.lock().unwrap().push(e.to_string()));
+
+        let context = Arc::clone(&layer.context);
+        let _ = std::thread::spawn(move || {
+            let _guard = context.lock().unwrap();
+            panic!("poison the context");
+        })
+        .join();
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("after_poison").entered();
+        });
+
+        assert_eq!(
+            *errors.lock().unwrap(),
+            ["perfetto context lock was poisoned"]
+        );
+        assert!(!layer.flush().unwrap().is_empty());
+    }
+
+    fn parse(buf: &[u8]) -> Trace {
+        Trace::parse_from_bytes(buf).unwrap()
+    }
+
+    fn annotation_values(trace: &Trace) -> Vec<String> {
+        trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| String::from_utf8_lossy(s.str()).into_owned())
+            .collect()
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_builder_filter_and_annotation_limits() {
+        let layer = PerfettoLayer::builder()
+            .filter(|meta| meta.name() != "noisy")
+            .max_annotations(2)
+            .max_annotation_len(4)
+            .thread_names(true)
+            .build();
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _noisy = tracing::info_span!("noisy", field = "dropped").entered();
+            let _span = tracing::info_span!("kept", a = "abcdefgh", b = 1, c = 2).entered();
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        assert_eq!(annotation_values(&trace), ["\"abc", "1"]);
+
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["kept"]);
+
+        let thread_name = std::thread::current().name().unwrap().to_string();
+        assert!(
+            trace
+                .packet
+                .iter()
+                .any(|p| p.has_track_descriptor() && p.track_descriptor().name() == thread_name)
+        );
+    }
+
+    #[test]
+    fn test_buffer_limit_flushes_to_sink() {
+        let out = SharedBuf::default();
+        let layer = PerfettoLayer::builder()
+            .sink(out.clone())
+            .max_buffered_packets(8)
+            .build();
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..20 {
+                let _span = tracing::info_span!("work", i).entered();
+            }
+        });
+        layer.flush_to_sink().unwrap();
+
+        let trace = parse(&out.0.lock().unwrap());
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 40);
+    }
+
+    #[test]
+    fn test_buffer_limit_without_sink_drops() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_sink = Arc::clone(&errors);
+        let layer = PerfettoLayer::builder()
+            .max_buffered_packets(8)
+            .on_error(move |e| errors_sink.lock().unwrap().push(e.to_string()))
+            .build();
+
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..20 {
+                let _span = tracing::info_span!("work", i).entered();
+            }
+        });
+
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        let stats = layer.stats();
+        assert!(stats.dropped_packets > 0);
+        assert!(stats.buffered_packets >= 8);
+        let trace = parse(&layer.flush().unwrap());
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .collect();
+        assert!(events.len() < 40);
+        assert_eq!(events.len() % 2, 0);
+    }
+
+    #[test]
+    fn test_set_enabled() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            layer.set_enabled(false);
+            let _hidden = tracing::info_span!("hidden").entered();
+            layer.set_enabled(true);
+            let open = tracing::info_span!("open").entered();
+            let _visible = tracing::info_span!("visible").entered();
+            layer.set_enabled(false);
+            tracing::info!("dropped");
+            drop(open);
+        });
+        assert!(!layer.is_enabled());
+
+        let trace = parse(&layer.flush().unwrap());
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["open", "visible"]);
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 4);
+    }
+
+    #[test]
+    fn test_min_span_duration() {
+        let layer = PerfettoLayer::builder()
+            .min_span_duration(std::time::Duration::from_millis(5))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _slow = tracing::info_span!("slow").entered();
+            for _ in 0..10 {
+                let _fast = tracing::info_span!("fast").entered();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                perfetto_protos::track_event::track_event::Type::TYPE_SLICE_BEGIN,
+                perfetto_protos::track_event::track_event::Type::TYPE_SLICE_END
+            ]
+        );
+    }
+
+    fn count_events(layer: &PerfettoLayer) -> usize {
+        let trace = parse(&layer.flush().unwrap());
+        trace.packet.iter().filter(|p| p.has_track_event()).count()
+    }
+
+    fn traced_requests(layer: &PerfettoLayer) {
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                let _request = tracing::info_span!("request").entered();
+                let _query = tracing::info_span!("query").entered();
+                tracing::info!("row");
+            }
+        });
+    }
+
+    #[test]
+    fn test_sample_root_spans() {
+        let all = PerfettoLayer::builder().sample_root_spans(1).build();
+        traced_requests(&all);
+        assert_eq!(count_events(&all), 50);
+
+        let none = PerfettoLayer::builder().sample_root_spans(u32::MAX).build();
+        traced_requests(&none);
+        assert_eq!(count_events(&none), 0);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_only_log_records_are_kept_outside_spans() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(name: "plain", "plain");
+            let record = log::Record::builder()
+                .args(format_args!("bridged"))
+                .level(log::Level::Info)
+                .target("app")
+                .build();
+            tracing_log::format_trace(&record).unwrap();
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["app: bridged"]);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_log_events_are_attributed() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("request").entered();
+            let record = log::Record::builder()
+                .args(format_args!("cache miss"))
+                .level(log::Level::Warn)
+                .target("app::cache")
+                .module_path_static(Some("app::cache"))
+                .file_static(Some("src/cache.rs"))
+                .line(Some(42))
+                .build();
+            tracing_log::format_trace(&record).unwrap();
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let interned: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .collect();
+        let names: Vec<_> = interned
+            .iter()
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["request", "app::cache: cache miss"]);
+        let annotations: Vec<_> = interned
+            .iter()
+            .flat_map(|i| i.debug_annotation_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(annotations, ["module_path", "message"]);
+        assert!(
+            interned
+                .iter()
+                .flat_map(|i| i.source_locations.iter())
+                .any(|l| l.file_name() == "src/cache.rs" && l.line_number() == 42)
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        let (first, second) = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("long").entered();
+            tracing::info!("before");
+            let first = layer.rotate();
+            tracing::info!("after");
+            drop(span);
+            (first, layer.rotate())
+        });
+
+        for (segment, expected) in [(first, ["long", "event"]), (second, ["event", "long"])] {
+            let trace = parse(&segment);
+            let names: Vec<_> = trace
+                .packet
+                .iter()
+                .filter_map(|p| p.interned_data.as_ref())
+                .flat_map(|i| i.event_names.iter())
+                .map(|n| n.name().split(' ').next().unwrap().to_string())
+                .collect();
+            assert_eq!(names, expected);
+            assert!(trace.packet.iter().any(|p| p.has_track_descriptor()));
+        }
+    }
+
+    #[test]
+    fn test_rotate_to_sink() {
+        let sink = SharedBuf::default();
+        let layer = PerfettoLayer::builder().sink(sink.clone()).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("long").entered();
+            layer.rotate_to_sink().unwrap();
+        });
+
+        let first = sink.0.lock().unwrap().clone();
+        let trace = parse(&first);
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+        let rest = layer.flush().unwrap();
+        let trace = parse(&rest);
+        assert_eq!(trace.packet[0].sequence_flags() & 1, 1);
+        assert!(trace.packet.iter().any(|p| p.has_track_event()));
+    }
+
+    #[test]
+    fn test_scope_field() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("deploy").entered();
+            tracing::info!(perfetto.scope = "global", "deploy started");
+            tracing::info!("step");
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let global = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().name() == "Global")
+            .unwrap()
+            .track_descriptor()
+            .uuid();
+        let instants: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .filter(|e| e.type_() == perfetto_protos::track_event::track_event::Type::TYPE_INSTANT)
+            .map(|e| (e.track_uuid() == global, e.debug_annotations.len()))
+            .collect();
+        assert_eq!(instants, [(true, 1), (false, 1)]);
+    }
+
+    #[test]
+    fn test_callsite_backtraces() {
+        let layer = PerfettoLayer::builder().callsite_backtraces(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let _span = tracing::info_span!("work").entered();
+                tracing::info!("tick");
+            }
+        });
+        assert_eq!(layer.backtraces.len(), 2);
+
+        let trace = parse(&layer.flush().unwrap());
+        let backtraces: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .map(|s| String::from_utf8_lossy(s.str()).into_owned())
+            .filter(|s| s.contains("test_callsite_backtraces"))
+            .collect();
+        assert_eq!(backtraces.len(), 2);
+        let annotated = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .filter(|p| !p.track_event().debug_annotations.is_empty())
+            .count();
+        assert_eq!(annotated, 6);
+    }
+
+    #[test]
+    fn test_follows_from_draws_flow() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let spawner = tracing::info_span!("spawner").entered();
+            let task = tracing::info_span!(parent: None, "task");
+            task.follows_from(spawner.id());
+            task.in_scope(|| {});
+            task.in_scope(|| {});
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let instants: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .filter(|e| e.type_() == perfetto_protos::track_event::track_event::Type::TYPE_INSTANT)
+            .collect();
+        assert_eq!(instants.len(), 2);
+        assert_eq!(instants[0].flow_ids.len(), 1);
+        assert_eq!(instants[1].terminating_flow_ids, instants[0].flow_ids);
+    }
+
+    #[test]
+    fn test_follows_from_running_span() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request");
+            let batch = tracing::info_span!(parent: None, "batch").entered();
+            tracing::Span::current().follows_from(&request);
+            drop(batch);
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let started: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == perfetto_protos::track_event::track_event::Type::TYPE_INSTANT)
+            .flat_map(|e| e.flow_ids.iter().copied())
+            .collect();
+        let terminated: Vec<_> = events
+            .iter()
+            .flat_map(|e| e.terminating_flow_ids.iter().copied())
+            .collect();
+        assert_eq!(started.len(), 1);
+        assert_eq!(terminated, started);
+    }
+
+    #[test]
+    fn test_late_fields_on_end_event() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("query", rows = tracing::field::Empty);
+            span.record("rows", 12);
+            span.record("rows", 1234);
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let end = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .find(|e| e.type_() == perfetto_protos::track_event::track_event::Type::TYPE_SLICE_END)
+            .unwrap();
+        assert_eq!(end.debug_annotations.len(), 1);
+        assert!(annotation_values(&trace).contains(&"1234".to_string()));
+        assert!(!annotation_values(&trace).contains(&"12".to_string()));
+    }
+
+    #[test]
+    fn test_separate_event_tracks() {
+        let layer = PerfettoLayer::builder().separate_event_tracks(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("work").entered();
+            tracing::info!("first");
+            tracing::info!("second");
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let logs: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor() && p.track_descriptor().name() == "logs")
+            .map(|p| p.track_descriptor())
+            .collect();
+        assert_eq!(logs.len(), 1);
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let begin = events[0];
+        assert_eq!(logs[0].parent_uuid(), begin.track_uuid());
+        let instants: Vec<_> = events
+            .iter()
+            .filter(|e| e.type_() == perfetto_protos::track_event::track_event::Type::TYPE_INSTANT)
+            .map(|e| e.track_uuid())
+            .collect();
+        assert_eq!(instants, [logs[0].uuid(), logs[0].uuid()]);
+    }
+
+    #[test]
+    fn test_event_tracks_are_described_after_rotate() {
+        let layer = PerfettoLayer::builder().separate_event_tracks(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        let second = tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("work").entered();
+            tracing::info!("first");
+            layer.rotate();
+            tracing::info!("second");
+            layer.rotate()
+        });
+
+        let trace = parse(&second);
+        let logs: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor() && p.track_descriptor().name() == "logs")
+            .map(|p| p.track_descriptor().uuid())
+            .collect();
+        let instant = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_event())
+            .map(|p| p.track_event().track_uuid())
+            .unwrap();
+        assert!(logs.contains(&instant));
+    }
+
+    #[test]
+    fn test_log_messages() {
+        let layer = PerfettoLayer::builder().log_messages(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("work").entered();
+            tracing::warn!(attempt = 2, "retrying");
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let bodies: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.log_message_body.iter())
+            .map(|b| b.body().to_string())
+            .collect();
+        assert_eq!(bodies, ["retrying"]);
+        let message = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .find_map(|p| p.track_event().log_message.clone().into_option())
+            .unwrap();
+        assert_eq!(message.prio(), LogPriority::PRIO_WARN);
+        assert!(message.has_source_location_iid());
+    }
+
+    #[test]
+    fn test_link_template() {
+        let layer = PerfettoLayer::builder()
+            .link_template("request_id", "https://grafana.example/explore?id={}")
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("handle", request_id = "r-42").entered();
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        assert_eq!(
+            annotation_values(&trace),
+            ["https://grafana.example/explore?id=r-42"]
+        );
+    }
+
+    #[test]
+    fn test_events_named_after_their_callsite() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("work").entered();
+            for user in 0..3 {
+                tracing::info!(rows = 3, "user {user} logged in");
+            }
+        });
+
+        let trace = parse(&layer.flush().unwrap());
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[1].starts_with("event ") && names[1].contains("lib.rs:"));
+        let values = annotation_values(&trace);
+        assert!(values.contains(&"user 2 logged in".to_string()));
+    }
+
+    #[test]
+    fn test_forked_child_forgets_the_parents_sink() {
+        let sink = SharedBuf::default();
+        let layer = PerfettoLayer::builder().sink(sink.clone()).build();
+        layer.lock().reinit_after_fork();
+        layer.flush_to_sink().unwrap();
+        assert!(sink.0.lock().unwrap().is_empty());
+        assert!(!layer.lock().has_sinks());
+    }
+
+    #[test]
+    fn test_shared_context_writes_into_the_layers_trace() {
+        let layer = PerfettoLayer::new();
+        {
+            let context = layer.shared_context();
+            let mut context = context.lock().unwrap();
+            let track = context.create_track("Metrics");
+            context
+                .event()
+                .with_counter()
+                .with_now()
+                .with_track_uuid(track)
+                .with_counter_value(3)
+                .build();
+        }
+
+        let trace = parse(&layer.flush().unwrap());
+        assert!(trace.packet.iter().any(|p| p.has_track_event()
+            && p.track_event().type_()
+                == perfetto_protos::track_event::track_event::Type::TYPE_COUNTER));
+    }
+
+    #[test]
+    fn test_record_exit() {
+        let sink = SharedBuf::default();
+        let layer = PerfettoLayer::builder().sink(sink.clone()).build();
+        layer.record_exit(0).unwrap();
+
+        let trace = parse(&sink.0.lock().unwrap());
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["process exit"]);
+    }
+
     #[test]
     fn test_layer_with_subscriber() {
         let layer = PerfettoLayer::new();
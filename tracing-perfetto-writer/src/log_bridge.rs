@@ -0,0 +1,45 @@
+use tracing_log::{LogTracer, log::SetLoggerError};
+
+/// Installs a global `log` logger that forwards `log!` records to
+/// `tracing`, so a [`PerfettoLayer`](crate::PerfettoLayer) records them
+/// alongside spans and events. Records keep their own target, file and line,
+/// and those logged outside any span land on the calling thread's track.
+///
+/// Fails if another `log` logger is already installed.
+pub fn init_log_bridge() -> Result<(), SetLoggerError> {
+    LogTracer::init()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PerfettoLayer;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn log_records_reach_the_trace() {
+        init_log_bridge().unwrap();
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            log::warn!(target: "app::db", "slow query");
+            let _span = tracing::info_span!("request").entered();
+            log::info!(target: "app::db", "connected");
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["app::db: slow query", "request", "app::db: connected"]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use perfetto_writer::Context;
+use std::{
+    sync::{
+        Condvar, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering::Relaxed},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{Error, PerfettoLayer};
+
+const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a [`PerfettoLayer`] does when the buffer reaches
+/// [`max_buffered_packets`](crate::PerfettoLayerBuilder::max_buffered_packets) or
+/// [`max_buffered_bytes`](crate::PerfettoLayerBuilder::max_buffered_bytes) and
+/// flushing to the sink did not free any room.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the new span or event, keeping the start of the recording.
+    #[default]
+    DropNewest,
+    /// Evicts the oldest buffered events, keeping the most recent window
+    /// like a flight recorder. Slices are evicted together with their end,
+    /// and slices still open are kept, so no slice is left half recorded.
+    /// Interned names and values are kept too, so once they alone fill the
+    /// buffer new events are dropped instead.
+    DropOldest,
+    /// Blocks the recording thread until another thread flushes, so nothing
+    /// is lost at the cost of stalling producers. If no flush comes within
+    /// the [`block_timeout`](crate::PerfettoLayerBuilder::block_timeout),
+    /// the new span or event is dropped as with `DropNewest`.
+    Block,
+}
+
+/// How often each [`OverflowPolicy`] kicked in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowStats {
+    pub dropped_newest: u64,
+    pub evicted_oldest: u64,
+    pub blocked: u64,
+    pub blocked_for: Duration,
+}
+
+/// Overflow counters shared by every clone of a layer, and the condition
+/// blocked producers wait on.
+#[derive(Default)]
+pub(crate) struct Overflow {
+    drained: Condvar,
+    dropped_newest: AtomicU64,
+    evicted_oldest: AtomicU64,
+    blocked: AtomicU64,
+    blocked_nanos: AtomicU64,
+}
+
+impl PerfettoLayer {
+    pub fn overflow_stats(&self) -> OverflowStats {
+        OverflowStats {
+            dropped_newest: self.overflow.dropped_newest.load(Relaxed),
+            evicted_oldest: self.overflow.evicted_oldest.load(Relaxed),
+            blocked: self.overflow.blocked.load(Relaxed),
+            blocked_for: Duration::from_nanos(self.overflow.blocked_nanos.load(Relaxed)),
+        }
+    }
+
+    /// Called whenever the buffer was emptied, waking blocked producers.
+    pub(crate) fn drained(&self) {
+        self.overflowed.store(false, Relaxed);
+        self.overflow.drained.notify_all();
+    }
+
+    /// Applies the overflow policy to a full buffer. Returns the context if
+    /// the new span or event can be recorded.
+    pub(crate) fn make_room<'a>(
+        &self,
+        mut context: MutexGuard<'a, Context>,
+    ) -> Option<MutexGuard<'a, Context>> {
+        match self.config.overflow_policy {
+            OverflowPolicy::DropOldest => {
+                while self.over_limit(&context) && context.evict_oldest() {
+                    self.overflow.evicted_oldest.fetch_add(1, Relaxed);
+                }
+                if !self.over_limit(&context) {
+                    return Some(context);
+                }
+            }
+            OverflowPolicy::Block => {
+                let start = Instant::now();
+                let timeout = self.config.block_timeout.unwrap_or(DEFAULT_BLOCK_TIMEOUT);
+                self.overflow.blocked.fetch_add(1, Relaxed);
+                while self.over_limit(&context) {
+                    let Some(left) = timeout.checked_sub(start.elapsed()) else {
+                        break;
+                    };
+                    context = self
+                        .overflow
+                        .drained
+                        .wait_timeout(context, left)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                let blocked = start.elapsed().as_nanos() as u64;
+                self.overflow.blocked_nanos.fetch_add(blocked, Relaxed);
+                if !self.over_limit(&context) {
+                    return Some(context);
+                }
+            }
+            OverflowPolicy::DropNewest => {}
+        }
+        context.record_dropped(1);
+        self.overflow.dropped_newest.fetch_add(1, Relaxed);
+        if !self.overflowed.swap(true, Relaxed) {
+            (self.on_error)(Error::BufferFull);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use std::thread;
+    use tracing_subscriber::prelude::*;
+
+    fn record(layer: &PerfettoLayer, spans: usize) {
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..spans {
+                let _span = tracing::info_span!("work").entered();
+            }
+        });
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_events() {
+        let layer = PerfettoLayer::builder()
+            .max_buffered_packets(16)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .build();
+        record(&layer, 50);
+
+        let stats = layer.overflow_stats();
+        assert!(stats.evicted_oldest > 0);
+        assert_eq!(stats.dropped_newest, 0);
+        // Each eviction takes a slice's begin and end.
+        assert_eq!(layer.stats().dropped_packets, 2 * stats.evicted_oldest);
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let begins = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().type_() == Type::TYPE_SLICE_BEGIN)
+            .count();
+        let ends = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().type_() == Type::TYPE_SLICE_END)
+            .count();
+        assert_eq!(begins, ends);
+    }
+
+    #[test]
+    fn exit_records_respect_the_limit() {
+        let layer = PerfettoLayer::builder().max_buffered_packets(16).build();
+        record(&layer, 50);
+        let dropped = layer.overflow_stats().dropped_newest;
+        layer.record_exit(0).unwrap();
+        assert_eq!(layer.overflow_stats().dropped_newest, dropped + 1);
+    }
+
+    #[test]
+    fn block_waits_for_a_flush() {
+        let layer = PerfettoLayer::builder()
+            .max_buffered_packets(16)
+            .overflow_policy(OverflowPolicy::Block)
+            .build();
+        let recorder = {
+            let layer = layer.clone();
+            thread::spawn(move || record(&layer, 50))
+        };
+
+        while !recorder.is_finished() {
+            layer.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        recorder.join().unwrap();
+
+        let stats = layer.overflow_stats();
+        assert!(stats.blocked > 0);
+        assert_eq!(stats.dropped_newest, 0);
+        assert_eq!(layer.stats().dropped_packets, 0);
+    }
+
+    #[test]
+    fn block_gives_up_without_a_flush() {
+        let layer = PerfettoLayer::builder()
+            .max_buffered_packets(16)
+            .overflow_policy(OverflowPolicy::Block)
+            .block_timeout(Duration::from_millis(1))
+            .build();
+        record(&layer, 50);
+
+        let stats = layer.overflow_stats();
+        assert!(stats.blocked > 0);
+        assert!(stats.dropped_newest > 0);
+        assert_eq!(layer.stats().dropped_packets, stats.dropped_newest);
+    }
+}
@@ -0,0 +1,179 @@
+use dashmap::DashMap;
+use perfetto_writer::Context;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::{PerfettoLayer, TrackId};
+
+/// Name of the slices written for each poll of a span.
+const POLL_SLICE: &str = "poll";
+
+/// The thread tracks of a span's open poll slices, innermost last, since a
+/// span may be entered again before it exits.
+#[derive(Debug, Default)]
+struct OpenPolls(Vec<TrackId>);
+
+/// Marks a span recorded on a track of its own, returned to the pool when
+/// the span closes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AsyncTrack(pub(crate) &'static str);
+
+/// Tracks of closed spans, by span name, for reuse by new spans of the same
+/// name so long-running services don't create a track per future.
+pub(crate) type AsyncTracks = DashMap<&'static str, Vec<u64>>;
+
+impl PerfettoLayer {
+    /// A track of its own for a span called `name`, so concurrent futures
+    /// on one thread don't break each other's nesting.
+    pub(crate) fn async_track(&self, context: &mut Context, name: &'static str) -> TrackId {
+        let free = self
+            .async_tracks
+            .get_mut(name)
+            .and_then(|mut free| free.pop());
+        free.unwrap_or_else(|| context.create_track(name)).into()
+    }
+
+    /// Returns the track of a closed span to the pool.
+    pub(crate) fn release_async_track(&self, AsyncTrack(name): AsyncTrack, track: TrackId) {
+        self.async_tracks.entry(name).or_default().push(track.0);
+    }
+
+    /// Begins a poll slice for a recorded span on the executing thread.
+    pub(crate) fn begin_poll<S>(&self, span: &SpanRef<'_, S>)
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        if !self.config.poll_slices || span.extensions().get::<TrackId>().is_none() {
+            return;
+        }
+        let Some(mut context) = self.writable() else {
+            return;
+        };
+        let track = self.thread_track(&mut context);
+        context
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track.into())
+            .with_name(POLL_SLICE)
+            .build();
+        let mut exe = span.extensions_mut();
+        match exe.get_mut::<OpenPolls>() {
+            Some(open) => open.0.push(track),
+            None => exe.insert(OpenPolls(vec![track])),
+        }
+    }
+
+    /// Ends the span's open poll slice, if any.
+    pub(crate) fn end_poll<S>(&self, span: &SpanRef<'_, S>)
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let track = span
+            .extensions_mut()
+            .get_mut::<OpenPolls>()
+            .and_then(|open| open.0.pop());
+        let Some(track) = track else {
+            return;
+        };
+        self.lock()
+            .event()
+            .with_end()
+            .with_now()
+            .with_track_uuid(track.into())
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::{trace::Trace, track_event::track_event::Type};
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn each_enter_writes_a_poll_slice() {
+        let layer = PerfettoLayer::builder().poll_slices(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("task");
+            for _ in 0..3 {
+                let _entered = span.enter();
+            }
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let types: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().type_())
+            .collect();
+        let begins = types
+            .iter()
+            .filter(|t| **t == Type::TYPE_SLICE_BEGIN)
+            .count();
+        let ends = types.iter().filter(|t| **t == Type::TYPE_SLICE_END).count();
+        assert_eq!(begins, 4);
+        assert_eq!(ends, 4);
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert_eq!(names, ["task", POLL_SLICE]);
+    }
+
+    #[test]
+    fn reentered_spans_nest_their_polls_on_the_thread_track() {
+        let layer = PerfettoLayer::builder().poll_slices(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("task");
+            let _outer = span.enter();
+            let _inner = span.enter();
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (p.track_event().type_(), p.track_event().track_uuid()))
+            .collect();
+        let task = tracks[0].1;
+        let polls: Vec<_> = tracks.iter().filter(|(_, t)| *t != task).collect();
+        assert_eq!(polls.len(), 4);
+        let thread = polls[0].1;
+        assert!(polls.iter().all(|(_, t)| *t == thread));
+        let task_track = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().uuid() == task)
+            .unwrap()
+            .track_descriptor();
+        assert_eq!(task_track.name(), "task");
+        assert!(task_track.thread.is_none());
+    }
+
+    #[test]
+    fn closed_spans_give_back_their_track() {
+        let layer = PerfettoLayer::builder().poll_slices(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let _entered = tracing::info_span!("task").entered();
+            }
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let tasks = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor() && p.track_descriptor().name() == "task")
+            .count();
+        assert_eq!(tasks, 1);
+    }
+}
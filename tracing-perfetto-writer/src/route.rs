@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use tracing::Metadata;
+use tracing_subscriber::registry::Extensions;
+
+use crate::PerfettoLayer;
+
+/// Identifies the layer, among a layer and its routes, that recorded a
+/// span, since they share the span's extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Owner(usize);
+
+impl PerfettoLayer {
+    /// The route whose target prefix matches `meta`, if any.
+    pub(crate) fn route(&self, meta: &Metadata<'_>) -> Option<&PerfettoLayer> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| meta.target().starts_with(prefix.as_str()))
+            .map(|(_, layer)| layer)
+    }
+
+    pub(crate) fn owner(&self) -> Owner {
+        Owner(Arc::as_ptr(&self.context) as usize)
+    }
+
+    /// Whether this layer recorded the span with extensions `exe`.
+    pub(crate) fn owns(&self, exe: &Extensions<'_>) -> bool {
+        exe.get::<Owner>() == Some(&self.owner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PerfettoLayer;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    fn names(buf: &[u8]) -> Vec<String> {
+        Trace::parse_from_bytes(buf)
+            .unwrap()
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn targets_are_split_between_layers() {
+        let io = PerfettoLayer::new();
+        let layer = PerfettoLayer::builder().route("io", io.clone()).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = tracing::info_span!("request").entered();
+            let _read = tracing::info_span!(target: "io::disk", "read").entered();
+            tracing::info!(name: "page fault", target: "io::disk", "page fault");
+            tracing::info!(name: "parsed", "parsed");
+        });
+
+        assert_eq!(names(&layer.flush().unwrap()), ["request", "parsed"]);
+        let io = io.flush().unwrap();
+        assert_eq!(names(&io), ["read", "page fault"]);
+        let trace = Trace::parse_from_bytes(&io).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor().uuid())
+            .collect();
+        assert!(
+            trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event())
+                .all(|p| tracks.contains(&p.track_event().track_uuid()))
+        );
+        // "read" only flows from its own slice, not from "request", which
+        // is in the other trace.
+        let flows: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().flow_ids.len())
+            .collect();
+        assert_eq!(flows[0], 1);
+    }
+}
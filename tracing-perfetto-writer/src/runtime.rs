@@ -0,0 +1,147 @@
+use perfetto_writer::{Context, CounterUnit};
+use std::time::{Duration, Instant};
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use crate::PerfettoLayer;
+
+/// Counter tracks fed from `tokio::runtime::RuntimeMetrics`.
+#[derive(Clone, Copy)]
+struct RuntimeTracks {
+    busy_workers: u64,
+    global_queue_depth: u64,
+    alive_tasks: u64,
+    parks: u64,
+    #[cfg(tokio_unstable)]
+    blocking_threads: u64,
+}
+
+impl RuntimeTracks {
+    fn new(ctx: &mut Context) -> Self {
+        let mut counter = |name: &str, unit| ctx.track().name(name).counter().unit(unit).build();
+        Self {
+            busy_workers: counter("tokio.busy_workers", CounterUnit::UNIT_COUNT),
+            global_queue_depth: counter("tokio.global_queue_depth", CounterUnit::UNIT_COUNT),
+            alive_tasks: counter("tokio.alive_tasks", CounterUnit::UNIT_COUNT),
+            parks: counter("tokio.parks", CounterUnit::UNIT_COUNT),
+            #[cfg(tokio_unstable)]
+            blocking_threads: counter("tokio.blocking_threads", CounterUnit::UNIT_COUNT),
+        }
+    }
+}
+
+/// Busy time and park count summed over all workers.
+#[derive(Default)]
+struct WorkerTotals {
+    busy: Duration,
+    parks: u64,
+}
+
+impl WorkerTotals {
+    fn read(handle: &Handle) -> Self {
+        let metrics = handle.metrics();
+        let mut totals = Self::default();
+        for worker in 0..metrics.num_workers() {
+            totals.busy += metrics.worker_total_busy_duration(worker);
+            totals.parks += metrics.worker_park_count(worker);
+        }
+        totals
+    }
+}
+
+impl PerfettoLayer {
+    /// Spawns a task on the current tokio runtime that samples its scheduler
+    /// metrics every `interval` and writes them as `tokio.*` counter tracks:
+    /// the average number of busy workers, the global queue depth, alive
+    /// tasks and worker parks. Blocking threads are also recorded when built
+    /// with `--cfg tokio_unstable`. Abort the returned handle to stop.
+    ///
+    /// # Panics
+    ///
+    /// Panics when called outside a tokio runtime.
+    pub fn spawn_runtime_metrics(&self, interval: Duration) -> JoinHandle<()> {
+        let layer = self.clone();
+        let handle = Handle::current();
+        tokio::spawn(async move {
+            let mut tracks = None;
+            let mut last = (Instant::now(), WorkerTotals::read(&handle));
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = (Instant::now(), WorkerTotals::read(&handle));
+                let elapsed = now.0 - last.0;
+                let busy = (now.1.busy - last.1.busy).as_secs_f64() / elapsed.as_secs_f64();
+                let metrics = handle.metrics();
+                let Some(mut ctx) = layer.writable() else {
+                    last = now;
+                    continue;
+                };
+                let tracks = *tracks.get_or_insert_with(|| RuntimeTracks::new(&mut ctx));
+                ctx.event()
+                    .with_counter()
+                    .with_now()
+                    .with_track_uuid(tracks.busy_workers)
+                    .with_double_counter_value(busy)
+                    .build();
+                let counts = [
+                    (
+                        tracks.global_queue_depth,
+                        metrics.global_queue_depth() as u64,
+                    ),
+                    (tracks.alive_tasks, metrics.num_alive_tasks() as u64),
+                    (tracks.parks, now.1.parks - last.1.parks),
+                    #[cfg(tokio_unstable)]
+                    (
+                        tracks.blocking_threads,
+                        metrics.num_blocking_threads() as u64,
+                    ),
+                ];
+                for (track, value) in counts {
+                    ctx.event()
+                        .with_counter()
+                        .with_now()
+                        .with_track_uuid(track)
+                        .with_counter_value(value as i64)
+                        .build();
+                }
+                last = now;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+
+    #[test]
+    fn scheduler_metrics_are_sampled() {
+        let layer = PerfettoLayer::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let sampler = layer.spawn_runtime_metrics(Duration::from_millis(5));
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            sampler.abort();
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| p.track_descriptor())
+            .filter(|t| t.name().starts_with("tokio."))
+            .map(|t| t.uuid())
+            .collect();
+        assert!(tracks.len() >= 4);
+        let samples = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && tracks.contains(&p.track_event().track_uuid()))
+            .count();
+        assert!(samples >= tracks.len());
+    }
+}
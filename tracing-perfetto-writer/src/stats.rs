@@ -0,0 +1,281 @@
+use perfetto_writer::{Context, CounterUnit};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::PoisonError,
+    time::Duration,
+};
+use tracing::{Metadata, callsite::Identifier};
+use web_time::Instant;
+
+use crate::PerfettoLayer;
+
+pub(crate) const DEFAULT_STATISTICS_INTERVAL: Duration = Duration::from_secs(1);
+
+const STATS: [(&str, CounterUnit); 5] = [
+    ("count", CounterUnit::UNIT_COUNT),
+    ("mean", CounterUnit::UNIT_TIME_NS),
+    ("p50", CounterUnit::UNIT_TIME_NS),
+    ("p99", CounterUnit::UNIT_TIME_NS),
+    ("max", CounterUnit::UNIT_TIME_NS),
+];
+
+/// Marks a span that is aggregated instead of recorded.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StatsStart(pub(crate) Instant);
+
+/// Streaming histogram of durations with power-of-two buckets.
+#[derive(Debug, Clone)]
+pub(crate) struct Histogram {
+    count: u64,
+    sum_ns: u64,
+    max_ns: u64,
+    buckets: [u64; 64],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum_ns: 0,
+            max_ns: 0,
+            buckets: [0; 64],
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, ns: u64) {
+        self.count += 1;
+        self.sum_ns = self.sum_ns.saturating_add(ns);
+        self.max_ns = self.max_ns.max(ns);
+        let bucket = (64 - ns.leading_zeros() as usize).min(63);
+        self.buckets[bucket] += 1;
+    }
+
+    fn mean(&self) -> u64 {
+        self.sum_ns.checked_div(self.count).unwrap_or_default()
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile.
+    fn quantile(&self, q: f64) -> u64 {
+        let target = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                let upper = if bucket == 0 { 0 } else { (1u64 << bucket) - 1 };
+                return upper.min(self.max_ns);
+            }
+        }
+        self.max_ns
+    }
+
+    fn values(&self) -> [u64; 5] {
+        [
+            self.count,
+            self.mean(),
+            self.quantile(0.5),
+            self.quantile(0.99),
+            self.max_ns,
+        ]
+    }
+}
+
+/// Aggregates kept for spans in statistics-only targets, per callsite.
+#[derive(Debug, Default)]
+pub(crate) struct Statistics {
+    histograms: HashMap<Identifier, (&'static Metadata<'static>, Histogram)>,
+    tracks: HashMap<Identifier, [u64; 5]>,
+    /// Span names that already have tracks, to tell callsites apart.
+    names: HashSet<&'static str>,
+    last_emit: Option<Instant>,
+}
+
+impl PerfettoLayer {
+    pub(crate) fn statistics_only(&self, meta: &Metadata<'_>) -> bool {
+        self.config
+            .statistics_targets
+            .iter()
+            .any(|target| meta.target().starts_with(target.as_str()))
+    }
+
+    pub(crate) fn record_statistic(&self, meta: &'static Metadata<'static>, elapsed: Duration) {
+        let interval = self
+            .config
+            .statistics_interval
+            .unwrap_or(DEFAULT_STATISTICS_INTERVAL);
+        let due = {
+            let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+            stats
+                .histograms
+                .entry(meta.callsite())
+                .or_insert_with(|| (meta, Histogram::default()))
+                .1
+                .record(elapsed.as_nanos() as u64);
+            let last = *stats.last_emit.get_or_insert_with(Instant::now);
+            last.elapsed() >= interval
+        };
+        if due {
+            self.emit_statistics();
+        }
+    }
+
+    /// Writes the count, mean, p50, p99 and max duration of every
+    /// statistics-only span callsite seen since the last emission to
+    /// counter tracks named e.g. "query p99", then starts a new interval.
+    /// Spans of the same name from another callsite get their location in
+    /// the track names, e.g. "query (src/db.rs:42) p99". Called
+    /// automatically once per statistics interval, and before flushing or
+    /// rotating.
+    pub fn emit_statistics(&self) {
+        let histograms = {
+            let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+            stats.last_emit = Some(Instant::now());
+            std::mem::take(&mut stats.histograms)
+        };
+        if histograms.is_empty() {
+            return;
+        }
+        let mut context = self.lock();
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        let stats = &mut *stats;
+        for (callsite, (meta, histogram)) in histograms {
+            let tracks = *stats.tracks.entry(callsite).or_insert_with(|| {
+                let name = match stats.names.insert(meta.name()) {
+                    true => meta.name().to_string(),
+                    false => format!(
+                        "{} ({}:{})",
+                        meta.name(),
+                        meta.file().unwrap_or("?"),
+                        meta.line().unwrap_or_default()
+                    ),
+                };
+                stat_tracks(&mut context, &name)
+            });
+            for (track, value) in tracks.into_iter().zip(histogram.values()) {
+                context
+                    .event()
+                    .with_counter()
+                    .with_now()
+                    .with_track_uuid(track)
+                    .with_counter_value(value as i64)
+                    .build();
+            }
+        }
+    }
+}
+
+fn stat_tracks(context: &mut Context, name: &str) -> [u64; 5] {
+    STATS.map(|(stat, unit)| {
+        context
+            .track()
+            .name(format!("{} {}", name, stat))
+            .counter()
+            .unit(unit)
+            .build()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        for ns in 1..=100 {
+            histogram.record(ns * 1000);
+        }
+        let [count, mean, p50, p99, max] = histogram.values();
+        assert_eq!(count, 100);
+        assert_eq!(mean, 50_500);
+        assert_eq!(max, 100_000);
+        assert!((50_000..=65_535).contains(&p50));
+        assert!((99_000..=100_000).contains(&p99));
+    }
+
+    #[test]
+    fn hot_spans_are_aggregated() {
+        let layer = PerfettoLayer::builder()
+            .statistics_only("hot")
+            .statistics_interval(Duration::from_secs(3600))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("request").entered();
+            for _ in 0..1000 {
+                let _hot = tracing::info_span!(target: "hot::path", "lookup").entered();
+            }
+        });
+        layer.emit_statistics();
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let slices = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && !p.track_event().has_counter_value())
+            .count();
+        assert_eq!(slices, 2);
+
+        let tracks: HashMap<_, _> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| (p.track_descriptor().uuid(), p.track_descriptor().name()))
+            .collect();
+        let counters: HashMap<_, _> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().has_counter_value())
+            .map(|p| {
+                let e = p.track_event();
+                (tracks[&e.track_uuid()], e.counter_value())
+            })
+            .collect();
+        assert_eq!(counters.len(), 5);
+        assert_eq!(counters["lookup count"], 1000);
+        assert!(counters["lookup p99"] <= counters["lookup max"]);
+    }
+
+    #[test]
+    fn callsites_are_kept_apart_and_flushed() {
+        let layer = PerfettoLayer::builder()
+            .statistics_only("hot")
+            .statistics_interval(Duration::from_secs(3600))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let _hot = tracing::info_span!(target: "hot::a", "lookup").entered();
+            }
+            let _hot = tracing::info_span!(target: "hot::b", "lookup").entered();
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let tracks: HashMap<_, _> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .map(|p| (p.track_descriptor().uuid(), p.track_descriptor().name()))
+            .collect();
+        let mut counts: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| (tracks[&p.track_event().track_uuid()], p.track_event()))
+            .filter(|(name, _)| name.ends_with(" count"))
+            .map(|(name, e)| (name, e.counter_value()))
+            .collect();
+        // Either callsite may be the first to claim the plain name.
+        counts.sort_by_key(|(name, _)| name.len());
+        assert_eq!(counts.len(), 2, "{counts:?}");
+        assert_eq!(counts[0].0, "lookup count");
+        assert!(counts[1].0.starts_with("lookup (") && counts[1].0.contains("stats.rs:"));
+        let mut values = [counts[0].1, counts[1].1];
+        values.sort();
+        assert_eq!(values, [1, 3]);
+    }
+}
@@ -0,0 +1,309 @@
+use dashmap::DashMap;
+use smol_str::SmolStr;
+use std::{
+    sync::{Mutex, PoisonError},
+    thread::{self, ThreadId},
+};
+use tracing::{field::Visit, span};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::{PerfettoLayer, TrackId};
+
+/// A span Bevy emits around its systems and schedules, named by its `name`
+/// field.
+#[derive(Debug, Clone)]
+pub(crate) enum BevySpan {
+    /// Created once per system and entered on every run.
+    System(SmolStr),
+    Schedule(SmolStr),
+}
+
+/// The tracks of a system's runs in progress, innermost last, since a
+/// system span may be entered again before it exits.
+#[derive(Debug, Default)]
+struct OpenRuns(Vec<TrackId>);
+
+/// The schedules running now, and the per-thread tracks of their systems.
+#[derive(Default)]
+pub(crate) struct Schedules {
+    running: Mutex<Vec<(span::Id, ThreadId, SmolStr)>>,
+    tracks: DashMap<(u64, SmolStr), u64>,
+}
+
+#[derive(Default)]
+struct NameField(Option<String>);
+
+impl Visit for NameField {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "name" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "name" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Drops the module paths of a type name, e.g. `game::move_player` becomes
+/// `move_player`, including inside generics.
+fn short_name(name: &str) -> SmolStr {
+    let mut short = String::with_capacity(name.len());
+    let mut segment = 0;
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            short.truncate(segment);
+        } else {
+            short.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                segment = short.len();
+            }
+        }
+    }
+    short.into()
+}
+
+impl PerfettoLayer {
+    /// Recognizes the system and schedule spans of Bevy.
+    pub(crate) fn bevy_span(&self, attrs: &span::Attributes<'_>) -> Option<BevySpan> {
+        if !self.config.bevy_systems {
+            return None;
+        }
+        let kind = attrs.metadata().name();
+        if !matches!(kind, "system" | "system_commands" | "schedule") {
+            return None;
+        }
+        let mut name = NameField::default();
+        attrs.record(&mut name);
+        let name = short_name(&name.0?);
+        Some(match kind {
+            "system" => BevySpan::System(name),
+            "system_commands" => BevySpan::System(format!("{name} (commands)").into()),
+            _ => BevySpan::Schedule(name),
+        })
+    }
+
+    /// Begins a slice for a run of a Bevy system, on a track of the running
+    /// schedule under the thread's track, or notes that a schedule started.
+    /// Returns false for other spans.
+    pub(crate) fn enter_bevy_span<S>(&self, id: &span::Id, span: &SpanRef<'_, S>) -> bool
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let name = match span.extensions().get::<BevySpan>() {
+            Some(BevySpan::System(name)) => name.clone(),
+            Some(BevySpan::Schedule(name)) => {
+                self.schedules
+                    .running
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((id.clone(), thread::current().id(), name.clone()));
+                return false;
+            }
+            None => return false,
+        };
+        let Some(mut context) = self.writable() else {
+            return true;
+        };
+        let thread = self.thread_track(&mut context);
+        let track = match self.running_schedule(span) {
+            Some(schedule) => *self
+                .schedules
+                .tracks
+                .entry((thread.0, schedule.clone()))
+                .or_insert_with(|| context.create_child_track(thread.0, schedule.as_str())),
+            None => thread.0,
+        };
+        context
+            .event()
+            .with_begin()
+            .with_now()
+            .with_track_uuid(track)
+            .with_category(span.metadata().level().as_str())
+            .with_name(name)
+            .build();
+        let mut exe = span.extensions_mut();
+        match exe.get_mut::<OpenRuns>() {
+            Some(open) => open.0.push(TrackId(track)),
+            None => exe.insert(OpenRuns(vec![TrackId(track)])),
+        }
+        // Events inside the run go on its track.
+        exe.replace(TrackId(track));
+        true
+    }
+
+    /// Ends the slice of a Bevy system run. Returns false for other spans.
+    pub(crate) fn exit_bevy_span<S>(&self, id: &span::Id, span: &SpanRef<'_, S>) -> bool
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        match span.extensions().get::<BevySpan>() {
+            Some(BevySpan::System(_)) => {}
+            Some(BevySpan::Schedule(_)) => {
+                let mut running = self
+                    .schedules
+                    .running
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                running.retain(|(schedule, _, _)| schedule != id);
+                return false;
+            }
+            None => return false,
+        }
+        let mut exe = span.extensions_mut();
+        let open = exe.get_mut::<OpenRuns>();
+        let (track, outer) = match open {
+            Some(open) => (open.0.pop(), open.0.last().copied()),
+            None => (None, None),
+        };
+        match outer {
+            Some(outer) => exe.replace(outer),
+            None => exe.remove::<TrackId>(),
+        };
+        drop(exe);
+        if let Some(track) = track {
+            self.lock()
+                .event()
+                .with_end()
+                .with_now()
+                .with_track_uuid(track.into())
+                .build();
+        }
+        true
+    }
+
+    /// The schedule a system runs in: the schedule span it was created in,
+    /// or else the innermost schedule entered on this thread. Systems the
+    /// multithreaded executor runs on worker threads have neither and stay
+    /// on the thread's track.
+    fn running_schedule<S>(&self, span: &SpanRef<'_, S>) -> Option<SmolStr>
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let parent = span
+            .scope()
+            .skip(1)
+            .find_map(|s| match s.extensions().get() {
+                Some(BevySpan::Schedule(name)) => Some(name.clone()),
+                _ => None,
+            });
+        if parent.is_some() {
+            return parent;
+        }
+        let running = self
+            .schedules
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let current = thread::current().id();
+        running
+            .iter()
+            .rev()
+            .find(|(_, thread, _)| *thread == current)
+            .map(|(_, _, name)| name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[derive(Debug)]
+    struct Update;
+
+    #[test]
+    fn shortens_type_paths() {
+        assert_eq!(short_name("game::systems::move_player"), "move_player");
+        assert_eq!(
+            short_name("bevy_ecs::Pipe<game::a, game::b::c>"),
+            "Pipe<a, c>"
+        );
+        assert_eq!(short_name("Update"), "Update");
+    }
+
+    #[test]
+    fn system_runs_become_slices_on_a_schedule_track() {
+        let layer = PerfettoLayer::builder().bevy_systems(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let system = tracing::info_span!("system", name = "game::move_player");
+            for _ in 0..3 {
+                let _schedule = tracing::info_span!("schedule", name = ?Update).entered();
+                let _run = system.enter();
+                tracing::info!("moved");
+            }
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let update = trace
+            .packet
+            .iter()
+            .find(|p| p.has_track_descriptor() && p.track_descriptor().name() == "Update")
+            .unwrap()
+            .track_descriptor()
+            .uuid();
+        let on_update = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event() && p.track_event().track_uuid() == update)
+            .count();
+        assert_eq!(on_update, 3 * 3);
+        let names: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|p| p.interned_data.as_ref())
+            .flat_map(|i| i.event_names.iter())
+            .map(|n| n.name().to_string())
+            .collect();
+        assert!(names.contains(&"move_player".to_string()), "{names:?}");
+        assert!(!names.contains(&"system".to_string()), "{names:?}");
+    }
+
+    #[test]
+    fn reentered_systems_end_each_run() {
+        let layer = PerfettoLayer::builder().bevy_systems(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let system = tracing::info_span!("system", name = "game::move_player");
+            let _outer = system.enter();
+            let _inner = system.enter();
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let events = trace.packet.iter().filter(|p| p.has_track_event()).count();
+        assert_eq!(events, 4);
+    }
+
+    #[test]
+    fn systems_on_other_threads_keep_their_own_schedule() {
+        let layer = PerfettoLayer::builder().bevy_systems(true).build();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        let dispatch = tracing::Dispatch::new(subscriber);
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _schedule = tracing::info_span!("schedule", name = ?Update).entered();
+            thread::scope(|s| {
+                s.spawn(|| {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let system = tracing::info_span!(parent: None, "system", name = "render");
+                        let _run = system.enter();
+                    })
+                });
+            });
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        assert!(
+            !trace
+                .packet
+                .iter()
+                .any(|p| p.has_track_descriptor() && p.track_descriptor().name() == "Update")
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+/// Spawns `future` on the tokio runtime inside a new root "task" span that
+/// follows from the current span, so the layer draws a flow arrow from the
+/// spawn site to the task's first poll.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = tracing::info_span!(parent: None, "task");
+    span.follows_from(Span::current());
+    tokio::spawn(future.instrument(span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PerfettoLayer;
+    use perfetto_protos::trace::Trace;
+    use protobuf::Message;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn spawned_tasks_are_linked() {
+        let layer = PerfettoLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let _parent = tracing::info_span!("fan_out").entered();
+                let handles: Vec<_> = (0..3).map(|i| spawn(async move { i })).collect();
+                for handle in handles {
+                    handle.await.unwrap();
+                }
+            });
+        });
+
+        let trace = Trace::parse_from_bytes(&layer.flush().unwrap()).unwrap();
+        let events: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event())
+            .collect();
+        let started = events.iter().flat_map(|e| e.flow_ids.iter()).count();
+        let terminated = events
+            .iter()
+            .flat_map(|e| e.terminating_flow_ids.iter())
+            .count();
+        assert!(started >= 3);
+        assert_eq!(terminated, 3);
+    }
+}
@@ -0,0 +1,54 @@
+//! The panic hook is process-wide, so it is tested in a binary of its own
+//! where no other test can panic while it is installed.
+
+use perfetto_protos::trace::Trace;
+use protobuf::Message;
+use std::{
+    io::{self, Write},
+    panic,
+    sync::{Arc, Mutex},
+};
+use tracing_perfetto_writer::PerfettoLayer;
+
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn panics_are_recorded_and_flushed() {
+    let sink = Shared::default();
+    let layer = PerfettoLayer::builder().sink(sink.clone()).build();
+    layer.install_panic_hook();
+    let _ = panic::catch_unwind(|| panic!("boom"));
+    let _ = panic::take_hook();
+
+    let bytes = sink.0.lock().unwrap().clone();
+    let trace = Trace::parse_from_bytes(&bytes).unwrap();
+    let interned: Vec<_> = trace
+        .packet
+        .iter()
+        .filter_map(|p| p.interned_data.as_ref())
+        .collect();
+    assert!(
+        interned
+            .iter()
+            .flat_map(|i| i.event_names.iter())
+            .any(|n| n.name() == "panic")
+    );
+    assert!(
+        interned
+            .iter()
+            .flat_map(|i| i.debug_annotation_string_values.iter())
+            .any(|s| s.str() == b"boom")
+    );
+}